@@ -0,0 +1,112 @@
+// Benchmarks the per-read and per-position counts of a synthetic BAM file, written to the
+// temporary directory on the first run. Most reads are aligned without gaps, like short reads,
+// every tenth read has a deletion and an insertion.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+use bam::RecordWriter;
+use bampile::{Mode, PileupConfig};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+const CONTIG_LENGTH: u32 = 200_000;
+const READ_LENGTH: u32 = 150;
+const N_READS: u32 = 20_000;
+
+// Deterministic pseudo-random numbers, so the input files don't change between runs
+struct Xorshift(u64);
+
+impl Xorshift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn base(&mut self) -> u8 {
+        b"ACGT"[(self.next() % 4) as usize]
+    }
+}
+
+// Writes the FASTA, BAM and BED files unless they exist and returns their paths
+fn synthetic_input() -> (String, String, String) {
+    let dir = std::env::temp_dir().join("bampile-bench");
+    let path = |name: &str| -> PathBuf { dir.join(name) };
+    let (fasta_path, bam_path, bed_path) = (path("ref.fa"), path("reads.bam"), path("regions.bed"));
+    if !bam_path.exists() {
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut rng = Xorshift(0x2545_f491_4f6c_dd1d);
+        let reference: Vec<u8> = (0..CONTIG_LENGTH).map(|_| rng.base()).collect();
+
+        let mut fasta = File::create(&fasta_path).unwrap();
+        writeln!(fasta, ">chr1").unwrap();
+        for line in reference.chunks(60) {
+            fasta.write_all(line).unwrap();
+            writeln!(fasta).unwrap();
+        }
+
+        let mut header = bam::Header::new();
+        header.push_line("@HD\tVN:1.6\tSO:coordinate").unwrap();
+        header.push_line(&format!("@SQ\tSN:chr1\tLN:{}", CONTIG_LENGTH)).unwrap();
+        let mut starts: Vec<u32> = (0..N_READS)
+            .map(|_| (rng.next() % (CONTIG_LENGTH - READ_LENGTH - 10) as u64) as u32)
+            .collect();
+        starts.sort_unstable();
+        let mut writer = bam::BamWriter::build().write_header(true).from_path(&bam_path, header.clone()).unwrap();
+        let mut record = bam::Record::new();
+        for (i, &start) in starts.iter().enumerate() {
+            let s = start as usize;
+            let (cigar, mut sequence) = if i.is_multiple_of(10) {
+                ("70M2D1I79M", [&reference[s..s + 70], b"T", &reference[s + 72..s + 151]].concat())
+            } else {
+                ("150M", reference[s..s + READ_LENGTH as usize].to_vec())
+            };
+            // About one mismatch in a hundred bases
+            for base in sequence.iter_mut() {
+                if rng.next().is_multiple_of(100) {
+                    *base = rng.base();
+                }
+            }
+            let qualities: String = (0..sequence.len()).map(|_| (b'5' + (rng.next() % 21) as u8) as char).collect();
+            let line = format!(
+                "read{}\t{}\tchr1\t{}\t60\t{}\t*\t0\t0\t{}\t{}",
+                i,
+                if i.is_multiple_of(2) { 0 } else { 16 },
+                start + 1,
+                cigar,
+                String::from_utf8(sequence).unwrap(),
+                qualities
+            );
+            record.fill_from_sam(&line, &header).unwrap();
+            writer.write(&record).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut bed = File::create(&bed_path).unwrap();
+        for start in (0..CONTIG_LENGTH).step_by(10_000) {
+            writeln!(bed, "chr1\t{}\t{}\tinterval_{}", start, start + 5_000, start).unwrap();
+        }
+    }
+    let to_string = |path: PathBuf| path.to_str().unwrap().to_string();
+    (to_string(fasta_path), to_string(bam_path), to_string(bed_path))
+}
+
+fn bench_pileup(c: &mut Criterion) {
+    let (fasta_path, bam_path, bed_path) = synthetic_input();
+    let regions = bampile::load_bed_regions(&bed_path, false).unwrap();
+    let mut config = PileupConfig::new(&bam_path, &fasta_path, regions);
+    config.threads = 1;
+
+    let mut group = c.benchmark_group("pileup");
+    group.sample_size(20);
+    for (name, mode) in [("per-read", Mode::PerRead), ("per-position", Mode::PerPosition)] {
+        config.mode = mode;
+        group.bench_function(name, |b| b.iter(|| bampile::run(&config).unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_pileup);
+criterion_main!(benches);
@@ -0,0 +1,534 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+use crate::error::Error;
+use crate::BoxError;
+
+/// Strand of an interval, from the 6th BED column
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Strand {
+    /// `+`
+    Forward,
+    /// `-`
+    Reverse,
+    /// `.`, reads of both strands are counted
+    #[default]
+    Either,
+}
+
+impl Strand {
+    // Anything but `+` and `-` leaves an interval unstranded
+    fn from_column(column: &str) -> Strand {
+        match column.trim() {
+            "+" => Strand::Forward,
+            "-" => Strand::Reverse,
+            _ => Strand::Either,
+        }
+    }
+}
+
+impl fmt::Display for Strand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Strand::Forward => "+",
+            Strand::Reverse => "-",
+            Strand::Either => ".",
+        })
+    }
+}
+
+/// A single interval of a contig.
+///
+/// All intervals are stored 0-based and half-open ([start, end)), like in BED files. This is
+/// also what bam::Region, the FASTA IndexedReader::fetch and bam::Record::start() use, so the
+/// coordinates can be passed on unchanged. 1-based inputs (--region, --one-based) are
+/// converted when they are parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BedRegion {
+    /// Chromosome, the 1st BED column
+    pub ref_name: String,
+    /// First base, 0-based
+    pub start: u32,
+    /// One past the last base, 0-based
+    pub end: u32,
+    /// Name from the 4th BED column, if any
+    pub name: Option<String>,
+    /// Strand from the 6th BED column
+    pub strand: Strand,
+}
+
+impl BedRegion {
+    /// An unnamed interval on either strand
+    pub fn new(ref_name: &str, start: u32, end: u32) -> BedRegion {
+        BedRegion { ref_name: ref_name.to_string(), start, end, name: None, strand: Strand::Either }
+    }
+}
+
+/// Name of an interval in the output, its BED name or `chr:start-end` (1-based, inclusive) if it
+/// has none
+pub fn region_name(region: &BedRegion) -> String {
+    match &region.name {
+        Some(name) => name.clone(),
+        None => format!("{}:{}-{}", region.ref_name, region.start + 1, region.end),
+    }
+}
+
+// Opens a plain or gzip-compressed text file, told apart by the magic bytes rather than the
+// file name. `kind` names the file in the error message.
+fn open_text_file(file_path: &str, kind: &str) -> Result<Box<dyn BufRead>, BoxError> {
+    let open =
+        || File::open(file_path).map_err(|e| Error::Io(format!("failed to open {} {}: {}", kind, file_path, e)));
+    let mut magic = [0u8; 2];
+    let is_gzip = open()?.read(&mut magic)? == 2 && magic == [0x1f, 0x8b];
+    Ok(if is_gzip {
+        Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(open()?)))
+    } else {
+        Box::new(BufReader::new(open()?))
+    })
+}
+
+/// Loads all intervals of a plain or gzip-compressed BED file in file order, `one_based` reads
+/// 1-based closed intervals. Blank lines, `track` and `browser` lines and everything after a
+/// `#` are skipped, any other line has to be a valid interval.
+pub fn load_bed_regions(bed_file_path: &str, one_based: bool) -> Result<Vec<BedRegion>, BoxError> {
+    let reader = open_text_file(bed_file_path, "BED file")?;
+    // Keep every interval in file order, several intervals may share a chromosome
+    let mut regions_of_interest: Vec<BedRegion> = Vec::new();
+
+    for (line_index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| Error::Io(format!("failed to read BED file {}: {}", bed_file_path, e)))?;
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() || is_browser_line(line) {
+            continue;
+        }
+        let malformed =
+            |problem: String| Error::BedParse(format!("{} line {}: {}", bed_file_path, line_index + 1, problem));
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            return Err(malformed(format!("expected at least 3 tab-separated columns, found \"{}\"", line)).into());
+        }
+
+        let chromosome = fields[0].to_string();
+        let mut start = fields[1]
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| malformed(format!("start \"{}\" is not a coordinate", fields[1])))?;
+        let end = fields[2]
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| malformed(format!("end \"{}\" is not a coordinate", fields[2])))?;
+        // A 1-based closed [start, end] interval is [start - 1, end) 0-based
+        if one_based {
+            if start == 0 {
+                return Err(malformed("start is 0, but --one-based coordinates start at 1".to_string()).into());
+            }
+            start -= 1;
+        }
+        if end < start {
+            return Err(malformed(format!("end {} is before start {}", fields[2], fields[1])).into());
+        }
+        // `.` is the BED placeholder for a missing name
+        let name = fields
+            .get(3)
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty() && *name != ".")
+            .map(str::to_string);
+        // Extra columns don't always follow BED6
+        let strand = fields.get(5).map_or(Strand::Either, |strand| Strand::from_column(strand));
+        regions_of_interest.push(BedRegion { ref_name: chromosome, start, end, name, strand });
+    }
+
+    Ok(regions_of_interest)
+}
+
+/// Loads the positions of a plain or gzip-compressed `chrom<TAB>pos` file (1-based) in file
+/// order, each as a 1 bp interval. Further columns are ignored, blank lines and everything
+/// after a `#` are skipped.
+pub fn load_positions(positions_file_path: &str) -> Result<Vec<BedRegion>, BoxError> {
+    let reader = open_text_file(positions_file_path, "positions file")?;
+    let mut positions: Vec<BedRegion> = Vec::new();
+
+    for (line_index, line) in reader.lines().enumerate() {
+        let line =
+            line.map_err(|e| Error::Io(format!("failed to read positions file {}: {}", positions_file_path, e)))?;
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let malformed =
+            |problem: String| Error::BedParse(format!("{} line {}: {}", positions_file_path, line_index + 1, problem));
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 2 {
+            return Err(malformed(format!("expected at least 2 tab-separated columns, found \"{}\"", line)).into());
+        }
+        let position = fields[1]
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| malformed(format!("position \"{}\" is not a coordinate", fields[1])))?;
+        if position == 0 {
+            return Err(malformed("position is 0, but positions are 1-based".to_string()).into());
+        }
+        positions.push(BedRegion::new(fields[0], position - 1, position));
+    }
+
+    Ok(positions)
+}
+
+/// Loads the features of one type (3rd column), such as `exon` or `CDS`, of a plain or
+/// gzip-compressed GFF3 or GTF file in file order. The 1-based closed coordinates become
+/// 0-based half-open intervals. An interval is named by the `Name` or `ID` attribute of GFF3,
+/// or the `gene_name` or `gene_id` attribute of GTF, whichever comes first in this order, and
+/// takes the strand of its feature. Blank lines and lines starting with `#` are skipped, a
+/// `##FASTA` line ends the features.
+pub fn load_gff_features(gff_file_path: &str, feature_type: &str) -> Result<Vec<BedRegion>, BoxError> {
+    let reader = open_text_file(gff_file_path, "GFF file")?;
+    let mut features: Vec<BedRegion> = Vec::new();
+
+    for (line_index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| Error::Io(format!("failed to read GFF file {}: {}", gff_file_path, e)))?;
+        if line.starts_with("##FASTA") {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let malformed =
+            |problem: String| Error::BedParse(format!("{} line {}: {}", gff_file_path, line_index + 1, problem));
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            return Err(malformed(format!("expected 9 tab-separated columns, found \"{}\"", line)).into());
+        }
+        if fields[2] != feature_type {
+            continue;
+        }
+
+        let start = fields[3]
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| malformed(format!("start \"{}\" is not a coordinate", fields[3])))?;
+        let end = fields[4]
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| malformed(format!("end \"{}\" is not a coordinate", fields[4])))?;
+        if start == 0 {
+            return Err(malformed("start is 0, but GFF coordinates start at 1".to_string()).into());
+        }
+        if end < start {
+            return Err(malformed(format!("end {} is before start {}", fields[4], fields[3])).into());
+        }
+        let attributes = gff_attributes(fields[8]);
+        let name = ["Name", "ID", "gene_name", "gene_id"]
+            .iter()
+            .find_map(|key| attributes.iter().find(|(attribute, _)| attribute == key))
+            .map(|(_, value)| value.to_string());
+        // A 1-based closed [start, end] feature is [start - 1, end) 0-based
+        features.push(BedRegion {
+            ref_name: fields[0].to_string(),
+            start: start - 1,
+            end,
+            name,
+            strand: Strand::from_column(fields[6]),
+        });
+    }
+
+    Ok(features)
+}
+
+// Splits the 9th GFF column into its non-empty attributes, `key=value` in GFF3 and
+// `key "value"` in GTF
+fn gff_attributes(column: &str) -> Vec<(&str, &str)> {
+    column
+        .split(';')
+        .filter_map(|attribute| {
+            let attribute = attribute.trim();
+            let (key, value) = match attribute.split_once('=') {
+                Some((key, value)) if !key.contains(' ') => (key, value),
+                _ => attribute.split_once([' ', '\t'])?,
+            };
+            let value = value.trim().trim_matches('"');
+            (!value.is_empty()).then_some((key.trim(), value))
+        })
+        .collect()
+}
+
+// Whether a line is a UCSC `track` or `browser` line rather than an interval
+fn is_browser_line(line: &str) -> bool {
+    ["track", "browser"]
+        .iter()
+        .any(|keyword| line.strip_prefix(keyword).is_some_and(|rest| rest.is_empty() || rest.starts_with([' ', '\t'])))
+}
+
+/// Parses a samtools-style chr:start-end region (1-based, inclusive) into a BED interval
+pub fn parse_region(region: &str) -> Result<BedRegion, Error> {
+    let malformed = || Error::BadRegion(format!("malformed region \"{}\", expected chr:start-end", region));
+    let (chromosome, coordinates) = region.rsplit_once(':').ok_or_else(malformed)?;
+    let (start, end) = coordinates.split_once('-').ok_or_else(malformed)?;
+    let start = start.trim().replace(',', "").parse::<u32>().map_err(|_| malformed())?;
+    let end = end.trim().replace(',', "").parse::<u32>().map_err(|_| malformed())?;
+    if chromosome.is_empty() {
+        return Err(malformed());
+    }
+    if start == 0 {
+        return Err(Error::BadRegion(format!(
+            "invalid region \"{}\": coordinates are 1-based, start must be at least 1",
+            region
+        )));
+    }
+    if end < start {
+        return Err(Error::BadRegion(format!("invalid region \"{}\": end is before start", region)));
+    }
+    Ok(BedRegion::new(chromosome, start - 1, end))
+}
+
+/// Merges overlapping and adjacent intervals of each contig, so that every reference position
+/// is part of at most one interval and reads spanning several intervals are not counted twice.
+/// Contigs stay in their first-seen order, the merged intervals of a contig are sorted by start.
+/// If any of the merged intervals has a name, the merged one is named after all of them, comma
+/// separated. Intervals are only merged with intervals of the same strand, as they count
+/// different reads.
+pub fn merge_overlapping_regions(regions: &[BedRegion]) -> Vec<BedRegion> {
+    let mut merged_regions: Vec<BedRegion> = Vec::with_capacity(regions.len());
+    for (_, mut contig_regions) in crate::group_regions_by_contig(regions) {
+        contig_regions.sort_by_key(|region| (region.start, region.end));
+        // Last merged interval of each strand so far, with the names of the intervals merged
+        // into it and whether any has a name
+        let mut last_by_strand: HashMap<Strand, (usize, Vec<String>, bool)> = HashMap::new();
+        for region in contig_regions {
+            match last_by_strand.get_mut(&region.strand) {
+                Some((last_index, merged_names, named)) if region.start <= merged_regions[*last_index].end => {
+                    let last = &mut merged_regions[*last_index];
+                    last.end = last.end.max(region.end);
+                    merged_names.push(region_name(&region));
+                    *named |= region.name.is_some();
+                    if *named {
+                        last.name = Some(merged_names.join(","));
+                    }
+                }
+                _ => {
+                    let named = region.name.is_some();
+                    last_by_strand.insert(region.strand, (merged_regions.len(), vec![region_name(&region)], named));
+                    merged_regions.push(region);
+                }
+            }
+        }
+    }
+    merged_regions
+}
+
+/// Sorts the intervals by the order of their contigs in `contigs`, such as the BAM header's, so
+/// that tables holding several contigs list them in that order, as tabix expects. Intervals of
+/// a contig keep their order, those on contigs missing from `contigs` come last.
+pub fn sort_by_contig_order(regions: &mut [BedRegion], contigs: &[String]) {
+    let contig_rank: HashMap<&str, usize> =
+        contigs.iter().enumerate().map(|(rank, contig)| (contig.as_str(), rank)).collect();
+    regions.sort_by_key(|region| contig_rank.get(region.ref_name.as_str()).copied().unwrap_or(usize::MAX));
+}
+
+/// Clamps every interval to the length of its contig from `contig_lengths`, so that intervals
+/// running past the end of their contig, e.g. after a lift-over, stop at its last base, and
+/// removes the intervals starting at or past the end, which have no bases left. Returns the names
+/// (see [`region_name`]) of the clamped and of the removed intervals. Intervals on contigs
+/// without a length are left as they are.
+pub fn clamp_to_contig_lengths(
+    regions: &mut Vec<BedRegion>,
+    contig_lengths: &HashMap<String, u64>,
+) -> (Vec<String>, Vec<String>) {
+    let mut clamped = Vec::new();
+    let mut dropped = Vec::new();
+    regions.retain_mut(|region| {
+        let Some(&contig_length) = contig_lengths.get(&region.ref_name) else {
+            return true;
+        };
+        let contig_length = contig_length.min(u32::MAX as u64) as u32;
+        if region.start >= contig_length {
+            dropped.push(region_name(region));
+            return false;
+        }
+        if region.end > contig_length {
+            clamped.push(region_name(region));
+            region.end = contig_length;
+        }
+        true
+    });
+    (clamped, dropped)
+}
+
+/// Extends every interval by `flank` bases on both sides, clamped to the start of its contig and
+/// to its length from `contig_lengths`. Intervals on contigs without a length only get clamped
+/// at the start.
+pub fn add_flanks(regions: &[BedRegion], flank: u32, contig_lengths: &HashMap<String, u64>) -> Vec<BedRegion> {
+    regions
+        .iter()
+        .map(|region| {
+            let mut flanked_end = region.end.saturating_add(flank);
+            if let Some(&contig_length) = contig_lengths.get(&region.ref_name) {
+                flanked_end = flanked_end.min(contig_length.min(u32::MAX as u64) as u32).max(region.end);
+            }
+            BedRegion { start: region.start.saturating_sub(flank), end: flanked_end, ..region.clone() }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_one_based_intervals_to_zero_based() {
+        let dir = tempfile::tempdir().unwrap();
+        let bed_file_path = dir.path().join("regions.bed");
+        std::fs::write(&bed_file_path, "chr1\t11\t11\tsnv\nchr1\t1\t100\n").unwrap();
+        let regions = load_bed_regions(bed_file_path.to_str().unwrap(), true).unwrap();
+        assert_eq!(regions[0], BedRegion { name: Some("snv".to_string()), ..BedRegion::new("chr1", 10, 11) });
+        assert_eq!(regions[1], BedRegion::new("chr1", 0, 100));
+        let regions = load_bed_regions(bed_file_path.to_str().unwrap(), false).unwrap();
+        assert_eq!(regions[0].start..regions[0].end, 11..11);
+
+        std::fs::write(&bed_file_path, "chr1\t0\t10\n").unwrap();
+        let error = load_bed_regions(bed_file_path.to_str().unwrap(), true).unwrap_err();
+        assert!(error.to_string().contains("line 1: start is 0"), "{}", error);
+    }
+
+    #[test]
+    fn skips_track_and_comment_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let bed_file_path = dir.path().join("regions.bed");
+        let bed_file_path = bed_file_path.to_str().unwrap();
+        std::fs::write(bed_file_path, "# targets\ntrack name=targets\nbrowser position chr1:1-100\n\nchr1\t0\t10\n")
+            .unwrap();
+        assert_eq!(load_bed_regions(bed_file_path, false).unwrap(), [BedRegion::new("chr1", 0, 10)]);
+        // A header row is no interval
+        std::fs::write(bed_file_path, "# targets\nchrom\tstart\tend\nchr1\t0\t10\n").unwrap();
+        let error = load_bed_regions(bed_file_path, false).unwrap_err();
+        assert!(matches!(error.downcast_ref::<Error>(), Some(Error::BedParse(_))), "{}", error);
+        assert!(error.to_string().contains("line 2: start \"start\" is not a coordinate"), "{}", error);
+    }
+
+    #[test]
+    fn parses_a_region_to_a_zero_based_interval() {
+        assert_eq!(parse_region("chr1:100-200").unwrap(), BedRegion::new("chr1", 99, 200));
+        assert_eq!(parse_region("chr1:1,000-1,000").unwrap(), BedRegion::new("chr1", 999, 1000));
+        // Only the last colon separates the coordinates
+        assert_eq!(parse_region("HLA-A*01:01:5-6").unwrap(), BedRegion::new("HLA-A*01:01", 4, 6));
+    }
+
+    #[test]
+    fn rejects_malformed_regions() {
+        for region in ["chr1", "chr1:100", "chr1:-200", "chr1:100-", ":100-200", "chr1:a-200", "chr1:100-2e2"] {
+            let error = parse_region(region).unwrap_err();
+            assert!(matches!(error, Error::BadRegion(_)), "{}: {}", region, error);
+            assert!(error.to_string().contains("expected chr:start-end"), "{}: {}", region, error);
+        }
+    }
+
+    #[test]
+    fn rejects_a_region_ending_before_its_start() {
+        let error = parse_region("chr1:200-100").unwrap_err();
+        assert!(error.to_string().contains("end is before start"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_a_region_starting_at_zero() {
+        let error = parse_region("chr1:0-100").unwrap_err();
+        assert!(error.to_string().contains("1-based"), "{}", error);
+    }
+
+    #[test]
+    fn adds_flanks_within_the_bounds_of_the_contig() {
+        let regions = [
+            BedRegion::new("chr1", 50, 51),
+            BedRegion { name: Some("start".to_string()), strand: Strand::Forward, ..BedRegion::new("chr1", 2, 3) },
+            BedRegion::new("chr1", 98, 99),
+            BedRegion::new("chr2", 98, 99),
+        ];
+        let contig_lengths = HashMap::from([("chr1".to_string(), 100)]);
+        let flanked = add_flanks(&regions, 5, &contig_lengths);
+        assert_eq!(flanked[0], BedRegion::new("chr1", 45, 56));
+        assert_eq!(
+            flanked[1],
+            BedRegion { name: Some("start".to_string()), strand: Strand::Forward, ..BedRegion::new("chr1", 0, 8) }
+        );
+        assert_eq!(flanked[2], BedRegion::new("chr1", 93, 100));
+        // Without a length only the start is clamped
+        assert_eq!(flanked[3], BedRegion::new("chr2", 93, 104));
+    }
+
+    #[test]
+    fn clamps_intervals_to_the_contig_length_and_drops_those_past_its_end() {
+        let mut regions = vec![
+            BedRegion::new("chr1", 10, 20),
+            BedRegion { name: Some("tail".to_string()), ..BedRegion::new("chr1", 90, 120) },
+            BedRegion::new("chr1", 100, 110),
+            BedRegion::new("chr1", 150, 160),
+            BedRegion::new("chr2", 150, 160),
+        ];
+        let contig_lengths = HashMap::from([("chr1".to_string(), 100)]);
+        let (clamped, dropped) = clamp_to_contig_lengths(&mut regions, &contig_lengths);
+        assert_eq!(clamped, ["tail"]);
+        assert_eq!(dropped, ["chr1:101-110", "chr1:151-160"]);
+        assert_eq!(
+            regions,
+            [
+                BedRegion::new("chr1", 10, 20),
+                BedRegion { name: Some("tail".to_string()), ..BedRegion::new("chr1", 90, 100) },
+                // Without a length nothing is clamped
+                BedRegion::new("chr2", 150, 160),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_gzip_compressed_bed_and_positions_files_like_plain_ones() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, contents: &str, gzip: bool| {
+            let file_path = dir.path().join(name).to_str().unwrap().to_string();
+            let mut file = File::create(&file_path).unwrap();
+            if gzip {
+                let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+                encoder.write_all(contents.as_bytes()).unwrap();
+                encoder.finish().unwrap();
+            } else {
+                file.write_all(contents.as_bytes()).unwrap();
+            }
+            file_path
+        };
+        let bed = "track name=panel\nchr1\t10\t20\tampA\t0\t-\nchr2\t5\t8\n";
+        let plain = load_bed_regions(&write("regions.bed", bed, false), false).unwrap();
+        assert_eq!(plain.len(), 2);
+        assert_eq!(load_bed_regions(&write("regions.bed.gz", bed, true), false).unwrap(), plain);
+        let positions = "chr1\t11\nchr2\t6\n";
+        let plain = load_positions(&write("snps.tsv", positions, false)).unwrap();
+        assert_eq!(plain.len(), 2);
+        assert_eq!(load_positions(&write("snps.tsv.gz", positions, true)).unwrap(), plain);
+    }
+
+    #[test]
+    fn loads_the_exons_of_a_gtf_file_as_zero_based_intervals() {
+        let dir = tempfile::tempdir().unwrap();
+        let gtf_file_path = dir.path().join("genes.gtf");
+        let gtf = "#!genome-build test\n\
+                   chr1\ttest\tgene\t1\t100\t.\t+\t.\tgene_id \"g1\"; gene_name \"ABC\";\n\
+                   chr1\ttest\texon\t11\t20\t.\t+\t.\tgene_id \"g1\"; gene_name \"ABC\";\n\
+                   chr1\ttest\tCDS\t15\t20\t.\t+\t0\tgene_id \"g1\"; gene_name \"ABC\";\n\
+                   chr2\ttest\texon\t5\t5\t.\t-\t.\tgene_id \"g2\";\n";
+        std::fs::write(&gtf_file_path, gtf).unwrap();
+        let exons = load_gff_features(gtf_file_path.to_str().unwrap(), "exon").unwrap();
+        assert_eq!(
+            exons,
+            [
+                BedRegion { name: Some("ABC".to_string()), strand: Strand::Forward, ..BedRegion::new("chr1", 10, 20) },
+                BedRegion { name: Some("g2".to_string()), strand: Strand::Reverse, ..BedRegion::new("chr2", 4, 5) },
+            ]
+        );
+        let cds = load_gff_features(gtf_file_path.to_str().unwrap(), "CDS").unwrap();
+        assert_eq!(
+            cds,
+            [BedRegion { name: Some("ABC".to_string()), strand: Strand::Forward, ..BedRegion::new("chr1", 14, 20) }]
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Reading CRAM files, which store the reads as differences to the reference they were
+//! compressed against and need its FASTA file to be decoded.
+//!
+//! The reads are decoded with noodles and handed on as [`bam::Record`]s, so they are counted
+//! like those of a BAM file. CRAM files are read in a single pass like BAM files from stdin,
+//! without their `.crai` index.
+
+use std::fs::File;
+use std::io;
+
+use noodles::cram;
+use noodles::fasta;
+use noodles::sam;
+use noodles::sam::alignment::io::Write as _;
+
+use crate::error::Error;
+use crate::{reference, BoxError};
+
+/// Whether a file is a CRAM rather than a BAM file, by its extension
+pub fn is_cram(bam_file_path: &str) -> bool {
+    bam_file_path.to_ascii_lowercase().ends_with(".cram")
+}
+
+// Opens a CRAM file and reads its header. Reading the reads needs the FASTA file they were
+// compressed against, which isn't needed for the header alone.
+fn open(
+    cram_file_path: &str,
+    fasta_file_path: Option<&str>,
+) -> Result<(cram::io::Reader<File>, sam::Header), BoxError> {
+    let mut builder = cram::io::reader::Builder::default();
+    if let Some(fasta_file_path) = fasta_file_path {
+        reference::ensure_fasta_index(fasta_file_path)?;
+        let fasta_reader = fasta::io::indexed_reader::Builder::default()
+            .build_from_path(fasta_file_path)
+            .map_err(|e| Error::Io(format!("failed to open FASTA file {}: {}", fasta_file_path, e)))?;
+        let repository = fasta::Repository::new(fasta::repository::adapters::IndexedReader::new(fasta_reader));
+        builder = builder.set_reference_sequence_repository(repository);
+    }
+    let mut reader = builder
+        .build_from_path(cram_file_path)
+        .map_err(|e| Error::Io(format!("failed to open CRAM file {}: {}", cram_file_path, e)))?;
+    let header = reader
+        .read_header()
+        .map_err(|e| Error::Io(format!("failed to read CRAM header of {}: {}", cram_file_path, e)))?;
+    Ok((reader, header))
+}
+
+// The header of a CRAM file in the form of a BAM header, with the same references in the same
+// order, so reference IDs are the same in both
+fn bam_header(header: &sam::Header) -> Result<bam::Header, BoxError> {
+    let mut writer = sam::io::Writer::new(Vec::new());
+    writer.write_header(header)?;
+    let mut bam_header = bam::Header::new();
+    for line in String::from_utf8_lossy(writer.get_ref()).lines() {
+        bam_header
+            .push_line(line)
+            .map_err(|e| Error::Io(format!("failed to convert CRAM header line {}: {}", line, e)))?;
+    }
+    Ok(bam_header)
+}
+
+/// Reads the header of a CRAM file in the form of a BAM header
+pub fn read_header(cram_file_path: &str) -> Result<bam::Header, BoxError> {
+    let (_, header) = open(cram_file_path, None)?;
+    bam_header(&header)
+}
+
+/// Hands every read of a CRAM file to `handle_record` in file order, decoded with the FASTA file
+/// it was compressed against, with the reference IDs of [`read_header`]. A read that fails to
+/// decode ends the pass with an error, or with `skip_errors` with a warning, keeping the reads
+/// before it.
+pub(crate) fn for_each_record<F>(
+    cram_file_path: &str,
+    fasta_file_path: &str,
+    skip_errors: bool,
+    mut handle_record: F,
+) -> Result<(), BoxError>
+where
+    F: FnMut(&bam::Record) -> Result<(), BoxError>,
+{
+    let (mut reader, header) = open(cram_file_path, Some(fasta_file_path))?;
+    let bam_header = bam_header(&header)?;
+    // Every read goes through its SAM line, which the BAM crate parses like any other
+    let mut writer = sam::io::Writer::new(Vec::new());
+    let mut record = bam::Record::new();
+    for (n_read, result) in reader.records(&header).enumerate() {
+        let decoded = result.and_then(|record_buf| {
+            writer.get_mut().clear();
+            writer.write_alignment_record(&header, &record_buf)
+        });
+        if let Err(e) = decoded {
+            let message = format!("failed to read CRAM file {} after {} reads: {}", cram_file_path, n_read, e);
+            if skip_errors {
+                log::warn!("{}, counting the reads before", message);
+                return Ok(());
+            }
+            return Err(Error::Fetch(message).into());
+        }
+        let line = String::from_utf8_lossy(writer.get_ref());
+        record
+            .fill_from_sam(line.trim_end_matches('\n'), &bam_header)
+            .map_err(|e: io::Error| Error::Fetch(format!("failed to convert a read of {}: {}", cram_file_path, e)))?;
+        handle_record(&record)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+//! Kinds of errors that the command line tool tells apart by its exit code.
+//!
+//! Functions return them in a [`BoxError`] like any other error, so callers that only print the
+//! message don't have to care. [`exit_code`] looks through the box for the kind.
+
+use std::io;
+
+use crate::BoxError;
+
+/// An error of a kind worth its own exit code, see [`Error::exit_code`]
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Malformed line in a BED, positions or known sites file
+    #[error("{0}")]
+    BedParse(String),
+    /// Contig of an interval missing from the BAM header or the FASTA file
+    #[error("{0}")]
+    ContigMissing(String),
+    /// Reads or reference bases of an interval could not be read
+    #[error("{0}")]
+    Fetch(String),
+    /// An input file, its index or an output file could not be opened, read or written
+    #[error("{0}")]
+    Io(String),
+    /// Malformed region given on the command line
+    #[error("{0}")]
+    BadRegion(String),
+    /// Contig lengths in the BAM header that differ from the FASTA file
+    #[error("{0}")]
+    ReferenceMismatch(String),
+}
+
+impl Error {
+    /// Exit code of the command line tool for this kind of error. 1 is left for all other
+    /// errors and 2 for command line usage errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::BedParse(_) => 3,
+            Error::ContigMissing(_) => 4,
+            Error::Fetch(_) => 5,
+            Error::Io(_) => 6,
+            Error::BadRegion(_) => 7,
+            Error::ReferenceMismatch(_) => 8,
+        }
+    }
+}
+
+/// Puts `context` before the message of an error, keeping its kind and so its exit code
+pub fn with_context(error: BoxError, context: &str) -> BoxError {
+    let error = match error.downcast::<Error>() {
+        Ok(error) => error,
+        Err(error) if error.is::<io::Error>() => return Error::Io(format!("{}: {}", context, error)).into(),
+        Err(error) => return format!("{}: {}", context, error).into(),
+    };
+    let message = |message| format!("{}: {}", context, message);
+    match *error {
+        Error::BedParse(m) => Error::BedParse(message(m)),
+        Error::ContigMissing(m) => Error::ContigMissing(message(m)),
+        Error::Fetch(m) => Error::Fetch(message(m)),
+        Error::Io(m) => Error::Io(message(m)),
+        Error::BadRegion(m) => Error::BadRegion(message(m)),
+        Error::ReferenceMismatch(m) => Error::ReferenceMismatch(message(m)),
+    }
+    .into()
+}
+
+/// Exit code for a failed run: that of the [`Error`] kind if it is one, that of [`Error::Io`]
+/// for I/O errors passed on as they are, and 1 for anything else
+pub fn exit_code(error: &BoxError) -> i32 {
+    if let Some(error) = error.downcast_ref::<Error>() {
+        error.exit_code()
+    } else if error.is::<io::Error>() {
+        Error::Io(String::new()).exit_code()
+    } else {
+        1
+    }
+}
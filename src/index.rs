@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::error::Error;
+use crate::BoxError;
+
+/// Creates a <bam>.bai index next to a coordinate-sorted BAM file if there is none yet.
+/// Fails without writing an index if the reads turn out not to be sorted.
+pub fn ensure_bam_index(bam_file_path: &str) -> Result<(), BoxError> {
+    let bai_file_path = format!("{}.bai", bam_file_path);
+    if Path::new(&bai_file_path).exists() {
+        return Ok(());
+    }
+    log::info!("Creating missing BAM index {}", bai_file_path);
+
+    let mut reader = bam::bgzip::SeekReader::from_path(bam_file_path, 0)
+        .map_err(|e| Error::Io(format!("failed to open BAM file {}: {}", bam_file_path, e)))?;
+    reader.make_consecutive();
+    let header = bam::Header::from_bam(&mut reader)
+        .map_err(|e| Error::Io(format!("failed to read BAM header of {}: {}", bam_file_path, e)))?;
+    let not_sorted = || {
+        format!(
+            "BAM file {} has no index and is not coordinate-sorted, sort it with `samtools sort` first",
+            bam_file_path
+        )
+    };
+
+    let mut indices: Vec<BinIndexBuilder> = (0..header.n_references()).map(|_| BinIndexBuilder::default()).collect();
+    let mut record = bam::Record::new();
+    let mut previous: Option<(i32, i32)> = None;
+    let mut unplaced_seen = false;
+    loop {
+        let start_offset = reader.current_offset().raw();
+        if !record.fill_from_bam(&mut reader)? {
+            break;
+        }
+        let end_offset = reader.current_offset().raw();
+        // Reads without a reference come last, after all placed reads
+        if record.ref_id() < 0 {
+            unplaced_seen = true;
+            continue;
+        }
+        let position = (record.ref_id(), record.start());
+        if unplaced_seen || previous.is_some_and(|previous| position < previous) {
+            return Err(not_sorted().into());
+        }
+        previous = Some(position);
+        let Some(index) = indices.get_mut(record.ref_id() as usize) else {
+            return Err(format!("read in {} refers to a missing reference sequence", bam_file_path).into());
+        };
+        let start = record.start().max(0) as u32;
+        let end = record.calculate_end().max(record.start() + 1) as u32;
+        index.add(start, end, start_offset, end_offset);
+    }
+
+    // Write to a temporary file first, so that a failed run does not leave a broken index behind
+    let tmp_file_path = format!("{}.tmp", bai_file_path);
+    let mut writer = BufWriter::new(File::create(&tmp_file_path)?);
+    writer.write_all(b"BAI\x01")?;
+    writer.write_all(&(indices.len() as i32).to_le_bytes())?;
+    for index in &indices {
+        index.write(&mut writer)?;
+    }
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&tmp_file_path, &bai_file_path)?;
+    Ok(())
+}
+
+// Collects the binning and linear index of one reference sequence, as used by both BAI and tabix
+#[derive(Default)]
+pub(crate) struct BinIndexBuilder {
+    // Chunks of virtual offsets for each bin
+    bins: BTreeMap<u32, Vec<(u64, u64)>>,
+    // Smallest virtual offset of an entry overlapping each 16kb window
+    linear_index: Vec<u64>,
+}
+
+impl BinIndexBuilder {
+    // Adds an entry covering [beg, end) that is stored between the two virtual offsets
+    pub(crate) fn add(&mut self, beg: u32, end: u32, start_offset: u64, end_offset: u64) {
+        let end = end.max(beg + 1);
+        let bin = bam::index::region_to_bin(beg as i32, end as i32);
+        let chunks = self.bins.entry(bin).or_default();
+        // Entries written back to back extend the previous chunk
+        match chunks.last_mut() {
+            Some(chunk) if chunk.1 == start_offset => chunk.1 = end_offset,
+            _ => chunks.push((start_offset, end_offset)),
+        }
+
+        let last_window = ((end - 1) >> 14) as usize;
+        if self.linear_index.len() <= last_window {
+            self.linear_index.resize(last_window + 1, u64::MAX);
+        }
+        for window_offset in &mut self.linear_index[(beg >> 14) as usize..=last_window] {
+            if *window_offset == u64::MAX {
+                *window_offset = start_offset;
+            }
+        }
+    }
+
+    pub(crate) fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&(self.bins.len() as i32).to_le_bytes())?;
+        for (bin, chunks) in &self.bins {
+            writer.write_all(&bin.to_le_bytes())?;
+            writer.write_all(&(chunks.len() as i32).to_le_bytes())?;
+            for (start_offset, end_offset) in chunks {
+                writer.write_all(&start_offset.to_le_bytes())?;
+                writer.write_all(&end_offset.to_le_bytes())?;
+            }
+        }
+        // Windows without entries take the offset of the next window that has one
+        let mut linear_index = self.linear_index.clone();
+        let mut next_offset = 0;
+        for window_offset in linear_index.iter_mut().rev() {
+            if *window_offset == u64::MAX {
+                *window_offset = next_offset;
+            } else {
+                next_offset = *window_offset;
+            }
+        }
+        writer.write_all(&(linear_index.len() as i32).to_le_bytes())?;
+        for window_offset in linear_index {
+            writer.write_all(&window_offset.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
@@ -0,0 +1,98 @@
+//! Known SNV sites to genotype, read from a VCF or a `chrom<TAB>pos<TAB>ref<TAB>alt` table.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+use crate::error::Error;
+use crate::{BedRegion, BoxError};
+
+/// A known SNV: contig, 0-based position and the uppercase reference and alternate base
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KnownSite {
+    pub ref_name: String,
+    pub pos: u32,
+    pub ref_base: u8,
+    pub alt_base: u8,
+}
+
+impl KnownSite {
+    /// The 1 bp interval of the site
+    pub fn region(&self) -> BedRegion {
+        BedRegion::new(&self.ref_name, self.pos, self.pos + 1)
+    }
+}
+
+/// Loads the known sites of a plain or gzip-compressed VCF file, told apart by its
+/// `##fileformat=VCF` first line, or a table of 1-based `chrom<TAB>pos<TAB>ref<TAB>alt` rows,
+/// in file order. Blank lines and lines starting with `#` are skipped. A site with several
+/// comma-separated alternate bases becomes one site for each of them. Sites that are not SNVs,
+/// such as indels, are left out; their number is returned with the sites.
+pub fn load_known_sites(sites_file_path: &str) -> Result<(Vec<KnownSite>, usize), BoxError> {
+    let open = || {
+        File::open(sites_file_path)
+            .map_err(|e| Error::Io(format!("failed to open sites file {}: {}", sites_file_path, e)))
+    };
+    // Tell gzip from plain text by the magic bytes rather than the file name
+    let mut magic = [0u8; 2];
+    let is_gzip = open()?.read(&mut magic)? == 2 && magic == [0x1f, 0x8b];
+    let reader: Box<dyn BufRead> = if is_gzip {
+        Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(open()?)))
+    } else {
+        Box::new(BufReader::new(open()?))
+    };
+
+    let mut sites = Vec::new();
+    let mut n_skipped = 0;
+    // Columns of the reference and alternate alleles, VCF has an ID column before them
+    let mut allele_columns = (2, 3);
+    for (line_index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| Error::Io(format!("failed to read sites file {}: {}", sites_file_path, e)))?;
+        if line_index == 0 && line.starts_with("##fileformat=VCF") {
+            allele_columns = (3, 4);
+        }
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let malformed =
+            |problem: String| Error::BedParse(format!("{} line {}: {}", sites_file_path, line_index + 1, problem));
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() <= allele_columns.1 {
+            return Err(malformed(format!(
+                "expected at least {} tab-separated columns, found \"{}\"",
+                allele_columns.1 + 1,
+                line
+            ))
+            .into());
+        }
+        let position = fields[1]
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| malformed(format!("position \"{}\" is not a coordinate", fields[1])))?;
+        if position == 0 {
+            return Err(malformed("position is 0, but positions are 1-based".to_string()).into());
+        }
+        let ref_base = match fields[allele_columns.0].trim().as_bytes() {
+            [base] if b"ACGT".contains(&base.to_ascii_uppercase()) => base.to_ascii_uppercase(),
+            _ => {
+                n_skipped += 1;
+                continue;
+            }
+        };
+        for alt in fields[allele_columns.1].trim().split(',') {
+            match alt.as_bytes() {
+                [base] if b"ACGT".contains(&base.to_ascii_uppercase()) && base.to_ascii_uppercase() != ref_base => {
+                    sites.push(KnownSite {
+                        ref_name: fields[0].to_string(),
+                        pos: position - 1,
+                        ref_base,
+                        alt_base: base.to_ascii_uppercase(),
+                    });
+                }
+                _ => n_skipped += 1,
+            }
+        }
+    }
+
+    Ok((sites, n_skipped))
+}
@@ -0,0 +1,1092 @@
+//! Pileup analysis of BAM files at positions of interest.
+//!
+//! The command line tool is a thin wrapper around [`for_each_contig`], which counts the reads of
+//! all regions in a [`PileupConfig`] one contig at a time. [`run`] collects the same counts into
+//! a [`Summary`] for programmatic use.
+
+extern crate bam;
+extern crate bio;
+
+pub mod bed;
+pub mod cram;
+pub mod error;
+pub mod index;
+pub mod known_sites;
+pub mod logger;
+pub mod mods;
+pub mod output;
+pub mod pileup;
+mod progress;
+pub mod read_list;
+pub mod reference;
+pub mod remote;
+#[cfg(test)]
+mod test_util;
+
+use bam::RecordReader;
+use rayon::prelude::*;
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::thread;
+
+pub use bed::{
+    add_flanks, clamp_to_contig_lengths, load_bed_regions, load_gff_features, load_positions, merge_overlapping_regions,
+    parse_region, region_name, sort_by_contig_order, BedRegion, Strand,
+};
+pub use error::Error;
+pub use known_sites::{load_known_sites, KnownSite};
+pub use mods::{parse_mod_code, read_mod_code_probabilities, read_mod_probabilities, ModCode, ModCounts};
+pub use pileup::{
+    call_consensus, compare_base, count_aligned_bases, count_insertions_deletions, count_matches_mismatches,
+    count_softclips, is_ambiguous_base, query_pos_for_ref, BaseCounts, BaseObservation, ConsensusCall, ConsensusSettings,
+    DebugPositions, IntervalCoverage, MismatchProfile, MismatchSpectrum, PileupIterator, PileupPosition, QualHistogram,
+    ReadCounts, ReadTotals, ReadsAcrossContigs, SkippedReads, StrandCounts,
+};
+pub use read_list::load_read_list;
+
+use pileup::{count_region, earlier_overlaps, RegionCounts};
+use progress::Progress;
+use reference::ReferenceCache;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// What is counted for each contig
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Match/mismatch counts for every read
+    PerRead,
+    /// Base counts for every position
+    PerPosition,
+    /// Modification calls from MM/ML tags for every position
+    Modifications,
+    /// Every read base aligned to a position, with its reference base and quality
+    Long,
+}
+
+/// Highest base quality in BAM files, Phred 93 is written as `~` in SAM
+pub const MAX_PHRED_QUALITY: u8 = 93;
+
+/// Input files, regions and settings controlling which reads and bases are counted
+#[derive(Clone, Debug)]
+pub struct PileupConfig {
+    /// Coordinate-sorted BAM file with a .bai index
+    pub bam_file_path: String,
+    /// Reference FASTA file, a missing .fai index is created. Not read with `use_md`.
+    pub fasta_file_path: String,
+    /// Regions of interest, 0-based and half-open
+    pub regions: Vec<BedRegion>,
+    /// Bases with a lower Q-score are not counted. BAM files hold base qualities as plain Phred
+    /// scores from 0 to 93, without the ASCII offset of 33 used in SAM and FASTQ text.
+    pub qscore_cutoff: u8,
+    /// Reads with a lower mapping quality are skipped
+    pub min_mapq: u8,
+    /// Reads with any of these SAM flag bits set are skipped
+    pub exclude_flags: u16,
+    /// Reads without all of these SAM flag bits set are skipped
+    pub include_flags: u16,
+    /// Only count the reads aligned to the strand of their interval, forward for `+` and
+    /// reverse for `-`, and reads on both strands for `.`
+    pub respect_bed_strand: bool,
+    /// Reads aligned to fewer reference bases are skipped
+    pub min_read_length: u32,
+    /// Reads aligned to more reference bases are skipped, if set
+    pub max_read_length: Option<u32>,
+    /// Reads of longer intervals are fetched in windows of this many bases one after the
+    /// other, if set, with the same counts. The counts of the whole interval are kept until it
+    /// is finished either way. Reads from stdin are never fetched by region.
+    pub interval_batch_size: Option<u32>,
+    /// In [`Mode::PerRead`], count the target positions deleted from a read as mismatches instead
+    /// of leaving them out of the match/mismatch counts
+    pub deletions_as_mismatches: bool,
+    /// Leave out this many bases at both ends of the aligned part of every read, after its soft
+    /// clips, from all counts but the coverage and the soft clips themselves
+    pub trim_ends: u32,
+    /// Only reads with one of these names are counted, if set
+    pub read_list: Option<HashSet<Vec<u8>>>,
+    /// Reads with one of these names are skipped
+    pub exclude_read_list: Option<HashSet<Vec<u8>>>,
+    /// Only count about this fraction (0-1) of the reads, if set. Reads are picked by a hash of
+    /// their name and [`seed`](PileupConfig::seed), so both reads of a pair are kept or skipped
+    /// together and the same seed keeps the same reads.
+    pub subsample: Option<f64>,
+    /// Seed of the read subsampling
+    pub seed: u64,
+    pub mode: Mode,
+    /// Modifications counted in [`Mode::Modifications`], each position has counts for each of
+    /// them in this order
+    pub mod_codes: Vec<ModCode>,
+    /// Only modification calls with a higher probability (0-255, as in ML tags) of being
+    /// modified or unmodified are counted, calls at this probability are skipped
+    pub min_mod_prob: u8,
+    /// Number of worker threads
+    pub threads: usize,
+    /// Report the progress over the regions on stderr
+    pub progress: bool,
+    /// Also collect the [`IntervalCoverage`] of every region
+    pub coverage: bool,
+    /// Also collect a [`QualHistogram`] of the bases at the positions of interest
+    pub qual_histogram: bool,
+    /// Also collect a [`MismatchSpectrum`] of the bases at the positions of interest
+    pub mismatch_spectrum: bool,
+    /// Also collect a [`MismatchProfile`] along the intervals
+    pub mismatch_profile: bool,
+    /// Also collect the [`DebugPositions`] of every region, at most this many positions each
+    pub debug_positions: Option<usize>,
+    /// Skip reads without base qualities instead of letting all their bases pass the Q-score
+    /// cutoff
+    pub require_qual: bool,
+    /// Log a warning and go on with the next interval when the reads of an interval can't be
+    /// read, keeping the reads read before, instead of stopping with an error. Also only warns
+    /// about a BAM file without the end-of-file marker of complete files.
+    pub skip_errors: bool,
+    /// Stop with an error on reads without a stored sequence (`*` in SAM) instead of skipping
+    /// them
+    pub strict: bool,
+    /// In [`Mode::PerRead`], count each position of a read only once if it is in several
+    /// intervals, in the first of them. Only matters for overlapping intervals, which are
+    /// otherwise merged. The strands of the intervals are not taken into account.
+    pub unique_positions: bool,
+    /// In [`Mode::PerPosition`], count the positions covered by both reads of an overlapping
+    /// pair only once, taking the base with the higher quality
+    pub dedup_overlap: bool,
+    /// In [`Mode::PerPosition`], stop counting a position once this many reads are counted at
+    /// it, taking the first ones of each interval in BAM order
+    pub max_depth: Option<usize>,
+    /// Leave out positions at soft-masked (lowercase) reference bases instead of comparing them
+    /// like uppercase ones. Per-read counts take read bases there as aligned to an ambiguous
+    /// base like `N`, per-position counts skip these positions.
+    pub fasta_case_sensitive: bool,
+    /// In [`Mode::PerRead`], count every aligned base passing the Q-score cutoff as a match
+    /// without comparing it to the reference, which is then never read. Faster, but mismatches
+    /// count as matches and the mismatch and ambiguous reference counts stay 0.
+    pub count_matches_only: bool,
+    /// Take all IUPAC codes in the reference as ambiguous bases, instead of counting read bases
+    /// that are one of the bases of a code like `R` (`A` or `G`) as matches, see
+    /// [`compare_base`]
+    pub strict_iupac: bool,
+    /// Rebuild the reference bases of every read from its bases and its `MD` tag instead of
+    /// reading them from the FASTA file. Reads without an `MD` tag are an error.
+    pub use_md: bool,
+    /// In [`Mode::PerPosition`], set the [`BaseCounts::hp_len`] of every position from the
+    /// reference bases of its interval. Needs the FASTA file, not `use_md`.
+    pub homopolymer_length: bool,
+}
+
+impl PileupConfig {
+    /// Creates a config with the same defaults as the command line tool
+    pub fn new(bam_file_path: &str, fasta_file_path: &str, regions: Vec<BedRegion>) -> PileupConfig {
+        PileupConfig {
+            bam_file_path: bam_file_path.to_string(),
+            fasta_file_path: fasta_file_path.to_string(),
+            regions,
+            qscore_cutoff: 30,
+            min_mapq: 0,
+            exclude_flags: 0xF04,
+            include_flags: 0,
+            respect_bed_strand: false,
+            min_read_length: 0,
+            max_read_length: None,
+            interval_batch_size: None,
+            deletions_as_mismatches: false,
+            trim_ends: 0,
+            read_list: None,
+            exclude_read_list: None,
+            subsample: None,
+            seed: 0,
+            mode: Mode::PerRead,
+            mod_codes: vec![ModCode {
+                base: b'C',
+                strand: b'+',
+                code: "m".to_string(),
+            }],
+            min_mod_prob: 0,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            progress: false,
+            coverage: false,
+            qual_histogram: false,
+            mismatch_spectrum: false,
+            mismatch_profile: false,
+            debug_positions: None,
+            require_qual: false,
+            strict: false,
+            skip_errors: false,
+            unique_positions: false,
+            dedup_overlap: false,
+            max_depth: None,
+            fasta_case_sensitive: false,
+            count_matches_only: false,
+            strict_iupac: false,
+            use_md: false,
+            homopolymer_length: false,
+        }
+    }
+}
+
+/// Counts of a single contig, depending on the [`Mode`]
+pub enum ContigCounts {
+    /// Counts for each read_id
+    PerRead(HashMap<String, ReadCounts>),
+    /// Reference base and base counts for each 0-based position
+    PerPosition(BTreeMap<u32, (u8, BaseCounts)>),
+    /// Reference base and the counts of each modification of [`PileupConfig::mod_codes`] for
+    /// each 0-based position
+    Modifications(BTreeMap<u32, (u8, Vec<ModCounts>)>),
+    /// Aligned read bases sorted by position, and in BAM order at each position
+    Long(Vec<BaseObservation>),
+}
+
+impl ContigCounts {
+    /// Number of counted bases: the read bases that matched or mismatched the reference, the
+    /// bases at all positions, the modification calls or the listed read bases
+    pub fn counted_bases(&self) -> u64 {
+        match self {
+            ContigCounts::PerRead(read_counts) => read_counts
+                .values()
+                .map(|counts| (counts.matches() + counts.mismatches()) as u64)
+                .sum(),
+            ContigCounts::PerPosition(positions) => positions
+                .values()
+                .map(|(_, counts)| (counts.depth() - counts.del) as u64)
+                .sum(),
+            ContigCounts::Modifications(positions) => positions
+                .values()
+                .flat_map(|(_, code_counts)| code_counts)
+                .map(|counts| counts.calls() as u64)
+                .sum(),
+            ContigCounts::Long(observations) => observations.len() as u64,
+        }
+    }
+}
+
+/// Counts of all contigs, keyed by contig name
+#[derive(Default)]
+pub struct Summary {
+    /// Per-read counts of each contig, filled in [`Mode::PerRead`]
+    pub read_counts: HashMap<String, HashMap<String, ReadCounts>>,
+    /// Per-position counts of each contig, filled in [`Mode::PerPosition`]
+    pub position_counts: HashMap<String, BTreeMap<u32, (u8, BaseCounts)>>,
+    /// Modification counts of each contig, filled in [`Mode::Modifications`]
+    pub mod_counts: HashMap<String, BTreeMap<u32, (u8, Vec<ModCounts>)>>,
+    /// Aligned read bases of each contig, filled in [`Mode::Long`]
+    pub base_observations: HashMap<String, Vec<BaseObservation>>,
+    /// Coverage of every region in contig order, filled if [`PileupConfig::coverage`] is set
+    pub coverage: Vec<IntervalCoverage>,
+    /// Quality histogram of all regions, filled if [`PileupConfig::qual_histogram`] is set
+    pub qual_histogram: Option<QualHistogram>,
+    /// Mismatch spectrum of each contig, filled if [`PileupConfig::mismatch_spectrum`] is set
+    pub mismatch_spectra: HashMap<String, MismatchSpectrum>,
+    /// Mismatch profile of all regions, filled if [`PileupConfig::mismatch_profile`] is set
+    pub mismatch_profile: Option<MismatchProfile>,
+    /// First mismatching positions of every region in contig order, filled if
+    /// [`PileupConfig::debug_positions`] is set
+    pub debug_positions: Vec<DebugPositions>,
+    /// Reads of all regions that were not counted
+    pub skipped: SkippedReads,
+}
+
+/// Result of [`check_inputs`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputCheck {
+    pub n_intervals: usize,
+    pub n_contigs: usize,
+    /// Mapped reads expected in the intervals, estimated from the read counts in the BAM index
+    /// as if the reads were spread evenly over each contig. None for BAM input from stdin or
+    /// without an index that has these counts.
+    pub estimated_reads: Option<u64>,
+}
+
+/// Checks that the input files of the config can be read and that every contig of its regions
+/// is in both the BAM header and the FASTA file, without creating any index files. From stdin,
+/// only the BAM header is read. With `use_md` or per-read `count_matches_only`, the FASTA file
+/// is not checked.
+pub fn check_inputs(config: &PileupConfig) -> Result<InputCheck, BoxError> {
+    check_cram(config)?;
+    check_not_truncated(config)?;
+    let header = read_bam_header(&config.bam_file_path)?;
+    let fasta_names = if !reads_reference(config) {
+        None
+    } else {
+        reference::check_fasta_compression(&config.fasta_file_path)?;
+        Some(reference::fasta_sequence_names(&config.fasta_file_path)?)
+    };
+
+    let contig_regions = group_regions_by_contig(&config.regions);
+    for (ref_name, _) in &contig_regions {
+        if header.reference_id(ref_name).is_none() {
+            let message = format!("contig \"{}\" from BED not found in BAM header", ref_name);
+            return Err(Error::ContigMissing(message).into());
+        }
+        if fasta_names.as_ref().is_some_and(|fasta_names| !fasta_names.contains(ref_name)) {
+            return Err(Error::ContigMissing(format!(
+                "contig \"{}\" from BED not found in FASTA file {}",
+                ref_name, config.fasta_file_path
+            ))
+            .into());
+        }
+    }
+
+    // The pseudo-bin of every reference in the index holds its number of mapped reads
+    let bai_file_path = format!("{}.bai", config.bam_file_path);
+    let index = if remote::is_remote(&config.bam_file_path) {
+        let index_bytes = remote::fetch_remote_index(&config.bam_file_path)?;
+        Some(
+            bam::index::Index::from_stream(index_bytes.as_slice())
+                .map_err(|e| Error::Io(format!("failed to read BAM index {}: {}", bai_file_path, e)))?,
+        )
+    } else if config.bam_file_path != "-" && Path::new(&bai_file_path).exists() {
+        Some(
+            bam::index::Index::from_path(&bai_file_path)
+                .map_err(|e| Error::Io(format!("failed to read BAM index {}: {}", bai_file_path, e)))?,
+        )
+    } else {
+        None
+    };
+    let estimated_reads = if let Some(index) = index {
+        let mut estimated_reads = Some(0.0f64);
+        for BedRegion { ref_name, start, end, .. } in &config.regions {
+            let ref_id = header.reference_id(ref_name).unwrap();
+            let ref_len = header.reference_len(ref_id).unwrap_or(0).max(1);
+            let mapped = index
+                .references()
+                .get(ref_id as usize)
+                .and_then(|reference| reference.bins().get(&SUMMARY_BIN))
+                .and_then(|bin| bin.chunks().get(1))
+                .map(|chunk| chunk.start().raw());
+            estimated_reads = estimated_reads.zip(mapped).map(|(estimated_reads, mapped)| {
+                estimated_reads + mapped as f64 * end.saturating_sub(*start).min(ref_len) as f64 / ref_len as f64
+            });
+        }
+        estimated_reads.map(|estimated_reads| estimated_reads.round() as u64)
+    } else {
+        None
+    };
+
+    Ok(InputCheck {
+        n_intervals: config.regions.len(),
+        n_contigs: contig_regions.len(),
+        estimated_reads,
+    })
+}
+
+/// Names and lengths of the reference sequences in the header of a BAM file, in header order.
+/// With `-` the header is read from stdin, which leaves the reads unreadable.
+pub fn bam_reference_sequences(bam_file_path: &str) -> Result<Vec<(String, u32)>, BoxError> {
+    let header = read_bam_header(bam_file_path)?;
+    Ok(header
+        .reference_names()
+        .iter()
+        .cloned()
+        .zip(header.reference_lengths().iter().copied())
+        .collect())
+}
+
+/// Contigs in both a BAM header and a FASTA file whose lengths differ, with the length in the
+/// BAM header and in the FASTA file, in header order. A different length usually means the
+/// reads were aligned to another genome build.
+pub fn reference_length_mismatches(
+    bam_file_path: &str,
+    fasta_file_path: &str,
+) -> Result<Vec<(String, u32, u64)>, BoxError> {
+    let fasta_lengths = reference::fasta_sequence_lengths(fasta_file_path)?;
+    Ok(bam_reference_sequences(bam_file_path)?
+        .into_iter()
+        .filter_map(|(ref_name, bam_len)| match fasta_lengths.get(&ref_name) {
+            Some(&fasta_len) if fasta_len != bam_len as u64 => Some((ref_name, bam_len, fasta_len)),
+            _ => None,
+        })
+        .collect())
+}
+
+// Reads only the header of a BAM or CRAM file, from stdin for `-`
+fn read_bam_header(bam_file_path: &str) -> Result<bam::Header, BoxError> {
+    if cram::is_cram(bam_file_path) {
+        check_cram_is_local(bam_file_path)?;
+        return cram::read_header(bam_file_path);
+    }
+    if bam_file_path == "-" {
+        return Ok(bam::BamReader::from_stream(io::stdin().lock(), 0)
+            .map_err(|e| Error::Io(format!("failed to read BAM from stdin: {}", e)))?
+            .header()
+            .clone());
+    }
+    if remote::is_remote(bam_file_path) {
+        let mut reader = bam::bgzip::SeekReader::from_stream(remote::RemoteFile::open(bam_file_path)?, 0)
+            .map_err(|e| Error::Io(format!("failed to open BAM file {}: {}", bam_file_path, e)))?;
+        reader.make_consecutive();
+        return Ok(bam::Header::from_bam(&mut reader)
+            .map_err(|e| Error::Io(format!("failed to read BAM header of {}: {}", bam_file_path, e)))?);
+    }
+    let mut reader = bam::bgzip::SeekReader::from_path(bam_file_path, 0)
+        .map_err(|e| Error::Io(format!("failed to open BAM file {}: {}", bam_file_path, e)))?;
+    reader.make_consecutive();
+    Ok(bam::Header::from_bam(&mut reader)
+        .map_err(|e| Error::Io(format!("failed to read BAM header of {}: {}", bam_file_path, e)))?)
+}
+
+// Bin of the BAI index that holds the read counts of a reference instead of chunks
+const SUMMARY_BIN: u32 = 37450;
+
+// Checks that a local BAM file ends with the empty BGZF block that marks the end of complete
+// files. Reads of a file cut off at the end of a block would otherwise just seem to end early.
+fn check_not_truncated(config: &PileupConfig) -> Result<(), BoxError> {
+    const BGZF_EOF: [u8; 28] = [
+        0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00, 0x1b, 0x00,
+        0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let bam_file_path = &config.bam_file_path;
+    if bam_file_path == "-" || remote::is_remote(bam_file_path) || cram::is_cram(bam_file_path) {
+        return Ok(());
+    }
+    let io_error = |e: io::Error| Error::Io(format!("failed to read BAM file {}: {}", bam_file_path, e));
+    let mut file = File::open(bam_file_path).map_err(io_error)?;
+    let mut tail = [0u8; 28];
+    let complete = match file.seek(SeekFrom::End(-(BGZF_EOF.len() as i64))) {
+        Ok(_) => {
+            file.read_exact(&mut tail).map_err(io_error)?;
+            tail == BGZF_EOF
+        }
+        // Shorter than the marker itself
+        Err(_) => false,
+    };
+    if !complete {
+        let message = format!("BAM file {} is truncated, it lacks the BGZF end-of-file marker", bam_file_path);
+        if !config.skip_errors {
+            return Err(Error::Io(message).into());
+        }
+        log::warn!("{}, counting the reads before the end", message);
+    }
+    Ok(())
+}
+
+// CRAM files are decoded from a local file with the reference they were compressed against, so
+// fail early with a hint instead of a decoding error
+fn check_cram(config: &PileupConfig) -> Result<(), BoxError> {
+    if !cram::is_cram(&config.bam_file_path) {
+        return Ok(());
+    }
+    check_cram_is_local(&config.bam_file_path)?;
+    if config.fasta_file_path.is_empty() {
+        return Err(format!(
+            "CRAM file {} can only be decoded with the reference it was compressed against, pass it with --fasta",
+            config.bam_file_path
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn check_cram_is_local(cram_file_path: &str) -> Result<(), BoxError> {
+    if remote::is_remote(cram_file_path) {
+        return Err(format!("remote CRAM files are not supported ({}), download it first", cram_file_path).into());
+    }
+    Ok(())
+}
+
+// Whether the reads of a file are counted in a single pass instead of being fetched by region,
+// for BAM files from stdin and CRAM files
+fn is_read_linearly(bam_file_path: &str) -> bool {
+    bam_file_path == "-" || cram::is_cram(bam_file_path)
+}
+
+/// Counts all regions of the config and returns the counts of all contigs
+pub fn run(config: &PileupConfig) -> Result<Summary, BoxError> {
+    let mut summary = Summary::default();
+    for_each_sample_contig(config, &[config_sample(config)], |ref_name, mut sample_counts| {
+        let SampleCounts {
+            counts,
+            coverage,
+            qual_histogram,
+            mismatch_spectrum,
+            mismatch_profile,
+            debug_positions,
+            skipped,
+            ..
+        } = sample_counts.pop().unwrap();
+        summary.coverage.extend(coverage);
+        summary.debug_positions.extend(debug_positions);
+        summary.skipped.merge(&skipped);
+        if let Some(qual_histogram) = qual_histogram {
+            summary.qual_histogram.get_or_insert_with(QualHistogram::new).merge(&qual_histogram);
+        }
+        if let Some(mismatch_spectrum) = mismatch_spectrum {
+            summary.mismatch_spectra.insert(ref_name.to_string(), mismatch_spectrum);
+        }
+        if let Some(mismatch_profile) = mismatch_profile {
+            summary.mismatch_profile.get_or_insert_with(MismatchProfile::default).merge(&mismatch_profile);
+        }
+        match counts {
+            ContigCounts::PerRead(read_counts) => {
+                summary.read_counts.insert(ref_name.to_string(), read_counts);
+            }
+            ContigCounts::PerPosition(positions) => {
+                summary.position_counts.insert(ref_name.to_string(), positions);
+            }
+            ContigCounts::Modifications(positions) => {
+                summary.mod_counts.insert(ref_name.to_string(), positions);
+            }
+            ContigCounts::Long(observations) => {
+                summary.base_observations.insert(ref_name.to_string(), observations);
+            }
+        }
+        Ok(())
+    })?;
+    Ok(summary)
+}
+
+/// A BAM file counted as one sample of a multi-sample run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sample {
+    pub name: String,
+    /// BAM file of the sample, `-` for stdin
+    pub bam_file_path: String,
+}
+
+/// Counts of one contig in a single sample
+pub struct SampleCounts {
+    pub counts: ContigCounts,
+    /// Number of reads counted in the contig's regions, reads in several regions once for each
+    pub n_reads: usize,
+    /// Reads of the contig's regions that were not counted
+    pub skipped: SkippedReads,
+    /// Coverage of the contig's regions, filled if [`PileupConfig::coverage`] is set
+    pub coverage: Vec<IntervalCoverage>,
+    /// Quality histogram of the contig's regions, filled if [`PileupConfig::qual_histogram`] is set
+    pub qual_histogram: Option<QualHistogram>,
+    /// Mismatch spectrum of the contig's regions, filled if [`PileupConfig::mismatch_spectrum`] is set
+    pub mismatch_spectrum: Option<MismatchSpectrum>,
+    /// Mismatch profile of the contig's regions, filled if [`PileupConfig::mismatch_profile`] is set
+    pub mismatch_profile: Option<MismatchProfile>,
+    /// First mismatching positions of the contig's regions, filled if
+    /// [`PileupConfig::debug_positions`] is set
+    pub debug_positions: Vec<DebugPositions>,
+}
+
+// The BAM file of the config as the only sample
+fn config_sample(config: &PileupConfig) -> Sample {
+    Sample {
+        name: String::new(),
+        bam_file_path: config.bam_file_path.clone(),
+    }
+}
+
+/// Counts all regions of the config and hands the counts of each contig to `handle_contig`,
+/// together with the coverage of its regions if [`PileupConfig::coverage`] is set.
+///
+/// The intervals are processed one contig at a time, so that each contig's counts can be
+/// written out and dropped as soon as its last interval is done. This relies on all intervals
+/// of a contig being grouped together, which [`group_regions_by_contig`] takes care of.
+pub fn for_each_contig<F>(config: &PileupConfig, mut handle_contig: F) -> Result<(), BoxError>
+where
+    F: FnMut(&str, ContigCounts, Vec<IntervalCoverage>) -> Result<(), BoxError>,
+{
+    for_each_sample_contig(config, &[config_sample(config)], |ref_name, mut sample_counts| {
+        let SampleCounts { counts, coverage, .. } = sample_counts.pop().unwrap();
+        handle_contig(ref_name, counts, coverage)
+    })
+}
+
+/// Like [`for_each_contig`], but counts the regions of the config in the BAM file of every
+/// sample instead of `config.bam_file_path`. Each contig is counted in all samples before the
+/// next one, and handed to `handle_contig` with the counts of the samples in the given order.
+/// With several samples, errors name the sample they happened in.
+pub fn for_each_sample_contig<F>(
+    config: &PileupConfig,
+    samples: &[Sample],
+    mut handle_contig: F,
+) -> Result<(), BoxError>
+where
+    F: FnMut(&str, Vec<SampleCounts>) -> Result<(), BoxError>,
+{
+    count_samples(config, samples, false, |ref_name, _, sample_counts| handle_contig(ref_name, sample_counts))
+}
+
+/// Like [`for_each_sample_contig`], but hands the counts of every interval to `handle_interval`
+/// on their own instead of summing up each contig. The intervals of a contig are still counted
+/// together, and come in the order of the config's regions grouped by contig.
+pub fn for_each_sample_interval<F>(
+    config: &PileupConfig,
+    samples: &[Sample],
+    mut handle_interval: F,
+) -> Result<(), BoxError>
+where
+    F: FnMut(&BedRegion, Vec<SampleCounts>) -> Result<(), BoxError>,
+{
+    count_samples(config, samples, true, |_, region, sample_counts| {
+        handle_interval(region.expect("intervals are handled one at a time"), sample_counts)
+    })
+}
+
+// Counts the regions of the config in all samples, one contig at a time, and hands the counts
+// of every contig, or with `by_interval` of every interval of it, to `handle_counts`
+fn count_samples<F>(
+    config: &PileupConfig,
+    samples: &[Sample],
+    by_interval: bool,
+    mut handle_counts: F,
+) -> Result<(), BoxError>
+where
+    F: FnMut(&str, Option<&BedRegion>, Vec<SampleCounts>) -> Result<(), BoxError>,
+{
+    if samples.iter().filter(|sample| sample.bam_file_path == "-").count() > 1 {
+        return Err("only one BAM file can be read from stdin".into());
+    }
+    let sample_error = |sample: &Sample, e: BoxError| -> BoxError {
+        if samples.len() > 1 {
+            error::with_context(e, &format!("sample {}", sample.name))
+        } else {
+            e
+        }
+    };
+    let sample_configs: Vec<PileupConfig> = samples
+        .iter()
+        .map(|sample| PileupConfig {
+            bam_file_path: sample.bam_file_path.clone(),
+            ..config.clone()
+        })
+        .collect();
+    for (sample, sample_config) in samples.iter().zip(&sample_configs) {
+        check_cram(sample_config).map_err(|e| sample_error(sample, e))?;
+        check_not_truncated(sample_config).map_err(|e| sample_error(sample, e))?;
+    }
+
+    // Random access into the reference needs a .fai index, create it if it's missing
+    if reads_reference(config) {
+        reference::ensure_fasta_index(&config.fasta_file_path)?;
+    }
+    // Same for the BAM files, unless they are read in a single pass. Remote files can't get one,
+    // so their index is downloaded once and the server is checked for range requests up front.
+    let mut remote_indices = Vec::with_capacity(samples.len());
+    for sample in samples {
+        remote_indices.push(if remote::is_remote(&sample.bam_file_path) {
+            remote::RemoteFile::open(&sample.bam_file_path).map_err(|e| sample_error(sample, e))?;
+            Some(remote::fetch_remote_index(&sample.bam_file_path).map_err(|e| sample_error(sample, e))?)
+        } else {
+            if !is_read_linearly(&sample.bam_file_path) {
+                index::ensure_bam_index(&sample.bam_file_path).map_err(|e| sample_error(sample, e))?;
+            }
+            None
+        });
+    }
+
+    let progress = Progress::new(config.regions.len() * samples.len(), config.progress);
+    let contig_regions = group_regions_by_contig(&config.regions);
+    // Reads from stdin or a CRAM file aren't fetched by region, so all contigs are counted up
+    // front in a single pass over the reads
+    let mut linear_results = Vec::with_capacity(samples.len());
+    for (sample, sample_config) in samples.iter().zip(&sample_configs) {
+        linear_results.push(if is_read_linearly(&sample.bam_file_path) {
+            let results = count_regions_linear(&contig_regions, sample_config, &progress)
+                .map_err(|e| sample_error(sample, e))
+                .inspect_err(|_| progress.clear())?;
+            Some(results.into_iter())
+        } else {
+            None
+        });
+    }
+    let any_indexed = samples.iter().any(|sample| !is_read_linearly(&sample.bam_file_path));
+    for (ref_name, regions) in contig_regions {
+        // The reference bases of the contig's intervals are read once for all samples and
+        // worker threads, and dropped before the next contig
+        let reference = if !reads_reference(config) || !any_indexed {
+            None
+        } else {
+            Some(ReferenceCache::load(&config.fasta_file_path, &regions).inspect_err(|_| progress.clear())?)
+        };
+        let mut sample_results = Vec::with_capacity(samples.len());
+        let sample_inputs = samples.iter().zip(&sample_configs).zip(&remote_indices);
+        for (((sample, sample_config), remote_index), linear_results) in sample_inputs.zip(&mut linear_results) {
+            sample_results.push(match linear_results {
+                Some(results) => results.next().unwrap_or_default(),
+                // Count reads in all intervals of the contig, distributed over the worker threads
+                None => process_regions(&regions, sample_config, reference.as_ref(), remote_index.as_deref(), &progress)
+                    .map_err(|e| sample_error(sample, e))
+                    .inspect_err(|_| progress.clear())?,
+            });
+        }
+        let log_name = |sample: &Sample, name: &str| {
+            if samples.len() > 1 {
+                format!("{} {}", sample.name, name)
+            } else {
+                name.to_string()
+            }
+        };
+        // Nothing else may be printed while the progress bar is shown
+        progress.suspend(|| -> Result<(), BoxError> {
+            if by_interval {
+                let mut sample_results: Vec<_> = sample_results.into_iter().map(Vec::into_iter).collect();
+                for region in &regions {
+                    let interval_name = format!("{}:{}-{}", region.ref_name, region.start + 1, region.end);
+                    let sample_counts = samples
+                        .iter()
+                        .zip(&mut sample_results)
+                        .map(|(sample, results)| {
+                            let region_results = results.next().into_iter().collect();
+                            merge_region_counts(config.mode, region_results, &log_name(sample, &interval_name))
+                        })
+                        .collect();
+                    handle_counts(&ref_name, Some(region), sample_counts)?;
+                }
+            } else {
+                let sample_counts = samples
+                    .iter()
+                    .zip(sample_results)
+                    .map(|(sample, region_results)| {
+                        merge_region_counts(config.mode, region_results, &log_name(sample, &ref_name))
+                    })
+                    .collect();
+                handle_counts(&ref_name, None, sample_counts)?;
+            }
+            Ok(())
+        })
+        .inspect_err(|_| progress.clear())?;
+    }
+    progress.finish();
+
+    Ok(())
+}
+
+// Merges the counts of the regions of a contig, and logs how many reads were counted and skipped
+fn merge_region_counts(mode: Mode, mut region_results: Vec<RegionCounts>, log_name: &str) -> SampleCounts {
+    let coverage: Vec<IntervalCoverage> =
+        region_results.iter_mut().filter_map(|region_counts| region_counts.coverage.take()).collect();
+    let debug_positions: Vec<DebugPositions> =
+        region_results.iter_mut().filter_map(|region_counts| region_counts.debug_positions.take()).collect();
+    let mut qual_histogram: Option<QualHistogram> = None;
+    let mut mismatch_spectrum: Option<MismatchSpectrum> = None;
+    let mut mismatch_profile: Option<MismatchProfile> = None;
+    let mut records = 0;
+    let mut without_qual = 0;
+    let mut skipped = SkippedReads::default();
+    for region_counts in &region_results {
+        records += region_counts.records;
+        without_qual += region_counts.without_qual;
+        skipped.merge(&region_counts.skipped);
+        if let Some(region_histogram) = &region_counts.qual_histogram {
+            qual_histogram.get_or_insert_with(QualHistogram::new).merge(region_histogram);
+        }
+        if let Some(region_spectrum) = &region_counts.mismatch_spectrum {
+            mismatch_spectrum.get_or_insert_with(MismatchSpectrum::default).merge(region_spectrum);
+        }
+        if let Some(region_profile) = &region_counts.mismatch_profile {
+            mismatch_profile.get_or_insert_with(MismatchProfile::default).merge(region_profile);
+        }
+    }
+    log::debug!(
+        "{}: {} reads in {} intervals, skipped {} with low mapping quality, {} by flags, {} by strand, {} by read \
+         list, {} by subsampling, {} by aligned length, {} outside of the intervals, {} without sequence, {} \
+         without base qualities",
+        log_name,
+        records,
+        region_results.len(),
+        skipped.low_mapq,
+        skipped.filtered_flags,
+        skipped.wrong_strand,
+        skipped.read_list,
+        skipped.subsampled,
+        skipped.read_length,
+        skipped.out_of_bounds,
+        skipped.empty_seq,
+        skipped.missing_qual
+    );
+    if skipped.empty_seq > 0 {
+        log::warn!(
+            "{}: skipped {} reads without a stored sequence",
+            log_name,
+            skipped.empty_seq
+        );
+    }
+    if without_qual > 0 {
+        log::warn!(
+            "{}: {} reads have no base qualities, all of their bases pass the Q-score cutoff",
+            log_name,
+            without_qual
+        );
+    }
+
+    let counts = match mode {
+        Mode::PerPosition => {
+            // Merge the per-region base counts, keeping positions sorted
+            let mut positions: BTreeMap<u32, (u8, BaseCounts)> = BTreeMap::new();
+            for region_counts in region_results {
+                for (pos, (reference_base, counts)) in region_counts.position_counts {
+                    let (_, ref_counts) = positions
+                        .entry(pos)
+                        .or_insert_with(|| (reference_base, BaseCounts::default()));
+                    ref_counts.merge(&counts);
+                }
+            }
+            ContigCounts::PerPosition(positions)
+        }
+        Mode::Modifications => {
+            // Merge the per-region modification counts, keeping positions sorted
+            let mut positions: BTreeMap<u32, (u8, Vec<ModCounts>)> = BTreeMap::new();
+            for region_counts in region_results {
+                for (pos, (reference_base, code_counts)) in region_counts.mod_counts {
+                    match positions.entry(pos) {
+                        btree_map::Entry::Vacant(entry) => {
+                            entry.insert((reference_base, code_counts));
+                        }
+                        btree_map::Entry::Occupied(mut entry) => {
+                            for (ref_counts, counts) in entry.get_mut().1.iter_mut().zip(&code_counts) {
+                                ref_counts.merge(counts);
+                            }
+                        }
+                    }
+                }
+            }
+            ContigCounts::Modifications(positions)
+        }
+        Mode::Long => {
+            // Intervals may overlap with --keep-intervals, so sort the bases of all of them
+            let mut observations: Vec<BaseObservation> =
+                region_results.into_iter().flat_map(|region_counts| region_counts.base_observations).collect();
+            observations.sort_by_key(|observation| observation.pos);
+            ContigCounts::Long(observations)
+        }
+        Mode::PerRead => {
+            // Merge the per-region counts of each read_id
+            let mut read_counts: HashMap<String, ReadCounts> = HashMap::new();
+            for region_counts in region_results {
+                for (read_id, counts) in region_counts.read_counts {
+                    read_counts.entry(read_id).or_default().merge(&counts);
+                }
+            }
+            ContigCounts::PerRead(read_counts)
+        }
+    };
+    SampleCounts {
+        counts,
+        n_reads: records - skipped.total(),
+        skipped,
+        coverage,
+        qual_histogram,
+        mismatch_spectrum,
+        mismatch_profile,
+        debug_positions,
+    }
+}
+
+// Counts all regions in a single pass over the reads of a BAM file on stdin or of a CRAM file.
+// Every read is checked against all intervals of its contig, which are held in memory together
+// with their reference bases. Returns the counts of each contig's regions in the given order.
+fn count_regions_linear(
+    contig_regions: &[(String, Vec<BedRegion>)],
+    config: &PileupConfig,
+    progress: &Progress,
+) -> Result<Vec<Vec<RegionCounts>>, BoxError> {
+    // Look up the reference bases of all intervals, unless the reads carry them
+    let reference = if !reads_reference(config) {
+        None
+    } else {
+        Some(ReferenceCache::load(&config.fasta_file_path, &config.regions)?)
+    };
+    let counts = if cram::is_cram(&config.bam_file_path) {
+        let header = cram::read_header(&config.bam_file_path)?;
+        let mut counts = LinearCounts::new(contig_regions, &header, reference.as_ref(), config)?;
+        cram::for_each_record(&config.bam_file_path, &config.fasta_file_path, config.skip_errors, |record| {
+            counts.add_record(record, config)
+        })?;
+        counts.finish(config)
+    } else {
+        let additional_threads = config.threads.saturating_sub(1).min(u16::MAX as usize) as u16;
+        let mut bam = bam::BamReader::from_stream(io::stdin().lock(), additional_threads)
+            .map_err(|e| Error::Io(format!("failed to read BAM from stdin: {}", e)))?;
+        let mut counts = LinearCounts::new(contig_regions, bam.header(), reference.as_ref(), config)?;
+        let mut record = bam::Record::new();
+        let mut n_read = 0;
+        loop {
+            match bam.read_into(&mut record) {
+                Ok(false) => break,
+                Ok(true) => n_read += 1,
+                Err(e) => {
+                    let message = format!("failed to read BAM from stdin after {} reads: {}", n_read, e);
+                    if config.skip_errors {
+                        log::warn!("{}, counting the reads before", message);
+                        break;
+                    }
+                    return Err(Error::Fetch(message).into());
+                }
+            }
+            counts.add_record(&record, config)?;
+        }
+        counts.finish(config)
+    };
+    for _ in &config.regions {
+        progress.advance();
+    }
+    Ok(counts)
+}
+
+// Counts of the regions of all contigs while their reads are passed in one after the other
+struct LinearCounts<'a> {
+    contig_regions: &'a [(String, Vec<BedRegion>)],
+    // Index into the contigs of the regions by the reference ID in the header of the reads
+    contig_by_ref_id: HashMap<u32, usize>,
+    reference_sequences: Vec<Vec<&'a [u8]>>,
+    counts: Vec<Vec<RegionCounts>>,
+}
+
+impl<'a> LinearCounts<'a> {
+    fn new(
+        contig_regions: &'a [(String, Vec<BedRegion>)],
+        header: &bam::Header,
+        reference: Option<&'a ReferenceCache>,
+        config: &PileupConfig,
+    ) -> Result<LinearCounts<'a>, BoxError> {
+        let mut contig_by_ref_id: HashMap<u32, usize> = HashMap::new();
+        let mut reference_sequences: Vec<Vec<&[u8]>> = Vec::new();
+        for (contig, (ref_name, regions)) in contig_regions.iter().enumerate() {
+            let ref_id = header.reference_id(ref_name).ok_or_else(|| {
+                Error::ContigMissing(format!("contig \"{}\" from BED not found in BAM header", ref_name))
+            })?;
+            contig_by_ref_id.insert(ref_id, contig);
+            let mut sequences = Vec::new();
+            for BedRegion { start, end, .. } in regions {
+                let reference_sequence = match reference {
+                    Some(reference) => cached_interval(reference, ref_name, *start, *end),
+                    None => &[],
+                };
+                sequences.push(reference_sequence);
+            }
+            reference_sequences.push(sequences);
+        }
+
+        let mut counts: Vec<Vec<RegionCounts>> = contig_regions
+            .iter()
+            .map(|(_, regions)| regions.iter().map(|region| RegionCounts::new(region, config)).collect())
+            .collect();
+        if config.unique_positions {
+            for (contig_counts, (_, regions)) in counts.iter_mut().zip(contig_regions) {
+                for (region_counts, counted_before) in contig_counts.iter_mut().zip(earlier_overlaps(regions)) {
+                    region_counts.counted_before = counted_before;
+                }
+            }
+        }
+        for (contig_counts, sequences) in counts.iter_mut().zip(&reference_sequences) {
+            for (region_counts, reference_sequence) in contig_counts.iter_mut().zip(sequences) {
+                region_counts.mask_reference(reference_sequence, config);
+            }
+        }
+        Ok(LinearCounts {
+            contig_regions,
+            contig_by_ref_id,
+            reference_sequences,
+            counts,
+        })
+    }
+
+    // Adds a read to every interval of its contig it overlaps
+    fn add_record(&mut self, record: &bam::Record, config: &PileupConfig) -> Result<(), BoxError> {
+        let Some(&contig) = u32::try_from(record.ref_id()).ok().and_then(|ref_id| self.contig_by_ref_id.get(&ref_id))
+        else {
+            return Ok(());
+        };
+        let (read_start, read_end) = (record.start(), record.calculate_end());
+        for (index, BedRegion { start, end, .. }) in self.contig_regions[contig].1.iter().enumerate() {
+            if read_start < *end as i32 && read_end > *start as i32 {
+                let reference_sequence = self.reference_sequences[contig][index];
+                self.counts[contig][index].add_record(record, reference_sequence, *start, *end, config)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, config: &PileupConfig) -> Vec<Vec<RegionCounts>> {
+        let contigs = self.contig_regions.iter().zip(&mut self.counts).zip(&self.reference_sequences);
+        for (((_, regions), contig_counts), sequences) in contigs {
+            for ((BedRegion { start, .. }, region_counts), reference_sequence) in
+                regions.iter().zip(contig_counts).zip(sequences)
+            {
+                region_counts.finish(reference_sequence, *start, config);
+            }
+        }
+        self.counts
+    }
+}
+
+// Groups intervals by contig, keeping the contigs and their intervals in BED order
+pub fn group_regions_by_contig(regions: &[BedRegion]) -> Vec<(String, Vec<BedRegion>)> {
+    let mut contig_regions: Vec<(String, Vec<BedRegion>)> = Vec::new();
+    let mut contig_index: HashMap<String, usize> = HashMap::new();
+    for region in regions {
+        let index = *contig_index.entry(region.ref_name.clone()).or_insert_with(|| {
+            contig_regions.push((region.ref_name.clone(), Vec::new()));
+            contig_regions.len() - 1
+        });
+        contig_regions[index].1.push(region.clone());
+    }
+    contig_regions
+}
+
+// Counts all regions on a pool of `config.threads` worker threads and returns the counts in
+// region order. BAM readers are not shareable between threads, so every worker opens its own,
+// while all of them look up the reference bases in the same cache. There is none if the
+// reference bases come from MD tags. Remote BAM files are read with the given contents of
+// their index.
+fn process_regions(
+    regions: &[BedRegion],
+    config: &PileupConfig,
+    reference: Option<&ReferenceCache>,
+    remote_index: Option<&[u8]>,
+    progress: &Progress,
+) -> Result<Vec<RegionCounts>, BoxError> {
+    let bam_file_path = config.bam_file_path.as_str();
+    let open_error = |e: io::Error| format!("failed to open BAM file {}: {}", bam_file_path, e);
+    match remote_index {
+        Some(remote_index) => count_regions_parallel(regions, config, reference, progress, || {
+            let remote_file = remote::RemoteFile::open(bam_file_path).map_err(|e| e.to_string())?;
+            bam::IndexedReader::build().from_streams(remote_file, remote_index).map_err(open_error)
+        }),
+        None => count_regions_parallel(regions, config, reference, progress, || {
+            bam::IndexedReader::from_path(Path::new(bam_file_path)).map_err(open_error)
+        }),
+    }
+}
+
+// Counts the regions in parallel with the BAM readers opened by `open_bam`, one for each batch
+// of regions a worker takes on
+fn count_regions_parallel<R, F>(
+    regions: &[BedRegion],
+    config: &PileupConfig,
+    reference: Option<&ReferenceCache>,
+    progress: &Progress,
+    open_bam: F,
+) -> Result<Vec<RegionCounts>, BoxError>
+where
+    R: Read + Seek,
+    F: Fn() -> Result<bam::IndexedReader<R>, String> + Sync + Send,
+{
+    let counted_before = if config.unique_positions { earlier_overlaps(regions) } else { Vec::new() };
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.threads.clamp(1, regions.len().max(1)))
+        .build()?;
+    pool.install(|| {
+        regions
+            .par_iter()
+            .enumerate()
+            .map_init(&open_bam, |bam, (index, region)| {
+                let bam = bam.as_mut().map_err(|e| Error::Io(e.clone()))?;
+                let region_counted_before = counted_before.get(index).map_or(&[][..], Vec::as_slice);
+                let reference_sequence =
+                    reference.map(|reference| cached_interval(reference, &region.ref_name, region.start, region.end));
+                let counts = count_region(bam, reference_sequence, region, region_counted_before, config)?;
+                progress.advance();
+                Ok(counts)
+            })
+            .collect()
+    })
+}
+
+// Whether the counts need the reference bases from the FASTA file
+fn reads_reference(config: &PileupConfig) -> bool {
+    !(config.use_md || config.count_matches_only && config.mode == Mode::PerRead)
+}
+
+// Reference bases of a region from a cache loaded for it
+fn cached_interval<'a>(reference: &'a ReferenceCache, ref_name: &str, start: u32, end: u32) -> &'a [u8] {
+    reference
+        .get(ref_name, start, end)
+        .expect("reference bases of every region are cached")
+}
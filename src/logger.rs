@@ -0,0 +1,40 @@
+//! Diagnostic messages on stderr, written with the macros of the [`log`] crate.
+//!
+//! Messages never go to stdout, so they can't end up in tables written there. Errors are
+//! prefixed with `Error:` and warnings with `Warning:`, other messages are printed as they are. While a progress bar is
+//! drawn, messages of any thread are printed above it.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::progress;
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error => progress::print_message(format_args!("Error: {}", record.args())),
+            Level::Warn => progress::print_message(format_args!("Warning: {}", record.args())),
+            _ => progress::print_message(*record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Prints the messages of the `log` macros on stderr up to the given level. Can be called
+/// again to change the level.
+pub fn init(level: LevelFilter) {
+    // Fails only if a logger is already set, which is then this one
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
@@ -1,17 +1,38 @@
-extern crate bam;
-extern crate bio;
+extern crate bampile;
 extern crate clap;
 
-use bam::{RecordReader, RecordWriter};
-use bio::io::fasta;
-use clap::{Arg, ArgAction, Command};
-use std::collections::{HashMap, BTreeSet};
-use std::fs::{File, create_dir_all};
-use std::io::{BufRead, BufReader, Write};
-use std::path::{Path, PathBuf};
+use bampile::output::{
+    encode_filename, set_header_comments, set_table_style, write_contig_summary, write_manifest, write_mismatch_profiles,
+    write_mismatch_spectra, write_qual_histograms, BaseObservationWriter, Compression, CoverageWriter, DebugPositionsWriter, KnownSitesWriter,
+    ModCountsWriter, ParquetPositionCountsWriter, ParquetReadCountsWriter, PositionColumns, PositionCountsWriter,
+    ReadColumns, ReadCountsWriter, RunManifest, VcfWriter,
+};
+use bampile::reference::fasta_sequence_lengths;
+use bampile::{
+    add_flanks, clamp_to_contig_lengths, group_regions_by_contig, load_bed_regions, load_gff_features, load_known_sites,
+    load_positions, load_read_list, logger, merge_overlapping_regions, parse_mod_code, parse_region, sort_by_contig_order,
+    BedRegion, BoxError, ConsensusSettings, ContigCounts, KnownSite, MismatchProfile, MismatchSpectrum, Mode, PileupConfig,
+    QualHistogram, ReadTotals, ReadsAcrossContigs, Sample, SampleCounts, SkippedReads, Strand, MAX_PHRED_QUALITY,
+};
+use clap::{Arg, ArgAction, ArgGroup, Command};
+use log::LevelFilter;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, create_dir_all};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let matches = Command::new("BAM Pileup Analyzer")
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(bampile::error::exit_code(&e));
+    }
+}
+
+
+// Arguments of the command line
+fn command() -> Command {
+    Command::new("BAM Pileup Analyzer")
         .version("1.0")
         .author("Patrick Bohn")
         .about("Counts matches/mismatches at specified positions in a BAM file")
@@ -20,15 +41,109 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .short('b')
                 .long("bam")
                 .value_name("BAM_FILE")
-                .help("Input BAM file")
+                .help("Input BAM file, an http(s):// or s3:// URL, - to read an unindexed BAM from stdin, or a CRAM \
+                       file decoded with --fasta. Several files, comma-separated or by repeating --bam, are counted \
+                       as separate samples")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
                 .required(true),
         )
+        .arg(
+            Arg::new("sample_name")
+                .long("sample-name")
+                .value_name("NAME")
+                .help("Sample name of each BAM file for the sample column [default: file name without .bam or .cram]")
+                .action(ArgAction::Append)
+                .value_delimiter(','),
+        )
         .arg(
             Arg::new("bed")
                 .short('e')
                 .long("bed")
                 .value_name("BED_FILE")
-                .help("Input BED file")
+                .help("Input BED file"),
+        )
+        .arg(
+            Arg::new("region")
+                .short('r')
+                .long("region")
+                .value_name("REGION")
+                .help("Single region to analyze instead of a BED file, as chr:start-end (1-based, inclusive)"),
+        )
+        .arg(
+            Arg::new("positions")
+                .long("positions")
+                .value_name("FILE")
+                .help("File of single positions to analyze instead of a BED file, as chrom<TAB>pos (1-based) rows"),
+        )
+        .arg(
+            Arg::new("gff")
+                .long("gff")
+                .value_name("FILE")
+                .help("GFF3 or GTF file whose features of the --feature-type to analyze instead of a BED file, named \
+                       by their Name, ID, gene_name or gene_id attribute"),
+        )
+        .arg(
+            Arg::new("feature_type")
+                .long("feature-type")
+                .value_name("TYPE")
+                .help("Type of the --gff features to analyze, as in the 3rd GFF column, e.g. exon or CDS")
+                .requires("gff")
+                .default_value("exon"),
+        )
+        .arg(
+            Arg::new("known_sites")
+                .long("known-sites")
+                .value_name("FILE")
+                .help("Genotype the SNVs of this VCF or chrom<TAB>pos<TAB>ref<TAB>alt file instead, with the reads \
+                       showing the ref, alt and other bases and the VAF of each site")
+                .conflicts_with_all(["mode", "mods", "long"]),
+        )
+        .arg(
+            Arg::new("one_based")
+                .long("one-based")
+                .help("Interpret BED coordinates as 1-based, closed intervals")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("respect_bed_strand")
+                .long("respect-bed-strand")
+                .help("Only count forward reads in intervals with a + in the 6th BED column and reverse reads in \
+                       those with a -")
+                .conflicts_with_all(["region", "positions", "known_sites"])
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("flank")
+                .long("flank")
+                .value_name("BASES")
+                .help("Extend every interval by this many bases on both sides, within the bounds of its contig")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("contig_list")
+                .long("contig-list")
+                .value_name("CONTIGS")
+                .help("Only count the intervals on these contigs, comma-separated or one per line in a file"),
+        )
+        .arg(
+            Arg::new("keep_intervals")
+                .long("keep-intervals")
+                .help("Count every interval on its own instead of merging overlapping and adjacent ones first")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("count_only_unique_positions")
+                .long("count-only-unique-positions")
+                .help("With --keep-intervals, count each position of a read only in the first interval containing \
+                       it (per-read counts)")
+                .requires("keep_intervals")
+                .conflicts_with("respect_bed_strand")
+                .action(ArgAction::SetTrue),
+        )
+        .group(
+            ArgGroup::new("regions")
+                .args(["bed", "region", "positions", "gff", "known_sites"])
                 .required(true),
         )
         .arg(
@@ -36,15 +151,81 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .short('f')
                 .long("fasta")
                 .value_name("FASTA_FILE")
-                .help("Reference FASTA file")
-                .required(true),
+                .help("Reference FASTA file, optional with --use-md or --count-matches-only")
+                .required_unless_present_any(["use_md", "count_matches_only"]),
+        )
+        .arg(
+            Arg::new("use_md")
+                .long("use-md")
+                .help("Rebuild the reference bases from the MD tags of the reads instead of reading them from the FASTA file")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("fasta_case_sensitive"),
+        )
+        .arg(
+            Arg::new("reference_check")
+                .long("reference-check")
+                .help("Fail before counting if any contig has a different length in the BAM header than in the \
+                       FASTA file")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("use_md"),
+        )
+        .arg(
+            Arg::new("no_strict_check")
+                .long("no-strict-check")
+                .help("Only warn about the contig length mismatches found by --reference-check")
+                .action(ArgAction::SetTrue)
+                .requires("reference_check"),
         )
         .arg(
             Arg::new("output_dir")
                 .short('o')
                 .long("output-dir")
                 .value_name("OUTPUT_DIR")
-                .help("Output directory for TSV.gz files")
+                .help("Output directory with a subdirectory per sample holding one TSV.gz file per reference sequence"),
+        )
+        .arg(
+            Arg::new("no_sample_dirs")
+                .long("no-sample-dirs")
+                .help("Write the files of all samples straight into the output directory, with a sample column, \
+                       instead of into a subdirectory for each sample named after it, by default the BAM file \
+                       name without .bam or .cram")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("output_file"),
+        )
+        .arg(
+            Arg::new("split_by_interval")
+                .long("split-by-interval")
+                .action(ArgAction::SetTrue)
+                .help("Write one file per interval, named after its BED name or else <contig>_<start>-<end> \
+                       with 1-based coordinates, instead of one per contig")
+                .conflicts_with("output_file"),
+        )
+        .arg(
+            Arg::new("name_template")
+                .long("name-template")
+                .value_name("TEMPLATE")
+                .help("File name of each contig in the output directory with the placeholders {ref}, {qscore}, {mode} \
+                       and {date}, e.g. {ref}_q{qscore}.tsv.gz [default: {ref}.tsv.gz]")
+                .conflicts_with("output_file"),
+        )
+        .arg(
+            Arg::new("output_file")
+                .long("output-file")
+                .value_name("OUTPUT_FILE")
+                .help("Single output TSV file for all reference sequences, gzip-compressed if it ends in .gz"),
+        )
+        .arg(
+            Arg::new("aggregate_read_across_contigs")
+                .long("aggregate-read-across-contigs")
+                .help("Sum the per-read counts of each read over all contigs into one row, with the contigs it \
+                       was counted on as its reference, e.g. for chimeric reads with supplementary alignments \
+                       (counted with -F 0x704). Keeps all reads in memory until the end")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("output_dir"),
+        )
+        .group(
+            ArgGroup::new("output")
+                .args(["output_dir", "output_file"])
                 .required(true),
         )
         .arg(
@@ -52,131 +233,1939 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .short('q')
                 .long("qscore")
                 .value_name("QSCORE")
-                .help("Minimum Q-score cutoff for a match")
+                .help("Minimum Phred base quality for a base to be counted, 0-93")
                 .default_value("30"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("min_mapq")
+                .long("min-mapq")
+                .value_name("MAPQ")
+                .help("Minimum mapping quality of a read to be counted")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("trim_ends")
+                .long("trim-ends")
+                .value_name("N")
+                .help("Ignore the first and last N aligned bases of every read, not counting soft clips")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("min_read_length")
+                .long("min-read-length")
+                .value_name("LENGTH")
+                .help("Minimum number of reference bases a read is aligned to for it to be counted")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("max_read_length")
+                .long("max-read-length")
+                .value_name("LENGTH")
+                .help("Maximum number of reference bases a read is aligned to for it to be counted"),
+        )
+        .arg(
+            Arg::new("interval_batch_size")
+                .long("interval-batch-size")
+                .value_name("BP")
+                .help("Fetch the reads of longer intervals in windows of this many bases one after the other, \
+                       with the same counts. The counts of an interval are still kept until it is finished"),
+        )
+        .arg(
+            Arg::new("exclude_flags")
+                .short('F')
+                .long("exclude-flags")
+                .value_name("FLAGS")
+                .help("Skip reads with any of these SAM flag bits set (decimal or 0x hex)")
+                .default_value("0xF04"),
+        )
+        .arg(
+            Arg::new("include_flags")
+                .long("include-flags")
+                .value_name("FLAGS")
+                .help("Only count reads with all of these SAM flag bits set (decimal or 0x hex)")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("read_list")
+                .long("read-list")
+                .value_name("FILE")
+                .help("Only count reads named in this file, one name per line, optionally gzipped"),
+        )
+        .arg(
+            Arg::new("exclude_read_list")
+                .long("exclude-read-list")
+                .value_name("FILE")
+                .help("Skip reads named in this file, one name per line, optionally gzipped"),
+        )
+        .arg(
+            Arg::new("subsample")
+                .long("subsample")
+                .value_name("FRACTION")
+                .help("Only count a random fraction (0-1) of the reads, picked by read name so that pairs stay together"),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Seed of --subsample, the same seed keeps the same reads")
+                .requires("subsample")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("require_qual")
+                .long("require-qual")
+                .help("Skip reads without base qualities instead of counting all their bases")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .help("Stop with an error on reads without a stored sequence instead of skipping them")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("skip_errors")
+                .long("skip-errors")
+                .help("Warn about a truncated or corrupt BAM file and keep the reads read before the error instead \
+                       of stopping")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("fasta_case_sensitive")
+                .long("fasta-case-sensitive")
+                .help("Leave out soft-masked (lowercase) reference bases instead of comparing them like uppercase ones")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strict_iupac")
+                .long("strict-iupac")
+                .help("Take IUPAC codes like R in the reference as ambiguous bases instead of counting read bases \
+                       they stand for as matches")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("by_strand")
+                .long("by-strand")
+                .help("Split per-read counts by the mapped strand of the read")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("count_deletions_as_mismatch")
+                .long("count-deletions-as-mismatch")
+                .help("Count target positions deleted from a read as mismatches in per-read counts")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("count_matches_only")
+                .long("count-matches-only")
+                .help("Count every aligned base of a read passing the Q-score cutoff as a match without reading the \
+                       reference, which is faster. Mismatches count as matches, and the mismatch and ambiguous \
+                       reference columns are missing values (per-read counts)")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "use_md",
+                    "count_deletions_as_mismatch",
+                    "mismatch_rate",
+                    "qual_histogram",
+                    "mismatch_spectrum",
+                    "mismatch_profile",
+                    "contig_summary",
+                    "debug_positions",
+                ]),
+        )
+        .arg(
+            Arg::new("count_softclips")
+                .long("count-softclips")
+                .help("Add the number of soft-clipped bases of each read to the per-read counts")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("read_quality")
+                .long("read-quality")
+                .help("Add the number of aligned bases of each read in the intervals and their mean base quality \
+                       to the per-read counts")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("mismatch_rate")
+                .long("mismatch-rate")
+                .help("Add the number of positions each read covers, its matches, mismatches and bases below the \
+                       Q-score cutoff, and its mismatches divided by them to the per-read counts")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("threads")
+                .short('t')
+                .long("threads")
+                .value_name("THREADS")
+                .help("Number of worker threads [default: number of logical cores]"),
+        )
+        .arg(
+            Arg::new("coverage_summary")
+                .long("coverage-summary")
+                .value_name("FILE")
+                .help("Also write the read count, mean depth and covered fraction of each interval to this TSV file"),
+        )
+        .arg(
+            Arg::new("coverage_depth")
+                .long("coverage-depth")
+                .value_name("DEPTH")
+                .help("Minimum depth of a position to count as covered in the coverage summary")
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("qual_histogram")
+                .long("qual-histogram")
+                .value_name("FILE")
+                .help("Also write the number of matching and mismatching bases by base quality (0-60) to this TSV file"),
+        )
+        .arg(
+            Arg::new("mismatch_spectrum")
+                .long("mismatch-spectrum")
+                .value_name("FILE")
+                .help("Also write the number of mismatching bases for each of the 12 substitutions per contig to \
+                       this TSV file"),
+        )
+        .arg(
+            Arg::new("mismatch_profile")
+                .long("mismatch-profile")
+                .value_name("FILE")
+                .help("Also write the number of compared bases and the mismatch rate at each position relative to \
+                       the interval start, summed over all intervals, to this TSV file"),
+        )
+        .arg(
+            Arg::new("contig_summary")
+                .long("contig-summary")
+                .value_name("FILE")
+                .help("Also write the reads, counted bases, matches, mismatches and mismatch rate of each contig to \
+                       this TSV file (per-read mode)"),
+        )
+        .arg(
+            Arg::new("debug_positions")
+                .long("debug-positions")
+                .value_name("FILE")
+                .help("Also write the base counts of the first mismatching positions of each interval to this TSV \
+                       file, to check that reads and reference line up"),
+        )
+        .arg(
+            Arg::new("debug_positions_limit")
+                .long("debug-positions-limit")
+                .value_name("N")
+                .help("Maximum number of positions per interval in the --debug-positions file")
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("manifest")
+                .long("manifest")
+                .value_name("FILE")
+                .help("Also write the parameters, inputs and totals of the run to this JSON file"),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .help("Overwrite existing output files")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("append")
+                .long("append")
+                .help("Add the reads of this run to the end of existing per-read TSV tables with the same columns \
+                       instead of overwriting them")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .help("Only check the inputs and that the output can be written, without writing any files")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("quiet")
+                .long("quiet")
+                .help("Only print warnings and errors on stderr, no progress")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("Print more details on stderr, -vv also lists every skipped read")
+                .action(ArgAction::Count)
+                .conflicts_with("quiet"),
+        )
+        .arg(
+            Arg::new("bgzip")
+                .long("bgzip")
+                .help("Compress output with BGZF instead of plain gzip, with a .gzi index of its blocks, \
+                       per-position output also gets a tabix index")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("compression")
+                .long("compression")
+                .value_name("COMPRESSION")
+                .help("Compression of the output [default: gzip, for --output-file by its .gz or .zst extension]")
+                .value_parser(["gzip", "zstd", "none"]),
+        )
+        .arg(
+            Arg::new("compression_level")
+                .long("compression-level")
+                .value_name("LEVEL")
+                .help("Compression level of gzip and BGZF output, from 0 (fastest) to 9 (smallest), of zstd output \
+                       from 1 to 22")
+                .default_value("6"),
+        )
+        .arg(
+            Arg::new("no_header_comments")
+                .long("no-header-comments")
+                .help("Start TSV tables with their column header, without the # lines recording the run parameters")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("delimiter")
+                .long("delimiter")
+                .value_name("DELIMITER")
+                .help("Column delimiter of TSV tables, comma writes them as quoted CSV files named .csv")
+                .value_parser(["tab", "comma"])
+                .default_value("tab"),
+        )
+        .arg(
+            Arg::new("na_string")
+                .long("na-string")
+                .value_name("STRING")
+                .help("Value written in TSV columns without one, such as a rate without any bases")
+                .default_value("*"),
+        )
+        .arg(
+            Arg::new("mode")
+                .short('m')
+                .long("mode")
+                .value_name("MODE")
+                .help("Output per-read match/mismatch counts, per-position base counts or per-position consensus calls")
+                .value_parser(["per-read", "per-position", "consensus"])
+                .default_value("per-read"),
+        )
+        .arg(
+            Arg::new("mods")
+                .long("mods")
+                .value_name("MOD_CODE")
+                .help("Count base modifications from MM/ML tags instead, e.g. C+m for 5mC. Several codes, \
+                       comma-separated or by repeating --mods, get a frequency column each")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .conflicts_with("mode"),
+        )
+        .arg(
+            Arg::new("long")
+                .long("long")
+                .help("Write a line for every read base aligned to a position of interest instead, with its \
+                       reference base, quality and whether it matches. The output has to be compressed")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["mode", "mods"]),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help(
+                    "Output format, bedmethyl writes modification counts as bedMethyl, jsonl per-read counts as JSON \
+                     Lines, vcf consensus variants as VCF and parquet per-read or per-position counts as Parquet",
+                )
+                .value_parser(["tsv", "bedmethyl", "jsonl", "vcf", "parquet"])
+                .default_value("tsv")
+                .requires_if("bedmethyl", "mods"),
+        )
+        .arg(
+            Arg::new("min_mod_prob")
+                .long("min-mod-prob")
+                .value_name("PROB")
+                .help("Only count modification calls more likely than this to be modified or unmodified, 0-255 or \
+                       0.0-1.0. Calls on bases below --qscore are dropped before, whatever their probability, and \
+                       dropped calls count neither as modified nor in the total")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("min_depth")
+                .long("min-depth")
+                .value_name("DEPTH")
+                .help("Minimum depth of a position to call a consensus base")
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("min_base_count")
+                .long("min-base-count")
+                .value_name("DEPTH")
+                .help("Leave out per-position base counts of positions with a lower depth")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("max_depth")
+                .long("max-depth")
+                .value_name("DEPTH")
+                .help("Stop counting the bases of further reads at a position once this many are counted, \
+                       taking the first reads in BAM order (per-position and consensus modes)"),
+        )
+        .arg(
+            Arg::new("strand_bias")
+                .long("strand-bias")
+                .help("Add the forward and reverse reads of the non-reference alleles and a Phred-scaled Fisher's \
+                       exact test of strand bias to per-position counts")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("end_distance")
+                .long("end-distance")
+                .help("Add the mean distance of the non-reference bases from the nearer end of their read to \
+                       per-position counts")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("hp_len")
+                .long("hp-len")
+                .help("Add the length of the reference homopolymer run each position belongs to to per-position \
+                       counts")
+                .conflicts_with("use_md")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("top2")
+                .long("top2")
+                .help("Add the two most frequent alleles of each position and their read counts to per-position \
+                       counts, ties in the order A, C, G, T, deletion")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dedup_overlap")
+                .long("dedup-overlap")
+                .help("Count the positions covered by both reads of an overlapping pair once, taking the base \
+                       with the higher quality (per-position and consensus modes)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("min_af")
+                .long("min-af")
+                .value_name("FRACTION")
+                .help("Minimum frequency of a consensus base differing from the reference to be a variant")
+                .default_value("0"),
+        )
+}
 
-    let bam_file_path = matches.get_one::<String>("bam").unwrap();
-    let bed_file_path = matches.get_one::<String>("bed").unwrap();
-    let fasta_file_path = matches.get_one::<String>("fasta").unwrap();
-    let output_dir_path = matches.get_one::<String>("output_dir").unwrap();
-    let qscore_cutoff: u8 = matches
-        .get_one::<String>("qscore_cutoff").unwrap()
-        .parse()
-        .expect("Invalid Q-score cutoff");
+fn run() -> Result<(), BoxError> {
+    let start_time = Instant::now();
+    let matches = command().get_matches();
 
-    // Create the output directory if it doesn't exist
-    create_dir_all(output_dir_path)?;
+    logger::init(match matches.get_count("verbose") {
+        _ if matches.get_flag("quiet") => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    });
 
-    // Open the BAM file for reading
-    let mut bam = bam::IndexedReader::from_path(&Path::new(bam_file_path)).unwrap();
+    let samples = parse_samples(&matches)?;
+    // Output only gets a sample column if there is more than one sample or they are named
+    let with_sample = samples.len() > 1 || matches.contains_id("sample_name");
+    let (config, known_sites) = build_config(&matches, &samples)?;
+    let mode_name = mode_name(&matches, &config, known_sites.is_some());
+    let options = output_options(&matches, &config, &samples, known_sites, with_sample, mode_name)?;
 
-    // Open the reference genome FASTA file
-    let reference = fasta::Reader::from_path(fasta_file_path)?;
+    // A FASTA file of another genome build than the reads were aligned to would pass silently
+    if matches.get_flag("reference_check") {
+        check_reference_lengths(&config, &samples, matches.get_flag("no_strict_check"))?;
+    }
+    // Refuse to overwrite the results of an earlier run unless asked to
+    if !matches.get_flag("force") {
+        check_output_files_absent(&matches, &config, &options, &samples)?;
+    }
+    if matches.get_flag("dry_run") {
+        return dry_run(&matches, &config, &samples, with_sample);
+    }
 
-    // Load the regions of interest from the BED file into a HashMap
-    let regions_of_interest = load_bed_regions(bed_file_path)?;
+    let compression_level = options.compression_level;
+    let split_by_interval = matches.get_flag("split_by_interval");
+    let with_contig_summary = matches.contains_id("contig_summary");
+    let aggregate_across_contigs = matches.get_flag("aggregate_read_across_contigs");
+    let mut output = match matches.get_one::<String>("output_file") {
+        Some(output_file_path) => CountsOutput::create_combined(&config, output_file_path, &options)?,
+        None => CountsOutput::create_contig_files(matches.get_one::<String>("output_dir").unwrap(), &options)?,
+    };
+    let mut coverage_writer = match matches.get_one::<String>("coverage_summary") {
+        Some(coverage_file_path) => Some(CoverageWriter::create(
+            coverage_file_path,
+            compression_for_file_name(coverage_file_path, compression_level),
+            options.coverage_depth,
+            with_sample,
+        )?),
+        None => None,
+    };
+    let mut debug_positions_writer = match matches.get_one::<String>("debug_positions") {
+        Some(debug_positions_file_path) => Some(DebugPositionsWriter::create(
+            debug_positions_file_path,
+            compression_for_file_name(debug_positions_file_path, compression_level),
+            with_sample,
+        )?),
+        None => None,
+    };
 
-    // Create a HashMap to store counts for each reference sequence and read_id
-    let mut reference_counts: HashMap<String, HashMap<String, (usize, usize)>> = HashMap::new();
+    // The quality histograms sum up all contigs and are written at the end
+    let mut qual_histograms: Vec<QualHistogram> = samples.iter().map(|_| QualHistogram::new()).collect();
+    // Same for the mismatch spectra of each contig in each sample, which are few lines each
+    let mut mismatch_spectra: Vec<(String, Vec<MismatchSpectrum>)> = Vec::new();
+    let mut mismatch_profiles: Vec<MismatchProfile> = samples.iter().map(|_| MismatchProfile::default()).collect();
+    // Per-read totals of each contig in each sample, in contig order
+    let mut contig_totals: Vec<(String, Vec<ReadTotals>)> = Vec::new();
+    // Counts of each read of each sample over all contigs, written at the end instead of per contig
+    let mut reads_across_contigs: Option<Vec<ReadsAcrossContigs>> =
+        aggregate_across_contigs.then(|| vec![ReadsAcrossContigs::default(); samples.len()]);
+    // Totals over all contigs and samples for the manifest
+    let mut total_reads: u64 = 0;
+    let mut total_bases: u64 = 0;
+    let mut skipped_reads = SkippedReads::default();
 
-    // Iterate through regions of interest and extract pileup
-    for (ref_name, (start, end)) in regions_of_interest {
-        let ref_id = bam.header().reference_id(&ref_name).unwrap();
+    // Write each contig's (or interval's) counts as soon as they are done in all samples
+    let contig_regions: HashMap<String, Vec<BedRegion>> =
+        group_regions_by_contig(&config.regions).into_iter().collect();
+    let mut write_counts = |file_stem: String, regions: &[BedRegion], sample_counts: Vec<SampleCounts>| {
+        for counts in &sample_counts {
+            total_reads += counts.n_reads as u64;
+            total_bases += counts.counts.counted_bases();
+            skipped_reads.merge(&counts.skipped);
+        }
+        if let Some(coverage_writer) = &mut coverage_writer {
+            for (sample, counts) in samples.iter().zip(&sample_counts) {
+                coverage_writer.write_sample_intervals(&sample.name, &counts.coverage)?;
+            }
+        }
+        if let Some(debug_positions_writer) = &mut debug_positions_writer {
+            for (sample, counts) in samples.iter().zip(&sample_counts) {
+                debug_positions_writer.write_sample_intervals(&sample.name, &counts.debug_positions)?;
+            }
+        }
+        for (qual_histogram, counts) in qual_histograms.iter_mut().zip(&sample_counts) {
+            if let Some(contig_histogram) = &counts.qual_histogram {
+                qual_histogram.merge(contig_histogram);
+            }
+        }
+        for (mismatch_profile, counts) in mismatch_profiles.iter_mut().zip(&sample_counts) {
+            if let Some(contig_profile) = &counts.mismatch_profile {
+                mismatch_profile.merge(contig_profile);
+            }
+        }
+        if config.mismatch_spectrum {
+            // The intervals of a contig come one after the other with --split-by-interval
+            let ref_name = &regions[0].ref_name;
+            if mismatch_spectra.last().is_none_or(|(last_ref_name, _)| last_ref_name != ref_name) {
+                mismatch_spectra.push((ref_name.clone(), vec![MismatchSpectrum::default(); samples.len()]));
+            }
+            let (_, contig_spectra) = mismatch_spectra.last_mut().unwrap();
+            for (contig_spectrum, counts) in contig_spectra.iter_mut().zip(&sample_counts) {
+                if let Some(spectrum) = &counts.mismatch_spectrum {
+                    contig_spectrum.merge(spectrum);
+                }
+            }
+        }
+        if with_contig_summary {
+            let ref_name = &regions[0].ref_name;
+            if contig_totals.last().is_none_or(|(last_ref_name, _)| last_ref_name != ref_name) {
+                contig_totals.push((ref_name.clone(), vec![ReadTotals::default(); samples.len()]));
+            }
+            let (_, sample_totals) = contig_totals.last_mut().unwrap();
+            for (totals, counts) in sample_totals.iter_mut().zip(&sample_counts) {
+                if let ContigCounts::PerRead(read_counts) = &counts.counts {
+                    totals.add_reads(read_counts.values());
+                }
+            }
+        }
+        if let Some(reads_across_contigs) = &mut reads_across_contigs {
+            for (reads, counts) in reads_across_contigs.iter_mut().zip(&sample_counts) {
+                if let ContigCounts::PerRead(read_counts) = &counts.counts {
+                    reads.add_contig(&regions[0].ref_name, read_counts);
+                }
+            }
+            return Ok(());
+        }
+        output.write_contig(&config, &options, &file_stem, regions, &samples, &sample_counts)
+    };
+    if split_by_interval {
+        bampile::for_each_sample_interval(&config, &samples, |region, sample_counts| {
+            write_counts(interval_file_stem(region), std::slice::from_ref(region), sample_counts)
+        })?;
+    } else {
+        bampile::for_each_sample_contig(&config, &samples, |ref_name, sample_counts| {
+            write_counts(encode_filename(ref_name), &contig_regions[ref_name], sample_counts)
+        })?;
+    }
+    if let Some(reads_across_contigs) = &reads_across_contigs {
+        output.write_reads_across_contigs(&samples, reads_across_contigs)?;
+    }
+    output.finish()?;
+    if let Some(coverage_writer) = coverage_writer {
+        coverage_writer.finish()?;
+    }
+    if let Some(debug_positions_writer) = debug_positions_writer {
+        debug_positions_writer.finish()?;
+    }
+    if let Some(histogram_file_path) = matches.get_one::<String>("qual_histogram") {
+        let named_histograms: Vec<(&str, &QualHistogram)> =
+            samples.iter().map(|sample| sample.name.as_str()).zip(&qual_histograms).collect();
+        write_qual_histograms(
+            histogram_file_path,
+            compression_for_file_name(histogram_file_path, compression_level),
+            &named_histograms,
+            with_sample,
+        )?;
+    }
+    if let Some(spectrum_file_path) = matches.get_one::<String>("mismatch_spectrum") {
+        let named_spectra: Vec<(&str, &str, &MismatchSpectrum)> = mismatch_spectra
+            .iter()
+            .flat_map(|(ref_name, contig_spectra)| {
+                samples
+                    .iter()
+                    .zip(contig_spectra)
+                    .map(|(sample, spectrum)| (sample.name.as_str(), ref_name.as_str(), spectrum))
+            })
+            .collect();
+        write_mismatch_spectra(
+            spectrum_file_path,
+            compression_for_file_name(spectrum_file_path, compression_level),
+            &named_spectra,
+            with_sample,
+        )?;
+    }
+    if let Some(profile_file_path) = matches.get_one::<String>("mismatch_profile") {
+        let named_profiles: Vec<(&str, &MismatchProfile)> =
+            samples.iter().map(|sample| sample.name.as_str()).zip(&mismatch_profiles).collect();
+        write_mismatch_profiles(
+            profile_file_path,
+            compression_for_file_name(profile_file_path, compression_level),
+            &named_profiles,
+            with_sample,
+        )?;
+    }
+    if let Some(summary_file_path) = matches.get_one::<String>("contig_summary") {
+        let named_totals: Vec<(&str, &str, &ReadTotals)> = contig_totals
+            .iter()
+            .flat_map(|(ref_name, sample_totals)| {
+                samples
+                    .iter()
+                    .zip(sample_totals)
+                    .map(|(sample, totals)| (sample.name.as_str(), ref_name.as_str(), totals))
+            })
+            .collect();
+        write_contig_summary(
+            summary_file_path,
+            compression_for_file_name(summary_file_path, compression_level),
+            &named_totals,
+            with_sample,
+        )?;
+    }
+    // What the filters left out, which explains surprisingly low counts
+    let skip_reasons: Vec<String> = skipped_reads
+        .by_reason()
+        .iter()
+        .filter(|(_, n_reads)| *n_reads > 0)
+        .map(|(reason, n_reads)| format!("{} {}", reason, n_reads))
+        .collect();
+    log::info!(
+        "counted {} reads, skipped {}{}{}",
+        total_reads,
+        skipped_reads.total(),
+        if skip_reasons.is_empty() { "" } else { ": " },
+        skip_reasons.join(", ")
+    );
+    if let Some(manifest_file_path) = matches.get_one::<String>("manifest") {
+        let manifest = RunManifest {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp: utc_timestamp(),
+            command_line: std::env::args().collect(),
+            bam_files: samples
+                .iter()
+                .map(|sample| (sample.name.clone(), absolute_path(&sample.bam_file_path)))
+                .collect(),
+            fasta_file: matches.get_one::<String>("fasta").map(|fasta_file_path| absolute_path(fasta_file_path)),
+            bed_file: matches.get_one::<String>("bed").map(|bed_file_path| absolute_path(bed_file_path)),
+            mode: mode_name.to_string(),
+            qscore_cutoff: config.qscore_cutoff,
+            min_mapq: config.min_mapq,
+            exclude_flags: config.exclude_flags,
+            include_flags: config.include_flags,
+            n_intervals: config.regions.len(),
+            total_reads,
+            skipped_reads,
+            total_bases,
+        };
+        write_manifest(manifest_file_path, &manifest)?;
+    }
+    log::debug!("finished in {:.2}s", start_time.elapsed().as_secs_f64());
+    Ok(())
+}
 
-        let pileup = bam.fetch(&bam::Region::new(ref_id, start, end)).unwrap();
+// Known sites of each contig, in position order
+type ContigSites = HashMap<String, Vec<KnownSite>>;
 
-        let mut reference_sequence = Vec::new();
-        reference.fetch_into_vec(ref_name, start as u64, (end - 1) as u64, &mut reference_sequence)?;
-        
-        
-        
-        let mut record = bam::Record::new();
-        loop {
-           // reader: impl RecordReader
-           // New record is saved into record.
-            match pileup.read_into(&mut record) {
-               // No more records to read.
-                Ok(false) => break,
-                Ok(true) => {},
-                Err(e) => panic!("{}", e),
+// Loads the intervals to count from the BED file, one entry per interval, the single region
+// given on the command line or a 1 bp interval for each listed position or site, in the order
+// of the BAM header. Known sites are also returned by contig.
+fn load_regions(
+    matches: &clap::ArgMatches,
+    samples: &[Sample],
+) -> Result<(Vec<BedRegion>, Option<ContigSites>), BoxError> {
+    let fasta_file_path = matches.get_one::<String>("fasta");
+    let mut known_sites: Option<ContigSites> = None;
+    let mut regions_of_interest = if let Some(region) = matches.get_one::<String>("region") {
+        vec![parse_region(region)?]
+    } else if let Some(positions_file_path) = matches.get_one::<String>("positions") {
+        load_positions(positions_file_path)?
+    } else if let Some(gff_file_path) = matches.get_one::<String>("gff") {
+        load_gff_features(gff_file_path, matches.get_one::<String>("feature_type").unwrap())?
+    } else if let Some(sites_file_path) = matches.get_one::<String>("known_sites") {
+        let (sites, n_skipped) = load_known_sites(sites_file_path)?;
+        if n_skipped > 0 {
+            log::warn!("skipped {} sites of {} that are not SNVs", n_skipped, sites_file_path);
+        }
+        let regions = sites.iter().map(KnownSite::region).collect();
+        // Each contig's sites are written in position order, sites listed twice only once
+        let mut contig_sites: ContigSites = HashMap::new();
+        for site in sites {
+            contig_sites.entry(site.ref_name.clone()).or_default().push(site);
+        }
+        for sites in contig_sites.values_mut() {
+            sites.sort_by_key(|site| (site.pos, site.alt_base));
+            sites.dedup();
+        }
+        known_sites = Some(contig_sites);
+        regions
+    } else {
+        load_bed_regions(matches.get_one::<String>("bed").unwrap(), matches.get_flag("one_based"))?
+    };
+    log::debug!("loaded {} intervals", regions_of_interest.len());
+    // Intervals on different strands are kept apart when merging, which only matters if the
+    // strands decide which reads are counted
+    let respect_bed_strand = matches.get_flag("respect_bed_strand");
+    if !respect_bed_strand {
+        for region in &mut regions_of_interest {
+            region.strand = Strand::Either;
+        }
+    }
+    if let Some(contig_list) = matches.get_one::<String>("contig_list") {
+        let contigs = parse_contig_list(contig_list)?;
+        let n_intervals = regions_of_interest.len();
+        regions_of_interest.retain(|region| contigs.contains(&region.ref_name));
+        log::debug!(
+            "ignored {} intervals on contigs missing from --contig-list",
+            n_intervals - regions_of_interest.len()
+        );
+        if regions_of_interest.is_empty() {
+            return Err("no intervals on the contigs of --contig-list".into());
+        }
+    }
+    let flank: u32 = matches
+        .get_one::<String>("flank").unwrap()
+        .parse()
+        .map_err(|_| "Invalid flank length")?;
+    // The BAM header can't be read ahead from stdin
+    let bam_contigs = if samples[0].bam_file_path == "-" {
+        None
+    } else {
+        Some(bampile::bam_reference_sequences(&samples[0].bam_file_path)?)
+    };
+    // Without a FASTA file, the contig lengths come from the BAM header
+    let contig_lengths: Option<HashMap<String, u64>> = match (fasta_file_path, &bam_contigs) {
+        (Some(fasta_file_path), _) => Some(fasta_sequence_lengths(fasta_file_path)?),
+        (None, None) => None,
+        (None, Some(bam_contigs)) => Some(
+            bam_contigs
+                .iter()
+                .map(|(ref_name, length)| (ref_name.clone(), *length as u64))
+                .collect(),
+        ),
+    };
+    if flank > 0 {
+        let contig_lengths = contig_lengths
+            .as_ref()
+            .ok_or("--flank needs --fasta for the contig lengths when reading the BAM from stdin")?;
+        regions_of_interest = add_flanks(&regions_of_interest, flank, contig_lengths);
+    }
+    // Intervals running past the end of their contig would fail to fetch their reference bases
+    if let Some(contig_lengths) = &contig_lengths {
+        let (clamped, dropped) = clamp_to_contig_lengths(&mut regions_of_interest, contig_lengths);
+        if let [first, ..] = clamped.as_slice() {
+            log::warn!(
+                "clamped {} intervals running past the end of their contig to its length, e.g. {}",
+                clamped.len(),
+                first
+            );
+            for region_name in &clamped {
+                log::debug!("clamped interval {} to the length of its contig", region_name);
+            }
+        }
+        if let [first, ..] = dropped.as_slice() {
+            log::warn!(
+                "dropped {} intervals starting at or past the end of their contig, e.g. {}",
+                dropped.len(),
+                first
+            );
+            for region_name in &dropped {
+                log::debug!("dropped interval {} past the end of its contig", region_name);
             }
-            // Do somethind with the record.
-            let record = record?;
-            let seq = record.seq();
-            let qual = record.qual();
-            let read_id = record.read().qname();
-            let reference_base = reference_sequence[(record.pos() - start) as usize];
+        }
+        if regions_of_interest.is_empty() {
+            return Err("no intervals within the lengths of their contigs".into());
+        }
+    }
+    // Positions in several intervals would otherwise be counted once for each of them
+    if !matches.get_flag("keep_intervals") {
+        let n_intervals = regions_of_interest.len();
+        regions_of_interest = merge_overlapping_regions(&regions_of_interest);
+        if regions_of_interest.len() < n_intervals {
+            log::debug!(
+                "merged overlapping intervals into {} intervals",
+                regions_of_interest.len()
+            );
+        }
+    }
+    // Contigs are counted and written in the order of the BAM header, positions of a contig in
+    // ascending order anyway. From stdin, they keep the order of the intervals.
+    if let Some(bam_contigs) = &bam_contigs {
+        let contigs: Vec<String> = bam_contigs.iter().map(|(ref_name, _)| ref_name.clone()).collect();
+        sort_by_contig_order(&mut regions_of_interest, &contigs);
+    }
+    Ok((regions_of_interest, known_sites))
+}
 
-            let (num_matches, num_mismatches) = count_matches_mismatches(seq, qual, reference_base, qscore_cutoff);
-            let ref_counts = reference_counts
-            .entry(ref_name.to_string())
-            .or_insert_with(HashMap::new);
+// Settings of the counting from the command line
+fn build_config(
+    matches: &clap::ArgMatches,
+    samples: &[Sample],
+) -> Result<(PileupConfig, Option<ContigSites>), BoxError> {
+    let (regions_of_interest, known_sites) = load_regions(matches, samples)?;
+    let mut config = PileupConfig::new(
+        &samples[0].bam_file_path,
+        matches.get_one::<String>("fasta").map_or("", String::as_str),
+        regions_of_interest,
+    );
+    config.qscore_cutoff = matches
+        .get_one::<String>("qscore_cutoff").unwrap()
+        .parse()
+        .map_err(|_| "Invalid Q-score cutoff")?;
+    if config.qscore_cutoff > MAX_PHRED_QUALITY {
+        return Err(format!(
+            "--qscore {} is above {}, the highest base quality in BAM files, so no base would be counted",
+            config.qscore_cutoff, MAX_PHRED_QUALITY
+        )
+        .into());
+    }
+    config.min_mapq = matches
+        .get_one::<String>("min_mapq").unwrap()
+        .parse()
+        .map_err(|_| "Invalid minimum mapping quality")?;
+    config.trim_ends = matches
+        .get_one::<String>("trim_ends").unwrap()
+        .parse()
+        .map_err(|_| "Invalid number of bases to trim")?;
+    config.min_read_length = matches
+        .get_one::<String>("min_read_length").unwrap()
+        .parse()
+        .map_err(|_| "Invalid minimum read length")?;
+    if let Some(max_read_length) = matches.get_one::<String>("max_read_length") {
+        let max_read_length = max_read_length.parse().map_err(|_| "Invalid maximum read length")?;
+        if max_read_length < config.min_read_length {
+            return Err("--max-read-length is below --min-read-length".into());
+        }
+        config.max_read_length = Some(max_read_length);
+    }
+    if let Some(batch_size) = matches.get_one::<String>("interval_batch_size") {
+        let batch_size: u32 = batch_size.parse().map_err(|_| "Invalid interval batch size")?;
+        if batch_size == 0 {
+            return Err("Invalid interval batch size, it has to be at least 1".into());
+        }
+        config.interval_batch_size = Some(batch_size);
+    }
+    config.exclude_flags = parse_flag_mask(matches.get_one::<String>("exclude_flags").unwrap())
+        .map_err(|_| "Invalid exclude flags")?;
+    config.include_flags = parse_flag_mask(matches.get_one::<String>("include_flags").unwrap())
+        .map_err(|_| "Invalid include flags")?;
+    config.respect_bed_strand = matches.get_flag("respect_bed_strand");
+    // Consensus calls are made from the per-position counts when writing the output
+    let mode = matches.get_one::<String>("mode").unwrap();
+    config.mode = match mode.as_str() {
+        _ if known_sites.is_some() => Mode::PerPosition,
+        "per-position" | "consensus" => Mode::PerPosition,
+        _ => Mode::PerRead,
+    };
+    if let Some(mod_codes) = matches.get_many::<String>("mods") {
+        config.mode = Mode::Modifications;
+        config.mod_codes.clear();
+        for mod_code in mod_codes {
+            let mod_code = parse_mod_code(mod_code)?;
+            if config.mod_codes.contains(&mod_code) {
+                return Err(format!("Modification code {} is given twice", mod_code).into());
+            }
+            config.mod_codes.push(mod_code);
+        }
+        config.min_mod_prob = parse_mod_prob(matches.get_one::<String>("min_mod_prob").unwrap())
+            .map_err(|e| format!("Invalid minimum modification probability: {}", e))?;
+    }
+    if matches.get_flag("long") {
+        config.mode = Mode::Long;
+    }
+    if let Some(read_list_path) = matches.get_one::<String>("read_list") {
+        config.read_list = Some(load_read_list(read_list_path)?);
+    }
+    if let Some(read_list_path) = matches.get_one::<String>("exclude_read_list") {
+        config.exclude_read_list = Some(load_read_list(read_list_path)?);
+    }
+    if let Some(subsample) = matches.get_one::<String>("subsample") {
+        let subsample: f64 = subsample.parse().map_err(|_| "Invalid subsampling fraction")?;
+        if !(0.0..=1.0).contains(&subsample) {
+            return Err(format!("--subsample {} is not a fraction between 0 and 1", subsample).into());
+        }
+        config.subsample = Some(subsample);
+    }
+    config.seed = matches
+        .get_one::<String>("seed").unwrap()
+        .parse()
+        .map_err(|_| "Invalid subsampling seed")?;
+    config.require_qual = matches.get_flag("require_qual");
+    config.strict = matches.get_flag("strict");
+    config.skip_errors = matches.get_flag("skip_errors");
+    config.fasta_case_sensitive = matches.get_flag("fasta_case_sensitive");
+    config.strict_iupac = matches.get_flag("strict_iupac");
+    config.use_md = matches.get_flag("use_md");
+    if let Some(max_depth) = matches.get_one::<String>("max_depth") {
+        if config.mode != Mode::PerPosition {
+            return Err("--max-depth only applies to --mode per-position and consensus".into());
+        }
+        config.max_depth = Some(max_depth.parse().map_err(|_| "Invalid maximum depth")?);
+    }
+    config.deletions_as_mismatches = matches.get_flag("count_deletions_as_mismatch");
+    config.count_matches_only = matches.get_flag("count_matches_only");
+    if config.count_matches_only && config.mode != Mode::PerRead {
+        return Err("--count-matches-only is only available for per-read counts".into());
+    }
+    if config.deletions_as_mismatches && config.mode != Mode::PerRead {
+        return Err("--count-deletions-as-mismatch only applies to per-read counts".into());
+    }
+    config.unique_positions = matches.get_flag("count_only_unique_positions");
+    if config.unique_positions && config.mode != Mode::PerRead {
+        return Err("--count-only-unique-positions only applies to per-read counts".into());
+    }
+    let aggregate_across_contigs = matches.get_flag("aggregate_read_across_contigs");
+    if aggregate_across_contigs && config.mode != Mode::PerRead {
+        return Err("--aggregate-read-across-contigs only applies to per-read counts".into());
+    }
+    if aggregate_across_contigs && config.exclude_flags & 0x800 != 0 {
+        log::warn!(
+            "supplementary alignments are skipped by --exclude-flags, so reads are only summed over their other \
+             alignments"
+        );
+    }
+    config.dedup_overlap = matches.get_flag("dedup_overlap");
+    if config.dedup_overlap && config.mode != Mode::PerPosition {
+        return Err("--dedup-overlap only applies to --mode per-position and consensus".into());
+    }
+    if let Some(threads) = matches.get_one::<String>("threads") {
+        config.threads = threads.parse().map_err(|_| "Invalid number of threads")?;
+    }
+    // Skipped reads are listed while counting, which would garble the progress bar
+    config.progress = !matches.get_flag("quiet") && matches.get_count("verbose") < 2;
+    config.homopolymer_length = matches.get_flag("hp_len");
+    config.coverage = matches.contains_id("coverage_summary");
+    config.qual_histogram = matches.contains_id("qual_histogram");
+    config.mismatch_spectrum = matches.contains_id("mismatch_spectrum");
+    config.mismatch_profile = matches.contains_id("mismatch_profile");
+    if matches.contains_id("debug_positions") {
+        let debug_positions_limit: usize = matches
+            .get_one::<String>("debug_positions_limit").unwrap()
+            .parse()
+            .map_err(|_| "Invalid debug positions limit")?;
+        config.debug_positions = Some(debug_positions_limit);
+    }
+    Ok((config, known_sites))
+}
 
-            let (matches, mismatches) = ref_counts
-                .entry(read_id.to_string())
-                .or_insert((0, 0));
-            *matches += num_matches;
-            *mismatches += num_mismatches;
+// Name of the mode in file name templates and the manifest
+fn mode_name<'a>(matches: &'a clap::ArgMatches, config: &PileupConfig, known_sites: bool) -> &'a str {
+    match config.mode {
+        Mode::Modifications => "mods",
+        Mode::Long => "long",
+        _ if known_sites => "known-sites",
+        _ => matches.get_one::<String>("mode").unwrap(),
+    }
+}
+
+// Settings of the output from the command line, checked against the counted mode
+fn output_options(
+    matches: &clap::ArgMatches,
+    config: &PileupConfig,
+    samples: &[Sample],
+    known_sites: Option<ContigSites>,
+    with_sample: bool,
+    mode_name: &str,
+) -> Result<OutputOptions, BoxError> {
+    // Count tables in a directory for each sample don't need a sample column
+    let sample_dirs = matches.contains_id("output_dir") && !matches.get_flag("no_sample_dirs");
+    let table_with_sample = with_sample && !sample_dirs;
+    // Record how the tables were made in # lines above their column headers
+    if !matches.get_flag("no_header_comments") {
+        set_header_comments(vec![
+            format!("bampile {}", env!("CARGO_PKG_VERSION")),
+            format!("command: {}", std::env::args().collect::<Vec<_>>().join(" ")),
+            format!("qscore_cutoff: {}", config.qscore_cutoff),
+        ]);
+    }
+    let comma_delimited = matches.get_one::<String>("delimiter").unwrap() == "comma";
+    let na_string = matches.get_one::<String>("na_string").unwrap();
+    if na_string.contains(['\t', '\n']) {
+        return Err("Invalid --na-string, it can't hold a tab or a line break".into());
+    }
+    set_table_style(if comma_delimited { b',' } else { b'\t' }, na_string);
+    let consensus = if matches.get_one::<String>("mode").unwrap() == "consensus" {
+        Some(ConsensusSettings {
+            min_depth: matches
+                .get_one::<String>("min_depth").unwrap()
+                .parse()
+                .map_err(|_| "Invalid minimum depth")?,
+            min_af: matches
+                .get_one::<String>("min_af").unwrap()
+                .parse()
+                .map_err(|_| "Invalid minimum allele frequency")?,
+        })
+    } else {
+        None
+    };
+    let zstd = matches.get_one::<String>("compression").is_some_and(|compression| compression == "zstd");
+    let compression_level = matches
+        .get_one::<String>("compression_level").unwrap()
+        .parse::<u32>()
+        .ok()
+        .filter(|level| if zstd { (1..=22).contains(level) } else { *level <= 9 })
+        .ok_or("Invalid compression level")?;
+    let compression = match matches.get_one::<String>("compression").map(String::as_str) {
+        Some(compression @ ("none" | "zstd")) if matches.get_flag("bgzip") => {
+            return Err(format!("--bgzip can't be combined with --compression {}", compression).into());
+        }
+        _ if matches.get_flag("bgzip") => Some(Compression::Bgzf(compression_level)),
+        Some("none") => Some(Compression::None),
+        Some("zstd") => Some(Compression::Zstd(compression_level)),
+        Some(_) => Some(Compression::Gzip(compression_level)),
+        None => None,
+    };
+    let format = matches.get_one::<String>("format").unwrap().as_str();
+    if format == "vcf" && consensus.is_none() {
+        return Err("--format vcf is only available with --mode consensus".into());
+    }
+    if format == "vcf" && table_with_sample {
+        return Err("--format vcf can only hold a single sample".into());
+    }
+    if format == "parquet" && !matches!(config.mode, Mode::PerRead | Mode::PerPosition) {
+        return Err("--format parquet is only available for per-read and per-position counts".into());
+    }
+    if known_sites.is_some() && format != "tsv" {
+        return Err("--known-sites tables are only written as TSV".into());
+    }
+    if format == "parquet" && matches!(compression, Some(Compression::Bgzf(_))) {
+        return Err("--bgzip can't be combined with --format parquet".into());
+    }
+    // Tabix only indexes tab-separated tables
+    if comma_delimited && matches!(compression, Some(Compression::Bgzf(_))) && config.mode != Mode::PerRead {
+        return Err("--bgzip can't be combined with --delimiter comma for per-position tables".into());
+    }
+    let coverage_depth: u32 = matches
+        .get_one::<String>("coverage_depth").unwrap()
+        .parse()
+        .map_err(|_| "Invalid coverage depth")?;
+    // The VCF header lists the contigs of the BAM header, which can't be read twice from stdin
+    let vcf_contigs = match format {
+        "vcf" if samples[0].bam_file_path == "-" => {
+            return Err("--format vcf can't read the BAM from stdin".into());
         }
+        "vcf" => Some(bampile::bam_reference_sequences(&samples[0].bam_file_path)?),
+        _ => None,
+    };
+    let options = OutputOptions {
+        by_strand: matches.get_flag("by_strand"),
+        count_softclips: matches.get_flag("count_softclips"),
+        read_quality: matches.get_flag("read_quality"),
+        mismatch_rate: matches.get_flag("mismatch_rate"),
+        count_matches_only: matches.get_flag("count_matches_only"),
+        append: matches.get_flag("append"),
+        compression,
+        compression_level,
+        consensus,
+        strand_bias: matches.get_flag("strand_bias"),
+        end_distance: matches.get_flag("end_distance"),
+        hp_len: matches.get_flag("hp_len"),
+        top2: matches.get_flag("top2"),
+        min_base_count: matches
+            .get_one::<String>("min_base_count").unwrap()
+            .parse()
+            .map_err(|_| "Invalid minimum base count")?,
+        bedmethyl: format == "bedmethyl",
+        jsonl: format == "jsonl",
+        parquet: format == "parquet",
+        csv: comma_delimited && format == "tsv",
+        vcf_contigs,
+        known_sites: known_sites.map(Arc::new),
+        with_sample: table_with_sample,
+        sample_dirs,
+        coverage_depth,
+        name_template: match matches.get_one::<String>("name_template") {
+            Some(template) => Some(render_name_template(template, config.qscore_cutoff, mode_name)?),
+            None => None,
+        },
+    };
+    if options.strand_bias && (mode_name != "per-position" || format != "tsv") {
+        return Err("--strand-bias is only available for --mode per-position tables".into());
+    }
+    if options.end_distance && (mode_name != "per-position" || format != "tsv") {
+        return Err("--end-distance is only available for --mode per-position tables".into());
+    }
+    if options.hp_len && (mode_name != "per-position" || format != "tsv") {
+        return Err("--hp-len is only available for --mode per-position tables".into());
+    }
+    if options.top2 && (mode_name != "per-position" || format != "tsv") {
+        return Err("--top2 is only available for --mode per-position tables".into());
+    }
+    if options.min_base_count > 0 && mode_name != "per-position" {
+        return Err("--min-base-count is only available for --mode per-position tables".into());
+    }
+    if options.read_quality && (config.mode != Mode::PerRead || options.parquet) {
+        return Err("--read-quality is only available for per-read TSV and JSON Lines counts".into());
     }
+    if options.mismatch_rate && (config.mode != Mode::PerRead || options.parquet) {
+        return Err("--mismatch-rate is only available for per-read TSV and JSON Lines counts".into());
+    }
+    if options.append && (config.mode != Mode::PerRead || format != "tsv") {
+        return Err("--append is only available for per-read TSV counts".into());
+    }
+    if options.jsonl && config.mode != Mode::PerRead {
+        return Err("--format jsonl is only available for per-read counts".into());
+    }
+    let with_contig_summary = matches.contains_id("contig_summary");
+    if with_contig_summary && config.mode != Mode::PerRead {
+        return Err("--contig-summary is only available for per-read counts".into());
+    }
+    if options.bedmethyl && table_with_sample {
+        return Err("--format bedmethyl can only hold a single sample".into());
+    }
+    if config.mode == Mode::Long {
+        let compression = match matches.get_one::<String>("output_file") {
+            Some(output_file_path) => options.single_file_compression(output_file_path),
+            None => options.contig_file_compression(),
+        };
+        if compression == Compression::None {
+            return Err(
+                "--long output has to be compressed, name the output file .gz or .zst or leave out --compression none"
+                    .into(),
+            );
+        }
+        log::warn!(
+            "--long writes a line for every aligned read base, which can be many times the size of the BAM file"
+        );
+    }
+    let split_by_interval = matches.get_flag("split_by_interval");
+    // Intervals kept twice by --keep-intervals or sharing a name would be written to the same file
+    if split_by_interval {
+        let mut file_stems_seen = HashSet::new();
+        if let Some(region) = config.regions.iter().find(|region| !file_stems_seen.insert(interval_file_stem(region))) {
+            return Err(format!(
+                "several intervals would be written to {}, --split-by-interval needs distinct intervals and names",
+                options.contig_file_name(&interval_file_stem(region))
+            )
+            .into());
+        }
+    }
+    Ok(options)
+}
 
-    // Write results to summary files, one per reference sequence
-    for (ref_name, read_counts) in reference_counts {
-        let output_file_name = format!("{}/{}.tsv.gz", output_dir_path, sanitize_filename(&ref_name));
-        let output_file = File::create(output_file_name)?;
-        let mut output_writer =
-            flate2::write::GzEncoder::new(output_file, flate2::Compression::default());
-        writeln!(output_writer, "read_id\tnum_matches\tnum_mismatches")?;
-        for (read_id, (matches, mismatches)) in read_counts {
-            writeln!(output_writer, "{}\t{}\t{}", read_id, matches, mismatches)?;
+// Fails if the contig lengths of a BAM file differ from those of the FASTA file, or with
+// `warn_only` only warns
+fn check_reference_lengths(config: &PileupConfig, samples: &[Sample], warn_only: bool) -> Result<(), BoxError> {
+    for sample in samples {
+        if sample.bam_file_path == "-" {
+            log::warn!(
+                "--reference-check skips the BAM file from stdin, its header can only be read once"
+            );
+            continue;
+        }
+        let mismatches = bampile::reference_length_mismatches(&sample.bam_file_path, &config.fasta_file_path)?;
+        if mismatches.is_empty() {
+            continue;
+        }
+        let contigs: Vec<String> = mismatches
+            .iter()
+            .map(|(ref_name, bam_len, fasta_len)| {
+                format!("{} ({} in BAM, {} in FASTA)", ref_name, bam_len, fasta_len)
+            })
+            .collect();
+        let message = format!(
+            "contig lengths of BAM file {} differ from FASTA file {}: {}",
+            sample.bam_file_path,
+            config.fasta_file_path,
+            contigs.join(", ")
+        );
+        if warn_only {
+            log::warn!("{}", message);
+        } else {
+            return Err(bampile::Error::ReferenceMismatch(message).into());
         }
     }
+    Ok(())
+}
 
+// Fails if any of the files a run would write exists already
+fn check_output_files_absent(
+    matches: &clap::ArgMatches,
+    config: &PileupConfig,
+    options: &OutputOptions,
+    samples: &[Sample],
+) -> Result<(), BoxError> {
+    // Counts are added to existing tables with --append
+    let mut file_paths = if options.append {
+        Vec::new()
+    } else {
+        count_file_paths(
+            config,
+            options,
+            samples,
+            matches.get_one::<String>("output_file").map(String::as_str),
+            matches.get_one::<String>("output_dir").map_or("", String::as_str),
+            matches.get_flag("split_by_interval"),
+        )
+    };
+    file_paths.extend(matches.get_one::<String>("coverage_summary").cloned());
+    file_paths.extend(matches.get_one::<String>("qual_histogram").cloned());
+    file_paths.extend(matches.get_one::<String>("mismatch_spectrum").cloned());
+    file_paths.extend(matches.get_one::<String>("mismatch_profile").cloned());
+    file_paths.extend(matches.get_one::<String>("contig_summary").cloned());
+    file_paths.extend(matches.get_one::<String>("debug_positions").cloned());
+    file_paths.extend(matches.get_one::<String>("manifest").cloned());
+    let existing: Vec<String> = file_paths.into_iter().filter(|path| Path::new(path).exists()).collect();
+    if !existing.is_empty() {
+        return Err(format!(
+            "output files already exist, use --force to overwrite them: {}",
+            existing.join(", ")
+        )
+        .into());
+    }
     Ok(())
 }
 
-fn count_matches_mismatches(
-    sequence: &[u8],
-    qual: &[u8],
-    reference_base: u8,
-    qscore_cutoff: u8,
-) -> (usize, usize) {
-    let mut num_matches = 0;
-    let mut num_mismatches = 0;
+// Checks the inputs and output directories without counting and prints what would be counted
+fn dry_run(
+    matches: &clap::ArgMatches,
+    config: &PileupConfig,
+    samples: &[Sample],
+    with_sample: bool,
+) -> Result<(), BoxError> {
+    let mut checks = Vec::new();
+    for sample in samples {
+        let sample_config = PileupConfig {
+            bam_file_path: sample.bam_file_path.clone(),
+            ..config.clone()
+        };
+        let check = bampile::check_inputs(&sample_config).map_err(|e| {
+            if with_sample {
+                bampile::error::with_context(e, &format!("sample {}", sample.name))
+            } else {
+                e
+            }
+        })?;
+        checks.push((sample, check));
+    }
+    let mut output_dirs = vec![match matches.get_one::<String>("output_file") {
+        Some(output_file_path) => parent_dir(output_file_path),
+        None => Path::new(matches.get_one::<String>("output_dir").unwrap()),
+    }];
+    for id in [
+        "coverage_summary",
+        "qual_histogram",
+        "mismatch_spectrum",
+        "mismatch_profile",
+        "contig_summary",
+        "debug_positions",
+        "manifest",
+    ] {
+        if let Some(file_path) = matches.get_one::<String>(id) {
+            output_dirs.push(parent_dir(file_path));
+        }
+    }
+    for output_dir in output_dirs {
+        check_writable_dir(output_dir)?;
+    }
+    for (sample, check) in checks {
+        if with_sample {
+            print!("{}: ", sample.name);
+        }
+        match check.estimated_reads {
+            Some(estimated_reads) => println!(
+                "{} intervals on {} contigs, about {} reads",
+                check.n_intervals, check.n_contigs, estimated_reads
+            ),
+            None => println!("{} intervals on {} contigs", check.n_intervals, check.n_contigs),
+        }
+    }
+    Ok(())
+}
+
+// Settings that only affect how the counts are written
+struct OutputOptions {
+    by_strand: bool,
+    // Add the soft-clipped bases to the per-read counts
+    count_softclips: bool,
+    // Add the aligned length and mean base quality of every read to the per-read counts
+    read_quality: bool,
+    // Add the covered positions and mismatch rate of every read to the per-read counts
+    mismatch_rate: bool,
+    count_matches_only: bool,
+    // Add the per-read counts to the end of existing tables
+    append: bool,
+    // Compression chosen on the command line, by default per-contig files are gzip-compressed
+    // and single files by their extension
+    compression: Option<Compression>,
+    compression_level: u32,
+    // Write consensus calls instead of per-position base counts
+    consensus: Option<ConsensusSettings>,
+    // Add the strand counts of the non-reference alleles and their strand bias to base counts
+    strand_bias: bool,
+    // Add the mean distance of the non-reference bases from their read ends to base counts
+    end_distance: bool,
+    // Add the length of the reference homopolymer of each position to base counts
+    hp_len: bool,
+    // Add the two most frequent alleles of each position to base counts
+    top2: bool,
+    // Leave out the base counts of positions with a lower depth
+    min_base_count: usize,
+    // Write modification counts as bedMethyl
+    bedmethyl: bool,
+    // Write per-read counts as JSON Lines
+    jsonl: bool,
+    // Write per-read or per-position counts as Parquet
+    parquet: bool,
+    // Write TSV tables comma-separated, as .csv files
+    csv: bool,
+    // Write consensus variants as VCF, with a header line for each of these contigs and lengths
+    vcf_contigs: Option<Vec<(String, u32)>>,
+    // Write the ref and alt counts of these known sites of each contig instead of base counts
+    known_sites: Option<Arc<HashMap<String, Vec<KnownSite>>>>,
+    // Add a column with the sample of each line
+    with_sample: bool,
+    // Write the per-contig files of each sample into a subdirectory named after it, unless
+    // --no-sample-dirs is given
+    sample_dirs: bool,
+    // Depth a position needs to count as covered in the coverage summary
+    coverage_depth: u32,
+    // File name of each contig's (or interval's) file with a {ref} placeholder left for its
+    // file stem, instead of the stem with the default extension
+    name_template: Option<String>,
+}
+
+impl OutputOptions {
+    fn read_columns(&self) -> ReadColumns {
+        ReadColumns {
+            softclips: self.count_softclips,
+            matches_only: self.count_matches_only,
+            read_quality: self.read_quality,
+            mismatch_rate: self.mismatch_rate,
+        }
+    }
+
+    fn contig_file_compression(&self) -> Compression {
+        self.compression.unwrap_or(Compression::Gzip(self.compression_level))
+    }
 
-    for (base, qscore) in sequence.iter().zip(qual.iter()) {
-        if *base == reference_base || *qscore >= qscore_cutoff {
-            num_matches += 1;
+    fn single_file_compression(&self, output_file_path: &str) -> Compression {
+        // Parquet files compress their pages themselves, whatever their name
+        if self.parquet {
+            return self.contig_file_compression();
+        }
+        self.compression
+            .unwrap_or_else(|| compression_for_file_name(output_file_path, self.compression_level))
+    }
+
+    // File name in the output directory for the file stem of a contig or interval
+    fn contig_file_name(&self, file_stem: &str) -> String {
+        match &self.name_template {
+            Some(name_template) => name_template.replace("{ref}", file_stem),
+            None => format!("{}.{}", file_stem, self.contig_file_extension()),
+        }
+    }
+
+    fn contig_file_extension(&self) -> String {
+        if self.parquet {
+            return "parquet".to_string();
+        }
+        let format_extension = if self.bedmethyl {
+            "bed"
+        } else if self.jsonl {
+            "jsonl"
+        } else if self.vcf_contigs.is_some() {
+            "vcf"
+        } else if self.csv {
+            "csv"
         } else {
-            num_mismatches += 1;
+            "tsv"
+        };
+        match self.contig_file_compression() {
+            Compression::None => format_extension.to_string(),
+            Compression::Gzip(_) | Compression::Bgzf(_) => format!("{}.gz", format_extension),
+            Compression::Zstd(_) => format!("{}.zst", format_extension),
         }
     }
+}
+
+// Paths of all count files a run writes, including BGZF and tabix indices, so that existing
+// files can be found before anything is written
+fn count_file_paths(
+    config: &PileupConfig,
+    options: &OutputOptions,
+    samples: &[Sample],
+    output_file_path: Option<&str>,
+    output_dir_path: &str,
+    split_by_interval: bool,
+) -> Vec<String> {
+    let (file_paths, compression) = match output_file_path {
+        Some(output_file_path) => (
+            vec![output_file_path.to_string()],
+            options.single_file_compression(output_file_path),
+        ),
+        None => {
+            let dir_paths: Vec<String> = if options.sample_dirs {
+                samples.iter().map(|sample| sample_dir_path(output_dir_path, sample)).collect()
+            } else {
+                vec![output_dir_path.to_string()]
+            };
+            let file_stems = file_stems(config, split_by_interval);
+            (
+                dir_paths
+                    .iter()
+                    .flat_map(|dir_path| {
+                        file_stems
+                            .iter()
+                            .map(move |file_stem| format!("{}/{}", dir_path, options.contig_file_name(file_stem)))
+                    })
+                    .collect(),
+                options.contig_file_compression(),
+            )
+        }
+    };
+    // All BGZF files get a .gzi index, only per-position tables a tabix index
+    let bgzf = matches!(compression, Compression::Bgzf(_));
+    let indexed = bgzf && config.mode != Mode::PerRead;
+    file_paths
+        .into_iter()
+        .flat_map(|file_path| {
+            let gzi_file_path = bgzf.then(|| format!("{}.gzi", file_path));
+            let tbi_file_path = indexed.then(|| format!("{}.tbi", file_path));
+            std::iter::once(file_path).chain(gzi_file_path).chain(tbi_file_path)
+        })
+        .collect()
+}
+
+// Subdirectory of the output directory with the files of a sample
+fn sample_dir_path(output_dir_path: &str, sample: &Sample) -> String {
+    format!("{}/{}", output_dir_path, encode_filename(&sample.name))
+}
+
+// File stems of the per-contig files, or with --split-by-interval of the per-interval files, in
+// the order they are written
+fn file_stems(config: &PileupConfig, split_by_interval: bool) -> Vec<String> {
+    group_regions_by_contig(&config.regions)
+        .iter()
+        .flat_map(|(ref_name, regions)| {
+            if split_by_interval {
+                regions.iter().map(interval_file_stem).collect()
+            } else {
+                vec![encode_filename(ref_name)]
+            }
+        })
+        .collect()
+}
+
+// File stem of an interval's file, its BED name or else its contig with 1-based, inclusive
+// coordinates like `chr1_100-200`
+fn interval_file_stem(region: &BedRegion) -> String {
+    match &region.name {
+        Some(name) => encode_filename(name),
+        None => format!("{}_{}-{}", encode_filename(&region.ref_name), region.start + 1, region.end),
+    }
+}
+
+// Fills in the placeholders of a --name-template except for {ref}, which differs per contig
+fn render_name_template(template: &str, qscore_cutoff: u8, mode: &str) -> Result<String, BoxError> {
+    if template.contains('/') {
+        return Err(format!("--name-template {} must be a file name, not a path", template).into());
+    }
+    if !template.contains("{ref}") {
+        return Err(format!(
+            "--name-template {} has no {{ref}} placeholder, so all contigs would be written to the same file",
+            template
+        )
+        .into());
+    }
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let close = rest[open..]
+            .find('}')
+            .ok_or_else(|| format!("--name-template {} has an unclosed {{", template))?;
+        match &rest[open + 1..open + close] {
+            "ref" => rendered.push_str("{ref}"),
+            "qscore" => rendered.push_str(&qscore_cutoff.to_string()),
+            "mode" => rendered.push_str(mode),
+            "date" => rendered.push_str(&today_utc()),
+            placeholder => {
+                return Err(format!(
+                    "unknown placeholder {{{}}} in --name-template, expected {{ref}}, {{qscore}}, {{mode}} or {{date}}",
+                    placeholder
+                )
+                .into());
+            }
+        }
+        rest = &rest[open + close + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+// Current UTC date as YYYYMMDD
+fn today_utc() -> String {
+    let (year, month, day, _) = utc_now();
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+// Current UTC time in ISO 8601, like 2024-05-01T12:30:00Z
+fn utc_timestamp() -> String {
+    let (year, month, day, seconds) = utc_now();
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// Current UTC year, month, day and second of the day, converted from days since the Unix epoch
+// (Howard Hinnant's civil_from_days algorithm)
+fn utc_now() -> (i64, i64, i64, u64) {
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day, seconds % 86400)
+}
+
+// Absolute path of an input file for the manifest, as given if it can't be resolved like `-`
+fn absolute_path(file_path: &str) -> String {
+    fs::canonicalize(file_path).map_or_else(|_| file_path.to_string(), |path| path.display().to_string())
+}
 
-    (num_matches, num_mismatches)
+// Where the counts of each contig are written to
+enum CountsOutput {
+    // One <contig>.tsv.gz (or .bed.gz, .jsonl.gz, .vcf.gz, without .gz if uncompressed, .parquet, or
+    // as named by the template) file per reference sequence in the output directory
+    ContigFiles {
+        output_dir_path: String,
+        compression: Compression,
+    },
+    // A single table for all reference sequences
+    PerRead(ReadCountsWriter),
+    PerPosition(PositionCountsWriter),
+    KnownSites(KnownSitesWriter),
+    Modifications(ModCountsWriter),
+    Variants(VcfWriter),
+    PerReadParquet(ParquetReadCountsWriter),
+    PerPositionParquet(ParquetPositionCountsWriter),
+    Long(BaseObservationWriter),
 }
 
-fn load_bed_regions(bed_file_path: &str) -> Result<HashMap<String, (u32, u32)>, Box<dyn std::error::Error>> {
-    let bed_file = File::open(bed_file_path)?;
-    let reader = BufReader::new(bed_file);
-    let mut regions_of_interest: HashMap<String, (u32, u32)> = HashMap::new();
+impl CountsOutput {
+    fn create_contig_files(output_dir_path: &str, options: &OutputOptions) -> Result<CountsOutput, BoxError> {
+        // Create the output directory if it doesn't exist
+        create_dir_all(output_dir_path)?;
+        Ok(CountsOutput::ContigFiles {
+            output_dir_path: output_dir_path.to_string(),
+            compression: options.contig_file_compression(),
+        })
+    }
+
+    fn create_combined(
+        config: &PileupConfig,
+        output_file_path: &str,
+        options: &OutputOptions,
+    ) -> Result<CountsOutput, BoxError> {
+        let compression = options.single_file_compression(output_file_path);
+        // Per-read tables get a leading reference column to tell the contigs apart
+        CountsOutput::create_table(config, options, output_file_path, compression, true)
+    }
+
+    fn create_table(
+        config: &PileupConfig,
+        options: &OutputOptions,
+        output_file_path: &str,
+        compression: Compression,
+        with_reference: bool,
+    ) -> Result<CountsOutput, BoxError> {
+        Ok(match config.mode {
+            Mode::PerRead if options.parquet => CountsOutput::PerReadParquet(ParquetReadCountsWriter::create(
+                output_file_path,
+                compression,
+                options.by_strand,
+                options.read_columns(),
+                options.with_sample,
+                true,
+            )?),
+            Mode::PerRead if options.jsonl => CountsOutput::PerRead(ReadCountsWriter::create_jsonl(
+                output_file_path,
+                compression,
+                options.by_strand,
+                options.read_columns(),
+                options.with_sample,
+                true,
+            )?),
+            Mode::PerRead if options.append => CountsOutput::PerRead(ReadCountsWriter::append(
+                output_file_path,
+                compression,
+                options.by_strand,
+                options.read_columns(),
+                with_reference,
+                options.with_sample,
+                true,
+            )?),
+            Mode::PerRead => CountsOutput::PerRead(ReadCountsWriter::create(
+                output_file_path,
+                compression,
+                options.by_strand,
+                options.read_columns(),
+                with_reference,
+                options.with_sample,
+                true,
+            )?),
+            Mode::PerPosition if options.parquet => CountsOutput::PerPositionParquet(ParquetPositionCountsWriter::create(
+                output_file_path,
+                compression,
+                options.consensus,
+                options.min_base_count,
+                options.with_sample,
+                true,
+            )?),
+            Mode::PerPosition if options.known_sites.is_some() => CountsOutput::KnownSites(KnownSitesWriter::create(
+                output_file_path,
+                compression,
+                options.known_sites.clone().unwrap(),
+                options.with_sample,
+            )?),
+            Mode::PerPosition if options.vcf_contigs.is_some() => CountsOutput::Variants(VcfWriter::create(
+                output_file_path,
+                compression,
+                options.vcf_contigs.as_ref().unwrap(),
+                options.consensus.expect("VCF output needs consensus settings"),
+            )?),
+            Mode::PerPosition => CountsOutput::PerPosition(PositionCountsWriter::create(
+                output_file_path,
+                compression,
+                options.consensus,
+                PositionColumns {
+                    strand_bias: options.strand_bias,
+                    end_distance: options.end_distance,
+                    hp_len: options.hp_len,
+                    top2: options.top2,
+                },
+                options.min_base_count,
+                options.with_sample,
+                true,
+            )?),
+            Mode::Modifications => CountsOutput::Modifications(ModCountsWriter::create(
+                output_file_path,
+                compression,
+                &config.mod_codes,
+                options.bedmethyl,
+                options.with_sample,
+            )?),
+            Mode::Long => CountsOutput::Long(BaseObservationWriter::create(
+                output_file_path,
+                compression,
+                options.with_sample,
+                true,
+                config.strict_iupac,
+            )?),
+        })
+    }
+
+    // Writes the counts of the intervals of a contig, given in `regions`, to the file with the
+    // given stem or to the single table
+    fn write_contig(
+        &mut self,
+        config: &PileupConfig,
+        options: &OutputOptions,
+        file_stem: &str,
+        regions: &[BedRegion],
+        samples: &[Sample],
+        sample_counts: &[SampleCounts],
+    ) -> Result<(), BoxError> {
+        let ref_name = regions[0].ref_name.as_str();
+        let CountsOutput::ContigFiles { output_dir_path, compression } = self else {
+            return self.write_samples(ref_name, regions, samples, sample_counts);
+        };
+        let file_name = options.contig_file_name(file_stem);
+        if encode_filename(ref_name) != ref_name {
+            log::warn!("writing contig {} to {}", ref_name, file_name);
+        }
+        if options.sample_dirs {
+            for (sample, counts) in samples.iter().zip(sample_counts) {
+                let sample_dir_path = sample_dir_path(output_dir_path, sample);
+                create_dir_all(&sample_dir_path)?;
+                let output_file_name = format!("{}/{}", sample_dir_path, file_name);
+                let mut table = CountsOutput::create_table(config, options, &output_file_name, *compression, false)?;
+                table.write_samples(ref_name, regions, std::slice::from_ref(sample), std::slice::from_ref(counts))?;
+                table.finish()?;
+            }
+            return Ok(());
+        }
+        let output_file_name = format!("{}/{}", output_dir_path, file_name);
+        let mut table = CountsOutput::create_table(config, options, &output_file_name, *compression, false)?;
+        table.write_samples(ref_name, regions, samples, sample_counts)?;
+        table.finish()
+    }
 
-    for line in reader.lines() {
-        let line = line?;
-        let fields: Vec<&str> = line.trim().split('\t').collect();
+    // Appends the counts of all samples of a contig to a single table
+    fn write_samples(
+        &mut self,
+        ref_name: &str,
+        regions: &[BedRegion],
+        samples: &[Sample],
+        sample_counts: &[SampleCounts],
+    ) -> Result<(), BoxError> {
+        let named_counts = samples
+            .iter()
+            .zip(sample_counts)
+            .map(|(sample, counts)| (sample.name.as_str(), &counts.counts));
+        match self {
+            CountsOutput::PerRead(writer) => {
+                let tables: Vec<_> = named_counts
+                    .map(|(name, counts)| match counts {
+                        ContigCounts::PerRead(read_counts) => (name, read_counts),
+                        _ => unreachable!("counts do not match the mode"),
+                    })
+                    .collect();
+                writer.write_contig_samples(ref_name, &tables)
+            }
+            CountsOutput::PerReadParquet(writer) => {
+                let tables: Vec<_> = named_counts
+                    .map(|(name, counts)| match counts {
+                        ContigCounts::PerRead(read_counts) => (name, read_counts),
+                        _ => unreachable!("counts do not match the mode"),
+                    })
+                    .collect();
+                writer.write_contig_samples(ref_name, &tables)
+            }
+            CountsOutput::PerPosition(writer) => {
+                let tables: Vec<_> = named_counts
+                    .map(|(name, counts)| match counts {
+                        ContigCounts::PerPosition(positions) => (name, positions),
+                        _ => unreachable!("counts do not match the mode"),
+                    })
+                    .collect();
+                writer.write_contig_samples(ref_name, regions, &tables)
+            }
+            CountsOutput::KnownSites(writer) => {
+                let tables: Vec<_> = named_counts
+                    .map(|(name, counts)| match counts {
+                        ContigCounts::PerPosition(positions) => (name, positions),
+                        _ => unreachable!("counts do not match the mode"),
+                    })
+                    .collect();
+                writer.write_contig_samples(ref_name, regions, &tables)
+            }
+            CountsOutput::PerPositionParquet(writer) => {
+                let tables: Vec<_> = named_counts
+                    .map(|(name, counts)| match counts {
+                        ContigCounts::PerPosition(positions) => (name, positions),
+                        _ => unreachable!("counts do not match the mode"),
+                    })
+                    .collect();
+                writer.write_contig_samples(ref_name, regions, &tables)
+            }
+            CountsOutput::Modifications(writer) => {
+                let tables: Vec<_> = named_counts
+                    .map(|(name, counts)| match counts {
+                        ContigCounts::Modifications(positions) => (name, positions),
+                        _ => unreachable!("counts do not match the mode"),
+                    })
+                    .collect();
+                writer.write_contig_samples(ref_name, &tables)
+            }
+            CountsOutput::Long(writer) => {
+                let tables: Vec<_> = named_counts
+                    .map(|(name, counts)| match counts {
+                        ContigCounts::Long(observations) => (name, observations.as_slice()),
+                        _ => unreachable!("counts do not match the mode"),
+                    })
+                    .collect();
+                writer.write_contig_samples(ref_name, regions, &tables)
+            }
+            CountsOutput::Variants(writer) => match sample_counts {
+                [SampleCounts {
+                    counts: ContigCounts::PerPosition(positions),
+                    ..
+                }] => writer.write_contig(ref_name, positions),
+                _ => unreachable!("VCF output holds the per-position counts of a single sample"),
+            },
+            CountsOutput::ContigFiles { .. } => unreachable!("contig files are written by write_contig"),
+        }
+    }
 
-        if fields.len() >= 3 {
-            let chromosome = fields[0].to_string();
-            let start = fields[1].parse::<u32>()?;
-            let end = fields[2].parse::<u32>()?;
-            regions_of_interest.insert(chromosome, (start, end));
+    // Appends the per-read counts of all samples summed over all contigs to the single table
+    fn write_reads_across_contigs(
+        &mut self,
+        samples: &[Sample],
+        reads_across_contigs: &[ReadsAcrossContigs],
+    ) -> Result<(), BoxError> {
+        let named_reads: Vec<_> =
+            samples.iter().map(|sample| sample.name.as_str()).zip(reads_across_contigs).collect();
+        match self {
+            CountsOutput::PerRead(writer) => writer.write_reads_across_contigs(&named_reads),
+            CountsOutput::PerReadParquet(writer) => writer.write_reads_across_contigs(&named_reads),
+            _ => unreachable!("reads are only summed over contigs in a single per-read table"),
         }
     }
 
-    Ok(regions_of_interest)
+    fn finish(self) -> Result<(), BoxError> {
+        match self {
+            CountsOutput::ContigFiles { .. } => Ok(()),
+            CountsOutput::PerRead(writer) => writer.finish(),
+            CountsOutput::PerPosition(writer) => writer.finish(),
+            CountsOutput::KnownSites(writer) => writer.finish(),
+            CountsOutput::Modifications(writer) => writer.finish(),
+            CountsOutput::Variants(writer) => writer.finish(),
+            CountsOutput::PerReadParquet(writer) => writer.finish(),
+            CountsOutput::PerPositionParquet(writer) => writer.finish(),
+            CountsOutput::Long(writer) => writer.finish(),
+        }
+    }
 }
 
-fn sanitize_filename(filename: &str) -> String {
-    filename.chars().filter(|c| c.is_alphanumeric() || *c == '_' || *c == '-').collect()
+// Samples of the BAM files on the command line, named by --sample-name or after their file
+fn parse_samples(matches: &clap::ArgMatches) -> Result<Vec<Sample>, BoxError> {
+    let bam_file_paths: Vec<&String> = matches.get_many::<String>("bam").unwrap().collect();
+    let names: Vec<String> = match matches.get_many::<String>("sample_name") {
+        Some(names) => names.cloned().collect(),
+        None => bam_file_paths.iter().map(|path| default_sample_name(path)).collect(),
+    };
+    if names.len() != bam_file_paths.len() {
+        return Err(format!(
+            "got {} sample names for {} BAM files",
+            names.len(),
+            bam_file_paths.len()
+        )
+        .into());
+    }
+    let mut seen = HashSet::new();
+    if let Some(duplicate) = names.iter().find(|name| !seen.insert(name.as_str())) {
+        return Err(format!("sample name {} is used more than once", duplicate).into());
+    }
+    Ok(names
+        .into_iter()
+        .zip(bam_file_paths)
+        .map(|(name, bam_file_path)| Sample {
+            name,
+            bam_file_path: bam_file_path.clone(),
+        })
+        .collect())
+}
+
+// Contigs of --contig-list, read from the file if there is one by that name, else split at commas
+fn parse_contig_list(contig_list: &str) -> Result<HashSet<String>, BoxError> {
+    if Path::new(contig_list).is_file() {
+        let contigs = load_read_list(contig_list)?;
+        return Ok(contigs.iter().map(|contig| String::from_utf8_lossy(contig).to_string()).collect());
+    }
+    Ok(contig_list
+        .split(',')
+        .map(str::trim)
+        .filter(|contig| !contig.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+// File name of a BAM or CRAM file without the extension, stdin for -
+fn default_sample_name(bam_file_path: &str) -> String {
+    if bam_file_path == "-" {
+        return "stdin".to_string();
+    }
+    let file_name = Path::new(bam_file_path)
+        .file_name()
+        .map_or(bam_file_path.into(), |name| name.to_string_lossy());
+    file_name
+        .strip_suffix(".bam")
+        .or_else(|| file_name.strip_suffix(".cram"))
+        .unwrap_or(&file_name)
+        .to_string()
+}
+
+// Directory a file is written to, the current one for plain file names
+fn parent_dir(file_path: &str) -> &Path {
+    match Path::new(file_path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+// Checks that files can be created in a directory, or in the closest existing parent if it
+// doesn't exist yet, by creating and removing an empty file
+fn check_writable_dir(dir: &Path) -> Result<(), BoxError> {
+    let existing_dir = dir.ancestors().find(|ancestor| ancestor.exists()).unwrap_or(Path::new("."));
+    if !existing_dir.is_dir() {
+        return Err(format!("output path {} is not a directory", existing_dir.display()).into());
+    }
+    let probe_path = existing_dir.join(format!(".bampile-dry-run-{}", std::process::id()));
+    fs::File::create(&probe_path)
+        .and_then(|_| fs::remove_file(&probe_path))
+        .map_err(|e| format!("output directory {} is not writable: {}", existing_dir.display(), e))?;
+    Ok(())
+}
+
+// Single output files are gzip-compressed if their name ends in .gz, zstd-compressed if in .zst
+fn compression_for_file_name(output_file_path: &str, level: u32) -> Compression {
+    if output_file_path.ends_with(".gz") {
+        // The level may be one of zstd
+        Compression::Gzip(level.min(9))
+    } else if output_file_path.ends_with(".zst") {
+        Compression::Zstd(level.max(1))
+    } else {
+        Compression::None
+    }
+}
+
+// Parses a probability given either on the 0-255 scale of ML tags or as a fraction with a decimal point
+fn parse_mod_prob(value: &str) -> Result<u8, String> {
+    if value.contains('.') {
+        let fraction: f64 = value.parse().map_err(|e| format!("{}", e))?;
+        if !(0.0..=1.0).contains(&fraction) {
+            return Err(format!("{} is not between 0.0 and 1.0", value));
+        }
+        Ok((fraction * 255.0).round() as u8)
+    } else {
+        value.parse::<u8>().map_err(|e| format!("{}", e))
+    }
+}
+
+// Parses a SAM flag bitmask given either as decimal or as 0x-prefixed hex
+fn parse_flag_mask(value: &str) -> Result<u16, std::num::ParseIntError> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse::<u16>(),
+    }
 }
@@ -0,0 +1,344 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::pileup::{lookup_ref_pos, overlapping_ref_positions, RefPosLookup};
+use crate::PileupConfig;
+
+/// A base modification as written in MM tags: fundamental base, strand and modification code,
+/// e.g. `C+m` for 5mC or `A+a` for 6mA
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModCode {
+    pub base: u8,
+    pub strand: u8,
+    /// Single-letter code or ChEBI number
+    pub code: String,
+}
+
+impl fmt::Display for ModCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}{}", self.base as char, self.strand as char, self.code)
+    }
+}
+
+/// Parses a modification code like `C+m` or `C+76792`
+pub fn parse_mod_code(value: &str) -> Result<ModCode, String> {
+    let malformed = || format!("malformed modification code \"{}\", expected e.g. C+m", value);
+    let bytes = value.as_bytes();
+    if bytes.len() < 3 || !b"ACGTUN".contains(&bytes[0].to_ascii_uppercase()) || !b"+-".contains(&bytes[1]) {
+        return Err(malformed());
+    }
+    let code = &value[2..];
+    let is_letter_code = code.len() == 1 && code.bytes().all(|c| c.is_ascii_alphabetic());
+    let is_chebi_code = code.bytes().all(|c| c.is_ascii_digit());
+    if !is_letter_code && !is_chebi_code {
+        return Err(malformed());
+    }
+    Ok(ModCode {
+        base: bytes[0].to_ascii_uppercase(),
+        strand: bytes[1],
+        code: code.to_string(),
+    })
+}
+
+/// Modification calls at a single reference position, split by the strand of the reads
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct ModCounts {
+    pub fwd_modified: usize,
+    pub fwd_calls: usize,
+    pub rev_modified: usize,
+    pub rev_calls: usize,
+}
+
+impl ModCounts {
+    pub fn merge(&mut self, other: &ModCounts) {
+        self.fwd_modified += other.fwd_modified;
+        self.fwd_calls += other.fwd_calls;
+        self.rev_modified += other.rev_modified;
+        self.rev_calls += other.rev_calls;
+    }
+
+    pub fn modified(&self) -> usize {
+        self.fwd_modified + self.rev_modified
+    }
+
+    pub fn calls(&self) -> usize {
+        self.fwd_calls + self.rev_calls
+    }
+
+    /// Fraction of calls that are modified, 0 without calls
+    pub fn frequency(&self) -> f64 {
+        if self.calls() == 0 {
+            0.0
+        } else {
+            self.modified() as f64 / self.calls() as f64
+        }
+    }
+}
+
+/// Reads the MM/ML tags of a record and returns the probability (0-255, as in ML) of the given
+/// modification for every base of the stored sequence, or None where the base has no call.
+///
+/// MM positions count occurrences of the fundamental base in the originally sequenced
+/// orientation, so for reverse strand reads they are counted from the end of the stored
+/// sequence on the complemented bases. Bases skipped by the MM deltas are unmodified for the
+/// `.` (default) skip mode and have no call for `?`. Without an ML tag, every listed position
+/// is taken as modified.
+pub fn read_mod_probabilities(record: &bam::Record, mod_code: &ModCode) -> Result<Vec<Option<u8>>, String> {
+    let mut probabilities = read_mod_code_probabilities(record, std::slice::from_ref(mod_code))?;
+    Ok(probabilities.pop().unwrap())
+}
+
+/// Like [`read_mod_probabilities`] for several modifications at once, with the probabilities
+/// of each modification in the order of `mod_codes`. The tags are only parsed once, and the ML
+/// values of MM entries with several codes, like `C+mh`, go to the code they are listed for.
+pub fn read_mod_code_probabilities(
+    record: &bam::Record,
+    mod_codes: &[ModCode],
+) -> Result<Vec<Vec<Option<u8>>>, String> {
+    use bam::record::tags::TagValue;
+
+    let sequence_len = record.sequence().len();
+    let mut probabilities = vec![vec![None; sequence_len]; mod_codes.len()];
+    let mm = match record.tags().get(b"MM").or_else(|| record.tags().get(b"Mm")) {
+        Some(TagValue::String(mm, _)) => String::from_utf8_lossy(mm).to_string(),
+        Some(_) => return Err("MM tag is not a string".to_string()),
+        None => return Ok(probabilities),
+    };
+    let ml: Option<Vec<u8>> = match record.tags().get(b"ML").or_else(|| record.tags().get(b"Ml")) {
+        Some(TagValue::IntArray(ml)) => Some(ml.iter().map(|p| p.clamp(0, 255) as u8).collect()),
+        Some(_) => return Err("ML tag is not an integer array".to_string()),
+        None => None,
+    };
+    if sequence_len == 0 {
+        return Ok(probabilities);
+    }
+
+    let reverse = record.flag().is_reverse_strand();
+    // Base at offset `i` of the originally sequenced read and its index in the stored sequence
+    let original_base = |i: usize| {
+        if reverse {
+            (complement(record.sequence().at(sequence_len - 1 - i)), sequence_len - 1 - i)
+        } else {
+            (record.sequence().at(i), i)
+        }
+    };
+
+    // ML values are stored for all MM entries in order, so count those of preceding entries
+    let mut ml_offset = 0;
+    for entry in mm.split(';').filter(|entry| !entry.is_empty()) {
+        let mut fields = entry.split(',');
+        let header = fields.next().unwrap_or("").as_bytes();
+        if header.len() < 3 {
+            return Err(format!("malformed MM entry \"{}\"", entry));
+        }
+        let deltas = fields
+            .map(|delta| delta.trim().parse::<usize>())
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| format!("malformed MM entry \"{}\"", entry))?;
+
+        let (base, strand) = (header[0].to_ascii_uppercase(), header[1]);
+        let (codes, skipped_unknown) = match header[header.len() - 1] {
+            b'?' => (&header[2..header.len() - 1], true),
+            b'.' => (&header[2..header.len() - 1], false),
+            _ => (&header[2..], false),
+        };
+        // Either several single-letter codes or one ChEBI number
+        let codes: Vec<String> = if codes.iter().all(|c| c.is_ascii_digit()) {
+            vec![String::from_utf8_lossy(codes).to_string()]
+        } else {
+            codes.iter().map(|c| (*c as char).to_string()).collect()
+        };
+        let entry_ml_offset = ml_offset;
+        ml_offset += deltas.len() * codes.len();
+
+        // Which of the requested modifications are in this entry, and at which of its codes
+        let requested: Vec<(usize, usize)> = mod_codes
+            .iter()
+            .enumerate()
+            .filter(|(_, mod_code)| base == mod_code.base && strand == mod_code.strand)
+            .filter_map(|(slot, mod_code)| Some((slot, codes.iter().position(|code| *code == mod_code.code)?)))
+            .collect();
+        if requested.is_empty() {
+            continue;
+        }
+
+        // Stored sequence indices of all occurrences of the fundamental base, or for
+        // modifications on the opposite strand, of its complement
+        let counted_base = if strand == b'-' { complement(base) } else { base };
+        let occurrences: Vec<usize> = (0..sequence_len)
+            .map(original_base)
+            .filter(|(read_base, _)| base == b'N' || read_base.to_ascii_uppercase() == counted_base)
+            .map(|(_, index)| index)
+            .collect();
+        let skipped_probability = if skipped_unknown { None } else { Some(0) };
+        for (slot, code_index) in requested {
+            let probabilities = &mut probabilities[slot];
+            let mut occurrence = 0;
+            for (call, delta) in deltas.iter().enumerate() {
+                let called = occurrence + delta;
+                let Some(&index) = occurrences.get(called) else {
+                    return Err(format!("MM entry \"{}\" runs past the end of the read", entry));
+                };
+                for &skipped in &occurrences[occurrence..called] {
+                    probabilities[skipped] = skipped_probability;
+                }
+                // ML holds the values of all codes of a call before those of the next call
+                probabilities[index] = match &ml {
+                    Some(ml) => Some(
+                        *ml.get(entry_ml_offset + call * codes.len() + code_index)
+                            .ok_or_else(|| "ML tag has fewer values than MM calls".to_string())?,
+                    ),
+                    None => Some(255),
+                };
+                occurrence = called + 1;
+            }
+            // Occurrences after the last call follow the skip mode as well
+            for &skipped in &occurrences[occurrence..] {
+                probabilities[skipped] = skipped_probability;
+            }
+        }
+    }
+
+    Ok(probabilities)
+}
+
+// Adds the modification calls of a read to the per-position counts of an interval, which hold
+// the counts of each modification of the config in its order. Calls on bases below the Q-score
+// cutoff and calls not more confident than `min_mod_prob` (in either direction) are not counted,
+// reads with malformed MM/ML tags have no calls.
+pub(crate) fn count_mod_calls(
+    record: &bam::Record,
+    reference_sequence: &[u8],
+    start: u32,
+    end: u32,
+    config: &PileupConfig,
+    positions: &mut BTreeMap<u32, (u8, Vec<ModCounts>)>,
+) {
+    let Ok(probabilities) = read_mod_code_probabilities(record, &config.mod_codes) else {
+        return;
+    };
+    for ref_pos in overlapping_ref_positions(record, start, end) {
+        let RefPosLookup::Aligned(index) = lookup_ref_pos(record, ref_pos) else {
+            continue;
+        };
+        let Some(reference_base) = reference_sequence.get((ref_pos - start) as usize) else {
+            continue;
+        };
+        let qscore = record.qualities().raw().get(index).copied().unwrap_or(0);
+        if qscore < config.qscore_cutoff {
+            continue;
+        }
+        for (slot, code_probabilities) in probabilities.iter().enumerate() {
+            let Some(Some(probability)) = code_probabilities.get(index) else {
+                continue;
+            };
+            // A probability above one half calls the base modified, otherwise unmodified with
+            // the complementary probability
+            let modified = *probability >= 128;
+            let confidence = if modified { *probability } else { 255 - *probability };
+            if confidence <= config.min_mod_prob {
+                continue;
+            }
+            let (_, code_counts) = positions
+                .entry(ref_pos)
+                .or_insert_with(|| (*reference_base, vec![ModCounts::default(); config.mod_codes.len()]));
+            let counts = &mut code_counts[slot];
+            if record.flag().is_reverse_strand() {
+                counts.rev_calls += 1;
+                counts.rev_modified += modified as usize;
+            } else {
+                counts.fwd_calls += 1;
+                counts.fwd_modified += modified as usize;
+            }
+        }
+    }
+}
+
+fn complement(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        _ => b'N',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::record;
+
+    fn c_plus_m() -> ModCode {
+        parse_mod_code("C+m").unwrap()
+    }
+
+    #[test]
+    fn reads_the_calls_of_mm_and_ml_tags() {
+        // The Cs are at 1, 3 and 4, the first is skipped and so unmodified, the others are called
+        let record = record("r1\t0\tchr1\t1\t60\t6M\t*\t0\t0\tACGCCA\tIIIIII\tMM:Z:C+m,1,0;\tML:B:C,200,50");
+        let probabilities = read_mod_probabilities(&record, &c_plus_m()).unwrap();
+        assert_eq!(probabilities, [None, Some(0), None, Some(200), Some(50), None]);
+    }
+
+    #[test]
+    fn leaves_bases_skipped_in_the_unknown_mode_without_a_call() {
+        let record = record("r1\t0\tchr1\t1\t60\t6M\t*\t0\t0\tACGCCA\tIIIIII\tMM:Z:C+m?,1;\tML:B:C,200");
+        let probabilities = read_mod_probabilities(&record, &c_plus_m()).unwrap();
+        assert_eq!(probabilities, [None, None, None, Some(200), None, None]);
+    }
+
+    #[test]
+    fn counts_the_calls_of_a_reverse_read_from_its_end() {
+        // Sequenced as TACCGC, whose Cs are the Gs at 3, 2 and 0 of the stored sequence
+        let record = record("r1\t16\tchr1\t1\t60\t6M\t*\t0\t0\tGCGGTA\tIIIIII\tMM:Z:C+m,1;\tML:B:C,230");
+        let probabilities = read_mod_probabilities(&record, &c_plus_m()).unwrap();
+        assert_eq!(probabilities, [Some(0), None, Some(230), Some(0), None, None]);
+    }
+
+    #[test]
+    fn places_calls_on_the_reference_through_the_cigar() {
+        // The inserted C at 2 of the read is called but aligned to no reference position, the C
+        // at 4 of the read is at 103 after the insertion
+        let record =
+            record("r1\t0\tchr1\t101\t60\t2M1I3M\t*\t0\t0\tACCTCA\tIIIIII\tMM:Z:C+m,0,0,0;\tML:B:C,255,255,10");
+        let mut config = PileupConfig::new("", "", Vec::new());
+        config.mod_codes = vec![c_plus_m()];
+        let mut positions = BTreeMap::new();
+        count_mod_calls(&record, b"ACTCA", 100, 105, &config, &mut positions);
+        let calls: Vec<(u32, usize, usize)> = positions
+            .iter()
+            .map(|(pos, (_, counts))| (*pos, counts[0].fwd_modified, counts[0].fwd_calls))
+            .collect();
+        assert_eq!(calls, [(101, 1, 1), (103, 0, 1)]);
+    }
+
+    // Counts of C+m at 100 of two reads, one called modified with probability 230 and the other
+    // unmodified with probability 155, and a third one with a base below --qscore
+    fn count_two_calls(min_mod_prob: u8) -> Option<ModCounts> {
+        let mut config = PileupConfig::new("", "", Vec::new());
+        config.mod_codes = vec![c_plus_m()];
+        config.qscore_cutoff = 10;
+        config.min_mod_prob = min_mod_prob;
+        let mut positions = BTreeMap::new();
+        for (qualities, probability) in [("I", 230), ("I", 100), ("#", 255)] {
+            let sam_line = format!("r1\t0\tchr1\t101\t60\t1M\t*\t0\t0\tC\t{}\tMM:Z:C+m,0;", qualities);
+            let sam_line = format!("{}\tML:B:C,{}", sam_line, probability);
+            count_mod_calls(&record(&sam_line), b"C", 100, 101, &config, &mut positions);
+        }
+        positions.remove(&100).map(|(_, mut counts)| counts.remove(0))
+    }
+
+    #[test]
+    fn counts_only_calls_above_the_probability_cutoff() {
+        let counts = count_two_calls(0).unwrap();
+        assert_eq!((counts.modified(), counts.calls()), (1, 2));
+        assert_eq!(counts.frequency(), 0.5);
+        // The unmodified call is below the cutoff and left out of both counts
+        let counts = count_two_calls(200).unwrap();
+        assert_eq!((counts.modified(), counts.calls()), (1, 1));
+        assert_eq!(counts.frequency(), 1.0);
+        // A call at the cutoff is left out as well
+        assert_eq!(count_two_calls(230), None);
+    }
+}
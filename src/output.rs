@@ -0,0 +1,2375 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use arrow_array::builder::{BooleanBuilder, Float64Builder, StringBuilder, UInt32Builder, UInt64Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{GzipLevel, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+
+use crate::error::Error;
+use crate::index::BinIndexBuilder;
+use crate::pileup::mismatch_rate;
+use crate::reference;
+use crate::{
+    call_consensus, region_name, BaseCounts, BaseObservation, BedRegion, BoxError, ConsensusSettings, DebugPositions,
+    IntervalCoverage, KnownSite, MismatchProfile, MismatchSpectrum, ModCode, ModCounts, QualHistogram, ReadCounts, ReadTotals,
+    ReadsAcrossContigs, SkippedReads, StrandCounts,
+};
+
+/// How output tables are compressed, gzip and BGZF with a level from 0 (none) to 9 (best), zstd
+/// with a level from 1 to 22
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Plain text
+    None,
+    /// Regular gzip
+    Gzip(u32),
+    /// Blocked gzip with a `.gzi` index of its blocks, per-position tables also get a tabix index
+    Bgzf(u32),
+    /// A zstd frame, or one per `--append` run
+    Zstd(u32),
+}
+
+impl Compression {
+    // How a file of this compression is described in messages, BGZF files are gzip files
+    fn description(self) -> &'static str {
+        match self {
+            Compression::None => "not compressed",
+            Compression::Gzip(_) | Compression::Bgzf(_) => "gzip-compressed",
+            Compression::Zstd(_) => "zstd-compressed",
+        }
+    }
+}
+
+/// Compression level of gzip and bgzip when none is given
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+
+// Lines written as `#` comments before the column header of every TSV table
+static HEADER_COMMENTS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Sets the lines, without their leading `#`, that every TSV table created afterwards starts
+/// with, before its column header. Tables have no comment lines by default.
+pub fn set_header_comments(lines: Vec<String>) {
+    *HEADER_COMMENTS.write().unwrap() = lines;
+}
+
+// Column delimiter of TSV tables and the value written where there is none
+struct TableStyle {
+    delimiter: u8,
+    na_string: Cow<'static, str>,
+}
+
+static TABLE_STYLE: RwLock<TableStyle> = RwLock::new(TableStyle {
+    delimiter: b'\t',
+    na_string: Cow::Borrowed("*"),
+});
+
+/// Sets the column delimiter and the missing value token of every TSV table created afterwards,
+/// by default a tab and `*`. With another delimiter than a tab, fields holding the delimiter
+/// or a double quote are quoted like in CSV, the header comments are left as they are.
+pub fn set_table_style(delimiter: u8, na_string: &str) {
+    *TABLE_STYLE.write().unwrap() = TableStyle {
+        delimiter,
+        na_string: Cow::Owned(na_string.to_string()),
+    };
+}
+
+// Value of a TSV column without one, such as a rate without any bases
+fn na_string() -> String {
+    TABLE_STYLE.read().unwrap().na_string.to_string()
+}
+
+/// Writes the per-read match/mismatch counts of one reference sequence, optionally as JSON Lines
+pub fn write_read_counts(
+    output_file_name: &str,
+    ref_name: &str,
+    read_counts: &HashMap<String, ReadCounts>,
+    by_strand: bool,
+    compression: Compression,
+    jsonl: bool,
+) -> Result<(), BoxError> {
+    let columns = ReadColumns::default();
+    let mut writer = if jsonl {
+        ReadCountsWriter::create_jsonl(output_file_name, compression, by_strand, columns, false, false)?
+    } else {
+        ReadCountsWriter::create(output_file_name, compression, by_strand, columns, false, false, false)?
+    };
+    writer.write_contig(ref_name, read_counts)?;
+    writer.finish()
+}
+
+/// Writes the per-position base counts (or consensus calls) of one reference sequence, sorted by position
+pub fn write_position_counts(
+    output_file_name: &str,
+    ref_name: &str,
+    positions: &BTreeMap<u32, (u8, BaseCounts)>,
+    compression: Compression,
+    consensus: Option<ConsensusSettings>,
+) -> Result<(), BoxError> {
+    let mut writer = PositionCountsWriter::create(
+        output_file_name,
+        compression,
+        consensus,
+        PositionColumns::default(),
+        0,
+        false,
+        false,
+    )?;
+    writer.write_contig(ref_name, positions)?;
+    writer.finish()
+}
+
+/// Writes the per-position counts of the modifications of one reference sequence, sorted by
+/// position, optionally as bedMethyl
+pub fn write_mod_counts(
+    output_file_name: &str,
+    ref_name: &str,
+    positions: &BTreeMap<u32, (u8, Vec<ModCounts>)>,
+    compression: Compression,
+    mod_codes: &[ModCode],
+    bedmethyl: bool,
+) -> Result<(), BoxError> {
+    let mut writer = ModCountsWriter::create(output_file_name, compression, mod_codes, bedmethyl, false)?;
+    writer.write_contig(ref_name, positions)?;
+    writer.finish()
+}
+
+/// Writes the quality histogram of each named sample, one line per quality with the number of
+/// matching and mismatching bases. The sample column is only written if `with_sample` is set.
+pub fn write_qual_histograms(
+    output_file_name: &str,
+    compression: Compression,
+    histograms: &[(&str, &QualHistogram)],
+    with_sample: bool,
+) -> Result<(), BoxError> {
+    let mut writer = OutputWriter::create_tsv(output_file_name, compression)?;
+    writeln!(
+        writer,
+        "{}qual\tmatch_count\tmismatch_count",
+        SampleColumn(with_sample.then_some("sample"))
+    )?;
+    for (sample, histogram) in histograms {
+        for (qual, (matches, mismatches)) in histogram.matches.iter().zip(&histogram.mismatches).enumerate() {
+            writeln!(
+                writer,
+                "{}{}\t{}\t{}",
+                SampleColumn(with_sample.then_some(*sample)),
+                qual,
+                matches,
+                mismatches
+            )?;
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes the mismatch profile of each named sample, one line per position relative to the
+/// interval start with the number of compared bases and the fraction of them mismatching the
+/// reference. The sample column is only written if `with_sample` is set.
+pub fn write_mismatch_profiles(
+    output_file_name: &str,
+    compression: Compression,
+    profiles: &[(&str, &MismatchProfile)],
+    with_sample: bool,
+) -> Result<(), BoxError> {
+    let mut writer = OutputWriter::create_tsv(output_file_name, compression)?;
+    writeln!(writer, "{}rel_pos\tdepth\tmismatch_rate", SampleColumn(with_sample.then_some("sample")))?;
+    for (sample, profile) in profiles {
+        for (rel_pos, depth) in profile.depth.iter().enumerate() {
+            writeln!(
+                writer,
+                "{}{}\t{}\t{}",
+                SampleColumn(with_sample.then_some(*sample)),
+                rel_pos,
+                depth,
+                profile.mismatch_rate(rel_pos).map_or_else(na_string, |rate| format!("{:.6}", rate))
+            )?;
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes the mismatch spectrum of each contig in each named sample, one line per substitution
+/// with its number of mismatching bases. The sample column is only written if `with_sample` is
+/// set.
+pub fn write_mismatch_spectra(
+    output_file_name: &str,
+    compression: Compression,
+    spectra: &[(&str, &str, &MismatchSpectrum)],
+    with_sample: bool,
+) -> Result<(), BoxError> {
+    let mut writer = OutputWriter::create_tsv(output_file_name, compression)?;
+    writeln!(writer, "{}chrom\tref\talt\tcount", SampleColumn(with_sample.then_some("sample")))?;
+    for (sample, ref_name, spectrum) in spectra {
+        for ref_base in MismatchSpectrum::BASES {
+            for alt_base in MismatchSpectrum::BASES.into_iter().filter(|alt_base| *alt_base != ref_base) {
+                writeln!(
+                    writer,
+                    "{}{}\t{}\t{}\t{}",
+                    SampleColumn(with_sample.then_some(*sample)),
+                    ref_name,
+                    ref_base as char,
+                    alt_base as char,
+                    spectrum.count(ref_base, alt_base)
+                )?;
+            }
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes the per-read totals of each contig in each named sample, one line per contig with its
+/// reads, counted bases, matches, mismatches and mismatch rate (`*` without counted bases).
+/// The sample column is only written if `with_sample` is set.
+pub fn write_contig_summary(
+    output_file_name: &str,
+    compression: Compression,
+    totals: &[(&str, &str, &ReadTotals)],
+    with_sample: bool,
+) -> Result<(), BoxError> {
+    let mut writer = OutputWriter::create_tsv(output_file_name, compression)?;
+    writeln!(
+        writer,
+        "{}chrom\tn_reads\tcounted_bases\tmatches\tmismatches\tmismatch_rate",
+        SampleColumn(with_sample.then_some("sample"))
+    )?;
+    for (sample, ref_name, contig_totals) in totals {
+        writeln!(
+            writer,
+            "{}{}\t{}\t{}\t{}\t{}\t{}",
+            SampleColumn(with_sample.then_some(*sample)),
+            ref_name,
+            contig_totals.n_reads,
+            contig_totals.counted_bases(),
+            contig_totals.matches,
+            contig_totals.mismatches,
+            contig_totals.mismatch_rate().map_or_else(na_string, |rate| format!("{:.6}", rate))
+        )?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Parameters, inputs and totals of a run, written as JSON by [`write_manifest`] to record how
+/// the output was made
+#[derive(Clone, Debug, Default)]
+pub struct RunManifest {
+    pub version: String,
+    /// Time the run finished, in ISO 8601 UTC
+    pub timestamp: String,
+    pub command_line: Vec<String>,
+    /// Name and absolute path of the BAM file of each sample
+    pub bam_files: Vec<(String, String)>,
+    /// None if the reference bases came from MD tags without a FASTA file
+    pub fasta_file: Option<String>,
+    /// BED file the intervals were loaded from, None for intervals given with --region
+    pub bed_file: Option<String>,
+    pub mode: String,
+    pub qscore_cutoff: u8,
+    pub min_mapq: u8,
+    pub exclude_flags: u16,
+    pub include_flags: u16,
+    pub n_intervals: usize,
+    /// Reads counted in all intervals and samples, reads in several intervals once for each
+    pub total_reads: u64,
+    /// Reads not counted in all intervals and samples, by the reason they were skipped
+    pub skipped_reads: SkippedReads,
+    /// Bases counted in all contigs and samples, see [`ContigCounts::counted_bases`](crate::ContigCounts::counted_bases)
+    pub total_bases: u64,
+}
+
+/// Writes the manifest of a run as a JSON object
+pub fn write_manifest(output_file_name: &str, manifest: &RunManifest) -> Result<(), BoxError> {
+    let mut writer = OutputWriter::create(output_file_name, Compression::None)?;
+    writeln!(writer, "{{")?;
+    write!(writer, "  \"version\": ")?;
+    write_json_string(&mut writer, &manifest.version)?;
+    write!(writer, ",\n  \"timestamp\": ")?;
+    write_json_string(&mut writer, &manifest.timestamp)?;
+    write!(writer, ",\n  \"command_line\": [")?;
+    for (i, arg) in manifest.command_line.iter().enumerate() {
+        if i > 0 {
+            write!(writer, ", ")?;
+        }
+        write_json_string(&mut writer, arg)?;
+    }
+    write!(writer, "],\n  \"bam_files\": [")?;
+    for (i, (sample, path)) in manifest.bam_files.iter().enumerate() {
+        write!(writer, "{}\n    {{\"sample\": ", if i > 0 { "," } else { "" })?;
+        write_json_string(&mut writer, sample)?;
+        write!(writer, ", \"path\": ")?;
+        write_json_string(&mut writer, path)?;
+        write!(writer, "}}")?;
+    }
+    write!(writer, "\n  ],\n  \"fasta_file\": ")?;
+    match &manifest.fasta_file {
+        Some(fasta_file) => write_json_string(&mut writer, fasta_file)?,
+        None => write!(writer, "null")?,
+    }
+    write!(writer, ",\n  \"bed_file\": ")?;
+    match &manifest.bed_file {
+        Some(bed_file) => write_json_string(&mut writer, bed_file)?,
+        None => write!(writer, "null")?,
+    }
+    write!(writer, ",\n  \"mode\": ")?;
+    write_json_string(&mut writer, &manifest.mode)?;
+    let numbers = [
+        ("qscore_cutoff", manifest.qscore_cutoff as u64),
+        ("min_mapq", manifest.min_mapq as u64),
+        ("exclude_flags", manifest.exclude_flags as u64),
+        ("include_flags", manifest.include_flags as u64),
+        ("n_intervals", manifest.n_intervals as u64),
+        ("total_reads", manifest.total_reads),
+        ("total_bases", manifest.total_bases),
+    ];
+    for (name, value) in numbers {
+        write!(writer, ",\n  \"{}\": {}", name, value)?;
+    }
+    write!(writer, ",\n  \"skipped_reads\": {{")?;
+    for (i, (reason, n_reads)) in manifest.skipped_reads.by_reason().iter().enumerate() {
+        write!(writer, "{}\"{}\": {}", if i > 0 { ", " } else { "" }, reason, n_reads)?;
+    }
+    write!(writer, "}}")?;
+    writeln!(writer, "\n}}")?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Optional columns of a per-read count table after the counts, in this order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadColumns {
+    /// Write the mismatch and ambiguous reference counts as `*`, for counts made with
+    /// [`PileupConfig::count_matches_only`](crate::PileupConfig::count_matches_only), which
+    /// has none. Not an extra column.
+    pub matches_only: bool,
+    /// `num_softclips`, or `num_softclips_fwd` and `num_softclips_rev` by strand
+    pub softclips: bool,
+    /// `aligned_length`, the [`ReadCounts::aligned_length`], and `mean_qual`, the
+    /// [`ReadCounts::mean_qual`] (`*` for reads without base qualities)
+    pub read_quality: bool,
+    /// `covered_positions`, the [`ReadCounts::covered_positions`], and `mismatch_rate`, the
+    /// [`ReadCounts::mismatch_rate`] (`*` for reads without covered positions), or both with
+    /// `_fwd` and `_rev` by strand
+    pub mismatch_rate: bool,
+}
+
+// Column header of a per-read count table, tab-separated
+fn read_counts_header(
+    by_strand: bool,
+    columns: ReadColumns,
+    with_reference: bool,
+    with_sample: bool,
+    with_region_name: bool,
+) -> String {
+    let mut header = String::new();
+    if with_sample {
+        header.push_str("sample\t");
+    }
+    if with_reference {
+        header.push_str("reference\t");
+    }
+    if by_strand {
+        header.push_str(
+            "read_id\tnum_matches_fwd\tnum_mismatches_fwd\tnum_matches_rev\tnum_mismatches_rev\t\
+             num_insertions_fwd\tnum_deletions_fwd\tnum_insertions_rev\tnum_deletions_rev\t\
+             num_ambiguous_ref_fwd\tnum_ambiguous_ref_rev",
+        );
+        if columns.softclips {
+            header.push_str("\tnum_softclips_fwd\tnum_softclips_rev");
+        }
+    } else {
+        header.push_str("read_id\tnum_matches\tnum_mismatches\tnum_insertions\tnum_deletions\tnum_ambiguous_ref");
+        if columns.softclips {
+            header.push_str("\tnum_softclips");
+        }
+    }
+    if columns.read_quality {
+        header.push_str("\taligned_length\tmean_qual");
+    }
+    if columns.mismatch_rate && by_strand {
+        header.push_str("\tcovered_positions_fwd\tcovered_positions_rev\tmismatch_rate_fwd\tmismatch_rate_rev");
+    } else if columns.mismatch_rate {
+        header.push_str("\tcovered_positions\tmismatch_rate");
+    }
+    if with_region_name {
+        header.push_str("\tregion_name");
+    }
+    header
+}
+
+// The column header of an existing table, its first line that isn't a `#` comment (empty if
+// there is none), and its compression, gzip for BGZF tables and without levels. None if the file
+// doesn't exist.
+fn existing_column_header(output_file_name: &str) -> Result<Option<(String, Compression)>, BoxError> {
+    if !Path::new(output_file_name).exists() {
+        return Ok(None);
+    }
+    let open = || {
+        File::open(output_file_name)
+            .map_err(|e| Error::Io(format!("failed to open output file {}: {}", output_file_name, e)))
+    };
+    let mut magic = [0u8; 4];
+    let magic_len = open()?.read(&mut magic)?;
+    let (compression, reader): (_, Box<dyn BufRead>) = if magic_len >= 2 && magic[..2] == [0x1f, 0x8b] {
+        (Compression::Gzip(0), Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(open()?))))
+    } else if magic_len == 4 && magic == ZSTD_MAGIC {
+        let decoder = zstd::Decoder::new(open()?)
+            .map_err(|e| Error::Io(format!("failed to read output file {}: {}", output_file_name, e)))?;
+        (Compression::Zstd(0), Box::new(BufReader::new(decoder)))
+    } else {
+        (Compression::None, Box::new(BufReader::new(open()?)))
+    };
+    for line in reader.lines() {
+        let line = line.map_err(|e| Error::Io(format!("failed to read output file {}: {}", output_file_name, e)))?;
+        if !line.starts_with('#') {
+            return Ok(Some((line, compression)));
+        }
+    }
+    Ok(Some((String::new(), compression)))
+}
+
+/// Name of a sample with its reference base and counts at each 0-based position of a contig
+pub type SamplePositions<'a, C> = (&'a str, &'a BTreeMap<u32, (u8, C)>);
+
+/// Per-read count table that can hold the reads of several contigs, which are then told apart
+/// by a leading `reference` column, and of several samples in a `sample` column before it. The
+/// names of the intervals each read was counted in can be added as a last `region_name` column.
+/// Counts can be followed by the optional [`ReadColumns`].
+/// As JSON Lines, every read is an object with the column names as keys instead, always
+/// including its `reference`, and `mean_qual` is `null` for reads without base qualities.
+pub struct ReadCountsWriter {
+    writer: OutputWriter,
+    by_strand: bool,
+    columns: ReadColumns,
+    with_reference: bool,
+    with_sample: bool,
+    with_region_name: bool,
+    jsonl: bool,
+}
+
+impl ReadCountsWriter {
+    /// Creates the table and writes its header line
+    pub fn create(
+        output_file_name: &str,
+        compression: Compression,
+        by_strand: bool,
+        columns: ReadColumns,
+        with_reference: bool,
+        with_sample: bool,
+        with_region_name: bool,
+    ) -> Result<ReadCountsWriter, BoxError> {
+        let mut writer = OutputWriter::create_tsv(output_file_name, compression)?;
+        writeln!(
+            writer,
+            "{}",
+            read_counts_header(by_strand, columns, with_reference, with_sample, with_region_name)
+        )?;
+        Ok(ReadCountsWriter {
+            writer,
+            by_strand,
+            columns,
+            with_reference,
+            with_sample,
+            with_region_name,
+            jsonl: false,
+        })
+    }
+
+    /// Opens an existing table to add reads to its end, without writing its header comments and
+    /// column header again, or creates the table like [`ReadCountsWriter::create`] if there is
+    /// none. Fails if the columns of the existing table differ from those of this table, or if
+    /// one is compressed and the other is not.
+    pub fn append(
+        output_file_name: &str,
+        compression: Compression,
+        by_strand: bool,
+        columns: ReadColumns,
+        with_reference: bool,
+        with_sample: bool,
+        with_region_name: bool,
+    ) -> Result<ReadCountsWriter, BoxError> {
+        let header = read_counts_header(by_strand, columns, with_reference, with_sample, with_region_name);
+        let delimiter = TABLE_STYLE.read().unwrap().delimiter;
+        let header = String::from_utf8(delimit_line(header.as_bytes(), delimiter)).unwrap();
+        let Some((existing_header, existing_compression)) = existing_column_header(output_file_name)? else {
+            return ReadCountsWriter::create(
+                output_file_name,
+                compression,
+                by_strand,
+                columns,
+                with_reference,
+                with_sample,
+                with_region_name,
+            );
+        };
+        if existing_compression.description() != compression.description() {
+            return Err(format!(
+                "can't append to {}, it is {} and the new reads would be {}",
+                output_file_name,
+                existing_compression.description(),
+                compression.description()
+            )
+            .into());
+        }
+        if existing_header != header {
+            return Err(format!(
+                "can't append to {}, its columns \"{}\" differ from those of this run \"{}\"",
+                output_file_name, existing_header, header
+            )
+            .into());
+        }
+        Ok(ReadCountsWriter {
+            writer: OutputWriter::open_append(output_file_name, compression)?,
+            by_strand,
+            columns,
+            with_reference,
+            with_sample,
+            with_region_name,
+            jsonl: false,
+        })
+    }
+
+    /// Creates a JSON Lines file, which has no header
+    pub fn create_jsonl(
+        output_file_name: &str,
+        compression: Compression,
+        by_strand: bool,
+        columns: ReadColumns,
+        with_sample: bool,
+        with_region_name: bool,
+    ) -> Result<ReadCountsWriter, BoxError> {
+        Ok(ReadCountsWriter {
+            writer: OutputWriter::create(output_file_name, compression)?,
+            by_strand,
+            columns,
+            with_reference: true,
+            with_sample,
+            with_region_name,
+            jsonl: true,
+        })
+    }
+
+    /// Appends the counts of all reads of one contig
+    pub fn write_contig(
+        &mut self,
+        ref_name: &str,
+        read_counts: &HashMap<String, ReadCounts>,
+    ) -> Result<(), BoxError> {
+        self.write_contig_samples(ref_name, &[("", read_counts)])
+    }
+
+    /// Appends the counts of all reads of one contig in each of the named samples
+    pub fn write_contig_samples(
+        &mut self,
+        ref_name: &str,
+        samples: &[(&str, &HashMap<String, ReadCounts>)],
+    ) -> Result<(), BoxError> {
+        for (sample, read_counts) in samples {
+            for (read_id, counts) in *read_counts {
+                self.write_read(sample, ref_name, read_id, counts)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the counts of all reads in each of the named samples summed over all contigs, with
+    /// the contigs of each read separated by commas as its reference
+    pub fn write_reads_across_contigs(&mut self, samples: &[(&str, &ReadsAcrossContigs)]) -> Result<(), BoxError> {
+        for (sample, reads) in samples {
+            for (read_id, (ref_names, counts)) in &reads.reads {
+                self.write_read(sample, &ref_names.join(","), read_id, counts)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_read(&mut self, sample: &str, ref_name: &str, read_id: &str, counts: &ReadCounts) -> io::Result<()> {
+        if self.jsonl {
+            return self.write_json_line(sample, ref_name, read_id, counts);
+        }
+        check_read_id(read_id)?;
+        if self.with_sample {
+            write!(self.writer, "{}\t", sample)?;
+        }
+        if self.with_reference {
+            write!(self.writer, "{}\t", ref_name)?;
+        }
+        // Counts that need the reference, missing without it
+        let compared = |count: usize| if self.columns.matches_only { na_string() } else { count.to_string() };
+        if self.by_strand {
+            write!(
+                self.writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                read_id,
+                counts.fwd_matches,
+                compared(counts.fwd_mismatches),
+                counts.rev_matches,
+                compared(counts.rev_mismatches),
+                counts.fwd_insertions,
+                counts.fwd_deletions,
+                counts.rev_insertions,
+                counts.rev_deletions,
+                compared(counts.fwd_ambiguous_ref),
+                compared(counts.rev_ambiguous_ref)
+            )?;
+            if self.columns.softclips {
+                write!(self.writer, "\t{}\t{}", counts.fwd_softclips, counts.rev_softclips)?;
+            }
+        } else {
+            write!(
+                self.writer,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                read_id,
+                counts.matches(),
+                compared(counts.mismatches()),
+                counts.insertions(),
+                counts.deletions(),
+                compared(counts.ambiguous_ref())
+            )?;
+            if self.columns.softclips {
+                write!(self.writer, "\t{}", counts.softclips())?;
+            }
+        }
+        if self.columns.read_quality {
+            let mean_qual = counts.mean_qual().map_or_else(na_string, |mean_qual| format!("{:.2}", mean_qual));
+            write!(self.writer, "\t{}\t{}", counts.aligned_length, mean_qual)?;
+        }
+        if self.columns.mismatch_rate {
+            let strands = covered_positions_by_strand(counts, self.by_strand);
+            for (_, covered_positions, _) in &strands {
+                write!(self.writer, "\t{}", covered_positions)?;
+            }
+            for (_, covered_positions, mismatches) in strands {
+                let rate = mismatch_rate(mismatches, covered_positions);
+                write!(self.writer, "\t{}", rate.map_or_else(na_string, |rate| format!("{:.6}", rate)))?;
+            }
+        }
+        if self.with_region_name {
+            write!(self.writer, "\t{}", counts.region_names.join(","))?;
+        }
+        writeln!(self.writer)
+    }
+
+    // Writes the counts of a read as one JSON object, with the same keys as the table columns
+    fn write_json_line(&mut self, sample: &str, ref_name: &str, read_id: &str, counts: &ReadCounts) -> io::Result<()> {
+        write!(self.writer, "{{")?;
+        if self.with_sample {
+            write!(self.writer, "\"sample\":")?;
+            write_json_string(&mut self.writer, sample)?;
+            write!(self.writer, ",")?;
+        }
+        write!(self.writer, "\"reference\":")?;
+        write_json_string(&mut self.writer, ref_name)?;
+        write!(self.writer, ",\"read_id\":")?;
+        write_json_string(&mut self.writer, read_id)?;
+        for (name, value) in &read_count_fields(counts, self.by_strand, self.columns.softclips) {
+            let compared = name.starts_with("num_mismatches") || name.starts_with("num_ambiguous_ref");
+            if self.columns.matches_only && compared {
+                write!(self.writer, ",\"{}\":null", name)?;
+            } else {
+                write!(self.writer, ",\"{}\":{}", name, value)?;
+            }
+        }
+        if self.columns.read_quality {
+            write!(self.writer, ",\"aligned_length\":{},\"mean_qual\":", counts.aligned_length)?;
+            match counts.mean_qual() {
+                Some(mean_qual) => write!(self.writer, "{:.2}", mean_qual)?,
+                None => write!(self.writer, "null")?,
+            }
+        }
+        if self.columns.mismatch_rate {
+            let strands = covered_positions_by_strand(counts, self.by_strand);
+            for (suffix, covered_positions, _) in &strands {
+                write!(self.writer, ",\"covered_positions{}\":{}", suffix, covered_positions)?;
+            }
+            for (suffix, covered_positions, mismatches) in strands {
+                match mismatch_rate(mismatches, covered_positions) {
+                    Some(rate) => write!(self.writer, ",\"mismatch_rate{}\":{:.6}", suffix, rate)?,
+                    None => write!(self.writer, ",\"mismatch_rate{}\":null", suffix)?,
+                }
+            }
+        }
+        if self.with_region_name {
+            write!(self.writer, ",\"region_name\":")?;
+            write_json_string(&mut self.writer, &counts.region_names.join(","))?;
+        }
+        writeln!(self.writer, "}}")
+    }
+
+    pub fn finish(self) -> Result<(), BoxError> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+// Names of the count columns of a per-read table with the values of a read
+fn read_count_fields(counts: &ReadCounts, by_strand: bool, with_softclips: bool) -> Vec<(&'static str, usize)> {
+    let mut fields = if by_strand {
+        vec![
+            ("num_matches_fwd", counts.fwd_matches),
+            ("num_mismatches_fwd", counts.fwd_mismatches),
+            ("num_matches_rev", counts.rev_matches),
+            ("num_mismatches_rev", counts.rev_mismatches),
+            ("num_insertions_fwd", counts.fwd_insertions),
+            ("num_deletions_fwd", counts.fwd_deletions),
+            ("num_insertions_rev", counts.rev_insertions),
+            ("num_deletions_rev", counts.rev_deletions),
+            ("num_ambiguous_ref_fwd", counts.fwd_ambiguous_ref),
+            ("num_ambiguous_ref_rev", counts.rev_ambiguous_ref),
+        ]
+    } else {
+        vec![
+            ("num_matches", counts.matches()),
+            ("num_mismatches", counts.mismatches()),
+            ("num_insertions", counts.insertions()),
+            ("num_deletions", counts.deletions()),
+            ("num_ambiguous_ref", counts.ambiguous_ref()),
+        ]
+    };
+    if with_softclips && by_strand {
+        fields.extend([("num_softclips_fwd", counts.fwd_softclips), ("num_softclips_rev", counts.rev_softclips)]);
+    } else if with_softclips {
+        fields.push(("num_softclips", counts.softclips()));
+    }
+    fields
+}
+
+// Column name suffix, covered positions and mismatches of a read, for each strand by strand
+fn covered_positions_by_strand(counts: &ReadCounts, by_strand: bool) -> Vec<(&'static str, usize, usize)> {
+    if by_strand {
+        vec![
+            ("_fwd", counts.fwd_matches + counts.fwd_mismatches + counts.fwd_low_qual, counts.fwd_mismatches),
+            ("_rev", counts.rev_matches + counts.rev_mismatches + counts.rev_low_qual, counts.rev_mismatches),
+        ]
+    } else {
+        vec![("", counts.covered_positions(), counts.mismatches())]
+    }
+}
+
+// Writes a quoted JSON string, escaping quotes, backslashes and control characters
+fn write_json_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    writer.write_all(b"\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => writer.write_all(b"\\\"")?,
+            '\\' => writer.write_all(b"\\\\")?,
+            '\n' => writer.write_all(b"\\n")?,
+            '\r' => writer.write_all(b"\\r")?,
+            '\t' => writer.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{}", c)?,
+        }
+    }
+    writer.write_all(b"\"")
+}
+
+/// Optional columns of a per-position base count table after the base counts, in this order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PositionColumns {
+    /// `alt_fwd` and `alt_rev`, the forward and reverse reads of the non-reference alleles, and
+    /// their [`StrandCounts::strand_bias`]
+    pub strand_bias: bool,
+    /// `alt_end_dist`, the [`BaseCounts::mean_alt_end_distance`] (`*` without non-reference
+    /// bases)
+    pub end_distance: bool,
+    /// `hp_len`, the [`BaseCounts::hp_len`] of the position
+    pub hp_len: bool,
+    /// `allele1`, `count1`, `allele2` and `count2`, the two most frequent alleles of
+    /// [`BaseCounts::sorted_alleles`] and their reads (`*` and 0 if fewer are observed)
+    pub top2: bool,
+}
+
+/// Per-position base count table that can hold several contigs, each written at most once.
+/// With consensus settings, the consensus call of each position is written instead of its counts.
+/// Base counts can be followed by the optional [`PositionColumns`].
+/// Positions with a depth below `min_base_count` get no line.
+/// Tables of several samples have a `sample` column after `pos`, so they stay sorted by position.
+/// The names of the intervals containing each position can be added as a last `region_name` column.
+pub struct PositionCountsWriter {
+    table: IndexedTable,
+    consensus: Option<ConsensusSettings>,
+    columns: PositionColumns,
+    min_base_count: usize,
+    with_sample: bool,
+    with_region_name: bool,
+}
+
+impl PositionCountsWriter {
+    /// Creates the table and writes its header line
+    pub fn create(
+        output_file_name: &str,
+        compression: Compression,
+        consensus: Option<ConsensusSettings>,
+        columns: PositionColumns,
+        min_base_count: usize,
+        with_sample: bool,
+        with_region_name: bool,
+    ) -> Result<PositionCountsWriter, BoxError> {
+        if consensus.is_some() && columns != PositionColumns::default() {
+            return Err("extra position columns are only written with base counts".into());
+        }
+        let count_columns = if consensus.is_some() {
+            "ref\tconsensus\tdepth\tconsensus_af\tis_variant"
+        } else {
+            "ref_base\tdepth\tA\tC\tG\tT\tN\tdel"
+        };
+        let header = format!(
+            "chrom\tpos\t{}{}{}{}{}{}{}",
+            SampleColumn(with_sample.then_some("sample")),
+            count_columns,
+            if columns.strand_bias { "\talt_fwd\talt_rev\tstrand_bias" } else { "" },
+            if columns.end_distance { "\talt_end_dist" } else { "" },
+            if columns.hp_len { "\thp_len" } else { "" },
+            if columns.top2 { "\tallele1\tcount1\tallele2\tcount2" } else { "" },
+            if with_region_name { "\tregion_name" } else { "" }
+        );
+        Ok(PositionCountsWriter {
+            table: IndexedTable::create(output_file_name, compression, TableFormat::Tsv, Some(&header))?,
+            consensus,
+            columns,
+            min_base_count,
+            with_sample,
+            with_region_name,
+        })
+    }
+
+    /// Appends the base counts of one contig, sorted by position
+    pub fn write_contig(
+        &mut self,
+        ref_name: &str,
+        positions: &BTreeMap<u32, (u8, BaseCounts)>,
+    ) -> Result<(), BoxError> {
+        self.write_contig_samples(ref_name, &[], &[("", positions)])
+    }
+
+    /// Appends the base counts of one contig in each of the named samples, sorted by position
+    /// and then in the order of the samples. The region names are looked up in the contig's
+    /// `regions`.
+    pub fn write_contig_samples(
+        &mut self,
+        ref_name: &str,
+        regions: &[BedRegion],
+        samples: &[SamplePositions<BaseCounts>],
+    ) -> Result<(), BoxError> {
+        self.table.start_contig(ref_name);
+        let region_lookup = RegionLookup::new(regions);
+        for pos in sample_positions(samples) {
+            let region_names = if self.with_region_name { region_lookup.names_at(pos) } else { String::new() };
+            for (sample, positions) in samples {
+                match positions.get(&pos) {
+                    Some((_, counts)) if counts.depth() < self.min_base_count => {}
+                    Some((ref_base, counts)) => {
+                        self.write_position(ref_name, pos, sample, &region_names, *ref_base, counts)?;
+                    }
+                    None => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_position(
+        &mut self,
+        ref_name: &str,
+        pos: u32,
+        sample: &str,
+        region_names: &str,
+        ref_base: u8,
+        counts: &BaseCounts,
+    ) -> Result<(), BoxError> {
+        let sample = SampleColumn(self.with_sample.then_some(sample));
+        let region_name = RegionNameColumn(self.with_region_name.then_some(region_names));
+        if let Some(settings) = &self.consensus {
+            // Positions below the minimum depth get no call and no line
+            let Some(call) = call_consensus(ref_base, counts, settings) else {
+                return Ok(());
+            };
+            self.table.write_line(
+                pos,
+                format_args!(
+                    "{}\t{}\t{}{}\t{}\t{}\t{:.4}\t{}{}",
+                    ref_name,
+                    pos + 1,
+                    sample,
+                    ref_base.to_ascii_uppercase() as char,
+                    call.base as char,
+                    call.depth,
+                    call.frequency,
+                    call.is_variant as u8,
+                    region_name
+                ),
+            )?;
+        } else {
+            let strand_bias = StrandBiasColumns(self.columns.strand_bias.then(|| counts.strand_counts(ref_base)));
+            let end_distance = EndDistanceColumn(self.columns.end_distance.then(|| counts.mean_alt_end_distance()));
+            let hp_len = TabColumn(self.columns.hp_len.then_some(counts.hp_len));
+            let top2 = TopAllelesColumns(self.columns.top2.then(|| counts.sorted_alleles()));
+            self.table.write_line(
+                pos,
+                format_args!(
+                    "{}\t{}\t{}{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}{}{}{}{}{}",
+                    ref_name,
+                    pos + 1,
+                    sample,
+                    ref_base as char,
+                    counts.depth(),
+                    counts.a,
+                    counts.c,
+                    counts.g,
+                    counts.t,
+                    counts.n,
+                    counts.del,
+                    strand_bias,
+                    end_distance,
+                    hp_len,
+                    top2,
+                    region_name
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the table and writes its tabix index for BGZF output
+    pub fn finish(self) -> Result<(), BoxError> {
+        self.table.finish()
+    }
+}
+
+/// Genotyping table of known sites with a line for every site: `chrom`, 1-based `pos`, `sample`
+/// if several samples are written, `ref`, `alt`, `depth`, the reads showing the reference base
+/// (`ref_count`), the alternate base (`alt_count`) or anything else including deletions
+/// (`other_count`) and the variant allele frequency `vaf`, alt_count / (ref_count + alt_count)
+/// or `*` without either. Sites without reads get a line with zero counts.
+pub struct KnownSitesWriter {
+    table: IndexedTable,
+    // Sites of each contig, sorted by position
+    sites: Arc<HashMap<String, Vec<KnownSite>>>,
+    with_sample: bool,
+}
+
+impl KnownSitesWriter {
+    /// Creates the table for the given sites of each contig, which have to be sorted by
+    /// position, and writes its header line
+    pub fn create(
+        output_file_name: &str,
+        compression: Compression,
+        sites: Arc<HashMap<String, Vec<KnownSite>>>,
+        with_sample: bool,
+    ) -> Result<KnownSitesWriter, BoxError> {
+        let header = format!(
+            "chrom\tpos\t{}ref\talt\tdepth\tref_count\talt_count\tother_count\tvaf",
+            SampleColumn(with_sample.then_some("sample"))
+        );
+        Ok(KnownSitesWriter {
+            table: IndexedTable::create(output_file_name, compression, TableFormat::Tsv, Some(&header))?,
+            sites,
+            with_sample,
+        })
+    }
+
+    /// Appends the known sites of one contig that lie in its `regions`, with the base counts of
+    /// each of the named samples, in the order of the samples at each site
+    pub fn write_contig_samples(
+        &mut self,
+        ref_name: &str,
+        regions: &[BedRegion],
+        samples: &[SamplePositions<BaseCounts>],
+    ) -> Result<(), BoxError> {
+        self.table.start_contig(ref_name);
+        let Some(sites) = self.sites.get(ref_name) else {
+            return Ok(());
+        };
+        let region_lookup = RegionLookup::new(regions);
+        let no_reads = BaseCounts::default();
+        for site in sites.iter().filter(|site| !region_lookup.names_at(site.pos).is_empty()) {
+            for (sample, positions) in samples {
+                let counts = positions.get(&site.pos).map_or(&no_reads, |(_, counts)| counts);
+                let ref_count = counts.base_count(site.ref_base);
+                let alt_count = counts.base_count(site.alt_base);
+                let vaf = (ref_count + alt_count > 0).then(|| alt_count as f64 / (ref_count + alt_count) as f64);
+                self.table.write_line(
+                    site.pos,
+                    format_args!(
+                        "{}\t{}\t{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        ref_name,
+                        site.pos + 1,
+                        SampleColumn(self.with_sample.then_some(*sample)),
+                        site.ref_base as char,
+                        site.alt_base as char,
+                        counts.depth(),
+                        ref_count,
+                        alt_count,
+                        counts.depth() - ref_count - alt_count,
+                        vaf.map_or_else(na_string, |vaf| format!("{:.4}", vaf))
+                    ),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finishes the table and writes its tabix index for BGZF output
+    pub fn finish(self) -> Result<(), BoxError> {
+        self.table.finish()
+    }
+}
+
+/// Long table with a line for every read base aligned to a position, sorted by position and
+/// then in the order of the samples and reads: `chrom`, 1-based `pos`, `sample` if several
+/// samples are written, `read_id`, `ref_base`, `read_base`, `qual` (`*` without base qualities)
+/// and `is_match`, optionally followed by `region_name`.
+pub struct BaseObservationWriter {
+    table: IndexedTable,
+    with_sample: bool,
+    with_region_name: bool,
+    strict_iupac: bool,
+}
+
+impl BaseObservationWriter {
+    /// Creates the table and writes its header line
+    pub fn create(
+        output_file_name: &str,
+        compression: Compression,
+        with_sample: bool,
+        with_region_name: bool,
+        strict_iupac: bool,
+    ) -> Result<BaseObservationWriter, BoxError> {
+        let header = format!(
+            "chrom\tpos\t{}read_id\tref_base\tread_base\tqual\tis_match{}",
+            SampleColumn(with_sample.then_some("sample")),
+            if with_region_name { "\tregion_name" } else { "" }
+        );
+        Ok(BaseObservationWriter {
+            table: IndexedTable::create(output_file_name, compression, TableFormat::Tsv, Some(&header))?,
+            with_sample,
+            with_region_name,
+            strict_iupac,
+        })
+    }
+
+    /// Appends the aligned read bases of one contig in each of the named samples, each sorted
+    /// by position. The region names are looked up in the contig's `regions`.
+    pub fn write_contig_samples(
+        &mut self,
+        ref_name: &str,
+        regions: &[BedRegion],
+        samples: &[(&str, &[BaseObservation])],
+    ) -> Result<(), BoxError> {
+        self.table.start_contig(ref_name);
+        let region_lookup = RegionLookup::new(regions);
+        let mut observations: Vec<(&str, &BaseObservation)> = samples
+            .iter()
+            .flat_map(|(sample, observations)| observations.iter().map(move |observation| (*sample, observation)))
+            .collect();
+        // Stable, so that the samples and reads at a position stay in order
+        observations.sort_by_key(|(_, observation)| observation.pos);
+        let mut region_names = (u32::MAX, String::new());
+        for (sample, observation) in observations {
+            if self.with_region_name && region_names.0 != observation.pos {
+                region_names = (observation.pos, region_lookup.names_at(observation.pos));
+            }
+            let qual = match observation.qual {
+                Some(qual) => qual.to_string(),
+                None => na_string(),
+            };
+            check_read_id(&observation.read_id)?;
+            self.table.write_line(
+                observation.pos,
+                format_args!(
+                    "{}\t{}\t{}{}\t{}\t{}\t{}\t{}{}",
+                    ref_name,
+                    observation.pos + 1,
+                    SampleColumn(self.with_sample.then_some(sample)),
+                    observation.read_id,
+                    observation.ref_base as char,
+                    observation.read_base as char,
+                    qual,
+                    observation.is_match(self.strict_iupac) as u8,
+                    RegionNameColumn(self.with_region_name.then_some(&region_names.1))
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the table and writes its tabix index for BGZF output
+    pub fn finish(self) -> Result<(), BoxError> {
+        self.table.finish()
+    }
+}
+
+/// Per-read counts as a Parquet file with a typed schema, holding the same columns as the JSON
+/// Lines written by [`ReadCountsWriter`]: `sample` if several samples are written, `reference`,
+/// `read_id`, the counts as unsigned 64-bit integers and optionally `region_name`
+pub struct ParquetReadCountsWriter {
+    table: ParquetTable,
+    by_strand: bool,
+    with_softclips: bool,
+    with_sample: bool,
+    with_region_name: bool,
+}
+
+impl ParquetReadCountsWriter {
+    /// Creates the file, whose pages are compressed with gzip or not at all
+    pub fn create(
+        output_file_name: &str,
+        compression: Compression,
+        by_strand: bool,
+        read_columns: ReadColumns,
+        with_sample: bool,
+        with_region_name: bool,
+    ) -> Result<ParquetReadCountsWriter, BoxError> {
+        // Parquet columns have no missing values for reads without base qualities
+        if read_columns.read_quality {
+            return Err("the mean quality of reads is not written to Parquet".into());
+        }
+        if read_columns.mismatch_rate {
+            return Err("the mismatch rate of reads is not written to Parquet".into());
+        }
+        if read_columns.matches_only {
+            return Err("counts without mismatches are not written to Parquet".into());
+        }
+        let with_softclips = read_columns.softclips;
+        let mut columns = Vec::new();
+        if with_sample {
+            columns.push(("sample", DataType::Utf8));
+        }
+        columns.extend([("reference", DataType::Utf8), ("read_id", DataType::Utf8)]);
+        let count_fields = read_count_fields(&ReadCounts::default(), by_strand, with_softclips);
+        columns.extend(count_fields.into_iter().map(|(name, _)| (name, DataType::UInt64)));
+        if with_region_name {
+            columns.push(("region_name", DataType::Utf8));
+        }
+        Ok(ParquetReadCountsWriter {
+            table: ParquetTable::create(output_file_name, compression, &columns)?,
+            by_strand,
+            with_softclips,
+            with_sample,
+            with_region_name,
+        })
+    }
+
+    /// Appends the counts of all reads of one contig in each of the named samples
+    pub fn write_contig_samples(
+        &mut self,
+        ref_name: &str,
+        samples: &[(&str, &HashMap<String, ReadCounts>)],
+    ) -> Result<(), BoxError> {
+        for (sample, read_counts) in samples {
+            for (read_id, counts) in *read_counts {
+                self.write_read(sample, ref_name, read_id, counts)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the counts of all reads in each of the named samples summed over all contigs, like
+    /// [`ReadCountsWriter::write_reads_across_contigs`]
+    pub fn write_reads_across_contigs(&mut self, samples: &[(&str, &ReadsAcrossContigs)]) -> Result<(), BoxError> {
+        for (sample, reads) in samples {
+            for (read_id, (ref_names, counts)) in &reads.reads {
+                self.write_read(sample, &ref_names.join(","), read_id, counts)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_read(&mut self, sample: &str, ref_name: &str, read_id: &str, counts: &ReadCounts) -> Result<(), BoxError> {
+        let region_names = counts.region_names.join(",");
+        let mut row = Vec::new();
+        if self.with_sample {
+            row.push(ParquetValue::String(sample));
+        }
+        row.extend([ParquetValue::String(ref_name), ParquetValue::String(read_id)]);
+        let count_fields = read_count_fields(counts, self.by_strand, self.with_softclips);
+        row.extend(count_fields.into_iter().map(|(_, value)| ParquetValue::UInt64(value as u64)));
+        if self.with_region_name {
+            row.push(ParquetValue::String(&region_names));
+        }
+        self.table.append_row(&row)
+    }
+
+    pub fn finish(self) -> Result<(), BoxError> {
+        self.table.finish()
+    }
+}
+
+/// Per-position base counts (or consensus calls) as a Parquet file with the columns of
+/// [`PositionCountsWriter`], sorted by position and then in the order of the samples. `pos` is
+/// 1-based, the bases are strings and `consensus_af` a 64-bit float. Like there, positions with
+/// a depth below `min_base_count` get no row.
+pub struct ParquetPositionCountsWriter {
+    table: ParquetTable,
+    consensus: Option<ConsensusSettings>,
+    min_base_count: usize,
+    with_sample: bool,
+    with_region_name: bool,
+}
+
+impl ParquetPositionCountsWriter {
+    /// Creates the file, whose pages are compressed with gzip or not at all
+    pub fn create(
+        output_file_name: &str,
+        compression: Compression,
+        consensus: Option<ConsensusSettings>,
+        min_base_count: usize,
+        with_sample: bool,
+        with_region_name: bool,
+    ) -> Result<ParquetPositionCountsWriter, BoxError> {
+        let mut columns = vec![("chrom", DataType::Utf8), ("pos", DataType::UInt32)];
+        if with_sample {
+            columns.push(("sample", DataType::Utf8));
+        }
+        if consensus.is_some() {
+            columns.extend([
+                ("ref", DataType::Utf8),
+                ("consensus", DataType::Utf8),
+                ("depth", DataType::UInt64),
+                ("consensus_af", DataType::Float64),
+                ("is_variant", DataType::Boolean),
+            ]);
+        } else {
+            columns.extend([("ref_base", DataType::Utf8), ("depth", DataType::UInt64)]);
+            columns.extend(["A", "C", "G", "T", "N", "del"].map(|base| (base, DataType::UInt64)));
+        }
+        if with_region_name {
+            columns.push(("region_name", DataType::Utf8));
+        }
+        Ok(ParquetPositionCountsWriter {
+            table: ParquetTable::create(output_file_name, compression, &columns)?,
+            consensus,
+            min_base_count,
+            with_sample,
+            with_region_name,
+        })
+    }
+
+    /// Appends the base counts of one contig in each of the named samples, with the region
+    /// names looked up in the contig's `regions`
+    pub fn write_contig_samples(
+        &mut self,
+        ref_name: &str,
+        regions: &[BedRegion],
+        samples: &[SamplePositions<BaseCounts>],
+    ) -> Result<(), BoxError> {
+        let region_lookup = RegionLookup::new(regions);
+        for pos in sample_positions(samples) {
+            let region_names = if self.with_region_name { region_lookup.names_at(pos) } else { String::new() };
+            for (sample, positions) in samples {
+                let Some((ref_base, counts)) = positions.get(&pos) else {
+                    continue;
+                };
+                if counts.depth() < self.min_base_count {
+                    continue;
+                }
+                let mut row = vec![ParquetValue::String(ref_name), ParquetValue::UInt32(pos + 1)];
+                if self.with_sample {
+                    row.push(ParquetValue::String(sample));
+                }
+                let (ref_base_string, consensus_base);
+                if let Some(settings) = &self.consensus {
+                    // Positions below the minimum depth get no call and no row
+                    let Some(call) = call_consensus(*ref_base, counts, settings) else {
+                        continue;
+                    };
+                    ref_base_string = (ref_base.to_ascii_uppercase() as char).to_string();
+                    consensus_base = (call.base as char).to_string();
+                    row.extend([
+                        ParquetValue::String(&ref_base_string),
+                        ParquetValue::String(&consensus_base),
+                        ParquetValue::UInt64(call.depth as u64),
+                        ParquetValue::Float64(call.frequency),
+                        ParquetValue::Boolean(call.is_variant),
+                    ]);
+                } else {
+                    ref_base_string = (*ref_base as char).to_string();
+                    row.push(ParquetValue::String(&ref_base_string));
+                    let base_counts = [counts.depth(), counts.a, counts.c, counts.g, counts.t, counts.n, counts.del];
+                    row.extend(base_counts.map(|count| ParquetValue::UInt64(count as u64)));
+                }
+                if self.with_region_name {
+                    row.push(ParquetValue::String(&region_names));
+                }
+                self.table.append_row(&row)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), BoxError> {
+        self.table.finish()
+    }
+}
+
+/// Per-position modification table that can hold several contigs, each written at most once.
+/// As bedMethyl, every strand with calls at a position gets its own line instead. Tables of
+/// several samples have a `sample` column after `pos`, which bedMethyl has no room for. With
+/// several modification codes, each code gets its own `calls`, `modified` and `mod_frequency`
+/// columns, suffixed by the code, in place of the `mod_code` column.
+pub struct ModCountsWriter {
+    table: IndexedTable,
+    mod_codes: Vec<ModCode>,
+    bedmethyl: bool,
+    with_sample: bool,
+}
+
+impl ModCountsWriter {
+    /// Creates the table and writes its header line, bedMethyl has none
+    pub fn create(
+        output_file_name: &str,
+        compression: Compression,
+        mod_codes: &[ModCode],
+        bedmethyl: bool,
+        with_sample: bool,
+    ) -> Result<ModCountsWriter, BoxError> {
+        if bedmethyl && with_sample {
+            return Err("bedMethyl output can only hold a single sample".into());
+        }
+        let mut header = format!("chrom\tpos\t{}ref_base", SampleColumn(with_sample.then_some("sample")));
+        if let [_] = mod_codes {
+            header.push_str("\tmod_code\tcalls\tmodified\tmod_frequency");
+        } else {
+            for mod_code in mod_codes {
+                header.push_str(&format!("\tcalls_{0}\tmodified_{0}\tmod_frequency_{0}", mod_code));
+            }
+        }
+        Ok(ModCountsWriter {
+            table: if bedmethyl {
+                IndexedTable::create(output_file_name, compression, TableFormat::Bed, None)?
+            } else {
+                IndexedTable::create(output_file_name, compression, TableFormat::Tsv, Some(&header))?
+            },
+            mod_codes: mod_codes.to_vec(),
+            bedmethyl,
+            with_sample,
+        })
+    }
+
+    /// Appends the modification counts of one contig, sorted by position
+    pub fn write_contig(
+        &mut self,
+        ref_name: &str,
+        positions: &BTreeMap<u32, (u8, Vec<ModCounts>)>,
+    ) -> Result<(), BoxError> {
+        self.write_contig_samples(ref_name, &[("", positions)])
+    }
+
+    /// Appends the modification counts of one contig in each of the named samples, sorted by
+    /// position and then in the order of the samples
+    pub fn write_contig_samples(
+        &mut self,
+        ref_name: &str,
+        samples: &[SamplePositions<Vec<ModCounts>>],
+    ) -> Result<(), BoxError> {
+        self.table.start_contig(ref_name);
+        for pos in sample_positions(samples) {
+            for (sample, positions) in samples {
+                let Some((ref_base, counts)) = positions.get(&pos) else {
+                    continue;
+                };
+                if self.bedmethyl {
+                    for (code_index, counts) in counts.iter().enumerate() {
+                        self.write_bedmethyl_lines(ref_name, pos, code_index, counts)?;
+                    }
+                    continue;
+                }
+                let mut columns = String::new();
+                for (mod_code, counts) in self.mod_codes.iter().zip(counts) {
+                    if self.mod_codes.len() == 1 {
+                        columns.push_str(&format!("\t{}", mod_code));
+                    }
+                    // A code without calls at a position has no frequency, rather than an unmodified one
+                    let frequency = match counts.calls() {
+                        0 => na_string(),
+                        _ => format!("{:.4}", counts.frequency()),
+                    };
+                    columns.push_str(&format!("\t{}\t{}\t{}", counts.calls(), counts.modified(), frequency));
+                }
+                self.table.write_line(
+                    pos,
+                    format_args!(
+                        "{}\t{}\t{}{}{}",
+                        ref_name,
+                        pos + 1,
+                        SampleColumn(self.with_sample.then_some(*sample)),
+                        *ref_base as char,
+                        columns
+                    ),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the bedMethyl lines of a position and modification code, one per reference strand
+    // with calls. Forward reads call the base on the + strand, reverse reads the complementary
+    // base on the - strand.
+    fn write_bedmethyl_lines(
+        &mut self,
+        ref_name: &str,
+        pos: u32,
+        code_index: usize,
+        counts: &ModCounts,
+    ) -> Result<(), BoxError> {
+        for (strand, calls, modified) in [
+            ('+', counts.fwd_calls, counts.fwd_modified),
+            ('-', counts.rev_calls, counts.rev_modified),
+        ] {
+            if calls == 0 {
+                continue;
+            }
+            // Chrom, start, end, name, score, strand, thick start, thick end, color, coverage, percent
+            self.table.write_line(
+                pos,
+                format_args!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t255,0,0\t{}\t{:.2}",
+                    ref_name,
+                    pos,
+                    pos + 1,
+                    self.mod_codes[code_index].code,
+                    calls.min(1000),
+                    strand,
+                    pos,
+                    pos + 1,
+                    calls,
+                    modified as f64 * 100.0 / calls as f64
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the table and writes its tabix index for BGZF output
+    pub fn finish(self) -> Result<(), BoxError> {
+        self.table.finish()
+    }
+}
+
+/// VCF file of the positions where the consensus base is a variant, see [`call_consensus`]. The
+/// INFO field holds the depth (`DP`) and the frequency of the consensus base (`AF`). Deletions
+/// would need the preceding reference base, so only base substitutions are written.
+pub struct VcfWriter {
+    table: IndexedTable,
+    consensus: ConsensusSettings,
+}
+
+impl VcfWriter {
+    /// Creates the file and writes its header with a contig line for each given reference
+    /// sequence name and length
+    pub fn create(
+        output_file_name: &str,
+        compression: Compression,
+        contigs: &[(String, u32)],
+        consensus: ConsensusSettings,
+    ) -> Result<VcfWriter, BoxError> {
+        let mut header = String::from("##fileformat=VCFv4.2\n##source=bampile\n");
+        for (ref_name, ref_len) in contigs {
+            header.push_str(&format!("##contig=<ID={},length={}>\n", ref_name, ref_len));
+        }
+        header.push_str(
+            "##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Number of reads at the position\">\n\
+             ##INFO=<ID=AF,Number=A,Type=Float,Description=\"Fraction of reads showing the consensus base\">\n\
+             #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO",
+        );
+        Ok(VcfWriter {
+            table: IndexedTable::create(output_file_name, compression, TableFormat::Vcf, Some(&header))?,
+            consensus,
+        })
+    }
+
+    /// Appends the variants of one contig from its base counts, sorted by position
+    pub fn write_contig(
+        &mut self,
+        ref_name: &str,
+        positions: &BTreeMap<u32, (u8, BaseCounts)>,
+    ) -> Result<(), BoxError> {
+        self.table.start_contig(ref_name);
+        for (pos, (ref_base, counts)) in positions {
+            let Some(call) = call_consensus(*ref_base, counts, &self.consensus) else {
+                continue;
+            };
+            if !call.is_variant || call.base == b'*' {
+                continue;
+            }
+            // REF may only hold ACGTN, so other IUPAC codes of the reference become N
+            let ref_base = match ref_base.to_ascii_uppercase() {
+                base @ (b'A' | b'C' | b'G' | b'T') => base,
+                _ => b'N',
+            };
+            self.table.write_line(
+                *pos,
+                format_args!(
+                    "{}\t{}\t.\t{}\t{}\t.\tPASS\tDP={};AF={:.4}",
+                    ref_name,
+                    pos + 1,
+                    ref_base as char,
+                    call.base as char,
+                    call.depth,
+                    call.frequency
+                ),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the file and writes its tabix index for BGZF output
+    pub fn finish(self) -> Result<(), BoxError> {
+        self.table.finish()
+    }
+}
+
+/// Coverage summary table with one line per interval: its BED coordinates, the number of
+/// reads, the mean depth and the fraction of positions with at least `min_depth` reads. Tables
+/// of several samples have a `sample` column after `end`.
+pub struct CoverageWriter {
+    writer: OutputWriter,
+    min_depth: u32,
+    with_sample: bool,
+}
+
+impl CoverageWriter {
+    /// Creates the table and writes its header line
+    pub fn create(
+        output_file_name: &str,
+        compression: Compression,
+        min_depth: u32,
+        with_sample: bool,
+    ) -> Result<CoverageWriter, BoxError> {
+        let mut writer = OutputWriter::create_tsv(output_file_name, compression)?;
+        writeln!(
+            writer,
+            "chrom\tstart\tend\t{}n_reads\tmean_depth\tfrac_covered",
+            SampleColumn(with_sample.then_some("sample"))
+        )?;
+        Ok(CoverageWriter {
+            writer,
+            min_depth,
+            with_sample,
+        })
+    }
+
+    /// Appends the coverage of the given intervals
+    pub fn write_intervals(&mut self, intervals: &[IntervalCoverage]) -> Result<(), BoxError> {
+        self.write_sample_intervals("", intervals)
+    }
+
+    /// Appends the coverage of the given intervals in the named sample
+    pub fn write_sample_intervals(&mut self, sample: &str, intervals: &[IntervalCoverage]) -> Result<(), BoxError> {
+        for coverage in intervals {
+            let BedRegion { ref_name, start, end, .. } = &coverage.region;
+            writeln!(
+                self.writer,
+                "{}\t{}\t{}\t{}{}\t{:.4}\t{:.4}",
+                ref_name,
+                start,
+                end,
+                SampleColumn(self.with_sample.then_some(sample)),
+                coverage.n_reads,
+                coverage.mean_depth(),
+                coverage.frac_covered(self.min_depth)
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), BoxError> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Table of the first mismatching positions of each interval, with one line per position: the
+/// BED coordinates of its interval, the 1-based `pos`, `ref_base` and the base counts like in
+/// the per-position table. Tables of several samples have a `sample` column after `end`.
+pub struct DebugPositionsWriter {
+    writer: OutputWriter,
+    with_sample: bool,
+}
+
+impl DebugPositionsWriter {
+    /// Creates the table and writes its header line
+    pub fn create(
+        output_file_name: &str,
+        compression: Compression,
+        with_sample: bool,
+    ) -> Result<DebugPositionsWriter, BoxError> {
+        let mut writer = OutputWriter::create_tsv(output_file_name, compression)?;
+        writeln!(
+            writer,
+            "chrom\tstart\tend\t{}pos\tref_base\tdepth\tA\tC\tG\tT\tN\tdel",
+            SampleColumn(with_sample.then_some("sample"))
+        )?;
+        Ok(DebugPositionsWriter { writer, with_sample })
+    }
+
+    /// Appends the mismatching positions of the given intervals in the named sample
+    pub fn write_sample_intervals(&mut self, sample: &str, intervals: &[DebugPositions]) -> Result<(), BoxError> {
+        for debug_positions in intervals {
+            let BedRegion { ref_name, start, end, .. } = &debug_positions.region;
+            for (pos, ref_base, counts) in &debug_positions.positions {
+                writeln!(
+                    self.writer,
+                    "{}\t{}\t{}\t{}{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    ref_name,
+                    start,
+                    end,
+                    SampleColumn(self.with_sample.then_some(sample)),
+                    pos + 1,
+                    *ref_base as char,
+                    counts.depth(),
+                    counts.a,
+                    counts.c,
+                    counts.g,
+                    counts.t,
+                    counts.n,
+                    counts.del
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), BoxError> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+// Column with the sample of a line followed by a tab, empty in single-sample tables
+struct SampleColumn<'a>(Option<&'a str>);
+
+impl fmt::Display for SampleColumn<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(sample) => write!(f, "{}\t", sample),
+            None => Ok(()),
+        }
+    }
+}
+
+// Last column with the region names of a line preceded by a tab, empty without region names
+struct RegionNameColumn<'a>(Option<&'a str>);
+
+impl fmt::Display for RegionNameColumn<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(region_names) => write!(f, "\t{}", region_names),
+            None => Ok(()),
+        }
+    }
+}
+
+// Optional alt_fwd, alt_rev and strand_bias columns after the base counts of a position
+struct StrandBiasColumns(Option<StrandCounts>);
+
+impl fmt::Display for StrandBiasColumns {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(strand_counts) => write!(
+                f,
+                "\t{}\t{}\t{:.3}",
+                strand_counts.alt_fwd,
+                strand_counts.alt_rev,
+                strand_counts.strand_bias()
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+// Optional column preceded by a tab, empty if it is left out
+struct TabColumn<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for TabColumn<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(value) => write!(f, "\t{}", value),
+            None => Ok(()),
+        }
+    }
+}
+
+// Optional alt_end_dist column after the base counts of a position, the missing value token
+// without non-reference bases
+struct EndDistanceColumn(Option<Option<f64>>);
+
+impl fmt::Display for EndDistanceColumn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(Some(distance)) => write!(f, "\t{:.1}", distance),
+            Some(None) => write!(f, "\t{}", na_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+// Optional allele1, count1, allele2 and count2 columns after the base counts of a position from
+// its sorted alleles, the missing value token and 0 for alleles that aren't there
+struct TopAllelesColumns(Option<Vec<(u8, usize)>>);
+
+impl fmt::Display for TopAllelesColumns {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(alleles) = &self.0 else {
+            return Ok(());
+        };
+        for index in 0..2 {
+            match alleles.get(index) {
+                Some((allele, count)) => write!(f, "\t{}\t{}", *allele as char, count)?,
+                None => write!(f, "\t{}\t0", na_string())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Finds the intervals containing a position, which may overlap if they were not merged
+struct RegionLookup<'a> {
+    // Intervals sorted by start, each with the largest end of it and all intervals before it
+    regions: Vec<(&'a BedRegion, u32)>,
+}
+
+impl<'a> RegionLookup<'a> {
+    fn new(regions: &'a [BedRegion]) -> RegionLookup<'a> {
+        let mut sorted: Vec<&BedRegion> = regions.iter().collect();
+        sorted.sort_by_key(|region| (region.start, region.end));
+        let mut max_end = 0;
+        let regions = sorted
+            .into_iter()
+            .map(|region| {
+                max_end = max_end.max(region.end);
+                (region, max_end)
+            })
+            .collect();
+        RegionLookup { regions }
+    }
+
+    // Comma-separated names of the intervals containing the 0-based position, in order of start
+    fn names_at(&self, pos: u32) -> String {
+        let starting_before = self.regions.partition_point(|(region, _)| region.start <= pos);
+        // Going back from the last interval starting before the position, none of the earlier
+        // ones can contain it once the largest end is not past it
+        let mut names: Vec<String> = self.regions[..starting_before]
+            .iter()
+            .rev()
+            .take_while(|(_, max_end)| *max_end > pos)
+            .filter(|(region, _)| region.end > pos)
+            .map(|(region, _)| region_name(region))
+            .collect();
+        names.reverse();
+        names.join(",")
+    }
+}
+
+// All positions with counts in any of the samples, in order
+fn sample_positions<T>(samples: &[(&str, &BTreeMap<u32, T>)]) -> BTreeSet<u32> {
+    samples.iter().flat_map(|(_, positions)| positions.keys().copied()).collect()
+}
+
+// Rows collected before they are handed to the Parquet writer as one record batch
+const PARQUET_BATCH_SIZE: usize = 8192;
+
+// Rows of a Parquet row group, which the writer keeps in memory until it is complete
+const PARQUET_ROW_GROUP_SIZE: usize = 1 << 20;
+
+// Value of a single field in a row of a Parquet table
+enum ParquetValue<'a> {
+    String(&'a str),
+    UInt32(u32),
+    UInt64(u64),
+    Float64(f64),
+    Boolean(bool),
+}
+
+// Builder for the values of one column of the current record batch
+enum ParquetColumn {
+    String(StringBuilder),
+    UInt32(UInt32Builder),
+    UInt64(UInt64Builder),
+    Float64(Float64Builder),
+    Boolean(BooleanBuilder),
+}
+
+impl ParquetColumn {
+    fn new(data_type: &DataType) -> ParquetColumn {
+        match data_type {
+            DataType::Utf8 => ParquetColumn::String(StringBuilder::new()),
+            DataType::UInt32 => ParquetColumn::UInt32(UInt32Builder::new()),
+            DataType::UInt64 => ParquetColumn::UInt64(UInt64Builder::new()),
+            DataType::Float64 => ParquetColumn::Float64(Float64Builder::new()),
+            DataType::Boolean => ParquetColumn::Boolean(BooleanBuilder::new()),
+            _ => unreachable!("no Parquet columns of type {}", data_type),
+        }
+    }
+
+    fn append(&mut self, value: &ParquetValue) {
+        match (self, value) {
+            (ParquetColumn::String(builder), ParquetValue::String(value)) => builder.append_value(value),
+            (ParquetColumn::UInt32(builder), ParquetValue::UInt32(value)) => builder.append_value(*value),
+            (ParquetColumn::UInt64(builder), ParquetValue::UInt64(value)) => builder.append_value(*value),
+            (ParquetColumn::Float64(builder), ParquetValue::Float64(value)) => builder.append_value(*value),
+            (ParquetColumn::Boolean(builder), ParquetValue::Boolean(value)) => builder.append_value(*value),
+            _ => unreachable!("value does not match the column type"),
+        }
+    }
+
+    // Takes the values appended so far, leaving the builder empty for the next batch
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ParquetColumn::String(builder) => Arc::new(builder.finish()),
+            ParquetColumn::UInt32(builder) => Arc::new(builder.finish()),
+            ParquetColumn::UInt64(builder) => Arc::new(builder.finish()),
+            ParquetColumn::Float64(builder) => Arc::new(builder.finish()),
+            ParquetColumn::Boolean(builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+// Parquet file written row by row. Rows are collected in batches of PARQUET_BATCH_SIZE, and
+// the writer flushes a row group every PARQUET_ROW_GROUP_SIZE rows, so memory stays bounded
+// however many rows are written.
+struct ParquetTable {
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+    columns: Vec<ParquetColumn>,
+    rows: usize,
+}
+
+impl ParquetTable {
+    fn create(
+        output_file_name: &str,
+        compression: Compression,
+        columns: &[(&str, DataType)],
+    ) -> Result<ParquetTable, BoxError> {
+        let parquet_compression = match compression {
+            Compression::None => parquet::basic::Compression::UNCOMPRESSED,
+            Compression::Gzip(level) => parquet::basic::Compression::GZIP(GzipLevel::try_new(level)?),
+            Compression::Bgzf(_) => return Err("Parquet files can't be BGZF-compressed".into()),
+            Compression::Zstd(level) => parquet::basic::Compression::ZSTD(ZstdLevel::try_new(level as i32)?),
+        };
+        let properties = WriterProperties::builder()
+            .set_compression(parquet_compression)
+            .set_max_row_group_row_count(Some(PARQUET_ROW_GROUP_SIZE))
+            .build();
+        let schema: SchemaRef = Arc::new(Schema::new(
+            columns
+                .iter()
+                .map(|(name, data_type)| Field::new(*name, data_type.clone(), false))
+                .collect::<Vec<_>>(),
+        ));
+        let output_file = File::create(output_file_name)
+            .map_err(|e| Error::Io(format!("failed to create output file {}: {}", output_file_name, e)))?;
+        Ok(ParquetTable {
+            writer: ArrowWriter::try_new(output_file, schema.clone(), Some(properties))?,
+            columns: schema.fields().iter().map(|field| ParquetColumn::new(field.data_type())).collect(),
+            schema,
+            rows: 0,
+        })
+    }
+
+    // Appends a row with a value for every column, in the order of the schema
+    fn append_row(&mut self, row: &[ParquetValue]) -> Result<(), BoxError> {
+        for (column, value) in self.columns.iter_mut().zip(row) {
+            column.append(value);
+        }
+        self.rows += 1;
+        if self.rows == PARQUET_BATCH_SIZE {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> Result<(), BoxError> {
+        let arrays = self.columns.iter_mut().map(ParquetColumn::finish).collect();
+        self.writer.write(&RecordBatch::try_new(self.schema.clone(), arrays)?)?;
+        self.rows = 0;
+        Ok(())
+    }
+
+    // Writes the last batch and the file footer
+    fn finish(mut self) -> Result<(), BoxError> {
+        if self.rows > 0 {
+            self.write_batch()?;
+        }
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+// Layout of an indexed table, which tells tabix where to find the coordinates of each line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TableFormat {
+    // Header comment lines starting with #, a header line, then chrom and 1-based pos columns
+    Tsv,
+    // No header, chrom and 0-based half-open start and end columns
+    Bed,
+    // Header lines starting with #, then chrom and 1-based pos columns
+    Vcf,
+}
+
+// Table of per-position lines sorted by contig and position, which gets a tabix index on its
+// coordinate columns when written as BGZF
+struct IndexedTable {
+    writer: OutputWriter,
+    output_file_name: String,
+    format: TableFormat,
+    // Lines at the start of the file that tabix skips, the header comments and the column header
+    // of a TSV table
+    skip_lines: usize,
+    // With BGZF output, the tabix index of each contig in file order
+    tabix_indices: Vec<(String, BinIndexBuilder)>,
+}
+
+impl IndexedTable {
+    fn create(
+        output_file_name: &str,
+        compression: Compression,
+        format: TableFormat,
+        header: Option<&str>,
+    ) -> Result<IndexedTable, BoxError> {
+        let (mut writer, skip_lines) = if format == TableFormat::Tsv {
+            let skip_lines = HEADER_COMMENTS.read().unwrap().len() + 1;
+            (OutputWriter::create_tsv(output_file_name, compression)?, skip_lines)
+        } else {
+            (OutputWriter::create(output_file_name, compression)?, 0)
+        };
+        if let Some(header) = header {
+            writeln!(writer, "{}", header)?;
+        }
+        Ok(IndexedTable {
+            writer,
+            output_file_name: output_file_name.to_string(),
+            format,
+            skip_lines,
+            tabix_indices: Vec::new(),
+        })
+    }
+
+    // Starts the lines of the next contig
+    fn start_contig(&mut self, ref_name: &str) {
+        if self.writer.virtual_offset().is_some() {
+            self.tabix_indices.push((ref_name.to_string(), BinIndexBuilder::default()));
+        }
+    }
+
+    // Writes the line of a 0-based position and records where it starts and ends in the index
+    fn write_line(&mut self, pos: u32, line: fmt::Arguments) -> io::Result<()> {
+        let line_start = self.writer.virtual_offset();
+        self.writer.write_fmt(line)?;
+        self.writer.write_all(b"\n")?;
+        if let (Some(line_start), Some(line_end), Some((_, tabix_index))) =
+            (line_start, self.writer.virtual_offset(), self.tabix_indices.last_mut())
+        {
+            tabix_index.add(pos, pos + 1, line_start, line_end);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), BoxError> {
+        let bgzf = self.writer.virtual_offset().is_some();
+        self.writer.finish()?;
+        if bgzf {
+            let index_file_name = format!("{}.tbi", self.output_file_name);
+            write_tabix_index(&index_file_name, &self.tabix_indices, self.format, self.skip_lines)?;
+        }
+        Ok(())
+    }
+}
+
+// First bytes of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// TSV output, either plain, gzip, BGZF or zstd compressed
+enum OutputWriter {
+    Plain(BufWriter<File>),
+    Gzip(flate2::write::GzEncoder<File>),
+    Zstd(zstd::Encoder<'static, File>),
+    // With the name of the file, whose .gzi index is written when it is finished
+    Bgzf(BgzfWriter<File>, String),
+    // Tab-separated lines rewritten with another delimiter once they are complete, with the
+    // start of the current line
+    Delimited(Box<OutputWriter>, u8, Vec<u8>),
+}
+
+impl OutputWriter {
+    fn create(output_file_name: &str, compression: Compression) -> Result<OutputWriter, BoxError> {
+        let output_file = File::create(output_file_name)
+            .map_err(|e| Error::Io(format!("failed to create output file {}: {}", output_file_name, e)))?;
+        Ok(match compression {
+            Compression::None => OutputWriter::Plain(BufWriter::new(output_file)),
+            Compression::Gzip(level) => OutputWriter::Gzip(flate2::write::GzEncoder::new(
+                output_file,
+                flate2::Compression::new(level),
+            )),
+            Compression::Bgzf(level) => {
+                OutputWriter::Bgzf(BgzfWriter::new(output_file, level), output_file_name.to_string())
+            }
+            Compression::Zstd(level) => OutputWriter::zstd(output_file, level)?,
+        })
+    }
+
+    // Creates a TSV table and writes the header comments, if any, for its column header to follow.
+    // Lines are written with tabs and get the delimiter of the table style on the way out.
+    fn create_tsv(output_file_name: &str, compression: Compression) -> Result<OutputWriter, BoxError> {
+        let mut writer = OutputWriter::create(output_file_name, compression)?;
+        for line in HEADER_COMMENTS.read().unwrap().iter() {
+            writeln!(writer, "#{}", line)?;
+        }
+        Ok(writer.with_table_delimiter())
+    }
+
+    fn zstd(output_file: File, level: u32) -> io::Result<OutputWriter> {
+        Ok(OutputWriter::Zstd(zstd::Encoder::new(output_file, level as i32)?))
+    }
+
+    // Opens an existing TSV table to write further lines at its end. Compressed lines go into
+    // new gzip members or zstd frames, which readers of the file take as one stream. New BGZF blocks follow the
+    // blocks of the file, which are indexed again.
+    fn open_append(output_file_name: &str, compression: Compression) -> Result<OutputWriter, BoxError> {
+        let existing_blocks = match compression {
+            Compression::Bgzf(_) => Some(reference::bgzf_blocks(output_file_name).map_err(|_| {
+                format!("can't append BGZF blocks to {}, it is not BGZF-compressed", output_file_name)
+            })?),
+            _ => None,
+        };
+        let output_file = fs::OpenOptions::new()
+            .append(true)
+            .open(output_file_name)
+            .map_err(|e| Error::Io(format!("failed to open output file {}: {}", output_file_name, e)))?;
+        let writer = match (compression, existing_blocks) {
+            (Compression::Bgzf(level), Some(blocks)) => {
+                let mut writer = BgzfWriter::new(output_file, level);
+                writer.block_offsets = blocks.offsets;
+                writer.block_offset = blocks.compressed_len;
+                writer.uncompressed_offset = blocks.uncompressed_len;
+                OutputWriter::Bgzf(writer, output_file_name.to_string())
+            }
+            (Compression::Gzip(level), _) => {
+                OutputWriter::Gzip(flate2::write::GzEncoder::new(output_file, flate2::Compression::new(level)))
+            }
+            (Compression::Zstd(level), _) => OutputWriter::zstd(output_file, level)?,
+            _ => OutputWriter::Plain(BufWriter::new(output_file)),
+        };
+        Ok(writer.with_table_delimiter())
+    }
+
+    fn with_table_delimiter(self) -> OutputWriter {
+        match TABLE_STYLE.read().unwrap().delimiter {
+            b'\t' => self,
+            delimiter => OutputWriter::Delimited(Box::new(self), delimiter, Vec::new()),
+        }
+    }
+
+    // Current BGZF virtual offset, None for formats without random access
+    fn virtual_offset(&self) -> Option<u64> {
+        match self {
+            OutputWriter::Plain(_) | OutputWriter::Gzip(_) | OutputWriter::Zstd(_) => None,
+            OutputWriter::Bgzf(writer, _) => Some(writer.virtual_offset()),
+            OutputWriter::Delimited(writer, _, _) => writer.virtual_offset(),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(mut writer) => writer.flush(),
+            OutputWriter::Gzip(writer) => writer.finish().map(|_| ()),
+            OutputWriter::Zstd(writer) => writer.finish().map(|_| ()),
+            OutputWriter::Bgzf(writer, output_file_name) => {
+                let (_, block_offsets) = writer.finish()?;
+                reference::write_gzi_index(&format!("{}.gzi", output_file_name), &block_offsets)
+            }
+            OutputWriter::Delimited(mut writer, delimiter, line) => {
+                if !line.is_empty() {
+                    writer.write_all(&delimit_line(&line, delimiter))?;
+                }
+                writer.finish()
+            }
+        }
+    }
+}
+
+// Fails for a read ID with a tab or line break, which would split its line of a table, quoted
+// or not. SAM read names can't hold them, but those of BAM files are only NUL-terminated.
+fn check_read_id(read_id: &str) -> io::Result<()> {
+    if read_id.contains(['\t', '\n', '\r']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("read ID {:?} holds a tab or line break, which can't be written to a table", read_id),
+        ));
+    }
+    Ok(())
+}
+
+// Joins the tab-separated fields of a line with another delimiter, in double quotes if they
+// hold the delimiter or a double quote, which is doubled
+fn delimit_line(line: &[u8], delimiter: u8) -> Vec<u8> {
+    let mut delimited = Vec::with_capacity(line.len());
+    for (index, field) in line.split(|byte| *byte == b'\t').enumerate() {
+        if index > 0 {
+            delimited.push(delimiter);
+        }
+        if field.contains(&delimiter) || field.contains(&b'"') {
+            delimited.push(b'"');
+            for byte in field {
+                if *byte == b'"' {
+                    delimited.push(b'"');
+                }
+                delimited.push(*byte);
+            }
+            delimited.push(b'"');
+        } else {
+            delimited.extend_from_slice(field);
+        }
+    }
+    delimited
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(writer) => writer.write(buf),
+            OutputWriter::Gzip(writer) => writer.write(buf),
+            OutputWriter::Zstd(writer) => writer.write(buf),
+            OutputWriter::Bgzf(writer, _) => writer.write(buf),
+            OutputWriter::Delimited(writer, delimiter, line) => {
+                line.extend_from_slice(buf);
+                while let Some(line_end) = line.iter().position(|byte| *byte == b'\n') {
+                    let mut delimited = delimit_line(&line[..line_end], *delimiter);
+                    delimited.push(b'\n');
+                    writer.write_all(&delimited)?;
+                    line.drain(..=line_end);
+                }
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(writer) => writer.flush(),
+            OutputWriter::Gzip(writer) => writer.flush(),
+            OutputWriter::Zstd(writer) => writer.flush(),
+            OutputWriter::Bgzf(writer, _) => writer.flush(),
+            OutputWriter::Delimited(writer, _, _) => writer.flush(),
+        }
+    }
+}
+
+// Uncompressed size at which a BGZF block is written, same as bgzip/htslib
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+// BGZF writer that keeps track of block offsets, so that virtual offsets of the written
+// data are known for building indices
+struct BgzfWriter<W: Write> {
+    stream: W,
+    block: bam::bgzip::Block,
+    level: u32,
+    // Compressed and uncompressed offset of the current block in the output
+    block_offset: u64,
+    uncompressed_offset: u64,
+    // Both offsets of every non-empty block after the first, for the .gzi index
+    block_offsets: Vec<(u64, u64)>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    fn new(stream: W, level: u32) -> BgzfWriter<W> {
+        BgzfWriter {
+            stream,
+            block: bam::bgzip::Block::new(),
+            level,
+            block_offset: 0,
+            uncompressed_offset: 0,
+            block_offsets: Vec::new(),
+        }
+    }
+
+    // Virtual offset of the next byte written: compressed block offset and offset within the block
+    fn virtual_offset(&self) -> u64 {
+        (self.block_offset << 16) | self.block.uncompressed_size() as u64
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let uncompressed_size = self.block.uncompressed_size() as u64;
+        if self.block_offset > 0 && uncompressed_size > 0 {
+            self.block_offsets.push((self.block_offset, self.uncompressed_offset));
+        }
+        self.block.compress(flate2::Compression::new(self.level))?;
+        self.block.dump(&mut self.stream)?;
+        self.block_offset += self.block.block_size().unwrap() as u64;
+        self.uncompressed_offset += uncompressed_size;
+        self.block.reset();
+        Ok(())
+    }
+
+    // Writes the remaining data and the empty end-of-file block, returns the stream and the
+    // offsets of the blocks for the .gzi index
+    fn finish(mut self) -> io::Result<(W, Vec<(u64, u64)>)> {
+        if self.block.uncompressed_size() > 0 {
+            self.write_block()?;
+        }
+        self.write_block()?;
+        self.stream.flush()?;
+        Ok((self.stream, self.block_offsets))
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let free = BGZF_BLOCK_SIZE - self.block.uncompressed_size() as usize;
+        let consumed = self.block.extend_contents(&buf[..buf.len().min(free)]);
+        // Write full blocks right away, so that virtual offsets always point into the current block
+        if self.block.uncompressed_size() as usize >= BGZF_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(consumed)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// Writes a tabix index for a BGZF file with the given sequences in file order, where columns 1
+// and 2 hold the sequence name and the 1-based position (or for BED files columns 1 to 3 the
+// name and the 0-based start and end) and the first `skip_lines` lines are headers
+fn write_tabix_index(
+    index_file_name: &str,
+    sequences: &[(String, BinIndexBuilder)],
+    table_format: TableFormat,
+    skip_lines: usize,
+) -> Result<(), BoxError> {
+    let mut writer = BgzfWriter::new(File::create(index_file_name)?, DEFAULT_COMPRESSION_LEVEL);
+    writer.write_all(b"TBI\x01")?;
+    // Generic format (flagged as 0-based for BED files) or VCF, whose end is taken from REF
+    let (format, end_column) = match table_format {
+        TableFormat::Tsv => (0, 2),
+        TableFormat::Bed => (0x10000, 3),
+        TableFormat::Vcf => (2, 0),
+    };
+    // Number of sequences, format, sequence/begin/end columns, comment char, skipped lines
+    for value in [sequences.len() as i32, format, 1, 2, end_column, b'#' as i32, skip_lines as i32] {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    // Concatenated NUL-terminated sequence names
+    let names_len: usize = sequences.iter().map(|(ref_name, _)| ref_name.len() + 1).sum();
+    writer.write_all(&(names_len as i32).to_le_bytes())?;
+    for (ref_name, _) in sequences {
+        writer.write_all(ref_name.as_bytes())?;
+        writer.write_all(&[0])?;
+    }
+    for (_, index) in sequences {
+        index.write(&mut writer)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Encodes a contig name for use in a file name. Bytes other than ASCII letters, digits, `_`, `-`
+/// and `.` are percent-encoded (`%` becomes `%25`), so distinct names never share a file name.
+pub fn encode_filename(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' || byte == b'.' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_file_names_reversibly() {
+        assert_eq!(encode_filename("chr1_random-1.2"), "chr1_random-1.2");
+        assert_eq!(encode_filename("HLA-A*01:01"), "HLA-A%2A01%3A01");
+        // A literal % is encoded too, so an encoded name can't collide with another name
+        assert_eq!(encode_filename("chr1%7Ca"), "chr1%257Ca");
+        assert_eq!(encode_filename("chr1|a"), "chr1%7Ca");
+        assert_ne!(encode_filename("HLA:A*01"), encode_filename("HLAA01"));
+    }
+
+    #[test]
+    fn escapes_read_ids_in_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_file = dir.path().join("reads.jsonl");
+        let output_file = output_file.to_str().unwrap();
+        let read_id = "r1\"quoted\\\tend\u{1}";
+        let counts = ReadCounts { fwd_matches: 7, fwd_mismatches: 2, ..Default::default() };
+        let mut writer =
+            ReadCountsWriter::create_jsonl(output_file, Compression::None, false, ReadColumns::default(), false, false)
+                .unwrap();
+        writer.write_contig("chr1", &HashMap::from([(read_id.to_string(), counts)])).unwrap();
+        writer.finish().unwrap();
+
+        let lines = fs::read_to_string(output_file).unwrap();
+        let objects: Vec<serde_json::Value> = lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0]["reference"], "chr1");
+        assert_eq!(objects[0]["read_id"], read_id);
+        assert_eq!(objects[0]["num_matches"], 7);
+        assert_eq!(objects[0]["num_mismatches"], 2);
+    }
+
+    #[test]
+    fn rejects_read_ids_that_would_split_their_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_file = dir.path().join("reads.tsv");
+        let output_file = output_file.to_str().unwrap();
+        for read_id in ["r1\tsplit", "r1\nsplit"] {
+            let columns = ReadColumns::default();
+            let mut writer =
+                ReadCountsWriter::create(output_file, Compression::None, false, columns, false, false, false).unwrap();
+            let read_counts = HashMap::from([(read_id.to_string(), ReadCounts::default())]);
+            let error = writer.write_contig("chr1", &read_counts).unwrap_err();
+            assert!(error.to_string().contains("holds a tab or line break"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn indexes_the_blocks_of_bgzf_output_to_seek_to_the_last_one() {
+        use std::io::{Seek, SeekFrom};
+
+        let dir = tempfile::tempdir().unwrap();
+        let output_file = dir.path().join("positions.tsv.gz");
+        let output_file = output_file.to_str().unwrap();
+        // About 170 kB of distinct lines, which take three blocks
+        let contents: String = (0..10_000).map(|i| format!("chr1\t{}\tA\t{}\n", i + 1, i % 97)).collect();
+        let mut writer = OutputWriter::create(output_file, Compression::Bgzf(DEFAULT_COMPRESSION_LEVEL)).unwrap();
+        writer.write_all(contents.as_bytes()).unwrap();
+        writer.finish().unwrap();
+
+        let gzi_file = format!("{}.gzi", output_file);
+        let blocks = reference::bgzf_blocks(output_file).unwrap();
+        let mut expected_gzi = Vec::new();
+        expected_gzi.extend_from_slice(&(blocks.offsets.len() as u64).to_le_bytes());
+        for (compressed_offset, uncompressed_offset) in &blocks.offsets {
+            expected_gzi.extend_from_slice(&compressed_offset.to_le_bytes());
+            expected_gzi.extend_from_slice(&uncompressed_offset.to_le_bytes());
+        }
+        assert_eq!(fs::read(&gzi_file).unwrap(), expected_gzi);
+        assert_eq!(blocks.offsets.len(), contents.len() / BGZF_BLOCK_SIZE);
+
+        let (_, last_block_start) = *blocks.offsets.last().unwrap();
+        let mut reader = reference::BgzfReader::new(File::open(output_file).unwrap(), &gzi_file).unwrap();
+        for offset in [last_block_start, last_block_start + 100, contents.len() as u64 - 10] {
+            reader.seek(SeekFrom::Start(offset)).unwrap();
+            let mut tail = String::new();
+            reader.read_to_string(&mut tail).unwrap();
+            assert_eq!(tail, contents[offset as usize..]);
+        }
+    }
+}
@@ -0,0 +1,2323 @@
+use bam::RecordReader;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Seek};
+use std::ops::Range;
+
+use crate::error::Error;
+use crate::mods::{count_mod_calls, ModCounts};
+use crate::reference::{self, IndexedFasta};
+use crate::{region_name, BedRegion, BoxError, Mode, PileupConfig, Strand};
+
+// Counts collected for a single BED interval
+#[derive(Default)]
+pub(crate) struct RegionCounts {
+    // Name of the interval that per-read counts are labeled with
+    pub(crate) region_name: String,
+    // Strand of the reads counted in the interval
+    pub(crate) strand: Strand,
+    pub(crate) read_counts: HashMap<String, ReadCounts>,
+    pub(crate) position_counts: BTreeMap<u32, (u8, BaseCounts)>,
+    pub(crate) mod_counts: BTreeMap<u32, (u8, Vec<ModCounts>)>,
+    pub(crate) base_observations: Vec<BaseObservation>,
+    // Only collected if the config asks for coverage, a quality histogram or mismatch spectrum
+    pub(crate) coverage: Option<IntervalCoverage>,
+    pub(crate) qual_histogram: Option<QualHistogram>,
+    pub(crate) mismatch_spectrum: Option<MismatchSpectrum>,
+    pub(crate) mismatch_profile: Option<MismatchProfile>,
+    // Base counts of every position of the interval, only collected if the config asks for the
+    // first mismatching positions, which are picked from them once all reads are added
+    pub(crate) debug_counts: Option<BTreeMap<u32, (u8, BaseCounts)>>,
+    pub(crate) debug_positions: Option<DebugPositions>,
+    // Reference bases the reads are compared to with soft-masked bases replaced by N, only set
+    // if the config leaves soft-masked bases out
+    pub(crate) masked_reference: Option<Vec<u8>>,
+    // Bases of the interval rebuilt from the MD tag of the current read if the config says so,
+    // `N` where the read is not aligned. Kept between reads to be reused.
+    pub(crate) md_reference: Vec<u8>,
+    // Reads waiting for their overlapping mate if the config counts overlaps once, by name,
+    // with their span in the interval, after trimming their ends, and the reference bases of that span
+    pub(crate) pending_mates: HashMap<Vec<u8>, (bam::Record, Range<u32>, Vec<u8>)>,
+    // Number of reads seen in the interval and how many of them were not counted
+    pub(crate) records: usize,
+    pub(crate) skipped: SkippedReads,
+    // Counted reads without base qualities, all of their bases pass the Q-score cutoff
+    pub(crate) without_qual: usize,
+    // Sorted parts of the interval that per-read counts leave out, as earlier intervals already
+    // counted them, see `earlier_overlaps`
+    pub(crate) counted_before: Vec<Range<u32>>,
+}
+
+/// Reads that were not counted, by the reason they were skipped. Like counted reads, a read is
+/// skipped once for every interval it was fetched for.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct SkippedReads {
+    /// Mapping quality below [`PileupConfig::min_mapq`]
+    pub low_mapq: usize,
+    /// Filtered by [`PileupConfig::exclude_flags`] or [`PileupConfig::include_flags`]
+    pub filtered_flags: usize,
+    /// Aligned to the other strand than that of a stranded interval with
+    /// [`PileupConfig::respect_bed_strand`]
+    pub wrong_strand: usize,
+    /// Missing from the read list or on the exclude list
+    pub read_list: usize,
+    /// Left out by [`PileupConfig::subsample`]
+    pub subsampled: usize,
+    /// Aligned length outside of [`PileupConfig::min_read_length`] and
+    /// [`PileupConfig::max_read_length`]
+    pub read_length: usize,
+    /// Fetched by the BAM index for an interval the read doesn't overlap
+    pub out_of_bounds: usize,
+    /// No stored sequence (`*` in SAM)
+    pub empty_seq: usize,
+    /// No base qualities while [`PileupConfig::require_qual`] is set
+    pub missing_qual: usize,
+}
+
+impl SkippedReads {
+    pub fn merge(&mut self, other: &SkippedReads) {
+        self.low_mapq += other.low_mapq;
+        self.filtered_flags += other.filtered_flags;
+        self.wrong_strand += other.wrong_strand;
+        self.read_list += other.read_list;
+        self.subsampled += other.subsampled;
+        self.read_length += other.read_length;
+        self.out_of_bounds += other.out_of_bounds;
+        self.empty_seq += other.empty_seq;
+        self.missing_qual += other.missing_qual;
+    }
+
+    pub fn total(&self) -> usize {
+        self.low_mapq
+            + self.filtered_flags
+            + self.wrong_strand
+            + self.read_list
+            + self.subsampled
+            + self.read_length
+            + self.out_of_bounds
+            + self.empty_seq
+            + self.missing_qual
+    }
+
+    /// Number of skipped reads by the name of the reason, in the order of the filters
+    pub fn by_reason(&self) -> [(&'static str, usize); 9] {
+        [
+            ("low_mapq", self.low_mapq),
+            ("flags", self.filtered_flags),
+            ("strand", self.wrong_strand),
+            ("read_list", self.read_list),
+            ("subsample", self.subsampled),
+            ("read_length", self.read_length),
+            ("out_of_bounds", self.out_of_bounds),
+            ("empty_seq", self.empty_seq),
+            ("missing_qual", self.missing_qual),
+        ]
+    }
+}
+
+impl RegionCounts {
+    pub(crate) fn new(region: &BedRegion, config: &PileupConfig) -> RegionCounts {
+        RegionCounts {
+            region_name: region_name(region),
+            strand: if config.respect_bed_strand { region.strand } else { Strand::Either },
+            coverage: config.coverage.then(|| IntervalCoverage::new(region)),
+            qual_histogram: config.qual_histogram.then(QualHistogram::new),
+            mismatch_spectrum: config.mismatch_spectrum.then(MismatchSpectrum::default),
+            mismatch_profile: config
+                .mismatch_profile
+                .then(|| MismatchProfile::new((region.end - region.start) as usize)),
+            debug_counts: config.debug_positions.map(|_| BTreeMap::new()),
+            debug_positions: config.debug_positions.map(|_| DebugPositions::new(region)),
+            ..RegionCounts::default()
+        }
+    }
+
+    // Keeps the reference bases of the interval with soft-masked bases replaced by N to compare
+    // the reads against, if the config leaves them out
+    pub(crate) fn mask_reference(&mut self, reference_sequence: &[u8], config: &PileupConfig) {
+        if config.fasta_case_sensitive && !config.use_md {
+            let masked = reference_sequence
+                .iter()
+                .map(|base| if base.is_ascii_lowercase() { b'N' } else { *base })
+                .collect();
+            self.masked_reference = Some(masked);
+        }
+    }
+
+    // Adds a read of the [start, end) interval, `reference_sequence` holds the interval's bases
+    // unless the config takes them from the MD tags
+    pub(crate) fn add_record(
+        &mut self,
+        record: &bam::Record,
+        reference_sequence: &[u8],
+        start: u32,
+        end: u32,
+        config: &PileupConfig,
+    ) -> Result<(), BoxError> {
+        self.records += 1;
+        // Skip poorly mapped reads
+        if record.mapq() < config.min_mapq {
+            self.skipped.low_mapq += 1;
+            log::trace!(
+                "skipping read {}: mapping quality {} below {}",
+                String::from_utf8_lossy(record.name()),
+                record.mapq(),
+                config.min_mapq
+            );
+            return Ok(());
+        }
+        // Skip reads by SAM flag, by default unmapped, secondary, supplementary and duplicates
+        if record.flag().any_bit(config.exclude_flags) || !record.flag().all_bits(config.include_flags) {
+            self.skipped.filtered_flags += 1;
+            log::trace!(
+                "skipping read {}: flag {} filtered",
+                String::from_utf8_lossy(record.name()),
+                record.flag().0
+            );
+            return Ok(());
+        }
+        // Skip reads on the other strand of a stranded interval
+        let is_reverse = record.flag().is_reverse_strand();
+        if (self.strand == Strand::Forward && is_reverse) || (self.strand == Strand::Reverse && !is_reverse) {
+            self.skipped.wrong_strand += 1;
+            log::trace!(
+                "skipping read {}: not on the {} strand of its interval",
+                String::from_utf8_lossy(record.name()),
+                self.strand
+            );
+            return Ok(());
+        }
+        // Skip reads missing from the read list or on the exclude list
+        let listed = config.read_list.as_ref().is_none_or(|read_list| read_list.contains(record.name()));
+        let excluded = config
+            .exclude_read_list
+            .as_ref()
+            .is_some_and(|exclude_read_list| exclude_read_list.contains(record.name()));
+        if !listed || excluded {
+            self.skipped.read_list += 1;
+            log::trace!(
+                "skipping read {}: {} read list",
+                String::from_utf8_lossy(record.name()),
+                if excluded { "on exclude" } else { "not on" }
+            );
+            return Ok(());
+        }
+        // Keep the same random fraction of the reads in every run with the same seed
+        if config.subsample.is_some_and(|fraction| !subsample_keeps(record.name(), fraction, config.seed)) {
+            self.skipped.subsampled += 1;
+            log::trace!("skipping read {}: subsampled", String::from_utf8_lossy(record.name()));
+            return Ok(());
+        }
+        // Skip short spurious alignments and overly long ones by their reference span
+        let aligned_length = (record.calculate_end() - record.start()).max(0) as u32;
+        let too_long = config.max_read_length.is_some_and(|max_read_length| aligned_length > max_read_length);
+        if aligned_length < config.min_read_length || too_long {
+            self.skipped.read_length += 1;
+            log::trace!(
+                "skipping read {}: aligned to {} reference bases",
+                String::from_utf8_lossy(record.name()),
+                aligned_length
+            );
+            return Ok(());
+        }
+        // Reads fetched by their index bin may still end before or start after the interval
+        if record.start() >= end as i32 || record.calculate_end() <= start as i32 {
+            self.skipped.out_of_bounds += 1;
+            log::trace!(
+                "skipping read {}: aligned outside of interval {}-{}",
+                String::from_utf8_lossy(record.name()),
+                start,
+                end
+            );
+            return Ok(());
+        }
+        // Reads without a stored sequence ('*' in SAM) have no bases to compare
+        if record.sequence().len() == 0 {
+            if config.strict {
+                return Err(format!(
+                    "read {} has no stored sequence, which --strict doesn't allow",
+                    String::from_utf8_lossy(record.name())
+                )
+                .into());
+            }
+            self.skipped.empty_seq += 1;
+            log::trace!(
+                "skipping read {}: no stored sequence",
+                String::from_utf8_lossy(record.name())
+            );
+            return Ok(());
+        }
+        // Reads without base qualities ('*' in SAM) have them all set to 0xff in BAM
+        let qualities = record.qualities();
+        if qualities.available() && qualities.raw().len() != record.sequence().len() {
+            return Err(format!(
+                "read {} has {} base qualities for {} bases",
+                String::from_utf8_lossy(record.name()),
+                qualities.raw().len(),
+                record.sequence().len()
+            )
+            .into());
+        }
+        if !qualities.available() {
+            if config.require_qual {
+                self.skipped.missing_qual += 1;
+                log::trace!(
+                    "skipping read {}: no base qualities",
+                    String::from_utf8_lossy(record.name())
+                );
+                return Ok(());
+            }
+            self.without_qual += 1;
+        }
+        if !config.use_md {
+            return self.count_record(record, reference_sequence, start, end, config);
+        }
+        // Compare the read against the reference bases it carries in its MD tag, only touching
+        // the positions it is aligned to
+        let mut md_reference = std::mem::take(&mut self.md_reference);
+        md_reference.resize((end - start) as usize, b'N');
+        let read_span = fill_md_reference(record, start, end, &mut md_reference)?;
+        let result = self.count_record(record, &md_reference, start, end, config);
+        md_reference[read_span].fill(b'N');
+        self.md_reference = md_reference;
+        result
+    }
+
+    // Counts a read of the [start, end) interval that passed all filters
+    fn count_record(
+        &mut self,
+        record: &bam::Record,
+        reference_sequence: &[u8],
+        start: u32,
+        end: u32,
+        config: &PileupConfig,
+    ) -> Result<(), BoxError> {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.add_record(record);
+        }
+        let (interval_start, interval_end) = (start, end);
+        // Soft-masked bases are compared like any other unless the config leaves them out
+        let mut compared_sequence = self.masked_reference.as_deref().unwrap_or(reference_sequence);
+        let (mut reference_sequence, mut start, mut end) = (reference_sequence, start, end);
+        if config.trim_ends > 0 {
+            // Count the read only in the part of the interval its untrimmed bases are aligned to
+            let span = trimmed_ref_span(record, config.trim_ends);
+            let trimmed_start = span.start.clamp(start, end);
+            let trimmed_end = span.end.clamp(trimmed_start, end);
+            let offset = |pos: u32, len: usize| ((pos - start) as usize).min(len);
+            reference_sequence = &reference_sequence[offset(trimmed_start, reference_sequence.len())
+                ..offset(trimmed_end, reference_sequence.len())];
+            compared_sequence = &compared_sequence[offset(trimmed_start, compared_sequence.len())
+                ..offset(trimmed_end, compared_sequence.len())];
+            (start, end) = (trimmed_start, trimmed_end);
+        }
+        if let Some(qual_histogram) = &mut self.qual_histogram {
+            qual_histogram.add_record(record, compared_sequence, start, end, config.strict_iupac);
+        }
+        if let Some(mismatch_spectrum) = &mut self.mismatch_spectrum {
+            mismatch_spectrum.add_record(record, compared_sequence, start, end, config.qscore_cutoff);
+        }
+        if let Some(mismatch_profile) = &mut self.mismatch_profile {
+            mismatch_profile.add_record(record, compared_sequence, start, end, interval_start, config);
+        }
+        if let Some(debug_counts) = &mut self.debug_counts {
+            count_position_bases(record, reference_sequence, start, end, config, &HashSet::new(), debug_counts);
+        }
+        if config.mode == Mode::PerPosition {
+            if config.dedup_overlap {
+                self.count_mate_position_bases(record, reference_sequence, start, end, config);
+            } else {
+                count_position_bases(
+                    record,
+                    reference_sequence,
+                    start,
+                    end,
+                    config,
+                    &HashSet::new(),
+                    &mut self.position_counts,
+                );
+            }
+            return Ok(());
+        }
+        if config.mode == Mode::Long {
+            observe_bases(record, compared_sequence, start, end, &mut self.base_observations);
+            return Ok(());
+        }
+        if config.mode == Mode::Modifications {
+            count_mod_calls(
+                record,
+                reference_sequence,
+                start,
+                end,
+                config,
+                &mut self.mod_counts,
+            );
+            return Ok(());
+        }
+
+        let read_id = String::from_utf8_lossy(record.name());
+        let read_counts = self.read_counts.entry(read_id.to_string()).or_default();
+        if read_counts.region_names.is_empty() {
+            read_counts.region_names.push(self.region_name.clone());
+        }
+        let is_reverse = record.flag().is_reverse_strand();
+        // Compare the read against each target position it is aligned to, leaving out those
+        // already counted in earlier intervals
+        for piece in uncounted_pieces(start, end, &self.counted_before) {
+            let offset = |pos: u32| ((pos - start) as usize).min(compared_sequence.len());
+            let (num_matches, num_mismatches, num_ambiguous_ref, num_low_qual) = if config.count_matches_only {
+                let (num_passing, num_low_qual) =
+                    count_aligned_bases(record, piece.start, piece.end, config.qscore_cutoff);
+                (num_passing, 0, 0, num_low_qual)
+            } else {
+                count_matches_mismatches(
+                    record,
+                    &compared_sequence[offset(piece.start)..offset(piece.end)],
+                    piece.start,
+                    piece.end,
+                    config.qscore_cutoff,
+                    config.deletions_as_mismatches,
+                    config.strict_iupac,
+                )
+            };
+            let (num_insertions, num_deletions) = count_insertions_deletions(record, piece.start, piece.end);
+            let (aligned_length, qual_sum) = aligned_base_qualities(record, piece.start, piece.end);
+            if is_reverse {
+                read_counts.rev_matches += num_matches;
+                read_counts.rev_mismatches += num_mismatches;
+                read_counts.rev_insertions += num_insertions;
+                read_counts.rev_deletions += num_deletions;
+                read_counts.rev_ambiguous_ref += num_ambiguous_ref;
+                read_counts.rev_low_qual += num_low_qual;
+            } else {
+                read_counts.fwd_matches += num_matches;
+                read_counts.fwd_mismatches += num_mismatches;
+                read_counts.fwd_insertions += num_insertions;
+                read_counts.fwd_deletions += num_deletions;
+                read_counts.fwd_ambiguous_ref += num_ambiguous_ref;
+                read_counts.fwd_low_qual += num_low_qual;
+            }
+            read_counts.aligned_length += aligned_length;
+            if let Some(qual_sum) = qual_sum {
+                read_counts.qual_sum += qual_sum;
+                read_counts.qual_bases += aligned_length;
+            }
+        }
+        // Soft clips are never trimmed, they belong to the interval the read is aligned to
+        for piece in uncounted_pieces(interval_start, interval_end, &self.counted_before) {
+            let num_softclips = count_softclips(record, piece.start, piece.end);
+            if is_reverse {
+                read_counts.rev_softclips += num_softclips;
+            } else {
+                read_counts.fwd_softclips += num_softclips;
+            }
+        }
+        Ok(())
+    }
+
+    // Counts the bases of a read at each position, together with its mate if their alignments
+    // overlap, so that the positions the two cover are counted once for the fragment. The first
+    // read of such a pair is kept until its mate comes up.
+    fn count_mate_position_bases(
+        &mut self,
+        record: &bam::Record,
+        reference_sequence: &[u8],
+        start: u32,
+        end: u32,
+        config: &PileupConfig,
+    ) {
+        if let Some((mate, mate_span, mate_reference)) = self.pending_mates.remove(record.name()) {
+            // Only the positions both reads are counted at are in their overlap
+            let (mate_dropped, dropped) =
+                overlap_duplicates(&mate, record, start.max(mate_span.start), end.min(mate_span.end));
+            count_position_bases(
+                &mate,
+                &mate_reference,
+                mate_span.start,
+                mate_span.end,
+                config,
+                &mate_dropped,
+                &mut self.position_counts,
+            );
+            count_position_bases(
+                record,
+                reference_sequence,
+                start,
+                end,
+                config,
+                &dropped,
+                &mut self.position_counts,
+            );
+            return;
+        }
+        // The mate comes later in coordinate order if it starts within this read
+        let flag = record.flag();
+        let mate_overlaps = flag.is_paired()
+            && flag.mate_is_mapped()
+            && record.mate_ref_id() == record.ref_id()
+            && record.mate_start() >= record.start()
+            && record.mate_start() < record.calculate_end().min(end as i32);
+        if mate_overlaps {
+            // The reference of the read's span, the reference of the interval may be reused
+            let span = overlapping_ref_positions(record, start, end);
+            let span_reference = reference_sequence[(span.start - start) as usize..(span.end - start) as usize].to_vec();
+            self.pending_mates.insert(record.name().to_vec(), (record.clone(), span, span_reference));
+        } else {
+            count_position_bases(
+                record,
+                reference_sequence,
+                start,
+                end,
+                config,
+                &HashSet::new(),
+                &mut self.position_counts,
+            );
+        }
+    }
+
+    // Counts the reads whose mate never came up, e.g. because it was filtered, picks the first
+    // mismatching positions and sets the homopolymer lengths once all reads of the interval
+    // starting at `start` are added
+    pub(crate) fn finish(&mut self, reference_sequence: &[u8], start: u32, config: &PileupConfig) {
+        if let (Some(debug_counts), Some(debug_positions)) = (self.debug_counts.take(), &mut self.debug_positions) {
+            let limit = config.debug_positions.unwrap_or(0);
+            debug_positions.positions = debug_counts
+                .into_iter()
+                .filter(|(_, (reference_base, counts))| has_mismatch(*reference_base, counts))
+                .take(limit)
+                .map(|(pos, (reference_base, counts))| (pos, reference_base, counts))
+                .collect();
+        }
+        let mut pending: Vec<_> = self.pending_mates.drain().map(|(_, pending)| pending).collect();
+        // In BAM order, which decides the counted reads with a maximum depth
+        pending.sort_by_key(|(record, _, _)| (record.start(), record.name().to_vec()));
+        for (record, span, span_reference) in pending {
+            count_position_bases(
+                &record,
+                &span_reference,
+                span.start,
+                span.end,
+                config,
+                &HashSet::new(),
+                &mut self.position_counts,
+            );
+        }
+        if config.homopolymer_length {
+            let lengths = homopolymer_lengths(reference_sequence);
+            for (pos, (_, counts)) in self.position_counts.iter_mut() {
+                counts.hp_len = lengths.get((pos - start) as usize).copied().unwrap_or(0);
+            }
+        }
+    }
+}
+
+// Length of the run of identical bases that each base of a sequence belongs to, ignoring case
+fn homopolymer_lengths(sequence: &[u8]) -> Vec<u32> {
+    let mut lengths = Vec::with_capacity(sequence.len());
+    for run in sequence.chunk_by(|a, b| a.eq_ignore_ascii_case(b)) {
+        lengths.extend(std::iter::repeat_n(run.len() as u32, run.len()));
+    }
+    lengths
+}
+
+// Positions of the [start, end) interval covered by both reads of a pair, which are only counted
+// in the read with the higher base quality there, the first one on ties. A deletion counts as below
+// any base. Returns the positions to leave out of the first and of the second read.
+fn overlap_duplicates(first: &bam::Record, second: &bam::Record, start: u32, end: u32) -> (HashSet<u32>, HashSet<u32>) {
+    let overlap_quality = |record: &bam::Record, ref_pos: u32| match lookup_ref_pos(record, ref_pos) {
+        RefPosLookup::Aligned(index) => Some(record.qualities().raw().get(index).copied().unwrap_or(0) as i16),
+        RefPosLookup::Deletion => Some(-1),
+        RefPosLookup::NotAligned => None,
+    };
+    let (first_span, second_span) = (overlapping_ref_positions(first, start, end), overlapping_ref_positions(second, start, end));
+    let mut first_dropped = HashSet::new();
+    let mut second_dropped = HashSet::new();
+    for ref_pos in first_span.start.max(second_span.start)..first_span.end.min(second_span.end) {
+        if let (Some(first_quality), Some(second_quality)) =
+            (overlap_quality(first, ref_pos), overlap_quality(second, ref_pos))
+        {
+            if second_quality > first_quality {
+                first_dropped.insert(ref_pos);
+            } else {
+                second_dropped.insert(ref_pos);
+            }
+        }
+    }
+    (first_dropped, second_dropped)
+}
+
+// Parts of each interval of a contig covered by the intervals before it, sorted and merged, for
+// [`PileupConfig::unique_positions`]
+pub(crate) fn earlier_overlaps(regions: &[BedRegion]) -> Vec<Vec<Range<u32>>> {
+    // Merged intervals seen so far, by start
+    let mut covered: BTreeMap<u32, u32> = BTreeMap::new();
+    let mut overlaps = Vec::with_capacity(regions.len());
+    for region in regions {
+        let (mut start, mut end) = (region.start, region.end);
+        let mut overlap: Vec<Range<u32>> = covered
+            .range(..end)
+            .rev()
+            .take_while(|(_, covered_end)| **covered_end > start)
+            .map(|(covered_start, covered_end)| *covered_start.max(&start)..*covered_end.min(&end))
+            .collect();
+        overlap.reverse();
+        overlaps.push(overlap);
+        // Merge the interval with those it overlaps or touches
+        let touching: Vec<(u32, u32)> = covered
+            .range(..=end)
+            .rev()
+            .take_while(|(_, covered_end)| **covered_end >= start)
+            .map(|(covered_start, covered_end)| (*covered_start, *covered_end))
+            .collect();
+        for (covered_start, covered_end) in touching {
+            covered.remove(&covered_start);
+            start = start.min(covered_start);
+            end = end.max(covered_end);
+        }
+        covered.insert(start, end);
+    }
+    overlaps
+}
+
+// Parts of the [start, end) interval outside the sorted `counted` ranges
+fn uncounted_pieces(start: u32, end: u32, counted: &[Range<u32>]) -> Vec<Range<u32>> {
+    let mut pieces = Vec::new();
+    let mut piece_start = start;
+    for range in counted {
+        if range.start > piece_start {
+            pieces.push(piece_start..range.start.min(end));
+        }
+        piece_start = piece_start.max(range.end);
+    }
+    if piece_start < end {
+        pieces.push(piece_start..end);
+    }
+    pieces
+}
+
+// Extracts the pileup of a single region, leaving the `counted_before` parts of it out of the
+// per-read counts. The reference bases are exactly those of the interval, without them the reads
+// carry them in their MD tags.
+pub(crate) fn count_region<R: Read + Seek>(
+    bam: &mut bam::IndexedReader<R>,
+    reference_sequence: Option<&[u8]>,
+    region: &BedRegion,
+    counted_before: &[Range<u32>],
+    config: &PileupConfig,
+) -> Result<RegionCounts, BoxError> {
+    let (ref_name, start, end) = (&region.ref_name, region.start, region.end);
+    let mut region_counts = RegionCounts::new(region, config);
+    region_counts.counted_before = counted_before.to_vec();
+    let ref_id = bam
+        .header()
+        .reference_id(ref_name)
+        .ok_or_else(|| Error::ContigMissing(format!("contig \"{}\" from BED not found in BAM header", ref_name)))?;
+
+    if let Some(reference_sequence) = reference_sequence {
+        region_counts.mask_reference(reference_sequence, config);
+    }
+    let reference_sequence = reference_sequence.unwrap_or_default();
+
+    // Long intervals are fetched in windows one after the other. Reads spanning a window boundary
+    // come up in both windows, but only count in the one their first position in the interval
+    // falls into, so the counts are the same as with a single fetch.
+    let window_size = config.interval_batch_size.unwrap_or(u32::MAX).max(1);
+    let mut window_start = start;
+    let mut record = bam::Record::new();
+    let mut n_read = 0;
+    'windows: loop {
+        let window_end = window_start.saturating_add(window_size).min(end);
+        let mut pileup = bam.fetch(&bam::Region::new(ref_id, window_start, window_end)).map_err(|e| {
+            Error::Fetch(format!("failed to fetch reads for {}:{}-{}: {}", ref_name, window_start, window_end, e))
+        })?;
+        loop {
+           // reader: impl RecordReader
+           // New record is saved into record.
+            match pileup.read_into(&mut record) {
+               // No more records to read.
+                Ok(false) => break,
+                Ok(true) => {}
+                Err(e) => {
+                    // The reader can't find the start of the next read, so the rest of the interval is lost
+                    let offset = pileup.current_offset();
+                    let message = format!(
+                        "failed to read reads of {}:{}-{} after {} reads, at BAM offset {} (byte {} of its block): {}",
+                        ref_name,
+                        start,
+                        end,
+                        n_read,
+                        offset.block_offset(),
+                        offset.contents_offset(),
+                        e
+                    );
+                    if config.skip_errors {
+                        log::warn!("{}, skipping the rest of the interval", message);
+                        break 'windows;
+                    }
+                    return Err(Error::Fetch(message).into());
+                }
+            }
+            let first_pos = record.start().max(start as i32);
+            if first_pos < window_start as i32 || (window_end < end && first_pos >= window_end as i32) {
+                continue;
+            }
+            n_read += 1;
+            region_counts.add_record(&record, reference_sequence, start, end, config)?;
+        }
+        if window_end >= end {
+            break;
+        }
+        window_start = window_end;
+    }
+    region_counts.finish(reference_sequence, start, config);
+
+    Ok(region_counts)
+}
+
+/// Base counts of a single reference position, as yielded by [`PileupIterator`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PileupPosition {
+    pub ref_name: String,
+    /// 0-based position
+    pub pos: u32,
+    /// Reference base from the FASTA file, or from the MD tags with
+    /// [`PileupConfig::use_md`], `N` where no read tells it
+    pub ref_base: u8,
+    /// Number of reads with a base passing the Q-score cutoff or a deletion at the position
+    pub depth: usize,
+    pub counts: BaseCounts,
+}
+
+/// Iterates over the per-position base counts of the regions of a config, in the order of the
+/// regions and then by position, without writing any files. Reads are filtered and counted
+/// just like with [`Mode::PerPosition`], whatever mode the config asks for. Positions that no
+/// read overlaps are left out, and positions shared by overlapping regions come once for each.
+///
+/// Only one region is counted at a time, so memory stays bounded by the largest region. After
+/// the first error, the iterator yields nothing more.
+pub struct PileupIterator<R: Read + Seek> {
+    bam: bam::IndexedReader<R>,
+    reference: Option<IndexedFasta>,
+    config: PileupConfig,
+    next_region: usize,
+    ref_name: String,
+    positions: std::collections::btree_map::IntoIter<u32, (u8, BaseCounts)>,
+    failed: bool,
+}
+
+impl PileupIterator<std::fs::File> {
+    /// Opens the indexed BAM file of the config and, unless the reference bases come from MD
+    /// tags, its FASTA file
+    pub fn new(config: &PileupConfig) -> Result<PileupIterator<std::fs::File>, BoxError> {
+        if crate::cram::is_cram(&config.bam_file_path) {
+            return Err(format!("pileup iterators can't fetch the reads of CRAM file {}", config.bam_file_path).into());
+        }
+        let bam = bam::IndexedReader::from_path(&config.bam_file_path)
+            .map_err(|e| Error::Io(format!("failed to open BAM file {}: {}", config.bam_file_path, e)))?;
+        PileupIterator::from_reader(bam, config)
+    }
+}
+
+impl<R: Read + Seek> PileupIterator<R> {
+    /// Counts the regions of the config in an already opened BAM file, such as a remote one.
+    /// A missing index of the FASTA file is created like by [`crate::run`].
+    pub fn from_reader(bam: bam::IndexedReader<R>, config: &PileupConfig) -> Result<PileupIterator<R>, BoxError> {
+        let reference = if config.use_md {
+            None
+        } else {
+            reference::ensure_fasta_index(&config.fasta_file_path)?;
+            Some(reference::open_indexed_fasta(&config.fasta_file_path)?)
+        };
+        Ok(PileupIterator {
+            bam,
+            reference,
+            config: PileupConfig {
+                mode: Mode::PerPosition,
+                ..config.clone()
+            },
+            next_region: 0,
+            ref_name: String::new(),
+            positions: BTreeMap::new().into_iter(),
+            failed: false,
+        })
+    }
+}
+
+impl<R: Read + Seek> Iterator for PileupIterator<R> {
+    type Item = Result<PileupPosition, BoxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            if let Some((pos, (ref_base, counts))) = self.positions.next() {
+                return Some(Ok(PileupPosition {
+                    ref_name: self.ref_name.clone(),
+                    pos,
+                    ref_base,
+                    depth: counts.depth(),
+                    counts,
+                }));
+            }
+            let region = self.config.regions.get(self.next_region)?;
+            self.next_region += 1;
+            let counted = match self.reference.as_mut() {
+                Some(reference) => reference::fetch_interval(reference, &region.ref_name, region.start, region.end)
+                    .and_then(|sequence| count_region(&mut self.bam, Some(&sequence), region, &[], &self.config)),
+                None => count_region(&mut self.bam, None, region, &[], &self.config),
+            };
+            match counted {
+                Ok(region_counts) => {
+                    self.ref_name = region.ref_name.clone();
+                    self.positions = region_counts.position_counts.into_iter();
+                }
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Coverage of a single interval by the reads passing the filters
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IntervalCoverage {
+    pub region: BedRegion,
+    /// Number of reads overlapping the interval
+    pub n_reads: usize,
+    /// Number of reads with a base aligned to each position of the interval, regardless of its
+    /// quality. Deletions and reference skips don't count.
+    pub depth: Vec<u32>,
+}
+
+impl IntervalCoverage {
+    pub fn new(region: &BedRegion) -> IntervalCoverage {
+        IntervalCoverage {
+            region: region.clone(),
+            n_reads: 0,
+            depth: vec![0; region.end.saturating_sub(region.start) as usize],
+        }
+    }
+
+    pub(crate) fn add_record(&mut self, record: &bam::Record) {
+        let (start, end) = (self.region.start, self.region.end);
+        let positions = overlapping_ref_positions(record, start, end);
+        if positions.is_empty() {
+            return;
+        }
+        self.n_reads += 1;
+        for ref_pos in positions {
+            if let RefPosLookup::Aligned(_) = lookup_ref_pos(record, ref_pos) {
+                self.depth[(ref_pos - start) as usize] += 1;
+            }
+        }
+    }
+
+    /// Mean depth over all positions of the interval
+    pub fn mean_depth(&self) -> f64 {
+        if self.depth.is_empty() {
+            return 0.0;
+        }
+        self.depth.iter().map(|depth| *depth as f64).sum::<f64>() / self.depth.len() as f64
+    }
+
+    /// Fraction of the positions that have at least `min_depth` reads
+    pub fn frac_covered(&self, min_depth: u32) -> f64 {
+        if self.depth.is_empty() {
+            return 0.0;
+        }
+        self.depth.iter().filter(|depth| **depth >= min_depth).count() as f64 / self.depth.len() as f64
+    }
+}
+
+/// First positions of an interval where reads show a base other than the reference, with their
+/// base counts, to check that reads and reference line up. Only the bases `A`, `C`, `G` and `T`
+/// count as mismatches, not deletions or `N`, and never at ambiguous reference bases.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugPositions {
+    pub region: BedRegion,
+    /// 0-based position, reference base and base counts of at most
+    /// [`PileupConfig::debug_positions`] positions, sorted by position
+    pub positions: Vec<(u32, u8, BaseCounts)>,
+}
+
+impl DebugPositions {
+    pub fn new(region: &BedRegion) -> DebugPositions {
+        DebugPositions {
+            region: region.clone(),
+            positions: Vec::new(),
+        }
+    }
+}
+
+// Whether any counted read base at a position differs from its unambiguous reference base
+fn has_mismatch(reference_base: u8, counts: &BaseCounts) -> bool {
+    [(b'A', counts.a), (b'C', counts.c), (b'G', counts.g), (b'T', counts.t)]
+        .iter()
+        .any(|(base, count)| *count > 0 && is_alt_base(*base, reference_base))
+}
+
+// Whether a read base is one of A, C, G and T and differs from an unambiguous reference base
+fn is_alt_base(base: u8, reference_base: u8) -> bool {
+    !is_ambiguous_base(reference_base)
+        && b"ACGT".contains(&base.to_ascii_uppercase())
+        && !base.eq_ignore_ascii_case(&reference_base)
+}
+
+/// Number of matching and mismatching bases at the positions of interest by their base quality,
+/// regardless of the Q-score cutoff. Qualities above [`QualHistogram::MAX_QUAL`] are counted in
+/// its bin. Bases of reads without qualities and bases at ambiguous reference bases are not
+/// counted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QualHistogram {
+    /// Matching bases of each quality from 0 to [`QualHistogram::MAX_QUAL`]
+    pub matches: Vec<u64>,
+    /// Mismatching bases of each quality from 0 to [`QualHistogram::MAX_QUAL`]
+    pub mismatches: Vec<u64>,
+}
+
+impl Default for QualHistogram {
+    fn default() -> QualHistogram {
+        QualHistogram::new()
+    }
+}
+
+impl QualHistogram {
+    /// Highest quality with its own bin
+    pub const MAX_QUAL: u8 = 60;
+
+    pub fn new() -> QualHistogram {
+        QualHistogram {
+            matches: vec![0; QualHistogram::MAX_QUAL as usize + 1],
+            mismatches: vec![0; QualHistogram::MAX_QUAL as usize + 1],
+        }
+    }
+
+    /// Adds the bases of a read within the [start, end) interval, where `reference_sequence`
+    /// holds the reference bases of the interval. Bases at ambiguous reference bases are left
+    /// out, see [`compare_base`].
+    pub fn add_record(
+        &mut self,
+        record: &bam::Record,
+        reference_sequence: &[u8],
+        start: u32,
+        end: u32,
+        strict_iupac: bool,
+    ) {
+        if !record.qualities().available() {
+            return;
+        }
+        for ref_pos in overlapping_ref_positions(record, start, end) {
+            let (Some(reference_base), Some((base, qscore))) =
+                (reference_sequence.get((ref_pos - start) as usize), base_at_ref_pos(record, ref_pos))
+            else {
+                continue;
+            };
+            let Some(is_match) = compare_base(base, *reference_base, strict_iupac) else {
+                continue;
+            };
+            let bin = qscore.min(QualHistogram::MAX_QUAL) as usize;
+            if is_match {
+                self.matches[bin] += 1;
+            } else {
+                self.mismatches[bin] += 1;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &QualHistogram) {
+        for (count, other_count) in self.matches.iter_mut().zip(&other.matches) {
+            *count += other_count;
+        }
+        for (count, other_count) in self.mismatches.iter_mut().zip(&other.mismatches) {
+            *count += other_count;
+        }
+    }
+}
+
+/// Number of read bases differing from the reference for each of the 12 substitutions between
+/// `A`, `C`, `G` and `T`, counting the bases that pass the Q-score cutoff like the mismatches
+/// of the per-read counts. Read bases other than these four and ambiguous reference bases are
+/// not counted.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct MismatchSpectrum {
+    /// Counts indexed by the reference and the read base, in [`MismatchSpectrum::BASES`] order.
+    /// The diagonal of matching bases stays 0.
+    pub counts: [[u64; 4]; 4],
+}
+
+impl MismatchSpectrum {
+    /// Order of the bases in [`MismatchSpectrum::counts`]
+    pub const BASES: [u8; 4] = *b"ACGT";
+
+    /// Adds the mismatches of a read within the [start, end) interval, where
+    /// `reference_sequence` holds the reference bases of the interval
+    pub fn add_record(
+        &mut self,
+        record: &bam::Record,
+        reference_sequence: &[u8],
+        start: u32,
+        end: u32,
+        qscore_cutoff: u8,
+    ) {
+        for ref_pos in overlapping_ref_positions(record, start, end) {
+            let (Some(reference_base), Some((base, qscore))) =
+                (reference_sequence.get((ref_pos - start) as usize), base_at_ref_pos(record, ref_pos))
+            else {
+                continue;
+            };
+            if qscore < qscore_cutoff {
+                continue;
+            }
+            if let (Some(ref_index), Some(alt_index)) = (spectrum_index(*reference_base), spectrum_index(base)) {
+                if ref_index != alt_index {
+                    self.counts[ref_index][alt_index] += 1;
+                }
+            }
+        }
+    }
+
+    /// Number of `ref` bases read as `alt`, given as one of `ACGT`
+    pub fn count(&self, ref_base: u8, alt_base: u8) -> u64 {
+        match (spectrum_index(ref_base), spectrum_index(alt_base)) {
+            (Some(ref_index), Some(alt_index)) => self.counts[ref_index][alt_index],
+            _ => 0,
+        }
+    }
+
+    pub fn merge(&mut self, other: &MismatchSpectrum) {
+        for (row, other_row) in self.counts.iter_mut().zip(&other.counts) {
+            for (count, other_count) in row.iter_mut().zip(other_row) {
+                *count += other_count;
+            }
+        }
+    }
+}
+
+/// Number of read bases compared to the reference and how many of them mismatch it at each
+/// position relative to the start of an interval, summed over the intervals. Like the per-read
+/// counts, only bases passing the Q-score cutoff count, and bases at ambiguous reference bases
+/// and deleted positions don't.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct MismatchProfile {
+    /// Compared bases at each 0-based position from the start of the interval
+    pub depth: Vec<u64>,
+    /// Mismatching bases at each position
+    pub mismatches: Vec<u64>,
+}
+
+impl MismatchProfile {
+    /// Creates the profile of an interval of this length without any bases
+    pub fn new(length: usize) -> MismatchProfile {
+        MismatchProfile {
+            depth: vec![0; length],
+            mismatches: vec![0; length],
+        }
+    }
+
+    /// Adds the bases of a read within the [start, end) part of the interval starting at
+    /// `interval_start`, where `reference_sequence` holds the reference bases of that part.
+    /// Bases below the Q-score cutoff of the config and at ambiguous reference bases are left
+    /// out, see [`compare_base`].
+    pub fn add_record(
+        &mut self,
+        record: &bam::Record,
+        reference_sequence: &[u8],
+        start: u32,
+        end: u32,
+        interval_start: u32,
+        config: &PileupConfig,
+    ) {
+        for ref_pos in overlapping_ref_positions(record, start, end) {
+            let (Some(reference_base), Some((base, qscore))) =
+                (reference_sequence.get((ref_pos - start) as usize), base_at_ref_pos(record, ref_pos))
+            else {
+                continue;
+            };
+            if qscore < config.qscore_cutoff {
+                continue;
+            }
+            let Some(is_match) = compare_base(base, *reference_base, config.strict_iupac) else {
+                continue;
+            };
+            let rel_pos = (ref_pos - interval_start) as usize;
+            if rel_pos >= self.depth.len() {
+                self.depth.resize(rel_pos + 1, 0);
+                self.mismatches.resize(rel_pos + 1, 0);
+            }
+            self.depth[rel_pos] += 1;
+            if !is_match {
+                self.mismatches[rel_pos] += 1;
+            }
+        }
+    }
+
+    /// Fraction of the compared bases at a relative position that mismatch the reference, None
+    /// without any
+    pub fn mismatch_rate(&self, rel_pos: usize) -> Option<f64> {
+        match self.depth.get(rel_pos) {
+            Some(&depth) if depth > 0 => Some(self.mismatches[rel_pos] as f64 / depth as f64),
+            _ => None,
+        }
+    }
+
+    /// Adds the counts of another profile, which may be longer
+    pub fn merge(&mut self, other: &MismatchProfile) {
+        if other.depth.len() > self.depth.len() {
+            self.depth.resize(other.depth.len(), 0);
+            self.mismatches.resize(other.depth.len(), 0);
+        }
+        for (count, other_count) in self.depth.iter_mut().zip(&other.depth) {
+            *count += other_count;
+        }
+        for (count, other_count) in self.mismatches.iter_mut().zip(&other.mismatches) {
+            *count += other_count;
+        }
+    }
+}
+
+// Index of a base in MismatchSpectrum::BASES, ignoring its case
+fn spectrum_index(base: u8) -> Option<usize> {
+    MismatchSpectrum::BASES.iter().position(|b| b.eq_ignore_ascii_case(&base))
+}
+
+// Returns the reference positions of the [start, end) interval that are spanned by the read.
+// Reads often start before or end after the interval, so the span is clamped to the interval
+// and positions can safely be used as `ref_pos - start` offsets into the interval's reference.
+pub(crate) fn overlapping_ref_positions(record: &bam::Record, start: u32, end: u32) -> std::ops::Range<u32> {
+    let read_start = (record.start().max(0) as u32).max(start);
+    let read_end = (record.calculate_end().max(0) as u32).min(end);
+    read_start..read_end.max(read_start)
+}
+
+/// Counts the bases of a read which match or mismatch the reference within the [start, end)
+/// interval, where `reference_sequence` holds the reference bases of the interval, and the bases
+/// aligned to an ambiguous reference base, which are neither, see [`compare_base`] for how
+/// `strict_iupac` treats IUPAC codes. Case is ignored, so soft-masked reference bases compare
+/// like any other. Bases below the Q-score
+/// cutoff are neither and are counted on their own, last. Deleted positions are not counted at
+/// all unless `deletions_as_mismatches` counts them as mismatches, whatever the quality of the read.
+pub fn count_matches_mismatches(
+    record: &bam::Record,
+    reference_sequence: &[u8],
+    start: u32,
+    end: u32,
+    qscore_cutoff: u8,
+    deletions_as_mismatches: bool,
+    strict_iupac: bool,
+) -> (usize, usize, usize, usize) {
+    let mut num_matches = 0;
+    let mut num_mismatches = 0;
+    let mut num_ambiguous_ref = 0;
+    let mut num_low_qual = 0;
+
+    let alignment = ReadAlignment::new(record);
+    // Only visit the target positions the read actually covers
+    for ref_pos in overlapping_ref_positions(record, start, end) {
+        let reference_base = match reference_sequence.get((ref_pos - start) as usize) {
+            Some(reference_base) => *reference_base,
+            None => continue,
+        };
+        // Deleted positions have no base to compare, the reference base is missing from the read
+        let (base, qscore) = match alignment.lookup(ref_pos) {
+            RefPosLookup::Aligned(index) => (
+                record.sequence().at(index),
+                record.qualities().raw().get(index).copied().unwrap_or(0),
+            ),
+            lookup => {
+                // A deletion mismatches every reference base a read base could be compared to
+                if deletions_as_mismatches && matches!(lookup, RefPosLookup::Deletion) {
+                    if compare_base(b'-', reference_base, strict_iupac).is_none() {
+                        num_ambiguous_ref += 1;
+                    } else {
+                        num_mismatches += 1;
+                    }
+                }
+                continue;
+            }
+        };
+        // Bases below the Q-score cutoff are neither matches nor mismatches
+        if qscore < qscore_cutoff {
+            num_low_qual += 1;
+            continue;
+        }
+        match compare_base(base, reference_base, strict_iupac) {
+            Some(true) => num_matches += 1,
+            Some(false) => num_mismatches += 1,
+            None => num_ambiguous_ref += 1,
+        }
+    }
+
+    (num_matches, num_mismatches, num_ambiguous_ref, num_low_qual)
+}
+
+/// Whether a base is anything but A, C, G or T in either case, like `N` or another IUPAC code.
+/// Read bases can't match or mismatch such a reference base, unless it is one of the IUPAC
+/// codes of two or three bases, see [`compare_base`].
+pub fn is_ambiguous_base(base: u8) -> bool {
+    !matches!(base.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T')
+}
+
+// Bases an IUPAC ambiguity code of two or three bases stands for, in either case
+fn iupac_bases(code: u8) -> Option<&'static [u8]> {
+    Some(match code.to_ascii_uppercase() {
+        b'R' => b"AG",
+        b'Y' => b"CT",
+        b'S' => b"CG",
+        b'W' => b"AT",
+        b'K' => b"GT",
+        b'M' => b"AC",
+        b'B' => b"CGT",
+        b'D' => b"AGT",
+        b'H' => b"ACT",
+        b'V' => b"ACG",
+        _ => return None,
+    })
+}
+
+/// Compares a read base with a reference base, ignoring case: Some(true) for a match and
+/// Some(false) for a mismatch, None if the reference base is ambiguous. A read base matches an
+/// IUPAC code of two or three bases, like `R` for `A` or `G`, if it is one of them, unless
+/// `strict_iupac` takes all codes as ambiguous. `N` stands for any base and is always ambiguous.
+pub fn compare_base(base: u8, reference_base: u8, strict_iupac: bool) -> Option<bool> {
+    if !is_ambiguous_base(reference_base) {
+        return Some(base.eq_ignore_ascii_case(&reference_base));
+    }
+    if strict_iupac {
+        return None;
+    }
+    iupac_bases(reference_base).map(|bases| bases.contains(&base.to_ascii_uppercase()))
+}
+
+/// Counts the inserted bases and the deleted reference bases of a read within the [start, end)
+/// interval, regardless of their quality. Deletions partly outside the interval only count with
+/// their positions inside it. Like in samtools, an insertion belongs to the reference position
+/// before it, so it counts if that position is part of the interval.
+pub fn count_insertions_deletions(record: &bam::Record, start: u32, end: u32) -> (usize, usize) {
+    use bam::record::cigar::Operation;
+
+    let mut num_insertions = 0;
+    let mut num_deletions = 0;
+    if record.start() < 0 {
+        return (0, 0);
+    }
+    let mut current_ref = record.start() as u32;
+    for (len, operation) in record.cigar().iter() {
+        match operation {
+            Operation::Insertion => {
+                if current_ref > start && current_ref <= end {
+                    num_insertions += len as usize;
+                }
+            }
+            Operation::Deletion => {
+                // Clip the deleted positions to the interval
+                let deletion_start = current_ref.max(start);
+                let deletion_end = (current_ref + len).min(end);
+                num_deletions += deletion_end.saturating_sub(deletion_start) as usize;
+                current_ref += len;
+            }
+            Operation::AlnMatch | Operation::SeqMatch | Operation::SeqMismatch | Operation::Skip => {
+                current_ref += len
+            }
+            Operation::Soft | Operation::Hard | Operation::Padding => {}
+        }
+        if current_ref > end {
+            break;
+        }
+    }
+
+    (num_insertions, num_deletions)
+}
+
+/// Counts the bases of a read aligned (`M`, `=` or `X`) to the [start, end) interval that pass
+/// the Q-score cutoff, and those below it, from the CIGAR string alone. Unlike
+/// [`count_matches_mismatches`] nothing is compared to the reference, so mismatching bases are
+/// counted as well.
+pub fn count_aligned_bases(record: &bam::Record, start: u32, end: u32, qscore_cutoff: u8) -> (usize, usize) {
+    use bam::record::cigar::Operation;
+
+    if record.start() < 0 {
+        return (0, 0);
+    }
+    // Reads without base qualities have them all set to 0xff and pass any cutoff
+    let raw_qualities = record.qualities().raw();
+    let mut num_passing = 0;
+    let mut num_low_qual = 0;
+    let mut current_ref = record.start() as u32;
+    let mut current_query = 0;
+    for (len, operation) in record.cigar().iter() {
+        match operation {
+            Operation::AlnMatch | Operation::SeqMatch | Operation::SeqMismatch => {
+                let first = current_ref.max(start);
+                let last = (current_ref + len).min(end);
+                if first < last {
+                    let query_start = (current_query + first - current_ref) as usize;
+                    let query_end = (current_query + last - current_ref) as usize;
+                    let passing = raw_qualities
+                        .get(query_start..query_end)
+                        .map_or(0, |qualities| qualities.iter().filter(|qual| **qual >= qscore_cutoff).count());
+                    num_passing += passing;
+                    num_low_qual += query_end - query_start - passing;
+                }
+                current_ref += len;
+                current_query += len;
+            }
+            Operation::Insertion | Operation::Soft => current_query += len,
+            Operation::Deletion | Operation::Skip => current_ref += len,
+            Operation::Hard | Operation::Padding => {}
+        }
+        if current_ref >= end {
+            break;
+        }
+    }
+    (num_passing, num_low_qual)
+}
+
+// Number of read bases aligned (`M`, `=` or `X`) to the [start, end) interval and the sum of
+// their base qualities, None if the read has none
+fn aligned_base_qualities(record: &bam::Record, start: u32, end: u32) -> (usize, Option<usize>) {
+    use bam::record::cigar::Operation;
+
+    if record.start() < 0 {
+        return (0, None);
+    }
+    let qualities = record.qualities();
+    let raw_qualities = qualities.available().then(|| qualities.raw());
+    let mut num_aligned = 0;
+    let mut qual_sum = 0;
+    let mut current_ref = record.start() as u32;
+    let mut current_query = 0;
+    for (len, operation) in record.cigar().iter() {
+        match operation {
+            Operation::AlnMatch | Operation::SeqMatch | Operation::SeqMismatch => {
+                // Clip the aligned block to the interval
+                let first = current_ref.max(start);
+                let last = (current_ref + len).min(end);
+                if first < last {
+                    let query_start = (current_query + first - current_ref) as usize;
+                    let query_end = (current_query + last - current_ref) as usize;
+                    num_aligned += query_end - query_start;
+                    if let Some(raw_qualities) = raw_qualities {
+                        qual_sum += raw_qualities[query_start..query_end]
+                            .iter()
+                            .map(|qual| *qual as usize)
+                            .sum::<usize>();
+                    }
+                }
+                current_ref += len;
+                current_query += len;
+            }
+            Operation::Insertion | Operation::Soft => current_query += len,
+            Operation::Deletion | Operation::Skip => current_ref += len,
+            Operation::Hard | Operation::Padding => {}
+        }
+        if current_ref >= end {
+            break;
+        }
+    }
+    (num_aligned, raw_qualities.map(|_| qual_sum))
+}
+
+// Whether a read is kept when subsampling the given fraction of reads. The decision only
+// depends on the read name and the seed, so it is the same for both reads of a pair, in every
+// interval and in every run. The hash is FNV-1a followed by the SplitMix64 finalizer, which
+// unlike the std hashers is guaranteed not to change between Rust versions.
+fn subsample_keeps(read_name: &[u8], fraction: f64, seed: u64) -> bool {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for byte in seed.to_le_bytes().iter().chain(read_name) {
+        hash = (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    // The top 53 bits as a uniform number in [0, 1)
+    ((hash >> 11) as f64 / (1_u64 << 53) as f64) < fraction
+}
+
+// Reference positions [start, end) from the first to the last base of a read left after
+// trimming `trim` bases off both ends of its aligned part, that is without its soft clips.
+// Empty if the trimming leaves no base aligned to the reference.
+fn trimmed_ref_span(record: &bam::Record, trim: u32) -> Range<u32> {
+    use bam::record::cigar::Operation;
+
+    if record.start() < 0 {
+        return 0..0;
+    }
+    let cigar = record.cigar();
+    let operations: Vec<(u32, Operation)> =
+        cigar.iter().filter(|(_, operation)| *operation != Operation::Hard).collect();
+    let leading_softclip = match operations.as_slice() {
+        [(len, Operation::Soft), ..] => *len,
+        _ => 0,
+    };
+    let trailing_softclip = match operations.as_slice() {
+        [_, .., (len, Operation::Soft)] => *len,
+        _ => 0,
+    };
+    let query_len = record.sequence().len() as u32;
+    let kept_start = leading_softclip + trim;
+    let kept_end = query_len.saturating_sub(trailing_softclip + trim);
+
+    let mut span: Option<Range<u32>> = None;
+    let mut current_ref = record.start() as u32;
+    let mut current_query = 0;
+    for (len, operation) in operations {
+        match operation {
+            Operation::AlnMatch | Operation::SeqMatch | Operation::SeqMismatch => {
+                let first = current_query.max(kept_start);
+                let last = (current_query + len).min(kept_end);
+                if first < last {
+                    let span_start = span.map_or(current_ref + first - current_query, |span| span.start);
+                    span = Some(span_start..current_ref + last - current_query);
+                }
+                current_ref += len;
+                current_query += len;
+            }
+            Operation::Insertion | Operation::Soft => current_query += len,
+            Operation::Deletion | Operation::Skip => current_ref += len,
+            Operation::Hard | Operation::Padding => {}
+        }
+    }
+    span.unwrap_or(0..0)
+}
+
+/// Counts the soft-clipped bases of a read that belong to the [start, end) interval, without
+/// comparing them to the reference. Like insertions, a clip belongs to the aligned reference
+/// position next to it, so each clip counts in at most one of several disjoint intervals.
+pub fn count_softclips(record: &bam::Record, start: u32, end: u32) -> usize {
+    use bam::record::cigar::Operation;
+
+    if record.start() < 0 {
+        return 0;
+    }
+    let in_interval = |ref_pos: i32| ref_pos >= start as i32 && ref_pos < end as i32;
+    let cigar = record.cigar();
+    // Hard clips can only be outside of soft clips
+    let operations: Vec<(u32, Operation)> =
+        cigar.iter().filter(|(_, operation)| *operation != Operation::Hard).collect();
+    let mut num_softclips = 0;
+    if let [(len, Operation::Soft), ..] = operations.as_slice() {
+        if in_interval(record.start()) {
+            num_softclips += *len as usize;
+        }
+    }
+    if let [_, .., (len, Operation::Soft)] = operations.as_slice() {
+        if in_interval(record.calculate_end() - 1) {
+            num_softclips += *len as usize;
+        }
+    }
+    num_softclips
+}
+
+/// Match/mismatch and indel counts of a single read, split by the strand it is mapped to, the
+/// number of its bases aligned to ambiguous reference bases and of its soft-clipped bases
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct ReadCounts {
+    /// Names of the intervals the read was counted in, see [`region_name`](crate::region_name)
+    pub region_names: Vec<String>,
+    pub fwd_matches: usize,
+    pub fwd_mismatches: usize,
+    pub rev_matches: usize,
+    pub rev_mismatches: usize,
+    pub fwd_insertions: usize,
+    pub fwd_deletions: usize,
+    pub rev_insertions: usize,
+    pub rev_deletions: usize,
+    pub fwd_ambiguous_ref: usize,
+    pub rev_ambiguous_ref: usize,
+    pub fwd_softclips: usize,
+    pub rev_softclips: usize,
+    /// Bases aligned to the intervals below the Q-score cutoff, neither matches nor mismatches
+    pub fwd_low_qual: usize,
+    pub rev_low_qual: usize,
+    /// Read bases aligned to the intervals (`M`, `=` or `X`), whatever their quality
+    pub aligned_length: usize,
+    /// Sum of the base qualities of the aligned bases and the number of bases it is summed over,
+    /// 0 for reads without base qualities
+    pub qual_sum: usize,
+    pub qual_bases: usize,
+}
+
+impl ReadCounts {
+    pub fn merge(&mut self, other: &ReadCounts) {
+        self.fwd_matches += other.fwd_matches;
+        self.fwd_mismatches += other.fwd_mismatches;
+        self.rev_matches += other.rev_matches;
+        self.rev_mismatches += other.rev_mismatches;
+        self.fwd_insertions += other.fwd_insertions;
+        self.fwd_deletions += other.fwd_deletions;
+        self.rev_insertions += other.rev_insertions;
+        self.rev_deletions += other.rev_deletions;
+        self.fwd_ambiguous_ref += other.fwd_ambiguous_ref;
+        self.rev_ambiguous_ref += other.rev_ambiguous_ref;
+        self.fwd_softclips += other.fwd_softclips;
+        self.rev_softclips += other.rev_softclips;
+        self.fwd_low_qual += other.fwd_low_qual;
+        self.rev_low_qual += other.rev_low_qual;
+        self.aligned_length += other.aligned_length;
+        self.qual_sum += other.qual_sum;
+        self.qual_bases += other.qual_bases;
+        for name in &other.region_names {
+            if !self.region_names.contains(name) {
+                self.region_names.push(name.clone());
+            }
+        }
+    }
+
+    pub fn insertions(&self) -> usize {
+        self.fwd_insertions + self.rev_insertions
+    }
+
+    /// Mean base quality of the bases aligned to the intervals, None for reads without base
+    /// qualities
+    pub fn mean_qual(&self) -> Option<f64> {
+        (self.qual_bases > 0).then(|| self.qual_sum as f64 / self.qual_bases as f64)
+    }
+
+    pub fn deletions(&self) -> usize {
+        self.fwd_deletions + self.rev_deletions
+    }
+
+    pub fn matches(&self) -> usize {
+        self.fwd_matches + self.rev_matches
+    }
+
+    pub fn mismatches(&self) -> usize {
+        self.fwd_mismatches + self.rev_mismatches
+    }
+
+    pub fn ambiguous_ref(&self) -> usize {
+        self.fwd_ambiguous_ref + self.rev_ambiguous_ref
+    }
+
+    pub fn softclips(&self) -> usize {
+        self.fwd_softclips + self.rev_softclips
+    }
+
+    pub fn low_qual(&self) -> usize {
+        self.fwd_low_qual + self.rev_low_qual
+    }
+
+    /// Positions the read was compared to the reference at: its matches, its mismatches and its
+    /// bases below the Q-score cutoff
+    pub fn covered_positions(&self) -> usize {
+        self.matches() + self.mismatches() + self.low_qual()
+    }
+
+    /// Mismatches per covered position, see [`ReadCounts::covered_positions`], None for reads
+    /// without any
+    pub fn mismatch_rate(&self) -> Option<f64> {
+        mismatch_rate(self.mismatches(), self.covered_positions())
+    }
+}
+
+// Mismatches per covered position, None without covered positions
+pub(crate) fn mismatch_rate(mismatches: usize, covered_positions: usize) -> Option<f64> {
+    (covered_positions > 0).then(|| mismatches as f64 / covered_positions as f64)
+}
+
+/// Per-read counts of a contig summed over its reads
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadTotals {
+    pub n_reads: u64,
+    pub matches: u64,
+    pub mismatches: u64,
+}
+
+impl ReadTotals {
+    /// Adds the counts of the given reads
+    pub fn add_reads<'a>(&mut self, read_counts: impl IntoIterator<Item = &'a ReadCounts>) {
+        for counts in read_counts {
+            self.n_reads += 1;
+            self.matches += counts.matches() as u64;
+            self.mismatches += counts.mismatches() as u64;
+        }
+    }
+
+    /// Number of bases that matched or mismatched the reference
+    pub fn counted_bases(&self) -> u64 {
+        self.matches + self.mismatches
+    }
+
+    /// Fraction of the counted bases that mismatched the reference, None without counted bases
+    pub fn mismatch_rate(&self) -> Option<f64> {
+        (self.counted_bases() > 0).then(|| self.mismatches as f64 / self.counted_bases() as f64)
+    }
+}
+
+/// Per-read counts summed over all contigs, for reads with alignments on several contigs, like
+/// chimeric reads split into supplementary alignments
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct ReadsAcrossContigs {
+    /// Contigs each read was counted on, in the order they were added, and its summed counts
+    pub reads: HashMap<String, (Vec<String>, ReadCounts)>,
+}
+
+impl ReadsAcrossContigs {
+    /// Adds the counts of the reads of a contig, or of some of its intervals
+    pub fn add_contig(&mut self, ref_name: &str, read_counts: &HashMap<String, ReadCounts>) {
+        for (read_id, counts) in read_counts {
+            let (ref_names, total) = self.reads.entry(read_id.clone()).or_default();
+            if !ref_names.iter().any(|name| name == ref_name) {
+                ref_names.push(ref_name.to_string());
+            }
+            total.merge(counts);
+        }
+    }
+}
+
+/// A single read base aligned to a reference position, as listed in [`Mode::Long`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BaseObservation {
+    /// 0-based reference position
+    pub pos: u32,
+    pub read_id: String,
+    pub ref_base: u8,
+    pub read_base: u8,
+    /// Base quality, None for reads without base qualities
+    pub qual: Option<u8>,
+}
+
+impl BaseObservation {
+    /// Whether the read base matches the reference base, ignoring case, see [`compare_base`].
+    /// Ambiguous bases like `N` never match.
+    pub fn is_match(&self, strict_iupac: bool) -> bool {
+        compare_base(self.read_base, self.ref_base, strict_iupac) == Some(true)
+    }
+}
+
+// Lists every base of a read aligned to a position of the [start, end) interval, whatever its
+// quality. Deleted positions have no read base and are left out.
+fn observe_bases(
+    record: &bam::Record,
+    reference_sequence: &[u8],
+    start: u32,
+    end: u32,
+    observations: &mut Vec<BaseObservation>,
+) {
+    let read_id = String::from_utf8_lossy(record.name());
+    let qualities = record.qualities();
+    for ref_pos in overlapping_ref_positions(record, start, end) {
+        let (RefPosLookup::Aligned(index), Some(reference_base)) =
+            (lookup_ref_pos(record, ref_pos), reference_sequence.get((ref_pos - start) as usize))
+        else {
+            continue;
+        };
+        observations.push(BaseObservation {
+            pos: ref_pos,
+            read_id: read_id.to_string(),
+            ref_base: *reference_base,
+            read_base: record.sequence().at(index),
+            qual: if qualities.available() { qualities.raw().get(index).copied() } else { None },
+        });
+    }
+}
+
+/// Number of reads showing each base (or a deletion) at a single reference position, and how
+/// many of them are mapped to the reverse strand
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct BaseCounts {
+    pub a: usize,
+    pub c: usize,
+    pub g: usize,
+    pub t: usize,
+    pub n: usize,
+    pub del: usize,
+    pub rev_a: usize,
+    pub rev_c: usize,
+    pub rev_g: usize,
+    pub rev_t: usize,
+    pub rev_n: usize,
+    pub rev_del: usize,
+    /// Sum of the distances of the non-reference bases (`A`, `C`, `G` or `T` at an unambiguous
+    /// reference base) from the nearer end of their read, and the number of these bases
+    pub alt_end_distance: usize,
+    pub alt_end_bases: usize,
+    /// Length of the homopolymer run of the reference the position belongs to, cut off at the
+    /// ends of its interval. Only set with [`PileupConfig::homopolymer_length`], 0 otherwise.
+    pub hp_len: u32,
+}
+
+impl BaseCounts {
+    pub fn add_base(&mut self, base: u8) {
+        match base.to_ascii_uppercase() {
+            b'A' => self.a += 1,
+            b'C' => self.c += 1,
+            b'G' => self.g += 1,
+            b'T' => self.t += 1,
+            _ => self.n += 1,
+        }
+    }
+
+    /// Adds a base of a read, also to the reverse strand counts if the read is reversed
+    pub fn add_read_base(&mut self, base: u8, is_reverse: bool) {
+        self.add_base(base);
+        if is_reverse {
+            match base.to_ascii_uppercase() {
+                b'A' => self.rev_a += 1,
+                b'C' => self.rev_c += 1,
+                b'G' => self.rev_g += 1,
+                b'T' => self.rev_t += 1,
+                _ => self.rev_n += 1,
+            }
+        }
+    }
+
+    /// Adds a deletion of a read, also to the reverse strand counts if the read is reversed
+    pub fn add_read_deletion(&mut self, is_reverse: bool) {
+        self.del += 1;
+        if is_reverse {
+            self.rev_del += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &BaseCounts) {
+        self.a += other.a;
+        self.c += other.c;
+        self.g += other.g;
+        self.t += other.t;
+        self.n += other.n;
+        self.del += other.del;
+        self.rev_a += other.rev_a;
+        self.rev_c += other.rev_c;
+        self.rev_g += other.rev_g;
+        self.rev_t += other.rev_t;
+        self.rev_n += other.rev_n;
+        self.rev_del += other.rev_del;
+        self.alt_end_distance += other.alt_end_distance;
+        self.alt_end_bases += other.alt_end_bases;
+        // Overlapping intervals may cut the run off at different ends
+        self.hp_len = self.hp_len.max(other.hp_len);
+    }
+
+    /// Mean distance of the non-reference bases from the nearer end of their read, None without
+    /// any. Small values hint at sequencing errors, which cluster at read ends.
+    pub fn mean_alt_end_distance(&self) -> Option<f64> {
+        (self.alt_end_bases > 0).then(|| self.alt_end_distance as f64 / self.alt_end_bases as f64)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.a + self.c + self.g + self.t + self.n + self.del
+    }
+
+    /// Number of reads with the given base (`A`, `C`, `G`, `T` or `N`, in either case), 0 for
+    /// any other byte
+    pub fn base_count(&self, base: u8) -> usize {
+        match base.to_ascii_uppercase() {
+            b'A' => self.a,
+            b'C' => self.c,
+            b'G' => self.g,
+            b'T' => self.t,
+            b'N' => self.n,
+            _ => 0,
+        }
+    }
+
+    /// Observed alleles, the bases `A`, `C`, `G`, `T` and deletions (`-`), with their number of
+    /// reads, most frequent first. Alleles with the same count keep this order. Alleles no read
+    /// shows are left out, as are `N` bases.
+    pub fn sorted_alleles(&self) -> Vec<(u8, usize)> {
+        let alleles = [(b'A', self.a), (b'C', self.c), (b'G', self.g), (b'T', self.t), (b'-', self.del)];
+        let mut alleles: Vec<(u8, usize)> = alleles.into_iter().filter(|(_, count)| *count > 0).collect();
+        // Stable, so ties stay in base order
+        alleles.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        alleles
+    }
+
+    /// Forward and reverse strand reads showing the reference base, and those showing another
+    /// base or a deletion. Reads with an `N` are in neither.
+    pub fn strand_counts(&self, reference_base: u8) -> StrandCounts {
+        let alleles = [
+            (b'A', self.a, self.rev_a),
+            (b'C', self.c, self.rev_c),
+            (b'G', self.g, self.rev_g),
+            (b'T', self.t, self.rev_t),
+            (b'-', self.del, self.rev_del),
+        ];
+        let mut strand_counts = StrandCounts::default();
+        for (base, total, rev) in alleles {
+            if base.eq_ignore_ascii_case(&reference_base) {
+                strand_counts.ref_fwd += total - rev;
+                strand_counts.ref_rev += rev;
+            } else {
+                strand_counts.alt_fwd += total - rev;
+                strand_counts.alt_rev += rev;
+            }
+        }
+        strand_counts
+    }
+}
+
+/// Reads supporting the reference and the other alleles at a position, by strand
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StrandCounts {
+    pub ref_fwd: usize,
+    pub ref_rev: usize,
+    pub alt_fwd: usize,
+    pub alt_rev: usize,
+}
+
+impl StrandCounts {
+    /// Phred-scaled p-value of a two-sided Fisher's exact test of the strand counts of the
+    /// reference against the other alleles, like the FS annotation of GATK. 0 means no sign of
+    /// strand bias, higher values mean the other alleles favour one strand more than the
+    /// reference does.
+    pub fn strand_bias(&self) -> f64 {
+        let p_value_ln = fisher_exact_ln_p(self.ref_fwd, self.ref_rev, self.alt_fwd, self.alt_rev);
+        // Also turns -0 for a p-value of 1 into 0
+        if p_value_ln < 0.0 {
+            -10.0 * p_value_ln / std::f64::consts::LN_10
+        } else {
+            0.0
+        }
+    }
+}
+
+// Natural log of the two-sided p-value of Fisher's exact test of the 2x2 table [[a, b], [c, d]].
+// The hypergeometric probabilities of all tables with the same margins are built up by their
+// ratios in log space, which neither underflows nor needs factorials of large depths.
+fn fisher_exact_ln_p(a: usize, b: usize, c: usize, d: usize) -> f64 {
+    let (row1, col1, n) = (a + b, a + c, a + b + c + d);
+    let (min_a, max_a) = ((row1 + col1).saturating_sub(n), row1.min(col1));
+    // ln P(x) relative to ln P(min_a), with
+    // P(x + 1) / P(x) = (row1 - x)(col1 - x) / ((x + 1)(n - row1 - col1 + x + 1))
+    let mut ln_probs = Vec::with_capacity(max_a - min_a + 1);
+    let mut ln_prob = 0.0;
+    ln_probs.push(ln_prob);
+    for x in min_a..max_a {
+        ln_prob += (((row1 - x) * (col1 - x)) as f64).ln() - (((x + 1) * (n + x + 1 - row1 - col1)) as f64).ln();
+        ln_probs.push(ln_prob);
+    }
+    // Sum the tables at most as likely as the observed one, with some slack for rounding
+    let observed = ln_probs[a - min_a] + 1e-7;
+    let extreme: Vec<f64> = ln_probs.iter().copied().filter(|ln_prob| *ln_prob <= observed).collect();
+    (ln_sum_exp(&extreme) - ln_sum_exp(&ln_probs)).min(0.0)
+}
+
+// ln(sum(exp(x))) of values that may be too small or large to exponentiate on their own
+fn ln_sum_exp(ln_values: &[f64]) -> f64 {
+    let max = ln_values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    max + ln_values.iter().map(|ln_value| (ln_value - max).exp()).sum::<f64>().ln()
+}
+
+/// Thresholds for calling a consensus base from the base counts of a position
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConsensusSettings {
+    /// Positions with fewer reads get no call
+    pub min_depth: usize,
+    /// A consensus differing from the reference is only a variant at this frequency or above
+    pub min_af: f64,
+}
+
+/// Most frequent base at a position
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsensusCall {
+    /// Consensus base, `*` for a deletion
+    pub base: u8,
+    pub depth: usize,
+    /// Fraction of reads showing the consensus base
+    pub frequency: f64,
+    /// Whether the consensus differs from the reference base
+    pub is_variant: bool,
+}
+
+/// Calls the most frequent base at a position, preferring the reference base on ties.
+/// Returns None if the depth is below `settings.min_depth` or the position is not covered.
+pub fn call_consensus(
+    reference_base: u8,
+    counts: &BaseCounts,
+    settings: &ConsensusSettings,
+) -> Option<ConsensusCall> {
+    let depth = counts.depth();
+    if depth == 0 || depth < settings.min_depth {
+        return None;
+    }
+    let reference_base = reference_base.to_ascii_uppercase();
+    let candidates = [
+        (b'A', counts.a),
+        (b'C', counts.c),
+        (b'G', counts.g),
+        (b'T', counts.t),
+        (b'N', counts.n),
+        (b'*', counts.del),
+    ];
+    // Start from the reference base, so that only strictly more frequent bases replace it
+    let mut count = candidates
+        .iter()
+        .find(|(candidate, _)| *candidate == reference_base)
+        .map_or(0, |(_, candidate_count)| *candidate_count);
+    let mut base = reference_base;
+    for (candidate, candidate_count) in candidates {
+        if candidate_count > count {
+            (base, count) = (candidate, candidate_count);
+        }
+    }
+    let frequency = count as f64 / depth as f64;
+    Some(ConsensusCall {
+        base,
+        depth,
+        frequency,
+        is_variant: base != reference_base && frequency >= settings.min_af,
+    })
+}
+
+// Adds the bases of a read within the [start, end) interval to the per-position counts of the
+// interval, except at the `dropped` positions. Bases aligned before start or from end on are not
+// counted, the reference sequence only holds the interval's bases. Soft-masked positions are left
+// out if the config says so.
+pub(crate) fn count_position_bases(
+    record: &bam::Record,
+    reference_sequence: &[u8],
+    start: u32,
+    end: u32,
+    config: &PileupConfig,
+    dropped: &HashSet<u32>,
+    positions: &mut BTreeMap<u32, (u8, BaseCounts)>,
+) {
+    let alignment = ReadAlignment::new(record);
+    for ref_pos in overlapping_ref_positions(record, start, end) {
+        if dropped.contains(&ref_pos) {
+            continue;
+        }
+        let reference_base = match reference_sequence.get((ref_pos - start) as usize) {
+            Some(reference_base) => *reference_base,
+            None => continue,
+        };
+        if config.fasta_case_sensitive && reference_base.is_ascii_lowercase() {
+            continue;
+        }
+        // Skipped positions and bases below the cutoff leave no row behind for the position
+        let lookup = alignment.lookup(ref_pos);
+        let counted = match lookup {
+            RefPosLookup::Aligned(index) => {
+                record.qualities().raw().get(index).copied().unwrap_or(0) >= config.qscore_cutoff
+            }
+            RefPosLookup::Deletion => true,
+            RefPosLookup::NotAligned => false,
+        };
+        if !counted {
+            continue;
+        }
+        let (stored_base, counts) = positions
+            .entry(ref_pos)
+            .or_insert_with(|| (reference_base, BaseCounts::default()));
+        // Reference bases from MD tags are unknown where the first read skipped the position
+        if is_ambiguous_base(*stored_base) && !is_ambiguous_base(reference_base) {
+            *stored_base = reference_base;
+        }
+        if config.max_depth.is_some_and(|max_depth| counts.depth() >= max_depth) {
+            continue;
+        }
+        match lookup {
+            RefPosLookup::Aligned(index) => {
+                let base = record.sequence().at(index);
+                counts.add_read_base(base, record.flag().is_reverse_strand());
+                if is_alt_base(base, reference_base) {
+                    // Soft-clipped bases count towards the read ends, they were sequenced too
+                    counts.alt_end_distance += index.min(record.sequence().len() - 1 - index);
+                    counts.alt_end_bases += 1;
+                }
+            }
+            RefPosLookup::Deletion => counts.add_read_deletion(record.flag().is_reverse_strand()),
+            RefPosLookup::NotAligned => {}
+        }
+    }
+}
+
+// Writes the reference bases rebuilt from the MD tag of a read into `md_reference`, which holds
+// the bases of the [start, end) interval, and returns the offsets of the read's span in it
+fn fill_md_reference(
+    record: &bam::Record,
+    start: u32,
+    end: u32,
+    md_reference: &mut [u8],
+) -> Result<std::ops::Range<usize>, BoxError> {
+    let read_id = String::from_utf8_lossy(record.name());
+    let read_bases = md_reference_bases(record)
+        .map_err(|e| format!("read {}: {}", read_id, e))?
+        .ok_or_else(|| format!("read {} has no MD tag, which --use-md needs", read_id))?;
+    let read_start = record.start().max(0) as u32;
+    let span = overlapping_ref_positions(record, start, end);
+    for ref_pos in span.clone() {
+        if let Some(base) = read_bases.get((ref_pos - read_start) as usize) {
+            md_reference[(ref_pos - start) as usize] = *base;
+        }
+    }
+    Ok((span.start - start) as usize..(span.end - start) as usize)
+}
+
+/// Rebuilds the reference bases a read is aligned to from its bases and its MD tag, from its
+/// start up to its end as given by [`bam::Record::calculate_end`]. Reference skips (`N` in the
+/// CIGAR string) are not in the MD tag, their bases are returned as `N`. Returns None if the
+/// read has no MD tag and an error if the tag doesn't match the CIGAR string.
+pub fn md_reference_bases(record: &bam::Record) -> Result<Option<Vec<u8>>, String> {
+    use bam::record::cigar::Operation;
+    use bam::record::tags::TagValue;
+
+    let md = match record.tags().get(b"MD") {
+        Some(TagValue::String(md, _)) => md.to_vec(),
+        Some(_) => return Err("MD tag is not a string".to_string()),
+        None => return Ok(None),
+    };
+    let sequence = record.sequence();
+    if sequence.len() == 0 {
+        return Err("no read bases to rebuild the reference from".to_string());
+    }
+    let mismatch = || format!("MD tag {} does not match the CIGAR string", String::from_utf8_lossy(&md));
+    // MD tags alternate runs of matching bases with a mismatching reference base or a `^` and
+    // the deleted reference bases, e.g. 10A5^AC6
+    let mut md_index = 0;
+    let read_number = |md_index: &mut usize| {
+        let digits_start = *md_index;
+        while md.get(*md_index).is_some_and(u8::is_ascii_digit) {
+            *md_index += 1;
+        }
+        String::from_utf8_lossy(&md[digits_start..*md_index]).parse::<usize>().unwrap_or(0)
+    };
+    let mut bases = Vec::new();
+    let mut matches_left = 0;
+    let mut query_index = 0;
+    for (len, operation) in record.cigar().iter() {
+        let len = len as usize;
+        match operation {
+            Operation::AlnMatch | Operation::SeqMatch | Operation::SeqMismatch => {
+                for _ in 0..len {
+                    if matches_left == 0 {
+                        matches_left = read_number(&mut md_index);
+                    }
+                    if matches_left > 0 {
+                        if query_index >= sequence.len() {
+                            return Err(mismatch());
+                        }
+                        bases.push(sequence.at(query_index).to_ascii_uppercase());
+                        matches_left -= 1;
+                    } else {
+                        match md.get(md_index) {
+                            Some(base) if base.is_ascii_alphabetic() => bases.push(base.to_ascii_uppercase()),
+                            _ => return Err(mismatch()),
+                        }
+                        md_index += 1;
+                    }
+                    query_index += 1;
+                }
+            }
+            Operation::Deletion => {
+                if matches_left > 0 || read_number(&mut md_index) > 0 || md.get(md_index) != Some(&b'^') {
+                    return Err(mismatch());
+                }
+                md_index += 1;
+                for _ in 0..len {
+                    match md.get(md_index) {
+                        Some(base) if base.is_ascii_alphabetic() => bases.push(base.to_ascii_uppercase()),
+                        _ => return Err(mismatch()),
+                    }
+                    md_index += 1;
+                }
+            }
+            Operation::Skip => bases.extend(std::iter::repeat_n(b'N', len)),
+            Operation::Insertion | Operation::Soft => query_index += len,
+            Operation::Hard | Operation::Padding => {}
+        }
+    }
+    Ok(Some(bases))
+}
+
+/// Returns the read base and its quality aligned to a reference position, or None
+/// if the position is deleted in (or not covered by) the read
+pub fn base_at_ref_pos(record: &bam::Record, ref_pos: u32) -> Option<(u8, u8)> {
+    let (seq_index, qual_index) = query_pos_for_ref(record, ref_pos)?;
+    let qual = record.qualities().raw().get(qual_index).copied().unwrap_or(0);
+    Some((record.sequence().at(seq_index), qual))
+}
+
+/// Walks the CIGAR to translate a reference coordinate into (seq_index, qual_index).
+/// Returns None if the position falls into a deletion/skip or outside the alignment.
+pub fn query_pos_for_ref(record: &bam::Record, ref_pos: u32) -> Option<(usize, usize)> {
+    match lookup_ref_pos(record, ref_pos) {
+        // Hard clipped bases are not stored, so seq and qual share the index
+        RefPosLookup::Aligned(index) => Some((index, index)),
+        RefPosLookup::Deletion | RefPosLookup::NotAligned => None,
+    }
+}
+
+// Where a reference position ends up in a read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RefPosLookup {
+    // Aligned to the query base at this index
+    Aligned(usize),
+    // Inside a deletion (D) of the read
+    Deletion,
+    // Outside the alignment or inside a reference skip (N)
+    NotAligned,
+}
+
+// Looks up reference positions in a read. Reads aligned as a single `M` or `=` operation, like
+// most short reads, are looked up by their offset from the alignment start instead of walking
+// the CIGAR for every position.
+pub(crate) struct ReadAlignment<'a> {
+    record: &'a bam::Record,
+    // Reference span of a read without gaps or clips
+    ungapped_span: Option<Range<u32>>,
+}
+
+impl<'a> ReadAlignment<'a> {
+    pub(crate) fn new(record: &'a bam::Record) -> ReadAlignment<'a> {
+        use bam::record::cigar::Operation;
+
+        let mut operations = record.cigar().iter();
+        let ungapped_span = match (operations.next(), operations.next()) {
+            (Some((len, Operation::AlnMatch | Operation::SeqMatch)), None) if record.start() >= 0 => {
+                let read_start = record.start() as u32;
+                Some(read_start..read_start + len)
+            }
+            _ => None,
+        };
+        ReadAlignment { record, ungapped_span }
+    }
+
+    pub(crate) fn lookup(&self, ref_pos: u32) -> RefPosLookup {
+        match &self.ungapped_span {
+            Some(span) if span.contains(&ref_pos) => RefPosLookup::Aligned((ref_pos - span.start) as usize),
+            Some(_) => RefPosLookup::NotAligned,
+            None => lookup_ref_pos(self.record, ref_pos),
+        }
+    }
+}
+
+pub(crate) fn lookup_ref_pos(record: &bam::Record, ref_pos: u32) -> RefPosLookup {
+    use bam::record::cigar::Operation;
+
+    if record.start() < 0 || ref_pos < record.start() as u32 {
+        return RefPosLookup::NotAligned;
+    }
+    let mut current_ref = record.start() as u32;
+    let mut current_query = 0u32;
+
+    for (len, operation) in record.cigar().iter() {
+        match operation {
+            Operation::AlnMatch | Operation::SeqMatch | Operation::SeqMismatch => {
+                if ref_pos < current_ref + len {
+                    return RefPosLookup::Aligned((current_query + ref_pos - current_ref) as usize);
+                }
+                current_ref += len;
+                current_query += len;
+            }
+            Operation::Deletion => {
+                if ref_pos < current_ref + len {
+                    return RefPosLookup::Deletion;
+                }
+                current_ref += len;
+            }
+            Operation::Skip => {
+                if ref_pos < current_ref + len {
+                    return RefPosLookup::NotAligned;
+                }
+                current_ref += len;
+            }
+            Operation::Insertion | Operation::Soft => current_query += len,
+            Operation::Hard | Operation::Padding => {}
+        }
+    }
+
+    RefPosLookup::NotAligned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::read;
+
+    #[test]
+    fn counts_high_quality_matches_and_mismatches() {
+        // Second and fourth base mismatch the reference
+        let record = read(1, "4M", "AGGA", "IIII");
+        let counts = count_matches_mismatches(&record, b"ACGT", 0, 4, 30, false, false);
+        assert_eq!(counts, (2, 2, 0, 0));
+    }
+
+    #[test]
+    fn ignores_bases_below_the_qscore_cutoff() {
+        // A low-quality match and a low-quality mismatch, neither is counted as such
+        let record = read(1, "4M", "ACTA", "I#I#");
+        let counts = count_matches_mismatches(&record, b"ACGT", 0, 4, 30, false, false);
+        assert_eq!(counts, (1, 1, 0, 2));
+    }
+
+    #[test]
+    fn counts_a_high_quality_mismatch_as_mismatch() {
+        let record = read(1, "1M", "T", "I");
+        assert_eq!(count_matches_mismatches(&record, b"A", 0, 1, 0, false, false), (0, 1, 0, 0));
+    }
+
+    #[test]
+    fn counts_bases_at_an_ambiguous_reference_base_on_their_own() {
+        // An N and a soft-masked t in the reference, the read has a T at the N and a G at the t
+        let record = read(1, "4M", "ATGT", "IIII");
+        assert_eq!(count_matches_mismatches(&record, b"ANtT", 0, 4, 0, false, false), (2, 1, 1, 0));
+        // A lowercase reference base matches its read base
+        let record = read(1, "4M", "ACGT", "IIII");
+        assert_eq!(count_matches_mismatches(&record, b"acNt", 0, 4, 0, false, false), (3, 0, 1, 0));
+    }
+
+    #[test]
+    fn counts_only_the_bases_of_a_read_starting_before_the_interval() {
+        // Aligned to 0-20, the interval is 10-15, the mismatches at 2 and 12 are before and in it
+        let record = read(1, "20M", "ACTTTGCAAGCTAACGGATC", "IIIIIIIIIIIIIIIIIIII");
+        assert_eq!(count_matches_mismatches(&record, b"CTTAC", 10, 15, 0, false, false), (4, 1, 0, 0));
+        // Ending before the interval, it has no base to count
+        assert_eq!(count_matches_mismatches(&record, b"ACGTA", 20, 25, 0, false, false), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn counts_indels_clipped_to_the_interval() {
+        // A 3bp deletion of 5-8 and a 2bp insertion after 12 on a read aligned to 0-17
+        let record = read(1, "5M3D4M2I5M", "ACGTAACGTTTACGTA", "IIIIIIIIIIIIIIII");
+        assert_eq!(count_insertions_deletions(&record, 0, 30), (2, 3));
+        // Only 7-8 of the deletion is in the interval
+        assert_eq!(count_insertions_deletions(&record, 7, 20), (2, 1));
+        // The insertion is after the end of the interval
+        assert_eq!(count_insertions_deletions(&record, 0, 10), (0, 3));
+    }
+
+    #[test]
+    fn ignores_the_bases_of_a_read_past_the_interval_end() {
+        // Aligned to 0-25, the reference only holds the bases of the interval 0-5
+        let record = read(1, "25M", "ACTTTGCAAGCTTACGGATCCATGC", "IIIIIIIIIIIIIIIIIIIIIIIII");
+        assert_eq!(count_matches_mismatches(&record, b"ACGTT", 0, 5, 0, false, false), (4, 1, 0, 0));
+    }
+
+    #[test]
+    fn looks_up_the_base_aligned_to_a_position() {
+        // Aligned to 10-15 with a mismatching T at 12 in place of the reference G
+        let record = read(11, "5M", "ACTTA", "II5II");
+        assert_eq!(base_at_ref_pos(&record, 12), Some((b'T', 20)));
+        assert_eq!(base_at_ref_pos(&record, 10), Some((b'A', 40)));
+        assert_eq!(base_at_ref_pos(&record, 9), None);
+        assert_eq!(base_at_ref_pos(&record, 15), None);
+        // Only the mismatching base is counted as such against the reference of 10-15
+        assert_eq!(count_matches_mismatches(&record, b"ACGTA", 10, 15, 0, false, false), (4, 1, 0, 0));
+    }
+
+    #[test]
+    fn returns_none_for_a_deleted_position() {
+        let record = read(11, "2M1D2M", "ACTA", "IIII");
+        assert_eq!(base_at_ref_pos(&record, 12), None);
+        assert_eq!(base_at_ref_pos(&record, 13), Some((b'T', 40)));
+    }
+
+    #[test]
+    fn maps_positions_around_an_insertion_and_a_deletion() {
+        // Aligned to 100-103, 2 inserted bases, 103-105, 3 deleted positions 105-108 and 108-111
+        let record = read(101, "1H2S3M2I2M3D3M", "GGACGTTCAGTC", "IIIIIIIIIIII");
+        assert_eq!(query_pos_for_ref(&record, 99), None);
+        assert_eq!(query_pos_for_ref(&record, 100), Some((2, 2)));
+        assert_eq!(query_pos_for_ref(&record, 102), Some((4, 4)));
+        // The inserted bases 5 and 6 are skipped
+        assert_eq!(query_pos_for_ref(&record, 103), Some((7, 7)));
+        assert_eq!(query_pos_for_ref(&record, 104), Some((8, 8)));
+        for ref_pos in 105..108 {
+            assert_eq!(query_pos_for_ref(&record, ref_pos), None);
+        }
+        assert_eq!(query_pos_for_ref(&record, 108), Some((9, 9)));
+        assert_eq!(query_pos_for_ref(&record, 110), Some((11, 11)));
+        assert_eq!(query_pos_for_ref(&record, 111), None);
+    }
+
+    #[test]
+    fn maps_no_base_to_a_reference_skip() {
+        let record = read(1, "2M5N2=", "ACGT", "IIII");
+        assert_eq!(query_pos_for_ref(&record, 1), Some((1, 1)));
+        assert_eq!(query_pos_for_ref(&record, 4), None);
+        assert_eq!(query_pos_for_ref(&record, 7), Some((2, 2)));
+    }
+
+    #[test]
+    fn scores_alt_reads_on_one_strand_as_the_strongest_bias() {
+        let strand_counts = |alt_fwd, alt_rev| StrandCounts { ref_fwd: 5, ref_rev: 5, alt_fwd, alt_rev };
+        // p = (252 + 50 + 1) / 3003 for the tables as unlikely as 5 forward and no reverse alt reads
+        let one_strand = strand_counts(5, 0).strand_bias();
+        assert!((one_strand - -10.0 * (303.0f64 / 3003.0).log10()).abs() < 1e-9, "{}", one_strand);
+        for alt_fwd in 1..5 {
+            assert!(strand_counts(alt_fwd, 5 - alt_fwd).strand_bias() < one_strand);
+        }
+        assert!((strand_counts(0, 5).strand_bias() - one_strand).abs() < 1e-9);
+        assert_eq!(strand_counts(3, 3).strand_bias(), 0.0);
+    }
+
+    #[test]
+    fn looks_up_and_counts_positions_like_the_cigar_walk() {
+        let reference = b"ACGTTGCAAGCTTACGGATCCATGCAGTCAGTACGATCGA";
+        // Mismatches and a low-quality base, ungapped reads take the fast path
+        let reads = [
+            (read(3, "20M", "GTAGGCAAGCTTACGGATCC", "IIIII#IIIIIIIIIIIIII"), true),
+            (read(3, "20=", "GTTGGCAAGCTTACGGATCC", "IIIIIIIIIIIIIIIIIIII"), true),
+            (read(3, "12M8M", "GTAGGCAAGCTTACGGATCC", "IIIII#IIIIIIIIIIIIII"), false),
+            (read(3, "3S10M2I5M", "TTTGTAGGCAAGCAATTACG", "IIIIIIII#IIIIIIIIIII"), false),
+            (read(3, "5M3D6M4N5M2S", "GTAGGAGCTTAGTCAGGG", "IIIII#IIIIIIIIIIII"), false),
+            (read(3, "2H4M1I3M2D4M", "GTTGAGCACTTA", "IIIII5IIIIII"), false),
+        ];
+        for (record, ungapped) in &reads {
+            let alignment = ReadAlignment::new(record);
+            assert_eq!(alignment.ungapped_span.is_some(), *ungapped);
+            for ref_pos in 0..reference.len() as u32 {
+                assert_eq!(alignment.lookup(ref_pos), lookup_ref_pos(record, ref_pos), "{}", ref_pos);
+            }
+            // The counts of every position from the CIGAR walk alone
+            let (mut matches, mut mismatches, mut low_qual) = (0, 0, 0);
+            for ref_pos in 0..reference.len() as u32 {
+                if let RefPosLookup::Aligned(index) = lookup_ref_pos(record, ref_pos) {
+                    if record.qualities().raw()[index] < 30 {
+                        low_qual += 1;
+                    } else if record.sequence().at(index) == reference[ref_pos as usize] {
+                        matches += 1;
+                    } else {
+                        mismatches += 1;
+                    }
+                }
+            }
+            let counts = count_matches_mismatches(record, reference, 0, reference.len() as u32, 30, false, false);
+            assert_eq!(counts, (matches, mismatches, 0, low_qual));
+        }
+        // An ungapped read gets the same per-position counts as the same read with a split CIGAR
+        let config = PileupConfig::new("", "", Vec::new());
+        let position_counts = |record: &bam::Record| {
+            let mut positions = BTreeMap::new();
+            count_position_bases(record, reference, 0, 40, &config, &HashSet::new(), &mut positions);
+            positions
+        };
+        assert_eq!(position_counts(&reads[0].0), position_counts(&reads[2].0));
+        // The low-quality base leaves its position out
+        assert_eq!(position_counts(&reads[0].0).len(), 19);
+    }
+
+    #[test]
+    fn counts_no_position_of_a_skip_or_of_only_low_quality_bases() {
+        let config = PileupConfig::new("", "", Vec::new());
+        let reference = vec![b'A'; 100];
+        // Aligned at 0-9 and 60-69 around a skip, with a base below the cutoff at 5
+        let record = read(1, "10M50N10M", "AAAAAAAAAAAAAAAAAAAA", "IIIII#IIIIIIIIIIIIII");
+        let mut positions = BTreeMap::new();
+        count_position_bases(&record, &reference, 0, 100, &config, &HashSet::new(), &mut positions);
+        let expected: Vec<u32> = (0..5).chain(6..10).chain(60..70).collect();
+        assert_eq!(positions.keys().copied().collect::<Vec<_>>(), expected);
+        // A deletion is counted at its positions
+        let record = read(1, "5M3D5M", "AAAAAAAAAA", "IIIIIIIIII");
+        let mut positions = BTreeMap::new();
+        count_position_bases(&record, &reference, 0, 100, &config, &HashSet::new(), &mut positions);
+        assert_eq!(positions.len(), 13);
+        assert_eq!(positions[&6].1.depth(), 1);
+    }
+
+    #[test]
+    fn matches_the_bases_of_an_iupac_code_in_either_case() {
+        assert_eq!(compare_base(b'A', b'R', false), Some(true));
+        assert_eq!(compare_base(b'g', b'r', false), Some(true));
+        assert_eq!(compare_base(b'C', b'R', false), Some(false));
+        assert_eq!(compare_base(b'T', b'B', false), Some(true));
+        assert_eq!(compare_base(b'A', b'B', false), Some(false));
+        assert_eq!(compare_base(b'A', b'N', false), None);
+        assert_eq!(compare_base(b'A', b'R', true), None);
+        assert_eq!(compare_base(b'A', b'a', true), Some(true));
+    }
+}
@@ -0,0 +1,137 @@
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+// Time between progress lines when stderr is not a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+// Bar drawn on the terminal, if any, above which the messages of all threads are printed
+static ACTIVE_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+thread_local! {
+    // Whether the bar is already removed for this thread by Progress::suspend, which can't be nested
+    static SUSPENDED: Cell<bool> = const { Cell::new(false) };
+}
+
+// Prints a line on stderr, removing the progress bar while it is printed so the two aren't mixed
+pub(crate) fn print_message(message: fmt::Arguments) {
+    let bar = ACTIVE_BAR.lock().unwrap().clone();
+    match bar {
+        Some(bar) if !SUSPENDED.get() => bar.suspend(|| eprintln!("{}", message)),
+        _ => eprintln!("{}", message),
+    }
+}
+
+// Progress over the intervals of a run. On a terminal it is drawn as a bar on the last line of
+// stderr, otherwise a `processed N/M intervals` line is logged every LOG_INTERVAL.
+pub(crate) struct Progress {
+    bar: ProgressBar,
+    // Time of the last progress line, only set when the progress is logged
+    last_log: Option<Mutex<Instant>>,
+    total: usize,
+}
+
+impl Progress {
+    pub(crate) fn new(total: usize, enabled: bool) -> Progress {
+        let terminal = io::stderr().is_terminal();
+        let bar = if enabled && terminal {
+            let bar = ProgressBar::with_draw_target(Some(total as u64), ProgressDrawTarget::stderr());
+            bar.set_style(
+                ProgressStyle::with_template("[{bar:40}] {pos}/{len} intervals")
+                    .unwrap()
+                    .progress_chars("= "),
+            );
+            bar
+        } else {
+            ProgressBar::hidden()
+        };
+        if !bar.is_hidden() {
+            *ACTIVE_BAR.lock().unwrap() = Some(bar.clone());
+        }
+        Progress {
+            bar,
+            last_log: (enabled && !terminal).then(|| Mutex::new(Instant::now())),
+            total,
+        }
+    }
+
+    // Marks one more interval as done
+    pub(crate) fn advance(&self) {
+        self.bar.inc(1);
+        if let Some(last_log) = &self.last_log {
+            let mut last_log = last_log.lock().unwrap();
+            if last_log.elapsed() >= LOG_INTERVAL {
+                eprintln!("processed {}/{} intervals", self.bar.position(), self.total);
+                *last_log = Instant::now();
+            }
+        }
+    }
+
+    // Removes the bar while `print` writes to stderr or the terminal, so that its messages and
+    // output are not mixed into the bar, and draws it again after
+    pub(crate) fn suspend<R>(&self, print: impl FnOnce() -> R) -> R {
+        self.bar.suspend(|| {
+            SUSPENDED.set(true);
+            let result = print();
+            SUSPENDED.set(false);
+            result
+        })
+    }
+
+    // Removes the bar for good, so that an error can be printed
+    pub(crate) fn clear(&self) {
+        self.remove_bar();
+    }
+
+    // Removes the bar or logs the final count
+    pub(crate) fn finish(&self) {
+        self.remove_bar();
+        if self.last_log.is_some() {
+            eprintln!("processed {}/{} intervals", self.bar.position(), self.total);
+        }
+    }
+
+    fn remove_bar(&self) {
+        self.bar.finish_and_clear();
+        let mut active_bar = ACTIVE_BAR.lock().unwrap();
+        if active_bar.as_ref().is_some_and(|bar| bar.is_finished()) {
+            *active_bar = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_the_bar_only_on_a_terminal() {
+        let progress = Progress::new(3, true);
+        assert_eq!(progress.bar.is_hidden(), !io::stderr().is_terminal());
+        assert_eq!(progress.last_log.is_some(), !io::stderr().is_terminal());
+        progress.advance();
+        assert_eq!(progress.bar.position(), 1);
+    }
+
+    #[test]
+    fn shows_no_progress_when_disabled() {
+        let progress = Progress::new(3, false);
+        assert!(progress.bar.is_hidden());
+        assert!(progress.last_log.is_none());
+    }
+
+    #[test]
+    fn prints_messages_while_the_bar_is_suspended() {
+        let progress = Progress::new(3, true);
+        *ACTIVE_BAR.lock().unwrap() = Some(progress.bar.clone());
+        // Messages of the suspending thread and of others go through without waiting on each other
+        progress.suspend(|| print_message(format_args!("inside")));
+        std::thread::spawn(|| print_message(format_args!("worker"))).join().unwrap();
+        progress.finish();
+        assert!(ACTIVE_BAR.lock().unwrap().is_none());
+    }
+}
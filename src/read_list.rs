@@ -0,0 +1,32 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+use crate::error::Error;
+use crate::BoxError;
+
+/// Loads a list of read names, one per line, from a plain or gzip-compressed file. Whitespace
+/// around the names and empty lines are ignored.
+pub fn load_read_list(read_list_path: &str) -> Result<HashSet<Vec<u8>>, BoxError> {
+    let mut file = File::open(read_list_path)
+        .map_err(|e| Error::Io(format!("failed to open read list {}: {}", read_list_path, e)))?;
+    // Tell gzip from plain text by the magic bytes rather than the file name
+    let mut magic = [0u8; 2];
+    let is_gzip = file.read(&mut magic)? == 2 && magic == [0x1f, 0x8b];
+    let file = File::open(read_list_path)?;
+    let reader: Box<dyn BufRead> = if is_gzip {
+        Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(file)))
+    } else {
+        Box::new(BufReader::new(file))
+    };
+
+    let mut read_names = HashSet::new();
+    for line in reader.split(b'\n') {
+        let line = line.map_err(|e| Error::Io(format!("failed to read read list {}: {}", read_list_path, e)))?;
+        let read_name = line.trim_ascii();
+        if !read_name.is_empty() {
+            read_names.insert(read_name.to_vec());
+        }
+    }
+    Ok(read_names)
+}
@@ -0,0 +1,578 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use bio::io::fasta;
+use flate2::read::{GzDecoder, MultiGzDecoder};
+
+use crate::bed::BedRegion;
+use crate::error::Error;
+use crate::{group_regions_by_contig, BoxError};
+
+/// Random access reader of a plain or BGZF-compressed (`bgzip`) FASTA file
+pub type IndexedFasta = fasta::IndexedReader<FastaFile>;
+
+/// Creates a samtools-style <fasta>.fai index next to the FASTA file if there is none yet, and
+/// the <fasta>.gzi index of its blocks for a BGZF-compressed file. Plain gzip can't be read at
+/// random and is an error.
+pub fn ensure_fasta_index(fasta_file_path: &str) -> Result<(), BoxError> {
+    check_fasta_compression(fasta_file_path)?;
+    if fasta_compression(fasta_file_path)? == FastaCompression::Bgzf {
+        ensure_gzi_index(fasta_file_path)?;
+    }
+    let fai_file_path = format!("{}.fai", fasta_file_path);
+    if Path::new(&fai_file_path).exists() {
+        return Ok(());
+    }
+    log::info!("Creating missing FASTA index {}", fai_file_path);
+
+    // Offsets in the index are those of the uncompressed file, also for BGZF
+    let mut reader = open_fasta_text(fasta_file_path)?;
+    let mut fai_lines: Vec<String> = Vec::new();
+    // name, sequence length, offset of the first base, bases per line, bytes per line
+    let mut current: Option<(String, u64, u64, u64, u64)> = None;
+    // Set once a line shorter than the line width was seen, only the last line may be shorter
+    let mut short_line_seen = false;
+    let mut offset = 0u64;
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        let bytes_read = reader.read_line(&mut line)? as u64;
+        if bytes_read == 0 {
+            break;
+        }
+        let line_offset = offset;
+        offset += bytes_read;
+
+        if let Some(header) = line.strip_prefix('>') {
+            if let Some((name, len, seq_offset, line_bases, line_width)) = current.take() {
+                fai_lines.push(format!("{}\t{}\t{}\t{}\t{}", name, len, seq_offset, line_bases, line_width));
+            }
+            let name = header.split_whitespace().next().unwrap_or("").to_string();
+            current = Some((name, 0, offset, 0, 0));
+            short_line_seen = false;
+            continue;
+        }
+
+        let (name, len, _, line_bases, line_width) = current
+            .as_mut()
+            .ok_or_else(|| format!("FASTA file {} does not start with a '>' header", fasta_file_path))?;
+        let bases = line.trim_end_matches(['\n', '\r']).len() as u64;
+        if bases == 0 {
+            continue;
+        }
+        if *line_bases == 0 {
+            *line_bases = bases;
+            *line_width = bytes_read;
+        } else if short_line_seen || bases > *line_bases {
+            return Err(format!(
+                "cannot index {}: sequence {} has inconsistent line lengths (at byte {})",
+                fasta_file_path, name, line_offset
+            )
+            .into());
+        }
+        if bases < *line_bases {
+            short_line_seen = true;
+        }
+        *len += bases;
+    }
+    if let Some((name, len, seq_offset, line_bases, line_width)) = current {
+        fai_lines.push(format!("{}\t{}\t{}\t{}\t{}", name, len, seq_offset, line_bases, line_width));
+    }
+
+    let mut fai_file = File::create(&fai_file_path)?;
+    for fai_line in fai_lines {
+        writeln!(fai_file, "{}", fai_line)?;
+    }
+    Ok(())
+}
+
+/// Returns the names of all sequences in a FASTA file, read from its .fai index if there is one
+/// and otherwise from the `>` header lines
+pub fn fasta_sequence_names(fasta_file_path: &str) -> Result<HashSet<String>, BoxError> {
+    Ok(fasta_sequence_lengths(fasta_file_path)?.into_keys().collect())
+}
+
+/// Returns the length of every sequence in a FASTA file by its name, read from its .fai index if
+/// there is one and otherwise by counting the bases after each `>` header line
+pub fn fasta_sequence_lengths(fasta_file_path: &str) -> Result<HashMap<String, u64>, BoxError> {
+    let fai_file_path = format!("{}.fai", fasta_file_path);
+    let (file_path, from_index) = if Path::new(&fai_file_path).exists() {
+        (fai_file_path.as_str(), true)
+    } else {
+        (fasta_file_path, false)
+    };
+    let reader = if from_index {
+        Box::new(BufReader::new(
+            File::open(file_path).map_err(|e| Error::Io(format!("failed to open FASTA index {}: {}", file_path, e)))?,
+        ))
+    } else {
+        open_fasta_text(file_path)?
+    };
+
+    let mut lengths = HashMap::new();
+    let mut current_name: Option<String> = None;
+    for line in reader.lines() {
+        let line = line?;
+        if from_index {
+            let mut fields = line.split('\t');
+            if let (Some(name), Some(len)) = (fields.next().filter(|name| !name.is_empty()), fields.next()) {
+                let len = len
+                    .parse()
+                    .map_err(|_| format!("malformed line in FASTA index {}: {}", file_path, line))?;
+                lengths.insert(name.to_string(), len);
+            }
+        } else if let Some(header) = line.strip_prefix('>') {
+            current_name = header.split_whitespace().next().map(str::to_string);
+            if let Some(name) = &current_name {
+                lengths.insert(name.clone(), 0);
+            }
+        } else if let Some(len) = current_name.as_ref().and_then(|name| lengths.get_mut(name)) {
+            *len += line.trim_end().len() as u64;
+        }
+    }
+    Ok(lengths)
+}
+
+/// Opens a FASTA file with its .fai index for random access, through its .gzi index if it is
+/// BGZF-compressed. Both indices have to exist, see [`ensure_fasta_index`].
+pub fn open_indexed_fasta(fasta_file_path: &str) -> Result<IndexedFasta, BoxError> {
+    check_fasta_compression(fasta_file_path)?;
+    let open_error = |e| Error::Io(format!("failed to open FASTA file {}: {}", fasta_file_path, e));
+    let file = File::open(fasta_file_path).map_err(open_error)?;
+    let fasta_file = match fasta_compression(fasta_file_path)? {
+        FastaCompression::Bgzf => FastaFile::Bgzf(BgzfReader::new(file, &format!("{}.gzi", fasta_file_path))?),
+        _ => FastaFile::Plain(file),
+    };
+    let fai_file_path = format!("{}.fai", fasta_file_path);
+    let fai_file = File::open(&fai_file_path)
+        .map_err(|e| Error::Io(format!("failed to open FASTA index {}: {}", fai_file_path, e)))?;
+    Ok(fasta::IndexedReader::new(fasta_file, fai_file)
+        .map_err(|e| Error::Io(format!("failed to read FASTA index {}: {}", fai_file_path, e)))?)
+}
+
+/// Reads the reference bases of the [start, end) interval of a contig
+pub fn fetch_interval(reference: &mut IndexedFasta, ref_name: &str, start: u32, end: u32) -> Result<Vec<u8>, BoxError> {
+    let mut sequence = Vec::new();
+    let fetched = reference
+        .fetch(ref_name, start as u64, end as u64)
+        .and_then(|_| reference.read(&mut sequence));
+    if let Err(e) = fetched {
+        if !reference.index.sequences().iter().any(|contig| contig.name == ref_name) {
+            let message = format!("contig \"{}\" from BED not found in FASTA file", ref_name);
+            return Err(Error::ContigMissing(message).into());
+        }
+        return Err(Error::Fetch(format!(
+            "failed to read reference bases of {}:{}-{}: {}",
+            ref_name,
+            start + 1,
+            end,
+            e
+        ))
+        .into());
+    }
+    Ok(sequence)
+}
+
+/// Reference bases of a set of regions, read from the FASTA file once and then shared read-only
+/// by all worker threads and samples. Overlapping and adjacent regions are merged, so only the
+/// bases of the regions are held, and each of them once.
+#[derive(Debug, Default)]
+pub struct ReferenceCache {
+    // Merged intervals of each contig, sorted by start, with their bases
+    intervals: HashMap<String, Vec<(u32, Vec<u8>)>>,
+}
+
+impl ReferenceCache {
+    /// Reads the reference bases of the regions from a FASTA file with a .fai index
+    pub fn load(fasta_file_path: &str, regions: &[BedRegion]) -> Result<ReferenceCache, BoxError> {
+        let mut reference = open_indexed_fasta(fasta_file_path)?;
+        let mut intervals = HashMap::new();
+        for (ref_name, contig_regions) in group_regions_by_contig(regions) {
+            let mut spans: Vec<Range<u32>> = contig_regions.iter().map(|region| region.start..region.end).collect();
+            spans.sort_unstable_by_key(|span| span.start);
+            let mut merged: Vec<Range<u32>> = Vec::new();
+            for span in spans {
+                match merged.last_mut() {
+                    Some(last) if span.start <= last.end => last.end = last.end.max(span.end),
+                    _ => merged.push(span),
+                }
+            }
+            let mut contig_intervals = Vec::with_capacity(merged.len());
+            for span in merged {
+                let sequence = fetch_interval(&mut reference, &ref_name, span.start, span.end)?;
+                contig_intervals.push((span.start, sequence));
+            }
+            intervals.insert(ref_name, contig_intervals);
+        }
+        Ok(ReferenceCache { intervals })
+    }
+
+    /// Reference bases of the [start, end) interval of a contig, if it is part of the cached
+    /// regions
+    pub fn get(&self, ref_name: &str, start: u32, end: u32) -> Option<&[u8]> {
+        let contig_intervals = self.intervals.get(ref_name)?;
+        let index = contig_intervals.partition_point(|(interval_start, _)| *interval_start <= start);
+        let (interval_start, sequence) = contig_intervals.get(index.checked_sub(1)?)?;
+        sequence.get((start - interval_start) as usize..(end - interval_start) as usize)
+    }
+}
+
+/// Fails for a FASTA file compressed with plain gzip instead of `bgzip`, which can only be read
+/// from the start
+pub fn check_fasta_compression(fasta_file_path: &str) -> Result<(), BoxError> {
+    if fasta_compression(fasta_file_path)? == FastaCompression::Gzip {
+        return Err(format!(
+            "FASTA file {} is compressed with plain gzip, which can't be read at random; recompress it with \
+             `gunzip -c {} | bgzip > ref.fa.gz` or decompress it",
+            fasta_file_path, fasta_file_path
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FastaCompression {
+    None,
+    Gzip,
+    Bgzf,
+}
+
+// Tells plain, gzip and BGZF files apart by their first bytes rather than the file name. BGZF
+// blocks are gzip members with a BC extra field holding the block size.
+fn fasta_compression(fasta_file_path: &str) -> Result<FastaCompression, BoxError> {
+    let mut file = File::open(fasta_file_path)
+        .map_err(|e| Error::Io(format!("failed to open FASTA file {}: {}", fasta_file_path, e)))?;
+    let mut magic = [0u8; 2];
+    if file.read(&mut magic)? < 2 || magic != [0x1f, 0x8b] {
+        return Ok(FastaCompression::None);
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(match read_bgzf_block(&mut file) {
+        Ok(_) => FastaCompression::Bgzf,
+        Err(_) => FastaCompression::Gzip,
+    })
+}
+
+// Text of a FASTA file, decompressed if it is gzip- or BGZF-compressed
+fn open_fasta_text(fasta_file_path: &str) -> Result<Box<dyn BufRead>, BoxError> {
+    let file = File::open(fasta_file_path)
+        .map_err(|e| Error::Io(format!("failed to open FASTA file {}: {}", fasta_file_path, e)))?;
+    Ok(match fasta_compression(fasta_file_path)? {
+        FastaCompression::None => Box::new(BufReader::new(file)),
+        _ => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+    })
+}
+
+// Reads the next BGZF block, compressed and with its header, or None at the end of the file
+fn read_bgzf_block<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let not_bgzf = || io::Error::new(io::ErrorKind::InvalidData, "not a BGZF block");
+    // ID1, ID2, CM, FLG, MTIME (4), XFL, OS and XLEN (2), then the extra fields
+    let mut header = [0u8; 12];
+    if reader.read(&mut header[..1])? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[1..])?;
+    if header[..2] != [0x1f, 0x8b] || header[3] & 0x04 == 0 {
+        return Err(not_bgzf());
+    }
+    let mut extra = vec![0u8; u16::from_le_bytes([header[10], header[11]]) as usize];
+    reader.read_exact(&mut extra)?;
+    let mut fields = extra.as_slice();
+    let mut block_size = None;
+    while fields.len() >= 4 {
+        let field_len = u16::from_le_bytes([fields[2], fields[3]]) as usize;
+        if fields[..2] == *b"BC" && field_len == 2 && fields.len() >= 6 {
+            block_size = Some(u16::from_le_bytes([fields[4], fields[5]]) as usize + 1);
+        }
+        fields = &fields[(4 + field_len).min(fields.len())..];
+    }
+    let block_size = block_size.ok_or_else(not_bgzf)?;
+    let header_len = header.len() + extra.len();
+    if block_size < header_len + 8 {
+        return Err(not_bgzf());
+    }
+    let mut block = Vec::with_capacity(block_size);
+    block.extend_from_slice(&header);
+    block.extend_from_slice(&extra);
+    block.resize(block_size, 0);
+    reader.read_exact(&mut block[header_len..])?;
+    Ok(Some(block))
+}
+
+// Writes the .gzi index of a BGZF file next to it if there is none yet
+fn ensure_gzi_index(fasta_file_path: &str) -> Result<(), BoxError> {
+    let gzi_file_path = format!("{}.gzi", fasta_file_path);
+    if Path::new(&gzi_file_path).exists() {
+        return Ok(());
+    }
+    log::info!("Creating missing BGZF index {}", gzi_file_path);
+    write_gzi_index(&gzi_file_path, &bgzf_blocks(fasta_file_path)?.offsets)?;
+    Ok(())
+}
+
+// Blocks of a BGZF file as listed in its .gzi index, and the size of the file
+pub(crate) struct BgzfBlocks {
+    // Compressed and uncompressed offset of every non-empty block after the first
+    pub(crate) offsets: Vec<(u64, u64)>,
+    pub(crate) compressed_len: u64,
+    pub(crate) uncompressed_len: u64,
+}
+
+// Reads the headers of all blocks of a BGZF file
+pub(crate) fn bgzf_blocks(file_path: &str) -> Result<BgzfBlocks, BoxError> {
+    let mut reader = BufReader::new(File::open(file_path)?);
+    let mut offsets = Vec::new();
+    let (mut compressed_offset, mut uncompressed_offset) = (0u64, 0u64);
+    let read_error = |e| Error::Io(format!("failed to read BGZF file {}: {}", file_path, e));
+    while let Some(block) = read_bgzf_block(&mut reader).map_err(read_error)? {
+        // The last 4 bytes of a block hold its uncompressed size
+        let block_len = block.len();
+        let uncompressed_len = u32::from_le_bytes(block[block_len - 4..].try_into().unwrap()) as u64;
+        if compressed_offset > 0 && uncompressed_len > 0 {
+            offsets.push((compressed_offset, uncompressed_offset));
+        }
+        compressed_offset += block_len as u64;
+        uncompressed_offset += uncompressed_len;
+    }
+    Ok(BgzfBlocks {
+        offsets,
+        compressed_len: compressed_offset,
+        uncompressed_len: uncompressed_offset,
+    })
+}
+
+// Writes a .gzi index of BGZF blocks: the number of blocks after the first, then the compressed
+// and uncompressed offset of each (little-endian u64), as `bgzip -r` does
+pub(crate) fn write_gzi_index(gzi_file_path: &str, offsets: &[(u64, u64)]) -> io::Result<()> {
+    let mut gzi_file = BufWriter::new(File::create(gzi_file_path)?);
+    gzi_file.write_all(&(offsets.len() as u64).to_le_bytes())?;
+    for (compressed_offset, uncompressed_offset) in offsets {
+        gzi_file.write_all(&compressed_offset.to_le_bytes())?;
+        gzi_file.write_all(&uncompressed_offset.to_le_bytes())?;
+    }
+    gzi_file.flush()
+}
+
+/// FASTA file read and seeked in the offsets of its uncompressed bases, as in its .fai index
+pub enum FastaFile {
+    Plain(File),
+    Bgzf(BgzfReader),
+}
+
+impl Read for FastaFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            FastaFile::Plain(file) => file.read(buf),
+            FastaFile::Bgzf(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for FastaFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            FastaFile::Plain(file) => file.seek(pos),
+            FastaFile::Bgzf(reader) => reader.seek(pos),
+        }
+    }
+}
+
+/// BGZF-compressed file read and seeked in uncompressed offsets, decompressing only the blocks
+/// that are read. The blocks are found with the .gzi index written by `bgzip -r` or
+/// `samtools faidx`.
+pub struct BgzfReader {
+    file: BufReader<File>,
+    // Compressed and uncompressed offset of the start of each indexed block, from (0, 0)
+    blocks: Vec<(u64, u64)>,
+    pos: u64,
+    // Last decompressed block and its uncompressed offset
+    block_start: u64,
+    block: Vec<u8>,
+}
+
+impl BgzfReader {
+    /// Reads the .gzi index of the blocks of a BGZF file
+    pub fn new(file: File, gzi_file_path: &str) -> Result<BgzfReader, BoxError> {
+        let gzi_error = |e| Error::Io(format!("failed to read BGZF index {}: {}", gzi_file_path, e));
+        let mut gzi = BufReader::new(File::open(gzi_file_path).map_err(gzi_error)?);
+        let mut read_u64 = || -> io::Result<u64> {
+            let mut bytes = [0u8; 8];
+            gzi.read_exact(&mut bytes)?;
+            Ok(u64::from_le_bytes(bytes))
+        };
+        let n_blocks = read_u64().map_err(gzi_error)?;
+        let mut blocks = vec![(0, 0)];
+        for _ in 0..n_blocks {
+            blocks.push((read_u64().map_err(gzi_error)?, read_u64().map_err(gzi_error)?));
+        }
+        Ok(BgzfReader {
+            file: BufReader::new(file),
+            blocks,
+            pos: 0,
+            block_start: 0,
+            block: Vec::new(),
+        })
+    }
+
+    // Decompresses the block holding the current position, starting from the last indexed
+    // block before it. Leaves an empty block at the end of the file.
+    fn load_block(&mut self) -> io::Result<()> {
+        let index = self.blocks.partition_point(|(_, uncompressed_offset)| *uncompressed_offset <= self.pos) - 1;
+        let (compressed_offset, uncompressed_offset) = self.blocks[index];
+        self.file.seek(SeekFrom::Start(compressed_offset))?;
+        self.block_start = uncompressed_offset;
+        self.block.clear();
+        while self.pos >= self.block_start + self.block.len() as u64 {
+            self.block_start += self.block.len() as u64;
+            self.block.clear();
+            let Some(compressed) = read_bgzf_block(&mut self.file)? else {
+                break;
+            };
+            GzDecoder::new(compressed.as_slice()).read_to_end(&mut self.block)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for BgzfReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.block_start || self.pos >= self.block_start + self.block.len() as u64 {
+            self.load_block()?;
+        }
+        let offset = (self.pos - self.block_start) as usize;
+        if offset >= self.block.len() {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.block.len() - offset);
+        buf[..n].copy_from_slice(&self.block[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for BgzfReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            // The uncompressed size is only known after decompressing the last block
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "seeking from the end of a BGZF file"));
+            }
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Three contigs wrapped at 8 bases per line, so intervals cross line ends
+    const FASTA: &str = ">chr1 first\nAAAACCCC\nGGGGTTTT\nAC\n>chr2\nACGTACGT\nTTGGCCAA\nGATC\n>chr3\nCCCCCCC\n";
+
+    fn write_fasta(dir: &tempfile::TempDir) -> String {
+        let fasta_file_path = dir.path().join("ref.fa").to_str().unwrap().to_string();
+        std::fs::write(&fasta_file_path, FASTA).unwrap();
+        fasta_file_path
+    }
+
+    // The FASTA file compressed with a BGZF block per line and the end-of-file block
+    fn write_bgzf_fasta(dir: &tempfile::TempDir) -> String {
+        let fasta_file_path = dir.path().join("ref.fa.gz").to_str().unwrap().to_string();
+        let mut file = File::create(&fasta_file_path).unwrap();
+        let mut block = bam::bgzip::Block::new();
+        for line in FASTA.split_inclusive('\n').chain([""]) {
+            block.reset();
+            block.extend_contents(line.as_bytes());
+            block.compress(flate2::Compression::default()).unwrap();
+            block.dump(&mut file).unwrap();
+        }
+        fasta_file_path
+    }
+
+    #[test]
+    fn fetches_an_interval_starting_mid_contig() {
+        let dir = tempfile::tempdir().unwrap();
+        let fasta_file_path = write_fasta(&dir);
+        ensure_fasta_index(&fasta_file_path).unwrap();
+        let fai = std::fs::read_to_string(format!("{}.fai", fasta_file_path)).unwrap();
+        assert_eq!(fai, "chr1\t18\t12\t8\t9\nchr2\t20\t39\t8\t9\nchr3\t7\t68\t7\t8\n");
+
+        let mut reference = open_indexed_fasta(&fasta_file_path).unwrap();
+        assert_eq!(fetch_interval(&mut reference, "chr2", 6, 11).unwrap(), b"GTTTG");
+        assert_eq!(fetch_interval(&mut reference, "chr1", 15, 18).unwrap(), b"TAC");
+        assert_eq!(fetch_interval(&mut reference, "chr3", 0, 1).unwrap(), b"C");
+    }
+
+    #[test]
+    fn caches_the_bases_of_regions_on_several_contigs() {
+        let dir = tempfile::tempdir().unwrap();
+        let fasta_file_path = write_fasta(&dir);
+        ensure_fasta_index(&fasta_file_path).unwrap();
+        let regions = [BedRegion::new("chr2", 6, 11), BedRegion::new("chr2", 9, 14), BedRegion::new("chr1", 3, 5)];
+        let cache = ReferenceCache::load(&fasta_file_path, &regions).unwrap();
+        assert_eq!(cache.get("chr2", 6, 14), Some(&b"GTTTGGCC"[..]));
+        assert_eq!(cache.get("chr2", 10, 12), Some(&b"GG"[..]));
+        assert_eq!(cache.get("chr1", 3, 5), Some(&b"AC"[..]));
+        assert_eq!(cache.get("chr1", 0, 2), None);
+        assert_eq!(cache.get("chr3", 0, 1), None);
+    }
+
+    #[test]
+    fn names_a_contig_missing_from_the_fasta_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let fasta_file_path = write_fasta(&dir);
+        ensure_fasta_index(&fasta_file_path).unwrap();
+        let mut reference = open_indexed_fasta(&fasta_file_path).unwrap();
+        let error = fetch_interval(&mut reference, "chrX", 0, 1).unwrap_err();
+        assert!(error.to_string().contains("\"chrX\""), "{}", error);
+    }
+
+    #[test]
+    fn reads_a_bgzf_fasta_file_like_the_plain_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain_file_path = write_fasta(&dir);
+        let bgzf_file_path = write_bgzf_fasta(&dir);
+        ensure_fasta_index(&plain_file_path).unwrap();
+        ensure_fasta_index(&bgzf_file_path).unwrap();
+        let fai = |fasta_file_path| std::fs::read_to_string(format!("{}.fai", fasta_file_path)).unwrap();
+        assert_eq!(fai(&bgzf_file_path), fai(&plain_file_path));
+        assert!(Path::new(&format!("{}.gzi", bgzf_file_path)).exists());
+
+        let mut plain = open_indexed_fasta(&plain_file_path).unwrap();
+        let mut bgzf = open_indexed_fasta(&bgzf_file_path).unwrap();
+        for (ref_name, len) in [("chr1", 18), ("chr2", 20), ("chr3", 7)] {
+            for start in 0..len {
+                for end in start + 1..=len {
+                    assert_eq!(
+                        fetch_interval(&mut bgzf, ref_name, start, end).unwrap(),
+                        fetch_interval(&mut plain, ref_name, start, end).unwrap(),
+                        "{}:{}-{}",
+                        ref_name,
+                        start,
+                        end
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_a_fasta_file_compressed_with_plain_gzip() {
+        let dir = tempfile::tempdir().unwrap();
+        let fasta_file_path = dir.path().join("ref.fa.gz").to_str().unwrap().to_string();
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&fasta_file_path).unwrap(), Default::default());
+        encoder.write_all(FASTA.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+        let error = ensure_fasta_index(&fasta_file_path).unwrap_err();
+        assert!(error.to_string().contains("is compressed with plain gzip"), "{}", error);
+        assert!(open_indexed_fasta(&fasta_file_path).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Random access to BAM files on HTTP(S) servers and S3, fetching only the blocks that are read
+//! with range requests.
+//!
+//! `s3://bucket/key` URLs are read without credentials from
+//! `https://bucket.s3.amazonaws.com/key`, so the object has to be public, or from
+//! `$AWS_ENDPOINT_URL/bucket/key` if that is set, e.g. for MinIO.
+
+use std::env;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::error::Error;
+use crate::BoxError;
+
+// Bytes fetched by each range request, a few BGZF blocks
+const BLOCK_SIZE: u64 = 256 * 1024;
+
+/// Whether a BAM path is an `http://`, `https://` or `s3://` URL rather than a local file
+pub fn is_remote(bam_file_path: &str) -> bool {
+    ["http://", "https://", "s3://"].iter().any(|scheme| bam_file_path.starts_with(scheme))
+}
+
+/// HTTP(S) URL a remote file is read from, see the [module documentation](self) for S3
+pub fn http_url(url: &str) -> String {
+    let Some(bucket_key) = url.strip_prefix("s3://") else {
+        return url.to_string();
+    };
+    let (bucket, key) = bucket_key.split_once('/').unwrap_or((bucket_key, ""));
+    match env::var("AWS_ENDPOINT_URL") {
+        Ok(endpoint) if !endpoint.is_empty() => format!("{}/{}/{}", endpoint.trim_end_matches('/'), bucket, key),
+        _ => format!("https://{}.s3.amazonaws.com/{}", bucket, key),
+    }
+}
+
+/// Downloads the `.bai` index next to a remote BAM file
+pub fn fetch_remote_index(bam_url: &str) -> Result<Vec<u8>, BoxError> {
+    let bai_url = format!("{}.bai", bam_url);
+    let mut response = agent()
+        .get(&http_url(&bai_url))
+        .call()
+        .map_err(|e| Error::Io(format!("failed to fetch BAM index {}: {}", bai_url, e)))?;
+    match response.status().as_u16() {
+        200 => {}
+        404 | 403 => {
+            return Err(Error::Io(format!(
+                "BAM index {} not found, remote BAM files need a .bai index next to them",
+                bai_url
+            ))
+            .into());
+        }
+        status => {
+            return Err(Error::Io(format!("failed to fetch BAM index {}: HTTP status {}", bai_url, status)).into());
+        }
+    }
+    Ok(response
+        .body_mut()
+        .with_config()
+        .limit(u64::MAX)
+        .read_to_vec()
+        .map_err(|e| Error::Io(format!("failed to fetch BAM index {}: {}", bai_url, e)))?)
+}
+
+/// Remote file read and seeked like a local one. Every read outside of the last fetched block
+/// fetches the next 256 KiB from there with a range request.
+pub struct RemoteFile {
+    agent: ureq::Agent,
+    http_url: String,
+    len: u64,
+    pos: u64,
+    // Last fetched block and where it starts in the file
+    block_start: u64,
+    block: Vec<u8>,
+}
+
+impl RemoteFile {
+    /// Opens a remote file, failing if it is missing or the server ignores range requests
+    pub fn open(url: &str) -> Result<RemoteFile, BoxError> {
+        let http_url = http_url(url);
+        let agent = agent();
+        let mut response = agent
+            .get(&http_url)
+            .header("Range", format!("bytes=0-{}", BLOCK_SIZE - 1))
+            .call()
+            .map_err(|e| Error::Io(format!("failed to open remote BAM file {}: {}", url, e)))?;
+        match response.status().as_u16() {
+            206 => {}
+            200 => {
+                return Err(format!(
+                    "server of {} does not support range requests, which are needed to read reads by interval",
+                    url
+                )
+                .into());
+            }
+            404 | 403 => return Err(Error::Io(format!("remote BAM file {} not found", url)).into()),
+            status => {
+                let message = format!("failed to open remote BAM file {}: HTTP status {}", url, status);
+                return Err(Error::Io(message).into());
+            }
+        }
+        // Content-Range: bytes 0-262143/1234567
+        let len = response
+            .headers()
+            .get("content-range")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit('/').next())
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| format!("server of {} sent no file size with the range", url))?;
+        let block = response
+            .body_mut()
+            .read_to_vec()
+            .map_err(|e| Error::Io(format!("failed to read remote BAM file {}: {}", url, e)))?;
+        Ok(RemoteFile {
+            agent,
+            http_url,
+            len,
+            pos: 0,
+            block_start: 0,
+            block,
+        })
+    }
+
+    fn fetch_block(&mut self, start: u64) -> io::Result<()> {
+        let end = (start + BLOCK_SIZE).min(self.len) - 1;
+        let mut response = self
+            .agent
+            .get(&self.http_url)
+            .header("Range", format!("bytes={}-{}", start, end))
+            .call()
+            .map_err(io::Error::other)?;
+        if response.status().as_u16() != 206 {
+            return Err(io::Error::other(format!(
+                "range request to {} failed with HTTP status {}",
+                self.http_url,
+                response.status()
+            )));
+        }
+        let block = response.body_mut().read_to_vec().map_err(io::Error::other)?;
+        if block.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "empty range response"));
+        }
+        self.block_start = start;
+        self.block = block;
+        Ok(())
+    }
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos < self.block_start || self.pos >= self.block_start + self.block.len() as u64 {
+            self.fetch_block(self.pos)?;
+        }
+        let offset = (self.pos - self.block_start) as usize;
+        let n = buf.len().min(self.block.len() - offset);
+        buf[..n].copy_from_slice(&self.block[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for RemoteFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = new_pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file"))?;
+        Ok(self.pos)
+    }
+}
+
+// HTTP client that hands error statuses back as responses, so that they get a clear message
+fn agent() -> ureq::Agent {
+    ureq::Agent::config_builder().http_status_as_error(false).build().into()
+}
@@ -0,0 +1,22 @@
+// Reads for the unit tests, built from SAM lines against a small fixed header
+
+// Header with the contigs chr1 of 1000 and chr2 of 500 bases
+pub(crate) fn header() -> bam::Header {
+    let mut header = bam::Header::new();
+    header.push_line("@HD\tVN:1.6\tSO:coordinate").unwrap();
+    header.push_line("@SQ\tSN:chr1\tLN:1000").unwrap();
+    header.push_line("@SQ\tSN:chr2\tLN:500").unwrap();
+    header
+}
+
+// Parses a complete SAM line, tags included
+pub(crate) fn record(sam_line: &str) -> bam::Record {
+    let mut record = bam::Record::new();
+    record.fill_from_sam(sam_line, &header()).unwrap();
+    record
+}
+
+// A forward read named r1 on chr1 with mapping quality 60, `pos` is 1-based like in SAM
+pub(crate) fn read(pos: u32, cigar: &str, sequence: &str, qualities: &str) -> bam::Record {
+    record(&format!("r1\t0\tchr1\t{}\t60\t{}\t*\t0\t0\t{}\t{}", pos, cigar, sequence, qualities))
+}
@@ -0,0 +1,44 @@
+use crate::fixture::*;
+
+// Reads with mismatches, an insertion, a deletion, soft clips and a reference skip
+fn sam_lines() -> Vec<String> {
+    vec![
+        sam("r1", 0, 1, "20M", "ACGTAGCAAGCTTACCGATC", &quals(20)),
+        sam("r2", 16, 5, "3S10M2I6M", "GGGTGCAAGCTTACTTGGATC", &quals(21)),
+        sam("r3", 0, 11, "5M3D10M", "CTTACCATGCAGTCA", "IIII#IIIIIIIIII"),
+        sam("r4", 0, 21, "10M20N10M2S", "CATGCAGTCATACCTTAGGCAA", &quals(22)),
+    ]
+}
+
+#[test]
+fn counts_cram_like_bam() {
+    let fixture = Fixture::new();
+    let lines = sam_lines();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    fixture.write_inputs(&lines);
+    fixture.write_cram("reads.cram", "ref.fa", &[("chr1", CHR1.len())], &lines);
+    for mode in ["per-read", "per-position"] {
+        fixture.bampile_ok(&format!("-b reads.bam -f ref.fa -r chr1:1-80 -m {} --output-file bam.tsv --force", mode));
+        fixture.bampile_ok(&format!("-b reads.cram -f ref.fa -r chr1:1-80 -m {} --output-file cram.tsv --force", mode));
+        let mut bam_rows = tsv(&fixture.read("bam.tsv"));
+        let mut cram_rows = tsv(&fixture.read("cram.tsv"));
+        // Per-read rows come in no particular order
+        bam_rows.sort();
+        cram_rows.sort();
+        // Positions 1-30 and 51-60, the skip of r4 in between has no rows
+        assert_eq!(bam_rows.len(), if mode == "per-read" { 5 } else { 41 });
+
+        assert_eq!(cram_rows, bam_rows, "{}", mode);
+    }
+}
+
+#[test]
+fn needs_the_reference_to_decode_cram() {
+    let fixture = Fixture::new();
+    let lines = sam_lines();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    fixture.write_inputs(&lines);
+    fixture.write_cram("reads.cram", "ref.fa", &[("chr1", CHR1.len())], &lines);
+    let (_, stderr) = fixture.bampile_err("-b reads.cram --use-md -r chr1:1-80 --output-file out.tsv");
+    assert!(stderr.contains("pass it with --fasta"), "{}", stderr);
+}
@@ -0,0 +1,96 @@
+use crate::fixture::*;
+
+#[test]
+fn names_a_contig_missing_from_the_bam_header() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    // chrX is in the FASTA file but not in the BAM header
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1), ("chrX", CHR1)]);
+    fixture.write("regions.bed", "chr1\t0\t10\nchrX\t0\t10\n");
+    let (code, stderr) = fixture.bampile_err("-b reads.bam -f ref.fa -e regions.bed --output-file out.tsv");
+    assert_eq!(code, 4);
+    assert!(stderr.contains("contig \"chrX\" from BED not found in BAM header"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    // Nor in the FASTA file
+    fixture.write("regions.bed", "chr1\t0\t10\nchrY\t0\t10\n");
+    let (code, stderr) = fixture.bampile_err("-b reads.bam -f ref.fa -e regions.bed --output-file out.tsv --force");
+    assert_eq!(code, 4);
+    assert!(stderr.contains("contig \"chrY\" from BED not found in FASTA file"), "{}", stderr);
+}
+
+#[test]
+fn checks_the_inputs_of_a_dry_run_without_creating_the_output() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    fixture.write("regions.bed", "chr1\t0\t10\nchrX\t0\t10\n");
+    let (code, stderr) = fixture.bampile_err("-b reads.bam -f ref.fa -e regions.bed -o out --dry-run");
+    assert_eq!(code, 4);
+    assert!(stderr.contains("contig \"chrX\" from BED not found in BAM header"), "{}", stderr);
+    assert!(!fixture.exists("out"));
+    // With every contig present it reports the intervals and still writes nothing
+    fixture.write("regions.bed", "chr1\t0\t10\nchr1\t20\t30\n");
+    let output = fixture.bampile("-b reads.bam -f ref.fa -e regions.bed -o out --dry-run");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("2 intervals on 1 contigs"));
+    assert!(!fixture.exists("out"));
+}
+
+#[test]
+fn exits_with_the_code_of_the_error() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    let (code, stderr) = fixture.bampile_err("-b missing.bam -f ref.fa -r chr1:1-10 --output-file out.tsv");
+    assert_eq!(code, 6);
+    assert!(stderr.contains("missing.bam"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+
+    fixture.write("regions.bed", "chr1\t0\t10\nchr1\tten\t20\n");
+    let (code, stderr) = fixture.bampile_err("-b reads.bam -f ref.fa -e regions.bed --output-file out.tsv");
+    assert_eq!(code, 3);
+    assert!(stderr.contains("line 2"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn checks_the_contig_lengths_of_the_bam_header_against_the_fasta_file() {
+    let fixture = Fixture::new();
+    // The BAM header declares 10 bases more than the FASTA file has
+    fixture.write_bam("reads.bam", &[("chr1", CHR1.len() + 10)], &[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1)]);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-10 --reference-check --output-file out.tsv";
+    let (code, stderr) = fixture.bampile_err(args);
+    assert_eq!(code, 8);
+    assert!(
+        stderr.contains(
+            "contig lengths of BAM file reads.bam differ from FASTA file ref.fa: chr1 (110 in BAM, 100 in FASTA)"
+        ),
+        "{}",
+        stderr
+    );
+    assert!(!fixture.exists("out.tsv"));
+    let stderr = fixture.bampile_ok(&format!("{} --no-strict-check", args));
+    assert!(stderr.contains("Warning: contig lengths of BAM file reads.bam differ"), "{}", stderr);
+    assert_eq!(column(&tsv(&fixture.read("out.tsv")), "num_matches"), ["10"]);
+}
+
+#[test]
+fn stops_at_a_truncated_bam_file_unless_told_to_skip_errors() {
+    let fixture = Fixture::new();
+    // Enough reads for several BGZF blocks, so that cutting the file keeps the first blocks whole
+    let lines: Vec<String> =
+        (0..3000).map(|i| sam(&format!("r{}", i), 0, 1 + i / 60, "50M", chr1(1 + i / 60, 50), &quals(50))).collect();
+    fixture.write_inputs(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+    let bam = std::fs::read(fixture.path("reads.bam")).unwrap();
+    std::fs::write(fixture.path("reads.bam"), &bam[..bam.len() / 2]).unwrap();
+
+    let args = "-b reads.bam -f ref.fa -r chr1:1-100 --output-file out.tsv";
+    let (code, stderr) = fixture.bampile_err(args);
+    assert_eq!(code, 6);
+    assert!(stderr.contains("BAM file reads.bam is truncated"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+
+    let stderr = fixture.bampile_ok(&format!("{} --skip-errors --force", args));
+    assert!(stderr.contains("truncated"), "{}", stderr);
+    let n_reads = tsv(&fixture.read("out.tsv")).len() - 1;
+    assert!(n_reads > 0 && n_reads < lines.len(), "{} reads", n_reads);
+}
@@ -0,0 +1,168 @@
+use std::io::Write;
+
+use crate::fixture::*;
+
+// Read IDs in a per-read table
+fn read_ids(fixture: &Fixture, output_file: &str) -> Vec<String> {
+    let mut read_ids = column(&tsv(&fixture.read(output_file)), "read_id");
+    read_ids.sort();
+    read_ids
+}
+
+#[test]
+fn keeps_reads_at_the_minimum_mapping_quality() {
+    let fixture = Fixture::new();
+    let low_mapq = format!("low\t0\tchr1\t1\t5\t10M\t*\t0\t0\t{}\t{}", chr1(1, 10), quals(10));
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)), &low_mapq]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --min-mapq 10 --output-file mapq10.tsv");
+    assert_eq!(read_ids(&fixture, "mapq10.tsv"), ["r1"]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --min-mapq 5 --output-file mapq5.tsv");
+    assert_eq!(read_ids(&fixture, "mapq5.tsv"), ["low", "r1"]);
+}
+
+#[test]
+fn summarizes_the_skipped_reads_by_reason() {
+    let fixture = Fixture::new();
+    let low_mapq = format!("low\t0\tchr1\t1\t5\t10M\t*\t0\t0\t{}\t{}", chr1(1, 10), quals(10));
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)), &low_mapq]);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-20 --min-mapq 10 --manifest run.json --output-file out.tsv";
+    let stderr = fixture.bampile_ok(args);
+    assert!(stderr.contains("counted 1 reads, skipped 1: low_mapq 1\n"), "{}", stderr);
+    let manifest: serde_json::Value = serde_json::from_str(&fixture.read("run.json")).unwrap();
+    let skipped = manifest["skipped_reads"].as_object().unwrap();
+    assert_eq!(skipped["low_mapq"], 1);
+    assert!(skipped.iter().all(|(reason, n_reads)| reason == "low_mapq" || n_reads == 0), "{:?}", skipped);
+}
+
+#[test]
+fn names_reads_skipped_for_their_mapping_quality_at_trace_level() {
+    let fixture = Fixture::new();
+    let low_mapq = format!("low\t0\tchr1\t1\t5\t10M\t*\t0\t0\t{}\t{}", chr1(1, 10), quals(10));
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)), &low_mapq]);
+    let message = "skipping read low: mapping quality 5 below 10";
+    let stderr = fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --min-mapq 10 -vv --output-file trace.tsv");
+    assert!(stderr.contains(message), "{}", stderr);
+    let stderr = fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --min-mapq 10 -v --output-file debug.tsv");
+    assert!(!stderr.contains(message), "{}", stderr);
+}
+
+#[test]
+fn counts_or_skips_reads_without_base_qualities() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)),
+        &sam("noqual", 0, 1, "10M", chr1(1, 10), "*"),
+    ]);
+    let stderr = fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --qscore 30 --output-file default.tsv");
+    assert!(stderr.contains("Warning: chr1: 1 reads have no base qualities"), "{}", stderr);
+    // Every base of the read without qualities passes the cutoff
+    let rows = tsv(&fixture.read("default.tsv"));
+    assert_eq!(column(&rows, "num_matches"), ["10", "10"]);
+    assert_eq!(read_ids(&fixture, "default.tsv"), ["noqual", "r1"]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --qscore 30 --require-qual --output-file required.tsv");
+    assert_eq!(read_ids(&fixture, "required.tsv"), ["r1"]);
+}
+
+#[test]
+fn drops_secondary_alignments_by_default() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)),
+        &sam("secondary", 0x100, 1, "10M", chr1(1, 10), &quals(10)),
+    ]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --output-file default.tsv");
+    assert_eq!(read_ids(&fixture, "default.tsv"), ["r1"]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --exclude-flags 0 --output-file all.tsv");
+    assert_eq!(read_ids(&fixture, "all.tsv"), ["r1", "secondary"]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 -F 0 --include-flags 0x100 --output-file only.tsv");
+    assert_eq!(read_ids(&fixture, "only.tsv"), ["secondary"]);
+}
+
+#[test]
+fn counts_only_the_reads_of_a_read_list() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)),
+        &sam("r2", 0, 3, "10M", chr1(3, 10), &quals(10)),
+        &sam("r3", 16, 5, "10M", chr1(5, 10), &quals(10)),
+    ]);
+    fixture.write("reads.txt", "r2\n");
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --read-list reads.txt --output-file listed.tsv");
+    assert_eq!(read_ids(&fixture, "listed.tsv"), ["r2"]);
+    // Gzipped, the list is read the same
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(b"r2\n").unwrap();
+    std::fs::write(fixture.path("reads.txt.gz"), encoder.finish().unwrap()).unwrap();
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --exclude-read-list reads.txt.gz --output-file out.tsv");
+    assert_eq!(read_ids(&fixture, "out.tsv"), ["r1", "r3"]);
+}
+
+#[test]
+fn counts_only_reads_of_the_minimum_aligned_length() {
+    let fixture = Fixture::new();
+    let contig = CHR1.repeat(6);
+    fixture.write_fasta("ref.fa", &[("chr1", &contig)]);
+    let read = |name, length: usize| {
+        let sequence = &contig[..length];
+        format!("{}\t0\tchr1\t1\t60\t{}M\t*\t0\t0\t{}\t{}", name, length, sequence, quals(length))
+    };
+    let (short, long, exact) = (read("short", 50), read("long", 500), read("exact", 100));
+    fixture.write_bam("reads.bam", &[("chr1", contig.len())], &[&exact, &long, &short]);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-600 --min-read-length 100 -v --output-file out.tsv";
+    let stderr = fixture.bampile_ok(args);
+    assert_eq!(read_ids(&fixture, "out.tsv"), ["exact", "long"]);
+    assert!(stderr.contains("1 by aligned length"), "{}", stderr);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-600 --max-read-length 100 --output-file max.tsv");
+    assert_eq!(read_ids(&fixture, "max.tsv"), ["exact", "short"]);
+}
+
+#[test]
+fn counts_bases_from_the_phred_quality_cutoff() {
+    let fixture = Fixture::new();
+    // Phred 20 at 2 and 4, 30 at 6, 40 elsewhere
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), "I5I5I?IIII")]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --qscore 30 --output-file q30.tsv");
+    assert_eq!(column(&tsv(&fixture.read("q30.tsv")), "num_matches"), ["8"]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --qscore 31 --output-file q31.tsv");
+    assert_eq!(column(&tsv(&fixture.read("q31.tsv")), "num_matches"), ["7"]);
+    let (_, stderr) = fixture.bampile_err("-b reads.bam -f ref.fa -r chr1:1-20 --qscore 100 --output-file q100.tsv");
+    assert!(stderr.contains("--qscore 100 is above 93"), "{}", stderr);
+    assert!(!fixture.exists("q100.tsv"));
+}
+
+#[test]
+fn skips_reads_without_a_stored_sequence() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)), &sam("noseq", 0, 1, "10M", "*", "*")]);
+    for mode in ["per-read", "per-position"] {
+        let args = format!("-b reads.bam -f ref.fa -r chr1:1-20 -m {} --output-file out.tsv --force", mode);
+        let stderr = fixture.bampile_ok(&args);
+        assert!(stderr.contains("counted 1 reads, skipped 1: empty_seq 1"), "{}", stderr);
+        let rows = tsv(&fixture.read("out.tsv"));
+        if mode == "per-read" {
+            assert_eq!(column(&rows, "read_id"), ["r1"]);
+        } else {
+            assert!(column(&rows, "depth").iter().all(|depth| depth == "1"));
+        }
+        let (_, stderr) = fixture.bampile_err(&format!("{} --strict", args));
+        assert!(stderr.contains("read noseq has no stored sequence"), "{}", stderr);
+    }
+}
+
+#[test]
+fn subsamples_the_same_reads_with_the_same_seed() {
+    let fixture = Fixture::new();
+    let reads: Vec<String> =
+        (0..100).map(|index| sam(&format!("r{}", index), 0, 1, "10M", chr1(1, 10), &quals(10))).collect();
+    let reads: Vec<&str> = reads.iter().map(String::as_str).collect();
+    fixture.write_inputs(&reads);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-20 --subsample 0.5";
+    fixture.bampile_ok(&format!("{} --seed 7 --output-file first.tsv", args));
+    fixture.bampile_ok(&format!("{} --seed 7 --output-file second.tsv", args));
+    let kept = read_ids(&fixture, "first.tsv");
+    assert_eq!(read_ids(&fixture, "second.tsv"), kept);
+    // About half of the reads, and others with another seed
+    assert!((30..=70).contains(&kept.len()), "{}", kept.len());
+    fixture.bampile_ok(&format!("{} --seed 8 --output-file other.tsv", args));
+    assert_ne!(read_ids(&fixture, "other.tsv"), kept);
+}
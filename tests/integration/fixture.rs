@@ -0,0 +1,209 @@
+// Input files for the command line tests, written to a temporary directory that the binary is run in
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::process::{Command, Output};
+
+use bam::RecordWriter;
+
+// 100 bases of chr1, the reads of the tests are built from them
+pub const CHR1: &str =
+    "ACGTTGCAAGCTTACGGATCCATGCAGTCAGTACGATCGAACTGGTACCTTAGGCAATCGTACGGCTAGCATGCCAGTTACGATCCGTAGACTGCATTCG";
+
+pub struct Fixture {
+    dir: tempfile::TempDir,
+}
+
+impl Fixture {
+    pub fn new() -> Fixture {
+        Fixture { dir: tempfile::tempdir().unwrap() }
+    }
+
+    pub fn path(&self, name: &str) -> String {
+        self.dir.path().join(name).to_str().unwrap().to_string()
+    }
+
+    pub fn write(&self, name: &str, contents: &str) -> String {
+        let path = self.path(name);
+        if let Some(parent) = std::path::Path::new(&path).parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    pub fn read(&self, name: &str) -> String {
+        fs::read_to_string(self.path(name)).unwrap_or_else(|e| panic!("failed to read {}: {}", name, e))
+    }
+
+    // Decompresses a gzip or BGZF file
+    pub fn read_gz(&self, name: &str) -> String {
+        let mut contents = String::new();
+        let file = File::open(self.path(name)).unwrap_or_else(|e| panic!("failed to open {}: {}", name, e));
+        flate2::read::MultiGzDecoder::new(file).read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    // Decompresses a zstd file
+    pub fn read_zst(&self, name: &str) -> String {
+        let file = File::open(self.path(name)).unwrap_or_else(|e| panic!("failed to open {}: {}", name, e));
+        String::from_utf8(zstd::decode_all(file).unwrap()).unwrap()
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.dir.path().join(name).exists()
+    }
+
+    // Writes a FASTA file with 60 bases per line
+    pub fn write_fasta(&self, name: &str, contigs: &[(&str, &str)]) -> String {
+        let mut fasta = String::new();
+        for (contig, sequence) in contigs {
+            fasta.push_str(&format!(">{}\n", contig));
+            for line in sequence.as_bytes().chunks(60) {
+                fasta.push_str(std::str::from_utf8(line).unwrap());
+                fasta.push('\n');
+            }
+        }
+        self.write(name, &fasta)
+    }
+
+    // Writes a BAM file and its index from SAM lines sorted by position, on the given contigs
+    pub fn write_bam(&self, name: &str, contigs: &[(&str, usize)], sam_lines: &[&str]) -> String {
+        let path = self.write_unindexed_bam(name, contigs, sam_lines);
+        bampile::index::ensure_bam_index(&path).unwrap();
+        path
+    }
+
+    pub fn write_unindexed_bam(&self, name: &str, contigs: &[(&str, usize)], sam_lines: &[&str]) -> String {
+        let path = self.path(name);
+        let header = header(contigs);
+        let mut writer = bam::BamWriter::build().write_header(true).from_path(&path, header.clone()).unwrap();
+        for line in sam_lines {
+            let mut record = bam::Record::new();
+            record.fill_from_sam(line, &header).unwrap();
+            writer.write(&record).unwrap();
+        }
+        writer.finish().unwrap();
+        path
+    }
+
+    // Writes a CRAM file from SAM lines sorted by position, compressed against a FASTA file of
+    // the fixture
+    pub fn write_cram(&self, name: &str, fasta_name: &str, contigs: &[(&str, usize)], sam_lines: &[&str]) -> String {
+        use noodles::sam::alignment::io::Write as _;
+
+        let path = self.path(name);
+        let fasta_path = self.path(fasta_name);
+        bampile::reference::ensure_fasta_index(&fasta_path).unwrap();
+        let mut sam = String::new();
+        sam.push_str("@HD\tVN:1.6\tSO:coordinate\n");
+        for (contig, length) in contigs {
+            sam.push_str(&format!("@SQ\tSN:{}\tLN:{}\n", contig, length));
+        }
+        for line in sam_lines {
+            sam.push_str(line);
+            sam.push('\n');
+        }
+        let mut reader = noodles::sam::io::Reader::new(sam.as_bytes());
+        let header = reader.read_header().unwrap();
+        let fasta = noodles::fasta::io::indexed_reader::Builder::default().build_from_path(&fasta_path).unwrap();
+        let adapter = noodles::fasta::repository::adapters::IndexedReader::new(fasta);
+        let mut writer = noodles::cram::io::writer::Builder::default()
+            .set_reference_sequence_repository(noodles::fasta::Repository::new(adapter))
+            .build_from_path(&path)
+            .unwrap();
+        writer.write_header(&header).unwrap();
+        for record in reader.record_bufs(&header) {
+            writer.write_alignment_record(&header, &record.unwrap()).unwrap();
+        }
+        writer.try_finish(&header).unwrap();
+        path
+    }
+
+    // The usual inputs: chr1 in ref.fa and the reads in reads.bam
+    pub fn write_inputs(&self, sam_lines: &[&str]) {
+        self.write_fasta("ref.fa", &[("chr1", CHR1)]);
+        self.write_bam("reads.bam", &[("chr1", CHR1.len())], sam_lines);
+    }
+
+    // Runs bampile in the directory of the fixture with the arguments split at whitespace
+    pub fn bampile(&self, args: &str) -> Output {
+        self.bampile_args(&args.split_whitespace().collect::<Vec<_>>())
+    }
+
+    pub fn bampile_args(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_bampile")).args(args).current_dir(self.dir.path()).output().unwrap()
+    }
+
+    // Runs bampile, expects it to succeed and returns what it logged
+    pub fn bampile_ok(&self, args: &str) -> String {
+        let output = self.bampile(args);
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        assert!(output.status.success(), "bampile {} failed: {}", args, stderr);
+        stderr
+    }
+
+    // Runs bampile with a file of the fixture as its stdin, expects it to succeed and returns
+    // what it logged
+    pub fn bampile_stdin(&self, args: &str, stdin_name: &str) -> String {
+        let output = Command::new(env!("CARGO_BIN_EXE_bampile"))
+            .args(args.split_whitespace())
+            .current_dir(self.dir.path())
+            .stdin(File::open(self.path(stdin_name)).unwrap())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        assert!(output.status.success(), "bampile {} < {} failed: {}", args, stdin_name, stderr);
+        stderr
+    }
+
+    // Runs bampile, expects it to fail and returns its exit code and what it logged
+    pub fn bampile_err(&self, args: &str) -> (i32, String) {
+        let output = self.bampile(args);
+        assert!(!output.status.success(), "bampile {} did not fail", args);
+        (output.status.code().unwrap(), String::from_utf8_lossy(&output.stderr).to_string())
+    }
+}
+
+fn header(contigs: &[(&str, usize)]) -> bam::Header {
+    let mut header = bam::Header::new();
+    header.push_line("@HD\tVN:1.6\tSO:coordinate").unwrap();
+    for (contig, length) in contigs {
+        header.push_line(&format!("@SQ\tSN:{}\tLN:{}", contig, length)).unwrap();
+    }
+    header
+}
+
+// A SAM line of a read with mapping quality 60 on chr1, `pos` is 1-based
+pub fn sam(name: &str, flag: u16, pos: usize, cigar: &str, sequence: &str, qualities: &str) -> String {
+    format!("{}\t{}\tchr1\t{}\t60\t{}\t*\t0\t0\t{}\t{}", name, flag, pos, cigar, sequence, qualities)
+}
+
+// Bases of chr1 from the 1-based position `pos` on, `len` of them
+pub fn chr1(pos: usize, len: usize) -> &'static str {
+    &CHR1[pos - 1..pos - 1 + len]
+}
+
+// Quality string of `len` bases of quality 40
+pub fn quals(len: usize) -> String {
+    "I".repeat(len)
+}
+
+// Rows of a delimited table without its # comment lines, the header first
+pub fn table(contents: &str, delimiter: char) -> Vec<Vec<String>> {
+    contents
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| line.split(delimiter).map(str::to_string).collect())
+        .collect()
+}
+
+pub fn tsv(contents: &str) -> Vec<Vec<String>> {
+    table(contents, '\t')
+}
+
+// The values of one column of a table, by the name in its header
+pub fn column(rows: &[Vec<String>], name: &str) -> Vec<String> {
+    let index = rows[0].iter().position(|column| column == name).unwrap_or_else(|| panic!("no column {}", name));
+    rows[1..].iter().map(|row| row[index].clone()).collect()
+}
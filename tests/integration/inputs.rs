@@ -0,0 +1,113 @@
+use crate::fixture::*;
+
+// Reads on chr1 and chr2, some of them outside of the intervals
+fn write_two_contig_inputs(fixture: &Fixture) {
+    let chr2: String = CHR1.chars().rev().collect();
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1), ("chr2", &chr2)]);
+    let chr2_read = format!("r4\t16\tchr2\t31\t60\t20M\t*\t0\t0\t{}\t{}", &chr2[30..50], quals(20));
+    fixture.write_unindexed_bam(
+        "reads.bam",
+        &[("chr1", CHR1.len()), ("chr2", chr2.len())],
+        &[
+            &sam("r1", 0, 1, "20M", chr1(1, 20), &quals(20)),
+            &sam("r2", 16, 11, "5M2D10M", "GCTTAGATCCATGCA", &quals(15)),
+            &sam("r3", 0, 61, "30M", chr1(61, 30), &quals(30)),
+            &chr2_read,
+        ],
+    );
+    fixture.write("regions.bed", "chr1\t5\t25\nchr1\t70\t80\nchr2\t35\t45\n");
+}
+
+// Body rows of a table, sorted so that per-read tables of both runs compare equal
+fn sorted_rows(contents: &str) -> Vec<Vec<String>> {
+    let mut rows = tsv(contents);
+    rows[1..].sort();
+    rows
+}
+
+#[test]
+fn counts_a_bam_from_stdin_like_the_indexed_file() {
+    let fixture = Fixture::new();
+    write_two_contig_inputs(&fixture);
+    for mode in ["per-read", "per-position"] {
+        let args = format!("-f ref.fa -e regions.bed -m {} --no-header-comments --force", mode);
+        fixture.bampile_stdin(&format!("-b - {} --output-file stdin.tsv", args), "reads.bam");
+        assert!(!fixture.exists("reads.bam.bai"), "{}", mode);
+        fixture.bampile_ok(&format!("-b reads.bam {} --output-file indexed.tsv", args));
+        let rows = sorted_rows(&fixture.read("stdin.tsv"));
+        assert!(rows.len() > 3, "{}", mode);
+        assert_eq!(rows, sorted_rows(&fixture.read("indexed.tsv")), "{}", mode);
+        std::fs::remove_file(fixture.path("reads.bam.bai")).unwrap();
+    }
+}
+
+#[test]
+fn indexes_a_sorted_bam_without_an_index() {
+    let fixture = Fixture::new();
+    write_two_contig_inputs(&fixture);
+    let stderr = fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed --output-file out.tsv");
+    assert!(stderr.contains("Creating missing BAM index"), "{}", stderr);
+    assert!(fixture.exists("reads.bam.bai"));
+    let rows = tsv(&fixture.read("out.tsv"));
+    let mut read_ids = column(&rows, "read_id");
+    read_ids.sort();
+    assert_eq!(read_ids, ["r1", "r2", "r3", "r4"]);
+}
+
+#[test]
+fn asks_to_sort_a_bam_without_an_index() {
+    let fixture = Fixture::new();
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1)]);
+    fixture.write_unindexed_bam(
+        "reads.bam",
+        &[("chr1", CHR1.len())],
+        &[&sam("r1", 0, 41, "10M", chr1(41, 10), &quals(10)), &sam("r2", 0, 1, "10M", chr1(1, 10), &quals(10))],
+    );
+    let (_, stderr) = fixture.bampile_err("-b reads.bam -f ref.fa -r chr1:1-50 --output-file out.tsv");
+    assert!(stderr.contains("is not coordinate-sorted, sort it with `samtools sort` first"), "{}", stderr);
+    assert!(!fixture.exists("reads.bam.bai"));
+}
+
+#[test]
+fn counts_several_bams_as_samples() {
+    let fixture = Fixture::new();
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1), ("chr2", CHR1)]);
+    let contigs = [("chr1", CHR1.len()), ("chr2", CHR1.len())];
+    fixture.write_bam("sampleA.bam", &contigs, &[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    // With a mismatch at 3
+    fixture.write_bam("sampleB.bam", &contigs, &[&sam("r1", 0, 1, "10M", "ACTTTGCAAG", &quals(10))]);
+    fixture.bampile_ok("-b sampleA.bam,sampleB.bam -f ref.fa -r chr1:1-20 --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!(rows[0][..3], ["sample", "reference", "read_id"]);
+    let mut counts: Vec<[String; 3]> =
+        rows[1..].iter().map(|row| [row[0].clone(), row[3].clone(), row[4].clone()]).collect();
+    counts.sort();
+    assert_eq!(counts, [["sampleA", "10", "0"], ["sampleB", "9", "1"]].map(|row| row.map(String::from)));
+
+    // An error in one of the BAM files names its sample
+    fixture.write_bam("sampleC.bam", &[("chr1", CHR1.len())], &[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    let (code, stderr) = fixture.bampile_err("-b sampleA.bam -b sampleC.bam -f ref.fa -r chr2:1-20 --output-file c");
+    assert_eq!(code, 4);
+    assert!(stderr.contains("sample sampleC: contig \"chr2\" from BED not found in BAM header"), "{}", stderr);
+}
+
+#[test]
+fn writes_the_files_of_each_sample_into_its_own_directory() {
+    let fixture = Fixture::new();
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1)]);
+    let contigs = [("chr1", CHR1.len())];
+    std::fs::create_dir(fixture.path("data")).unwrap();
+    fixture.write_bam("sampleA.bam", &contigs, &[&sam("a1", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    fixture.write_bam("data/sampleB.bam", &contigs, &[&sam("b1", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    fixture.bampile_ok("-b sampleA.bam -b data/sampleB.bam -f ref.fa -r chr1:1-20 -o out");
+    for (sample, read_id) in [("sampleA", "a1"), ("sampleB", "b1")] {
+        let rows = tsv(&fixture.read_gz(&format!("out/{}/chr1.tsv.gz", sample)));
+        assert!(!rows[0].contains(&"sample".to_string()), "{}", sample);
+        assert_eq!(column(&rows, "read_id"), [read_id], "{}", sample);
+    }
+    assert!(!fixture.exists("out/chr1.tsv.gz"));
+
+    fixture.bampile_ok("-b sampleA.bam -b data/sampleB.bam -f ref.fa -r chr1:1-20 -o flat --no-sample-dirs");
+    let rows = tsv(&fixture.read_gz("flat/chr1.tsv.gz"));
+    assert_eq!(column(&rows, "sample"), ["sampleA", "sampleB"]);
+}
@@ -0,0 +1,313 @@
+use crate::fixture::*;
+
+// Three intervals on chr1, the last two overlap
+const THREE_INTERVALS: &str = "chr1\t0\t10\tfirst\nchr1\t40\t60\tsecond\nchr1\t50\t70\tthird\n";
+
+#[test]
+fn counts_every_interval_of_a_contig() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "100M", CHR1, &quals(100))]);
+    fixture.write("regions.bed", THREE_INTERVALS);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed -m per-position --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    let positions: Vec<usize> = column(&rows, "pos").iter().map(|pos| pos.parse().unwrap()).collect();
+    assert_eq!(positions, [(1..=10).collect::<Vec<_>>(), (41..=70).collect()].concat());
+    let region_names = column(&rows, "region_name");
+    assert_eq!(region_names[0], "first");
+    assert_eq!(region_names[10], "second,third");
+}
+
+#[test]
+fn counts_the_bases_of_overlapping_intervals_once() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "100M", CHR1, &quals(100))]);
+    fixture.write("regions.bed", "chr1\t40\t60\tsecond\nchr1\t50\t70\tthird\n");
+    fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed --output-file reads.tsv");
+    assert_eq!(column(&tsv(&fixture.read("reads.tsv")), "num_matches"), ["30"]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed -m per-position --output-file pos.tsv");
+    let rows = tsv(&fixture.read("pos.tsv"));
+    let positions: Vec<usize> = column(&rows, "pos").iter().map(|pos| pos.parse().unwrap()).collect();
+    assert_eq!(positions, (41..=70).collect::<Vec<_>>());
+    assert!(column(&rows, "depth").iter().all(|depth| depth == "1"));
+    // Kept apart, the bases of the overlap are only counted in the first interval if asked to
+    let args = "--keep-intervals --count-only-unique-positions --output-file unique.tsv";
+    fixture.bampile_ok(&format!("-b reads.bam -f ref.fa -e regions.bed {}", args));
+    assert_eq!(column(&tsv(&fixture.read("unique.tsv")), "num_matches"), ["30"]);
+}
+
+#[test]
+fn counts_overlapping_intervals_on_their_own() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "100M", CHR1, &quals(100))]);
+    fixture.write("regions.bed", THREE_INTERVALS);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed --keep-intervals --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!(column(&rows, "region_name"), ["first,second,third"]);
+    // The bases of the overlap are counted in both intervals
+    assert_eq!(column(&rows, "num_matches"), ["50"]);
+}
+
+#[test]
+fn counts_each_position_of_a_read_once_across_kept_intervals() {
+    let fixture = Fixture::new();
+    // Mismatches at 45 and 55, in the overlap of the second and third interval, and at 65
+    let mut sequence = CHR1.as_bytes().to_vec();
+    for index in [44, 54, 64] {
+        sequence[index] = if sequence[index] == b'A' { b'C' } else { b'A' };
+    }
+    let sequence = String::from_utf8(sequence).unwrap();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "100M", &sequence, &quals(100))]);
+    fixture.write("regions.bed", THREE_INTERVALS);
+    fixture.write("union.bed", "chr1\t0\t10\nchr1\t40\t70\n");
+    let counts = |args: &str| {
+        fixture.bampile_ok(&format!("-b reads.bam -f ref.fa {} --force --output-file out.tsv", args));
+        let rows = tsv(&fixture.read("out.tsv"));
+        ["num_matches", "num_mismatches", "region_name"].map(|name| column(&rows, name).remove(0))
+    };
+    assert_eq!(counts("-e regions.bed --keep-intervals")[..2], ["46", "4"]);
+    let unique = counts("-e regions.bed --keep-intervals --count-only-unique-positions");
+    assert_eq!(unique, ["37", "3", "first,second,third"]);
+    assert_eq!(counts("-e union.bed")[..2], unique[..2]);
+}
+
+#[test]
+fn counts_a_region_given_on_the_command_line() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "20M", chr1(1, 20), &quals(20))]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:5-14 -m per-position --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!(column(&rows, "pos"), ["5", "6", "7", "8", "9", "10", "11", "12", "13", "14"]);
+}
+
+#[test]
+fn takes_exactly_one_of_bed_and_region() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "20M", chr1(1, 20), &quals(20))]);
+    fixture.write("regions.bed", "chr1\t0\t10\n");
+    let (code, stderr) =
+        fixture.bampile_err("-b reads.bam -f ref.fa -e regions.bed -r chr1:1-10 --output-file out.tsv");
+    assert_eq!(code, 2);
+    assert!(stderr.contains("cannot be used with"), "{}", stderr);
+    let (code, stderr) = fixture.bampile_err("-b reads.bam -f ref.fa --output-file out.tsv");
+    assert_eq!(code, 2);
+    assert!(stderr.contains("--bed"), "{}", stderr);
+    let (code, stderr) = fixture.bampile_err("-b reads.bam -f ref.fa -r chr1:200-100 --output-file out.tsv");
+    assert_eq!(code, 7);
+    assert!(stderr.contains("end is before start"), "{}", stderr);
+}
+
+#[test]
+fn counts_a_single_base_interval_at_its_reference_column() {
+    let fixture = Fixture::new();
+    // Both reads cover 6-15, r2 has an A in place of the C at 11
+    fixture.write_inputs(&[
+        &sam("r1", 0, 6, "10M", chr1(6, 10), &quals(10)),
+        &sam("r2", 0, 6, "10M", "GCAAGATTAC", &quals(10)),
+    ]);
+    // The same base as 0-based half-open and as 1-based closed interval
+    fixture.write("zero_based.bed", "chr1\t10\t11\n");
+    fixture.write("one_based.bed", "chr1\t11\t11\n");
+    for bed in ["-e zero_based.bed", "-e one_based.bed --one-based"] {
+        fixture.bampile_ok(&format!("-b reads.bam -f ref.fa {} -m per-position --output-file pos.tsv --force", bed));
+        let rows = tsv(&fixture.read("pos.tsv"));
+        assert_eq!(rows.len(), 2, "{}", bed);
+        assert_eq!(rows[1][..6], ["chr1", "11", "C", "2", "1", "1"], "{}", bed);
+
+        fixture.bampile_ok(&format!("-b reads.bam -f ref.fa {} --output-file reads.tsv --force", bed));
+        let rows = tsv(&fixture.read("reads.tsv"));
+        let counts = |read_id: &str| {
+            let row = column(&rows, "read_id").iter().position(|id| id == read_id).unwrap();
+            [column(&rows, "num_matches")[row].clone(), column(&rows, "num_mismatches")[row].clone()]
+        };
+        assert_eq!(counts("r1"), ["1", "0"], "{}", bed);
+        assert_eq!(counts("r2"), ["0", "1"], "{}", bed);
+    }
+}
+
+#[test]
+fn counts_the_single_positions_of_a_positions_file() {
+    let fixture = Fixture::new();
+    // An A in place of the T at 5 and a T in place of the A at 30, the reference T at 12
+    let mut sequence = chr1(1, 40).as_bytes().to_vec();
+    sequence[4] = b'A';
+    sequence[29] = b'T';
+    let sequence = String::from_utf8(sequence).unwrap();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "40M", &sequence, &quals(40))]);
+    fixture.write("snps.tsv", "chr1\t5\nchr1\t12\nchr1\t30\n");
+    fixture.bampile_ok("-b reads.bam -f ref.fa --positions snps.tsv -m per-position --output-file pos.tsv");
+    let rows = tsv(&fixture.read("pos.tsv"));
+    assert_eq!(column(&rows, "pos"), ["5", "12", "30"]);
+    assert_eq!(column(&rows, "ref_base"), ["T", "T", "A"]);
+    assert_eq!(column(&rows, "A"), ["1", "0", "0"]);
+    assert_eq!(column(&rows, "T"), ["0", "1", "1"]);
+}
+
+#[test]
+fn counts_the_flanks_of_an_interval() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "100M", CHR1, &quals(100))]);
+    let positions = |region: &str| {
+        let args = "--flank 5 -m per-position --output-file pos.tsv --force";
+        fixture.bampile_ok(&format!("-b reads.bam -f ref.fa -r {} {}", region, args));
+        let rows = tsv(&fixture.read("pos.tsv"));
+        column(&rows, "pos").iter().map(|pos| pos.parse().unwrap()).collect::<Vec<usize>>()
+    };
+    assert_eq!(positions("chr1:51-51"), (46..=56).collect::<Vec<_>>());
+    // Clamped at the start and end of the contig
+    assert_eq!(positions("chr1:3-3"), (1..=8).collect::<Vec<_>>());
+    assert_eq!(positions("chr1:99-99"), (94..=100).collect::<Vec<_>>());
+}
+
+#[test]
+fn labels_the_counts_with_the_bed_name() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)),
+        &sam("r2", 0, 41, "10M", chr1(41, 10), &quals(10)),
+    ]);
+    // A named interval with a trailing comment and an unnamed one after a blank line
+    fixture.write("regions.bed", "chr1\t0\t10\tampA # pool 1\n\nchr1\t40\t50\n");
+    fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed --output-file reads.tsv");
+    let rows = tsv(&fixture.read("reads.tsv"));
+    let mut region_names: Vec<(String, String)> =
+        column(&rows, "read_id").into_iter().zip(column(&rows, "region_name")).collect();
+    region_names.sort();
+    assert_eq!(region_names, [("r1".to_string(), "ampA".to_string()), ("r2".to_string(), "chr1:41-50".to_string())]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed -m per-position --output-file pos.tsv");
+    let region_names = column(&tsv(&fixture.read("pos.tsv")), "region_name");
+    assert_eq!(region_names, [vec!["ampA"; 10], vec!["chr1:41-50"; 10]].concat());
+}
+
+// Depths of the given positions of a per-position table
+fn position_depths(rows: &[Vec<String>], positions: std::ops::RangeInclusive<usize>) -> Vec<String> {
+    let depths = column(rows, "depth");
+    column(rows, "pos")
+        .iter()
+        .zip(depths)
+        .filter(|(pos, _)| positions.contains(&pos.parse().unwrap()))
+        .map(|(_, depth)| depth)
+        .collect()
+}
+
+#[test]
+fn counts_only_the_reads_on_the_strand_of_an_interval() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[
+        &sam("fwd", 0, 1, "10M", chr1(1, 10), &quals(10)),
+        &sam("rev", 16, 1, "10M", chr1(1, 10), &quals(10)),
+        &sam("fwd2", 0, 41, "10M", chr1(41, 10), &quals(10)),
+        &sam("rev2", 16, 41, "10M", chr1(41, 10), &quals(10)),
+    ]);
+    fixture.write("regions.bed", "chr1\t0\t10\tminus\t0\t-\nchr1\t40\t50\tboth\t0\t.\n");
+    let args = "-b reads.bam -f ref.fa -e regions.bed";
+    fixture.bampile_ok(&format!("{} --respect-bed-strand --output-file reads.tsv", args));
+    let rows = tsv(&fixture.read("reads.tsv"));
+    let mut read_ids = column(&rows, "read_id");
+    read_ids.sort();
+    assert_eq!(read_ids, ["fwd2", "rev", "rev2"]);
+    fixture.bampile_ok(&format!("{} --respect-bed-strand -m per-position --output-file pos.tsv", args));
+    let rows = tsv(&fixture.read("pos.tsv"));
+    assert_eq!(position_depths(&rows, 1..=10), vec!["1"; 10]);
+    assert_eq!(position_depths(&rows, 41..=50), vec!["2"; 10]);
+    // Without the flag the strand column is ignored
+    fixture.bampile_ok(&format!("{} --output-file all.tsv", args));
+    assert_eq!(column(&tsv(&fixture.read("all.tsv")), "read_id").len(), 4);
+}
+
+#[test]
+fn counts_only_the_intervals_on_the_listed_contigs() {
+    let fixture = Fixture::new();
+    let contigs = [("chr1", CHR1.len()), ("chr2", CHR1.len()), ("chr3", CHR1.len())];
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1), ("chr2", CHR1), ("chr3", CHR1)]);
+    let reads: Vec<String> = ["chr1", "chr2", "chr3"]
+        .iter()
+        .map(|contig| format!("r_{}\t0\t{}\t1\t60\t10M\t*\t0\t0\t{}\t{}", contig, contig, chr1(1, 10), quals(10)))
+        .collect();
+    fixture.write_bam("reads.bam", &contigs, &reads.iter().map(String::as_str).collect::<Vec<_>>());
+    fixture.write("regions.bed", "chr1\t0\t20\nchr2\t0\t20\nchr2\t50\t60\nchr3\t0\t20\n");
+    let stderr = fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed --contig-list chr2 -v -o out");
+    assert!(stderr.contains("ignored 2 intervals on contigs missing from --contig-list"), "{}", stderr);
+    let file_names: Vec<String> = std::fs::read_dir(fixture.path("out/reads"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(file_names, ["chr2.tsv.gz"]);
+    assert_eq!(column(&tsv(&fixture.read_gz("out/reads/chr2.tsv.gz")), "read_id"), ["r_chr2"]);
+}
+
+#[test]
+fn clamps_intervals_past_the_end_of_their_contig() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 81, "20M", chr1(81, 20), &quals(20))]);
+    // chr1 has 100 bases
+    fixture.write("regions.bed", "chr1\t90\t130\nchr1\t100\t110\n");
+    let stderr = fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed -m per-position --output-file pos.tsv");
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert!(
+        stderr.contains(
+            "Warning: clamped 1 intervals running past the end of their contig to its length, e.g. chr1:91-130"
+        ),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Warning: dropped 1 intervals starting at or past the end of their contig, e.g. chr1:101-110"),
+        "{}",
+        stderr
+    );
+    let positions: Vec<usize> =
+        column(&tsv(&fixture.read("pos.tsv")), "pos").iter().map(|pos| pos.parse().unwrap()).collect();
+    assert_eq!(positions, (91..=100).collect::<Vec<_>>());
+
+    fixture.write("regions.bed", "chr1\t100\t110\n");
+    let (code, stderr) = fixture.bampile_err("-b reads.bam -f ref.fa -e regions.bed --output-file out.tsv");
+    assert_eq!(code, 1);
+    assert!(stderr.contains("no intervals within the lengths of their contigs"), "{}", stderr);
+}
+
+#[test]
+fn counts_a_long_interval_in_windows_like_in_one_fetch() {
+    let fixture = Fixture::new();
+    let contig = CHR1.repeat(30);
+    fixture.write_fasta("ref.fa", &[("chrL", &contig)]);
+    // Reads every 37 bases with deletions, insertions, skips and clips that cross the windows
+    let lines: Vec<String> = (0..70)
+        .map(|i| {
+            let pos = 1 + i * 37;
+            let bases = |start: usize, len: usize| &contig[start - 1..start - 1 + len];
+            let (cigar, sequence) = match i % 4 {
+                0 => ("60M".to_string(), bases(pos, 60).to_string()),
+                1 => ("30M2D30M".to_string(), format!("{}{}", bases(pos, 30), bases(pos + 32, 30))),
+                2 => ("5S20M3I32M".to_string(), format!("GGGGG{}TTT{}", bases(pos, 20), bases(pos + 20, 32))),
+                _ => ("25M200N35M".to_string(), format!("{}{}", bases(pos, 25), bases(pos + 225, 35))),
+            };
+            // The twelfth read base is aligned in every CIGAR, make it a mismatch in every fifth read
+            let mut sequence = sequence;
+            if i % 5 == 0 {
+                let mismatch = if &sequence[11..12] == "A" { "C" } else { "A" };
+                sequence.replace_range(11..12, mismatch);
+            }
+            let flag = if i % 3 == 0 { 16 } else { 0 };
+            let line = sam(&format!("r{}", i), flag, pos, &cigar, &sequence, &quals(sequence.len()));
+            line.replacen("\tchr1\t", "\tchrL\t", 1)
+        })
+        .collect();
+    fixture.write_bam("reads.bam", &[("chrL", contig.len())], &lines.iter().map(String::as_str).collect::<Vec<_>>());
+    for mode in ["per-read", "per-position"] {
+        let args =
+            format!("-b reads.bam -f ref.fa -r chrL:1-{} -m {} --no-header-comments --force", contig.len(), mode);
+        // Reads are written in no particular order
+        let rows = |output_file: &str| {
+            let mut rows = tsv(&fixture.read(output_file));
+            rows[1..].sort();
+            rows
+        };
+        fixture.bampile_ok(&format!("{} --output-file whole.tsv", args));
+        let whole = rows("whole.tsv");
+        assert!(whole.len() > 70, "{}", mode);
+        for batch_size in [7, 250, 1000] {
+            fixture.bampile_ok(&format!("{} --interval-batch-size {} --output-file windows.tsv", args, batch_size));
+            assert_eq!(rows("windows.tsv"), whole, "{} in windows of {}", mode, batch_size);
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use bampile::{BedRegion, PileupConfig, PileupIterator, PileupPosition};
+
+use crate::fixture::*;
+
+#[test]
+fn returns_the_per_read_counts_of_a_constructed_config() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[
+        // r2 mismatches at 3 and has a deleted base after 8
+        &sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)),
+        &sam("r2", 16, 1, "8M1D2M", "ACTTTGCAGC", &quals(10)),
+    ]);
+    let regions = vec![BedRegion { name: Some("first".to_string()), ..BedRegion::new("chr1", 0, 20) }];
+    let config = PileupConfig::new(&fixture.path("reads.bam"), &fixture.path("ref.fa"), regions);
+    let summary = bampile::run(&config).unwrap();
+
+    let read_counts = &summary.read_counts["chr1"];
+    assert_eq!(read_counts.len(), 2);
+    let r1 = &read_counts["r1"];
+    assert_eq!((r1.fwd_matches, r1.fwd_mismatches, r1.rev_matches), (10, 0, 0));
+    assert_eq!(r1.region_names, ["first"]);
+    let r2 = &read_counts["r2"];
+    assert_eq!((r2.rev_matches, r2.rev_mismatches, r2.rev_deletions, r2.fwd_matches), (9, 1, 1, 0));
+    assert!(summary.position_counts.is_empty());
+}
+
+#[test]
+fn iterates_over_the_counts_of_each_position() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)),
+        // A C in place of the A at 8
+        &sam("r2", 16, 6, "10M", &format!("{}C{}", chr1(6, 2), chr1(9, 7)), &quals(10)),
+    ]);
+    let regions = vec![BedRegion::new("chr1", 4, 12)];
+    let config = PileupConfig::new(&fixture.path("reads.bam"), &fixture.path("ref.fa"), regions);
+    let positions: Vec<PileupPosition> = PileupIterator::new(&config).unwrap().map(Result::unwrap).collect();
+
+    let depths: Vec<(u32, usize)> = positions.iter().map(|position| (position.pos, position.depth)).collect();
+    assert_eq!(depths, [(4, 1), (5, 2), (6, 2), (7, 2), (8, 2), (9, 2), (10, 1), (11, 1)]);
+    let position = &positions[3];
+    assert_eq!((position.ref_name.as_str(), position.ref_base), ("chr1", b'A'));
+    assert_eq!((position.counts.a, position.counts.c, position.counts.depth()), (1, 1, 2));
+}
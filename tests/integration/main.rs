@@ -0,0 +1,18 @@
+// Runs the bampile binary and library on small input files written by the tests
+
+mod cram;
+mod errors;
+mod filters;
+mod fixture;
+mod inputs;
+mod intervals;
+mod library;
+mod mods;
+mod output;
+mod per_position;
+mod per_read;
+mod progress;
+#[cfg(feature = "http-tests")]
+mod remote;
+mod streaming;
+mod threads;
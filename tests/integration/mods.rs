@@ -0,0 +1,59 @@
+use crate::fixture::*;
+
+#[test]
+fn writes_modification_counts_as_bedmethyl() {
+    let fixture = Fixture::new();
+    let read = |name, flag, tags: &str| format!("{}\t{}", sam(name, flag, 1, "10M", chr1(1, 10), &quals(10)), tags);
+    fixture.write_inputs(&[
+        // The C at 2 is modified in r1 and unmodified in r2
+        &read("r1", 0, "MM:Z:C+m?,0;\tML:B:C,250"),
+        &read("r2", 0, "MM:Z:C+m?,0;\tML:B:C,10"),
+        // Sequenced from the other strand, whose third C is the complement of the G at 3
+        &read("r3", 16, "MM:Z:C+m?,2;\tML:B:C,200"),
+    ]);
+    fixture.bampile_ok(
+        "-b reads.bam -f ref.fa -r chr1:1-10 --mods C+m --format bedmethyl --no-header-comments --output-file out.bed",
+    );
+    assert_eq!(
+        fixture.read("out.bed"),
+        "chr1\t1\t2\tm\t2\t+\t1\t2\t255,0,0\t2\t50.00\nchr1\t2\t3\tm\t1\t-\t2\t3\t255,0,0\t1\t100.00\n"
+    );
+}
+
+#[test]
+fn counts_each_modification_code_in_its_own_columns() {
+    let fixture = Fixture::new();
+    let read = |name, tags: &str| format!("{}\t{}", sam(name, 0, 1, "10M", chr1(1, 10), &quals(10)), tags);
+    fixture.write_inputs(&[
+        // The C at 2 is called 5mC and the C at 7 5hmC, in an entry of each code
+        &read("r1", "MM:Z:C+m,0,0;C+h,0,0;\tML:B:C,200,10,10,200"),
+        // One entry with both codes, whose ML values go by call and then code: 5mC at 2 only
+        &read("r2", "MM:Z:C+mh,0,0;\tML:B:C,200,10,10,10"),
+        // Only 5mC is called at the C at 11 and no other C of r3, so 11 has no 5hmC frequency
+        &format!("{}\tMM:Z:C+m?,0;\tML:B:C,200", sam("r3", 0, 11, "10M", chr1(11, 10), &quals(10))),
+    ]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --mods C+m,C+h --no-header-comments --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!(
+        rows[0],
+        [
+            "chrom",
+            "pos",
+            "ref_base",
+            "calls_C+m",
+            "modified_C+m",
+            "mod_frequency_C+m",
+            "calls_C+h",
+            "modified_C+h",
+            "mod_frequency_C+h"
+        ]
+    );
+    assert_eq!(
+        rows[1..],
+        [
+            ["chr1", "2", "C", "2", "2", "1.0000", "2", "0", "0.0000"],
+            ["chr1", "7", "C", "2", "0", "0.0000", "2", "1", "0.5000"],
+            ["chr1", "11", "C", "1", "1", "1.0000", "0", "0", "*"]
+        ]
+    );
+}
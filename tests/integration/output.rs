@@ -0,0 +1,480 @@
+use arrow_array::cast::AsArray;
+use arrow_array::types::UInt64Type;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+use crate::fixture::*;
+
+// Per-position counts over chr1:1-80 of reads along chr1
+fn write_output_inputs(fixture: &Fixture) {
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "40M", chr1(1, 40), &quals(40)),
+        &sam("r2", 16, 21, "30M", chr1(21, 30), &quals(30)),
+        &sam("r3", 0, 41, "40M", chr1(41, 40), &quals(40)),
+    ]);
+}
+
+// BGZF marks every block with a BC extra field and ends with an empty block
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, b'B', b'C', 0x02, 0, 0x1b, 0, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+#[test]
+fn writes_the_gzip_content_as_bgzf() {
+    let fixture = Fixture::new();
+    write_output_inputs(&fixture);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-80 -m per-position --no-header-comments";
+    fixture.bampile_ok(&format!("{} --output-file plain.tsv.gz", args));
+    fixture.bampile_ok(&format!("{} --output-file blocked.tsv.gz --bgzip", args));
+
+    let bgzf = std::fs::read(fixture.path("blocked.tsv.gz")).unwrap();
+    assert_eq!(bgzf[3] & 0x04, 0x04, "no extra field");
+    assert_eq!(&bgzf[12..14], b"BC");
+    assert!(bgzf.ends_with(&BGZF_EOF), "no BGZF end-of-file block");
+    assert!(fixture.exists("blocked.tsv.gz.gzi") && fixture.exists("blocked.tsv.gz.tbi"));
+    let gzip = std::fs::read(fixture.path("plain.tsv.gz")).unwrap();
+    assert_ne!(&gzip[12..14], b"BC");
+
+    let contents = fixture.read_gz("plain.tsv.gz");
+    assert_eq!(tsv(&contents).len(), 81);
+    assert_eq!(fixture.read_gz("blocked.tsv.gz"), contents);
+}
+
+#[test]
+fn writes_the_gzip_content_as_zstd() {
+    let fixture = Fixture::new();
+    write_output_inputs(&fixture);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-80 -m per-position --no-header-comments";
+    fixture.bampile_ok(&format!("{} -o gzip", args));
+    fixture.bampile_ok(&format!("{} -o zstd --compression zstd --compression-level 19", args));
+    fixture.bampile_ok(&format!("{} --output-file single.tsv.zst", args));
+
+    let contents = fixture.read_gz("gzip/reads/chr1.tsv.gz");
+    assert_eq!(tsv(&contents).len(), 81);
+    assert!(!fixture.exists("zstd/reads/chr1.tsv.gz"));
+    assert_eq!(fixture.read_zst("zstd/reads/chr1.tsv.zst"), contents);
+    assert_eq!(fixture.read_zst("single.tsv.zst"), contents);
+    let (_, stderr) = fixture.bampile_err(&format!("{} -o zstd23 --compression zstd --compression-level 23", args));
+    assert!(stderr.contains("Invalid compression level"), "{}", stderr);
+}
+
+#[test]
+fn overwrites_the_files_of_an_earlier_run_only_with_force() {
+    let fixture = Fixture::new();
+    write_output_inputs(&fixture);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-80 -m per-position -o out --coverage-summary coverage.tsv";
+    fixture.bampile_ok(args);
+    fixture.write("coverage.tsv", "earlier\n");
+    let (code, stderr) = fixture.bampile_err(args);
+    assert_eq!(code, 1);
+    assert!(
+        stderr
+            .contains("output files already exist, use --force to overwrite them: out/reads/chr1.tsv.gz, coverage.tsv"),
+        "{}",
+        stderr
+    );
+    assert_eq!(fixture.read("coverage.tsv"), "earlier\n");
+    fixture.bampile_ok(&format!("{} --force", args));
+    assert_eq!(column(&tsv(&fixture.read("coverage.tsv")), "chrom"), ["chr1"]);
+}
+
+#[test]
+fn writes_a_histogram_of_the_base_qualities() {
+    let fixture = Fixture::new();
+    // Mismatches at 3 and 6, qualities above 60 are counted at 60
+    fixture.write_inputs(&[&sam("r1", 0, 1, "6M", "ACTTTC", "5I5~+I")]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --qual-histogram hist.tsv --output-file out.tsv");
+    let rows = tsv(&fixture.read("hist.tsv"));
+    assert_eq!(rows[0], ["qual", "match_count", "mismatch_count"]);
+    assert_eq!(rows.len(), 62);
+    let counted: Vec<Vec<String>> = rows.into_iter().skip(1).filter(|row| row[1] != "0" || row[2] != "0").collect();
+    assert_eq!(counted, [["10", "1", "0"], ["20", "1", "1"], ["40", "1", "1"], ["60", "1", "0"]]);
+}
+
+#[test]
+fn names_contig_files_by_a_template() {
+    let fixture = Fixture::new();
+    write_output_inputs(&fixture);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-80 --qscore 20";
+    fixture.bampile_ok(&format!("{} -o params --name-template {{ref}}_q{{qscore}}_{{mode}}.tsv.gz", args));
+    assert!(fixture.exists("params/reads/chr1_q20_per-read.tsv.gz"));
+    fixture.bampile_ok(&format!("{} -o dated --name-template {{ref}}_{{date}}.tsv", args));
+    let file_names: Vec<String> = std::fs::read_dir(fixture.path("dated/reads"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(file_names.len(), 1);
+    let date = file_names[0].strip_prefix("chr1_").and_then(|name| name.strip_suffix(".tsv")).unwrap();
+    assert!(date.len() == 8 && date.starts_with("20") && date.bytes().all(|byte| byte.is_ascii_digit()), "{}", date);
+    let (_, stderr) = fixture.bampile_err(&format!("{} -o same --name-template counts.tsv.gz", args));
+    assert!(stderr.contains("has no {ref} placeholder"), "{}", stderr);
+}
+
+#[test]
+fn writes_a_file_per_interval() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)),
+        &sam("r2", 0, 15, "40M", chr1(15, 40), &quals(40)),
+        &sam("r3", 0, 55, "10M", chr1(55, 10), &quals(10)),
+    ]);
+    fixture.write("regions.bed", "chr1\t0\t10\tampA\nchr1\t50\t70\n");
+    fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed --split-by-interval -o out");
+    let read_ids = |file_name| {
+        let mut read_ids = column(&tsv(&fixture.read_gz(file_name)), "read_id");
+        read_ids.sort();
+        read_ids
+    };
+    assert_eq!(read_ids("out/reads/ampA.tsv.gz"), ["r1"]);
+    // r2 only reaches into the second interval, whose bases are the only ones counted
+    assert_eq!(read_ids("out/reads/chr1_51-70.tsv.gz"), ["r2", "r3"]);
+    let rows = tsv(&fixture.read_gz("out/reads/chr1_51-70.tsv.gz"));
+    let mut matches: Vec<(String, String)> =
+        column(&rows, "read_id").into_iter().zip(column(&rows, "num_matches")).collect();
+    matches.sort();
+    assert_eq!(matches, [("r2".to_string(), "4".to_string()), ("r3".to_string(), "10".to_string())]);
+}
+
+#[test]
+fn writes_the_mismatch_spectrum_of_a_contig() {
+    let fixture = Fixture::new();
+    // A>G at 1 and C>T at 2, then C>T at 2 again
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "10M", "GTGTTGCAAG", &quals(10)),
+        &sam("r2", 0, 1, "10M", "ATGTTGCAAG", &quals(10)),
+    ]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --mismatch-spectrum spectrum.tsv --output-file out.tsv");
+    let rows = tsv(&fixture.read("spectrum.tsv"));
+    assert_eq!(rows[0], ["chrom", "ref", "alt", "count"]);
+    assert_eq!(rows.len(), 13);
+    let count = |ref_base: &str, alt_base: &str| {
+        let row = rows.iter().find(|row| row[1] == ref_base && row[2] == alt_base).unwrap();
+        row[3].parse::<u64>().unwrap()
+    };
+    assert_eq!(count("A", "G"), 1);
+    assert_eq!(count("C", "T"), 2);
+    let total: u64 = rows[1..].iter().map(|row| row[3].parse::<u64>().unwrap()).sum();
+    assert_eq!(total, 3);
+}
+
+#[test]
+fn lists_the_mismatching_positions_of_an_interval() {
+    let fixture = Fixture::new();
+    // Three of four reads have a C in place of the A at 8, one of them also a G in place of the C at 15
+    let variant = format!("{}C{}", chr1(1, 7), chr1(9, 12));
+    let both = format!("{}C{}G{}", chr1(1, 7), chr1(9, 6), chr1(16, 5));
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "20M", chr1(1, 20), &quals(20)),
+        &sam("r2", 0, 1, "20M", &variant, &quals(20)),
+        &sam("r3", 16, 1, "20M", &variant, &quals(20)),
+        &sam("r4", 0, 1, "20M", &both, &quals(20)),
+    ]);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-20 --output-file out.tsv --force";
+    fixture.bampile_ok(&format!("{} --debug-positions debug.tsv", args));
+    let rows = tsv(&fixture.read("debug.tsv"));
+    assert_eq!(rows[0], ["chrom", "start", "end", "pos", "ref_base", "depth", "A", "C", "G", "T", "N", "del"]);
+    assert_eq!(
+        rows[1..],
+        [
+            ["chr1", "0", "20", "8", "A", "4", "1", "3", "0", "0", "0", "0"],
+            ["chr1", "0", "20", "15", "C", "4", "0", "3", "1", "0", "0", "0"],
+        ]
+    );
+    fixture.bampile_ok(&format!("{} --debug-positions first.tsv --debug-positions-limit 1", args));
+    assert_eq!(tsv(&fixture.read("first.tsv"))[1..], rows[1..2]);
+}
+
+#[test]
+fn records_the_run_in_a_manifest() {
+    let fixture = Fixture::new();
+    write_output_inputs(&fixture);
+    fixture.write("regions.bed", "chr1\t0\t20\nchr1\t60\t80\n");
+    fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed --qscore 20 --manifest run.json --output-file out.tsv");
+    let manifest: serde_json::Value = serde_json::from_str(&fixture.read("run.json")).unwrap();
+    assert_eq!(manifest["qscore_cutoff"], 20);
+    assert_eq!(manifest["n_intervals"], 2);
+    assert_eq!(manifest["mode"], "per-read");
+    assert_eq!(manifest["version"], env!("CARGO_PKG_VERSION"));
+    // r1 in the first interval and r3 in the second, with 20 bases each
+    assert_eq!(manifest["total_reads"], 2);
+    assert_eq!(manifest["total_bases"], 40);
+    let bam_path = manifest["bam_files"][0]["path"].as_str().unwrap();
+    assert_eq!(std::path::Path::new(bam_path), std::fs::canonicalize(fixture.path("reads.bam")).unwrap());
+}
+
+#[test]
+fn writes_the_counts_of_the_table_as_parquet() {
+    let fixture = Fixture::new();
+    write_output_inputs(&fixture);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-80";
+    fixture.bampile_ok(&format!("{} --output-file reads.tsv", args));
+    fixture.bampile_ok(&format!("{} --format parquet --output-file reads.parquet", args));
+
+    let rows = tsv(&fixture.read("reads.tsv"));
+    let mut expected: Vec<(String, u64, u64)> =
+        rows[1..].iter().map(|row| (row[1].clone(), row[2].parse().unwrap(), row[3].parse().unwrap())).collect();
+    expected.sort();
+    let file = std::fs::File::open(fixture.path("reads.parquet")).unwrap();
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file).unwrap().build().unwrap();
+    let mut counts = Vec::new();
+    for batch in reader {
+        let batch = batch.unwrap();
+        let column = |name| batch.column_by_name(name).unwrap_or_else(|| panic!("no column {}", name));
+        let read_ids = column("read_id").as_string::<i32>();
+        let matches = column("num_matches").as_primitive::<UInt64Type>();
+        let mismatches = column("num_mismatches").as_primitive::<UInt64Type>();
+        for row in 0..batch.num_rows() {
+            counts.push((read_ids.value(row).to_string(), matches.value(row), mismatches.value(row)));
+        }
+        assert!(column("reference").as_string::<i32>().iter().all(|reference| reference == Some("chr1")));
+    }
+    counts.sort();
+    assert_eq!(counts.len(), 3);
+    assert_eq!(counts, expected);
+}
+
+#[test]
+fn writes_a_line_for_every_read_base() {
+    let fixture = Fixture::new();
+    // r2 has a T in place of the C at 2
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)), &sam("r2", 0, 1, "5M", "ATGTT", "I5III")]);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-3 --long --no-header-comments";
+    let stderr = fixture.bampile_ok(&format!("{} --output-file long.tsv.gz", args));
+    assert!(stderr.contains("Warning: --long writes a line for every aligned read base"), "{}", stderr);
+    let mut rows: Vec<Vec<String>> =
+        tsv(&fixture.read_gz("long.tsv.gz")).into_iter().map(|row| row[..7].to_vec()).collect();
+    assert_eq!(rows[0], ["chrom", "pos", "read_id", "ref_base", "read_base", "qual", "is_match"]);
+    rows[1..].sort_by(|a, b| (&a[2], &a[1]).cmp(&(&b[2], &b[1])));
+    let expected = [
+        ["chr1", "1", "r1", "A", "A", "40", "1"],
+        ["chr1", "2", "r1", "C", "C", "40", "1"],
+        ["chr1", "3", "r1", "G", "G", "40", "1"],
+        ["chr1", "1", "r2", "A", "A", "40", "1"],
+        ["chr1", "2", "r2", "C", "T", "20", "0"],
+        ["chr1", "3", "r2", "G", "G", "40", "1"],
+    ];
+    assert_eq!(rows[1..], expected.map(|row| row.map(String::from)));
+    let (_, stderr) = fixture.bampile_err(&format!("{} --output-file long.tsv", args));
+    assert!(stderr.contains("--long output has to be compressed"), "{}", stderr);
+}
+
+// The same read on chr1|a and chr1a, whose names only differ in a character a file name can't
+// hold, removing it makes them the same
+fn write_colliding_inputs(fixture: &Fixture) {
+    fixture.write_fasta("ref.fa", &[("chr1|a", CHR1), ("chr1a", CHR1)]);
+    let sam_lines: Vec<String> = ["chr1|a", "chr1a"]
+        .iter()
+        .map(|contig| format!("r1\t0\t{}\t1\t60\t10M\t*\t0\t0\t{}\t{}", contig, chr1(1, 10), quals(10)))
+        .collect();
+    let sam_lines: Vec<&str> = sam_lines.iter().map(String::as_str).collect();
+    fixture.write_bam("reads.bam", &[("chr1|a", CHR1.len()), ("chr1a", CHR1.len())], &sam_lines);
+    fixture.write("regions.bed", "chr1|a\t0\t20\nchr1a\t0\t5\n");
+}
+
+#[test]
+fn keeps_contigs_apart_in_a_combined_table() {
+    let fixture = Fixture::new();
+    write_colliding_inputs(&fixture);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed --output-file out.tsv.gz");
+    let rows = tsv(&fixture.read_gz("out.tsv.gz"));
+    assert_eq!(rows[0][..4], ["reference", "read_id", "num_matches", "num_mismatches"]);
+    let mut counts: Vec<&[String]> = rows[1..].iter().map(|row| &row[..4]).collect();
+    counts.sort();
+    assert_eq!(counts, [["chr1a", "r1", "5", "0"], ["chr1|a", "r1", "10", "0"]]);
+
+    let (code, stderr) = fixture.bampile_err("-b reads.bam -f ref.fa -e regions.bed --output-file all.tsv -o out");
+    assert_eq!(code, 2);
+    assert!(stderr.contains("cannot be used with"), "{}", stderr);
+}
+
+#[test]
+fn writes_colliding_contig_names_to_distinct_files() {
+    let fixture = Fixture::new();
+    write_colliding_inputs(&fixture);
+    let stderr = fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed -o out --compression none");
+    assert!(stderr.contains("writing contig chr1|a to chr1%7Ca.tsv"), "{}", stderr);
+    let read_counts = |name: &str| column(&tsv(&fixture.read(name)), "num_matches");
+    assert_eq!(read_counts("out/reads/chr1%7Ca.tsv"), ["10"]);
+    assert_eq!(read_counts("out/reads/chr1a.tsv"), ["5"]);
+}
+
+#[test]
+fn summarizes_the_coverage_of_an_interval() {
+    let fixture = Fixture::new();
+    // Over 11-20, r1 covers 11-15, r2 16-20 and r3 14-18, r4 is outside
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "15M", chr1(1, 15), &quals(15)),
+        &sam("r3", 0, 14, "5M", chr1(14, 5), &quals(5)),
+        &sam("r2", 16, 16, "15M", chr1(16, 15), &quals(15)),
+        &sam("r4", 0, 41, "10M", chr1(41, 10), &quals(10)),
+    ]);
+    fixture.bampile_ok(
+        "-b reads.bam -f ref.fa -r chr1:11-20 --output-file out.tsv --coverage-summary coverage.tsv --coverage-depth 2",
+    );
+    let rows = tsv(&fixture.read("coverage.tsv"));
+    assert_eq!(
+        rows,
+        [
+            ["chrom", "start", "end", "n_reads", "mean_depth", "frac_covered"],
+            ["chr1", "10", "20", "3", "1.5000", "0.5000"]
+        ]
+    );
+}
+
+#[test]
+fn records_the_parameters_in_comment_lines_above_the_header() {
+    let fixture = Fixture::new();
+    write_output_inputs(&fixture);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-80 --qscore 25";
+    fixture.bampile_ok(&format!("{} --output-file out.tsv", args));
+    let contents = fixture.read("out.tsv");
+    let lines: Vec<&str> = contents.lines().collect();
+    let n_comments = lines.iter().take_while(|line| line.starts_with('#')).count();
+    assert!(lines[..n_comments].contains(&"#qscore_cutoff: 25"), "{}", contents);
+    assert!(lines[..n_comments].contains(&concat!("#bampile ", env!("CARGO_PKG_VERSION"))), "{}", contents);
+    assert!(lines[..n_comments].iter().any(|line| line.starts_with("#command: ") && line.contains("--qscore 25")));
+    assert!(lines[n_comments].starts_with("reference\tread_id\t"), "{}", contents);
+
+    fixture.bampile_ok(&format!("{} --no-header-comments --output-file plain.tsv", args));
+    let mut plain = tsv(&fixture.read("plain.tsv"));
+    let mut commented = tsv(&contents);
+    assert!(!fixture.read("plain.tsv").contains('#'));
+    plain.sort();
+    commented.sort();
+    assert_eq!(plain, commented);
+}
+
+#[test]
+fn summarizes_the_per_read_counts_of_each_contig() {
+    let fixture = Fixture::new();
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1), ("chr2", CHR1)]);
+    let on_chr2 = |name: &str, sequence: &str, qualities: &str| {
+        format!("{}\t0\tchr2\t1\t60\t10M\t*\t0\t0\t{}\t{}", name, sequence, qualities)
+    };
+    fixture.write_bam(
+        "reads.bam",
+        &[("chr1", CHR1.len()), ("chr2", CHR1.len())],
+        &[
+            // One and two mismatches on chr1 and three on chr2, one of them below the Q-score cutoff
+            &sam("r1", 0, 1, "10M", "ACGATGCAAG", &quals(10)),
+            &sam("r2", 0, 1, "20M", "ACGTTGCAAGCTAACGGTTC", &quals(20)),
+            &sam("r3", 0, 41, "30M", chr1(41, 30), &quals(30)),
+            &on_chr2("r4", "TCGTTGCAAG", &quals(10)),
+            &on_chr2("r5", "AGGTTGCAAC", "IIIIIIIII#"),
+        ],
+    );
+    fixture.write("regions.bed", "chr1\t0\t80\nchr2\t0\t20\n");
+    let args = "-b reads.bam -f ref.fa -e regions.bed --contig-summary summary.tsv --output-file reads.tsv";
+    fixture.bampile_ok(args);
+    let reads = tsv(&fixture.read("reads.tsv"));
+    let summary = tsv(&fixture.read("summary.tsv"));
+    assert_eq!(summary[0], ["chrom", "n_reads", "counted_bases", "matches", "mismatches", "mismatch_rate"]);
+    assert_eq!(column(&summary, "chrom"), ["chr1", "chr2"]);
+    for row in &summary[1..] {
+        let contig_reads: Vec<&Vec<String>> = reads[1..].iter().filter(|read| read[0] == row[0]).collect();
+        let total = |index: usize| contig_reads.iter().map(|read| read[index].parse::<u64>().unwrap()).sum::<u64>();
+        let (matches, mismatches) = (total(2), total(3));
+        assert_eq!(
+            row[1..5],
+            [contig_reads.len() as u64, matches + mismatches, matches, mismatches].map(|n| n.to_string())
+        );
+        let rate: f64 = row[5].parse().unwrap();
+        assert!((rate - mismatches as f64 / (matches + mismatches) as f64).abs() < 1e-6, "{:?}", row);
+    }
+    assert_eq!(summary[1][1..5], ["3", "60", "57", "3"]);
+    assert_eq!(summary[2][1..5], ["2", "19", "17", "2"]);
+}
+
+// Fields of the lines of a CSV file without its # comment lines, unquoting quoted fields
+fn csv_rows(contents: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    for line in contents.lines().filter(|line| !line.starts_with('#')) {
+        let mut fields = vec![String::new()];
+        let mut quoted = false;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    fields.last_mut().unwrap().push('"');
+                    chars.next();
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => fields.push(String::new()),
+                c => fields.last_mut().unwrap().push(c),
+            }
+        }
+        rows.push(fields);
+    }
+    rows
+}
+
+#[test]
+fn writes_tables_as_csv_that_parse_back_to_the_tsv_rows() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[
+        &sam("r1,comma", 0, 1, "10M", chr1(1, 10), &quals(10)),
+        &sam("r2\"quote", 0, 1, "10M", "ACGATGCAAG", "*"),
+    ]);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-20 --read-quality --na-string NA -o out";
+    fixture.bampile_ok(&format!("{} --compression none", args));
+    fixture.bampile_ok(&format!("{} --compression none --delimiter comma --force", args));
+    let mut tsv_rows = tsv(&fixture.read("out/reads/chr1.tsv"));
+    let mut csv_rows = csv_rows(&fixture.read("out/reads/chr1.csv"));
+    assert!(fixture.read("out/reads/chr1.csv").contains("\"r2\"\"quote\""));
+    assert_eq!(csv_rows[0], tsv_rows[0]);
+    tsv_rows[1..].sort();
+    csv_rows[1..].sort();
+    assert_eq!(csv_rows, tsv_rows);
+    assert_eq!(column(&csv_rows, "read_id"), ["r1,comma", "r2\"quote"]);
+    assert_eq!(column(&csv_rows, "mean_qual")[1], "NA");
+}
+
+#[test]
+fn appends_the_reads_of_a_second_run_without_a_second_header() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    fixture.write_bam("more.bam", &[("chr1", CHR1.len())], &[&sam("r2", 0, 11, "10M", chr1(11, 10), &quals(10))]);
+    let args = "-f ref.fa -r chr1:1-20";
+    for output in ["--output-file all.tsv", "-o out --no-sample-dirs"] {
+        fixture.bampile_ok(&format!("-b reads.bam {} {} --append", args, output));
+        fixture.bampile_ok(&format!("-b more.bam {} {} --append", args, output));
+    }
+    for contents in [fixture.read("all.tsv"), fixture.read_gz("out/chr1.tsv.gz")] {
+        let headers = contents.lines().filter(|line| line.contains("read_id")).count();
+        assert_eq!(headers, 1, "{}", contents);
+        assert_eq!(contents.lines().filter(|line| line.starts_with("#bampile")).count(), 1, "{}", contents);
+        assert_eq!(column(&tsv(&contents), "read_id"), ["r1", "r2"]);
+    }
+
+    let (code, stderr) =
+        fixture.bampile_err(&format!("-b more.bam {} --output-file all.tsv --append --read-quality", args));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("can't append to all.tsv, its columns"), "{}", stderr);
+    assert_eq!(column(&tsv(&fixture.read("all.tsv")), "read_id"), ["r1", "r2"]);
+}
+
+#[test]
+fn profiles_the_mismatch_rate_along_the_intervals() {
+    let fixture = Fixture::new();
+    // Three of four reads over 11-20 mismatch at its fifth base, none over 51-60
+    let mismatch = format!("{}A{}", chr1(11, 4), chr1(16, 5));
+    let mut reads = Vec::new();
+    for index in 0..4 {
+        let sequence = if index < 3 { mismatch.as_str() } else { chr1(11, 10) };
+        reads.push(sam(&format!("a{}", index), 0, 11, "10M", sequence, &quals(10)));
+    }
+    for index in 0..4 {
+        reads.push(sam(&format!("b{}", index), 0, 51, "10M", chr1(51, 10), &quals(10)));
+    }
+    let reads: Vec<&str> = reads.iter().map(String::as_str).collect();
+    fixture.write_inputs(&reads);
+    fixture.write("regions.bed", "chr1\t10\t20\nchr1\t50\t60\n");
+    fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed --mismatch-profile profile.tsv --output-file out.tsv");
+    let rows = tsv(&fixture.read("profile.tsv"));
+    assert_eq!(rows[0], ["rel_pos", "depth", "mismatch_rate"]);
+    let rel_positions: Vec<String> = (0..10).map(|rel_pos| rel_pos.to_string()).collect();
+    assert_eq!(column(&rows, "rel_pos"), rel_positions);
+    assert!(column(&rows, "depth").iter().all(|depth| depth == "8"));
+    let rates: Vec<f64> = column(&rows, "mismatch_rate").iter().map(|rate| rate.parse().unwrap()).collect();
+    assert_eq!(rates[4], 0.375);
+    assert!(rates.iter().enumerate().all(|(rel_pos, rate)| rel_pos == 4 || *rate == 0.0), "{:?}", rates);
+}
@@ -0,0 +1,322 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use noodles::vcf;
+use noodles::vcf::variant::record_buf::info::field::value::Array;
+use noodles::vcf::variant::record_buf::info::field::Value;
+
+use crate::fixture::*;
+
+// Reads over position 11, a C in chr1, with A, C, N, T and a deletion there
+fn write_pileup_inputs(fixture: &Fixture) {
+    fixture.write_inputs(&[
+        &sam("r1", 0, 6, "10M", chr1(6, 10), &quals(10)),
+        &sam("r2", 0, 6, "10M", "GCAAGATTAC", &quals(10)),
+        &sam("r3", 0, 6, "10M", "GCAAGNTTAC", &quals(10)),
+        &sam("r4", 0, 6, "5M1D4M", "GCAAGTTAC", &quals(9)),
+        &sam("r5", 16, 6, "10M", "GCAAGTTTAC", &quals(10)),
+    ]);
+}
+
+// The row of a position in a per-position table
+fn position_row<'a>(rows: &'a [Vec<String>], pos: &str) -> &'a [String] {
+    rows.iter().find(|row| row[1] == pos).unwrap_or_else(|| panic!("no position {}", pos))
+}
+
+#[test]
+fn counts_the_bases_of_a_position() {
+    let fixture = Fixture::new();
+    write_pileup_inputs(&fixture);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 -m per-position --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!(rows[0][..10], ["chrom", "pos", "ref_base", "depth", "A", "C", "G", "T", "N", "del"]);
+    assert_eq!(position_row(&rows, "11")[..10], ["chr1", "11", "C", "5", "1", "1", "0", "1", "1", "1"]);
+    // All reads agree with the reference at 12
+    assert_eq!(position_row(&rows, "12")[..10], ["chr1", "12", "T", "5", "0", "0", "0", "5", "0", "0"]);
+}
+
+// 8 of 10 reads over 6-10 have a C at 8, where the reference has an A
+fn write_variant_inputs(fixture: &Fixture) {
+    let mut reads = Vec::new();
+    for index in 0..10 {
+        let sequence = if index < 8 { "GCCAG" } else { chr1(6, 5) };
+        reads.push(sam(&format!("r{}", index), 0, 6, "5M", sequence, &quals(5)));
+    }
+    let reads: Vec<&str> = reads.iter().map(String::as_str).collect();
+    fixture.write_inputs(&reads);
+}
+
+#[test]
+fn calls_a_consensus_base_differing_from_the_reference() {
+    let fixture = Fixture::new();
+    write_variant_inputs(&fixture);
+    let args = "-b reads.bam -f ref.fa -r chr1:8-8 -m consensus --output-file out.tsv --force";
+
+    fixture.bampile_ok(args);
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!(rows[0][..7], ["chrom", "pos", "ref", "consensus", "depth", "consensus_af", "is_variant"]);
+    assert_eq!(rows[1][..7], ["chr1", "8", "A", "C", "10", "0.8000", "1"]);
+    // Below the allele frequency cutoff it is still the consensus, but no variant
+    fixture.bampile_ok(&format!("{} --min-af 0.9", args));
+    assert_eq!(tsv(&fixture.read("out.tsv"))[1][..7], ["chr1", "8", "A", "C", "10", "0.8000", "0"]);
+    // Below the minimum depth there is no call
+    fixture.bampile_ok(&format!("{} --min-depth 11", args));
+    assert_eq!(tsv(&fixture.read("out.tsv")).len(), 1);
+}
+
+#[test]
+fn writes_a_variant_as_vcf() {
+    let fixture = Fixture::new();
+    write_variant_inputs(&fixture);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 -m consensus --format vcf --output-file out.vcf");
+
+    let mut reader = vcf::io::Reader::new(File::open(fixture.path("out.vcf")).map(BufReader::new).unwrap());
+    let header = reader.read_header().unwrap();
+    assert_eq!(header.contigs().get("chr1").and_then(|contig| contig.length()), Some(CHR1.len()));
+    let records: Vec<vcf::variant::RecordBuf> = reader.record_bufs(&header).map(Result::unwrap).collect();
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record.reference_sequence_name(), "chr1");
+    assert_eq!(record.variant_start().map(usize::from), Some(8));
+    assert_eq!(record.reference_bases(), "A");
+    assert_eq!(record.alternate_bases().as_ref(), ["C"]);
+    assert_eq!(record.info().get("DP"), Some(Some(&Value::Integer(10))));
+    assert_eq!(record.info().get("AF"), Some(Some(&Value::Array(Array::Float(vec![Some(0.8)])))));
+}
+
+#[test]
+fn caps_the_depth_of_a_position() {
+    let fixture = Fixture::new();
+    let reads: Vec<String> =
+        (0..100).map(|index| sam(&format!("r{}", index), 0, 1, "10M", chr1(1, 10), &quals(10))).collect();
+    let reads: Vec<&str> = reads.iter().map(String::as_str).collect();
+    fixture.write_inputs(&reads);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:5-5 -m per-position --output-file all.tsv");
+    assert_eq!(column(&tsv(&fixture.read("all.tsv")), "depth"), ["100"]);
+    for mode in ["per-position", "consensus"] {
+        let args = format!("-r chr1:5-5 -m {} --max-depth 10 --output-file out.tsv --force", mode);
+        fixture.bampile_ok(&format!("-b reads.bam -f ref.fa {}", args));
+        assert_eq!(column(&tsv(&fixture.read("out.tsv")), "depth"), ["10"], "{}", mode);
+    }
+}
+
+#[test]
+fn counts_only_the_positions_in_the_interval_of_a_read_past_its_end() {
+    let fixture = Fixture::new();
+    // Ends 20 bases after the interval 11-20
+    fixture.write_inputs(&[&sam("r1", 0, 11, "30M", chr1(11, 30), &quals(30))]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:11-20 -m per-position --output-file pos.tsv");
+    let rows = tsv(&fixture.read("pos.tsv"));
+    let positions: Vec<usize> = column(&rows, "pos").iter().map(|pos| pos.parse().unwrap()).collect();
+    assert_eq!(positions, (11..=20).collect::<Vec<_>>());
+    assert!(column(&rows, "depth").iter().all(|depth| depth == "1"));
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:11-20 --output-file reads.tsv");
+    assert_eq!(column(&tsv(&fixture.read("reads.tsv")), "num_matches"), ["10"]);
+}
+
+#[test]
+fn counts_the_overlap_of_a_read_pair_once() {
+    let fixture = Fixture::new();
+    // The mates overlap at 21-40, where the first one has a C of quality 30 instead of the A at 30
+    let first_sequence = format!("{}C{}", chr1(1, 29), chr1(31, 10));
+    let first_qualities = format!("{}?{}", quals(29), quals(10));
+    let first = format!("p1\t99\tchr1\t1\t60\t40M\t=\t21\t60\t{}\t{}", first_sequence, first_qualities);
+    let second = format!("p1\t147\tchr1\t21\t60\t40M\t=\t1\t-60\t{}\t{}", chr1(21, 40), quals(40));
+    fixture.write_inputs(&[&first, &second]);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-60 -m per-position";
+    fixture.bampile_ok(&format!("{} --output-file all.tsv", args));
+    let rows = tsv(&fixture.read("all.tsv"));
+    assert_eq!(position_row(&rows, "21")[3], "2");
+    assert_eq!(position_row(&rows, "30")[3..6], ["2", "1", "1"]);
+
+    fixture.bampile_ok(&format!("{} --dedup-overlap --output-file dedup.tsv", args));
+    let rows = tsv(&fixture.read("dedup.tsv"));
+    assert_eq!(column(&rows, "depth"), vec!["1"; 60]);
+    // The A of the second mate has the higher quality
+    assert_eq!(position_row(&rows, "30")[3..6], ["1", "1", "0"]);
+}
+
+#[test]
+fn counts_the_overlap_of_a_read_pair_once_within_the_trimmed_ends_of_both() {
+    let fixture = Fixture::new();
+    // The second mate lies within the first, at 21-40 of its 1-60
+    let first = format!("p1\t99\tchr1\t1\t60\t60M\t=\t21\t40\t{}\t{}", chr1(1, 60), quals(60));
+    let second = format!("p1\t147\tchr1\t21\t60\t20M\t=\t1\t-40\t{}\t{}", chr1(21, 20), quals(20));
+    fixture.write_inputs(&[&first, &second]);
+    // Trimmed by 5 bases, the first mate counts at 6-55 and the second at 26-35
+    let args = "-b reads.bam -f ref.fa -r chr1:1-70 -m per-position --trim-ends 5";
+    let depths = |extra_args: &str| {
+        fixture.bampile_ok(&format!("{} {} --force --output-file out.tsv", args, extra_args));
+        let rows = tsv(&fixture.read("out.tsv"));
+        column(&rows, "pos").into_iter().zip(column(&rows, "depth")).collect::<Vec<_>>()
+    };
+    let expected = |overlap_depth: &str| {
+        (6..=55)
+            .map(|pos| (pos.to_string(), if (26..=35).contains(&pos) { overlap_depth } else { "1" }.to_string()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(depths(""), expected("2"));
+    assert_eq!(depths("--dedup-overlap"), expected("1"));
+}
+
+#[test]
+fn reports_the_strand_bias_of_alt_alleles_on_one_strand() {
+    let fixture = Fixture::new();
+    // At 8 the five forward and five reverse reads have the reference A, five other forward reads a C
+    let mut reads = Vec::new();
+    for index in 0..15 {
+        let (flag, sequence) = match index {
+            0..5 => (0, chr1(6, 5)),
+            5..10 => (16, chr1(6, 5)),
+            _ => (0, "GCCAG"),
+        };
+        reads.push(sam(&format!("r{}", index), flag, 6, "5M", sequence, &quals(5)));
+    }
+    let reads: Vec<&str> = reads.iter().map(String::as_str).collect();
+    fixture.write_inputs(&reads);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:6-10 -m per-position --strand-bias --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    let header = &rows[0];
+    let columns: Vec<usize> = ["alt_fwd", "alt_rev", "strand_bias"]
+        .iter()
+        .map(|name| header.iter().position(|c| c == name).unwrap())
+        .collect();
+    let strand_columns = |pos| columns.iter().map(|&index| position_row(&rows, pos)[index].clone()).collect::<Vec<_>>();
+    assert_eq!(strand_columns("8"), ["5", "0", "9.961"]);
+    assert_eq!(strand_columns("7"), ["0", "0", "0.000"]);
+}
+
+#[test]
+fn reports_the_distance_of_alt_alleles_from_the_read_end() {
+    let fixture = Fixture::new();
+    // A T in place of the G at 10 within 3 bases of the start of its reads, which start at 8-10,
+    // and an A in place of the G at 31 in the middle of a read over 11-50
+    let mut reads: Vec<String> = (8..=10)
+        .map(|pos| {
+            let sequence = format!("{}T{}", chr1(pos, 10 - pos), chr1(11, 10 + pos - 1));
+            sam(&format!("start{}", pos), 0, pos, "20M", &sequence, &quals(20))
+        })
+        .collect();
+    reads.push(sam("middle", 0, 11, "40M", &format!("{}A{}", chr1(11, 20), chr1(32, 19)), &quals(40)));
+    let reads: Vec<&str> = reads.iter().map(String::as_str).collect();
+    fixture.write_inputs(&reads);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-60 -m per-position --end-distance --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    let column_index = rows[0].iter().position(|name| name == "alt_end_dist").unwrap();
+    // The bases before the alt, 2, 1 and 0
+    assert_eq!(position_row(&rows, "10")[column_index], "1.0");
+    assert_eq!(position_row(&rows, "31")[column_index], "19.0");
+}
+
+#[test]
+fn annotates_positions_with_their_homopolymer_length() {
+    let fixture = Fixture::new();
+    let reference = format!("AAAAC{}", &CHR1[5..]);
+    fixture.write_fasta("ref.fa", &[("chr1", &reference)]);
+    fixture.write_bam(
+        "reads.bam",
+        &[("chr1", reference.len())],
+        &[&sam("r1", 0, 1, "10M", &reference[..10], &quals(10))],
+    );
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-5 -m per-position --hp-len --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!(column(&rows, "ref_base"), ["A", "A", "A", "A", "C"]);
+    assert_eq!(column(&rows, "hp_len"), ["4", "4", "4", "4", "1"]);
+}
+
+#[test]
+fn summarizes_the_two_most_frequent_alleles_of_a_position() {
+    let fixture = Fixture::new();
+    // Ten reads over 1-5 with a C in 7 and a T in 3 of them at the T at 5, and an A in half of
+    // them and a G in the others at the G at 3
+    let reads: Vec<String> = (0..10)
+        .map(|i| {
+            let sequence = format!("AC{}T{}", if i % 2 == 0 { "A" } else { "G" }, if i < 7 { "C" } else { "T" });
+            sam(&format!("r{}", i), 0, 1, "5M", &sequence, &quals(5))
+        })
+        .collect();
+    fixture.write_inputs(&reads.iter().map(String::as_str).collect::<Vec<_>>());
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-5 -m per-position --top2 --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    let alleles = |pos| {
+        let row = position_row(&rows, pos);
+        ["allele1", "count1", "allele2", "count2"]
+            .map(|name| row[rows[0].iter().position(|c| c == name).unwrap()].clone())
+    };
+    assert_eq!(alleles("5"), ["C", "7", "T", "3"]);
+    // Ties are listed in the order A, C, G, T
+    assert_eq!(alleles("3"), ["A", "5", "G", "5"]);
+    assert_eq!(alleles("1"), ["A", "10", "*", "0"]);
+}
+
+#[test]
+fn counts_the_ref_and_alt_reads_of_a_known_site() {
+    let fixture = Fixture::new();
+    // At 8, an A in the reference, three reads have the A, two the G and one a C
+    let mut reads = Vec::new();
+    for (index, base) in ["A", "A", "A", "G", "G", "C"].iter().enumerate() {
+        let sequence = format!("{}{}{}", chr1(6, 2), base, chr1(9, 2));
+        reads.push(sam(&format!("r{}", index), 0, 6, "5M", &sequence, &quals(5)));
+    }
+    let reads: Vec<&str> = reads.iter().map(String::as_str).collect();
+    fixture.write_inputs(&reads);
+    fixture.write("sites.tsv", "chr1\t8\tA\tG\n");
+    fixture.write(
+        "sites.vcf",
+        "##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\nchr1\t8\trs1\tA\tG\t.\tPASS\t.\n",
+    );
+    for sites_file in ["sites.tsv", "sites.vcf"] {
+        let output_file = format!("{}.counts.tsv", sites_file);
+        fixture
+            .bampile_ok(&format!("-b reads.bam -f ref.fa --known-sites {} --output-file {}", sites_file, output_file));
+        let rows = tsv(&fixture.read(&output_file));
+        assert_eq!(rows[0], ["chrom", "pos", "ref", "alt", "depth", "ref_count", "alt_count", "other_count", "vaf"]);
+        assert_eq!(rows[1..], [["chr1", "8", "A", "G", "6", "3", "2", "1", "0.4000"]], "{}", sites_file);
+    }
+}
+
+#[test]
+fn leaves_out_the_positions_below_the_minimum_base_count() {
+    let fixture = Fixture::new();
+    // Positions 1-5 are covered by one read, 6-10 by two
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)),
+        &sam("r2", 0, 6, "5M", chr1(6, 5), &quals(5)),
+    ]);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-10 -m per-position";
+    fixture.bampile_ok(&format!("{} --output-file all.tsv", args));
+    assert_eq!(column(&tsv(&fixture.read("all.tsv")), "pos").len(), 10);
+    fixture.bampile_ok(&format!("{} --min-base-count 2 --output-file out.tsv", args));
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!(column(&rows, "pos"), ["6", "7", "8", "9", "10"]);
+    assert!(column(&rows, "depth").iter().all(|depth| depth == "2"));
+}
+
+#[test]
+fn writes_positions_in_the_contig_order_of_the_bam_header() {
+    let fixture = Fixture::new();
+    // chr2 comes first in the BAM header, but last in the FASTA file
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1), ("chr2", CHR1)]);
+    let on_chr2 = |name: &str, pos: usize| {
+        format!("{}\t0\tchr2\t{}\t60\t20M\t*\t0\t0\t{}\t{}", name, pos, chr1(pos, 20), quals(20))
+    };
+    fixture.write_bam(
+        "reads.bam",
+        &[("chr2", CHR1.len()), ("chr1", CHR1.len())],
+        &[&on_chr2("r3", 1), &on_chr2("r4", 41), &sam("r1", 0, 1, "30M", chr1(1, 30), &quals(30))],
+    );
+    // Intervals out of order, on both contigs
+    fixture.write("regions.bed", "chr1\t20\t30\nchr2\t40\t50\nchr1\t0\t10\nchr2\t5\t15\n");
+    fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed -m per-position --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    let chroms = column(&rows, "chrom");
+    let positions: Vec<u32> = column(&rows, "pos").iter().map(|pos| pos.parse().unwrap()).collect();
+    assert_eq!(chroms.iter().filter(|chrom| *chrom == "chr2").count(), 20);
+    assert_eq!(chroms.iter().position(|chrom| chrom == "chr1"), Some(20));
+    for (index, window) in positions.windows(2).enumerate() {
+        if chroms[index] == chroms[index + 1] {
+            assert!(window[0] < window[1], "{} after {} on {}", window[1], window[0], chroms[index]);
+        }
+    }
+    assert_eq!(chroms.len(), 40);
+}
@@ -0,0 +1,287 @@
+use crate::fixture::*;
+
+// Value of a column in the row of a read in a per-read table
+fn read_value(rows: &[Vec<String>], read_id: &str, name: &str) -> String {
+    let read_ids = column(rows, "read_id");
+    let row = read_ids.iter().position(|id| id == read_id).unwrap_or_else(|| panic!("no read {}", read_id));
+    column(rows, name)[row].clone()
+}
+
+#[test]
+fn splits_counts_by_strand() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[
+        // One mismatch at 3 on the forward strand, two at 3 and 5 on the reverse strand
+        &sam("fwd", 0, 1, "10M", "ACTTTGCAAG", &quals(10)),
+        &sam("rev", 16, 1, "10M", "ACTTAGCAAG", &quals(10)),
+    ]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --by-strand --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    let columns = ["num_matches_fwd", "num_mismatches_fwd", "num_matches_rev", "num_mismatches_rev"];
+    let values = |read_id| columns.map(|name| read_value(&rows, read_id, name));
+    assert_eq!(values("fwd"), ["9", "1", "0", "0"]);
+    assert_eq!(values("rev"), ["0", "0", "8", "2"]);
+}
+
+#[test]
+fn counts_a_read_starting_before_the_interval() {
+    let fixture = Fixture::new();
+    // Starts 10 bases before the interval 21-30, with an A in place of the T at 13, outside it,
+    // and of the C at 25
+    let mut sequence = chr1(11, 30).as_bytes().to_vec();
+    sequence[2] = b'A';
+    sequence[14] = b'A';
+    let sequence = String::from_utf8(sequence).unwrap();
+    fixture.write_inputs(&[&sam("r1", 0, 11, "30M", &sequence, &quals(30))]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:21-30 --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!(read_value(&rows, "r1", "num_matches"), "9");
+    assert_eq!(read_value(&rows, "r1", "num_mismatches"), "1");
+}
+
+#[test]
+fn counts_insertions_and_deletions_in_the_interval() {
+    let fixture = Fixture::new();
+    // Deletes the 3 bases 6-8 and inserts 2 bases after 12
+    let sequence = format!("{}{}GG{}", chr1(1, 5), chr1(9, 4), chr1(13, 5));
+    fixture.write_inputs(&[&sam("r1", 0, 1, "5M3D4M2I5M", &sequence, &quals(16))]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-30 --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!(read_value(&rows, "r1", "num_insertions"), "2");
+    assert_eq!(read_value(&rows, "r1", "num_deletions"), "3");
+    assert_eq!(read_value(&rows, "r1", "num_matches"), "14");
+    assert_eq!(read_value(&rows, "r1", "num_mismatches"), "0");
+}
+
+#[test]
+fn counts_bases_at_ambiguous_reference_bases_apart() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    // An N in place of the G at 3 and the TG at 5-6 soft-masked
+    let reference = format!("ACNTtgCAAG{}", &CHR1[10..]);
+    fixture.write_fasta("ref.fa", &[("chr1", &reference)]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!(read_value(&rows, "r1", "num_matches"), "9");
+    assert_eq!(read_value(&rows, "r1", "num_mismatches"), "0");
+    assert_eq!(read_value(&rows, "r1", "num_ambiguous_ref"), "1");
+}
+
+#[test]
+fn counts_the_bases_of_an_iupac_code_as_matches_unless_strict() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[
+        // A at the R and C at the y, G at the R and T at the y, and bases of neither
+        &sam("r1", 0, 1, "10M", "ACATTCCAAG", &quals(10)),
+        &sam("r2", 0, 1, "10M", "ACGTTTCAAG", &quals(10)),
+        &sam("r3", 0, 1, "10M", "ACCTTGCAAG", &quals(10)),
+    ]);
+    // An R (A or G) in place of the G at 3 and a soft-masked y (C or T) in place of the G at 6
+    let reference = format!("ACRTTyCAAG{}", &CHR1[10..]);
+    fixture.write_fasta("ref.fa", &[("chr1", &reference)]);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-10 --force --output-file out.tsv";
+    let counts = |extra_args: &str| {
+        fixture.bampile_ok(&format!("{} {}", args, extra_args));
+        let rows = tsv(&fixture.read("out.tsv"));
+        ["r1", "r2", "r3"].map(|read_id| {
+            ["num_matches", "num_mismatches", "num_ambiguous_ref"].map(|name| read_value(&rows, read_id, name))
+        })
+    };
+    assert_eq!(counts(""), [["10", "0", "0"], ["10", "0", "0"], ["8", "2", "0"]]);
+    assert_eq!(counts("--strict-iupac"), [["8", "0", "2"], ["8", "0", "2"], ["8", "0", "2"]]);
+}
+
+#[test]
+fn leaves_soft_clipped_bases_out_of_the_matches() {
+    let fixture = Fixture::new();
+    // 5 clipped bases on each end, which mostly differ from the reference bases next to 11-20
+    let sequence = format!("GGGGG{}GGGGG", chr1(11, 10));
+    fixture.write_inputs(&[&sam("r1", 0, 11, "5S10M5S", &sequence, &quals(20))]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-40 --count-softclips --output-file out.tsv");
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!(read_value(&rows, "r1", "num_matches"), "10");
+    assert_eq!(read_value(&rows, "r1", "num_mismatches"), "0");
+    assert_eq!(read_value(&rows, "r1", "num_softclips"), "10");
+}
+
+#[test]
+fn compares_soft_masked_reference_bases_unless_case_sensitive() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    // The TG at 5-6 soft-masked
+    let reference = format!("ACGTtgCAAG{}", &CHR1[10..]);
+    fixture.write_fasta("ref.fa", &[("chr1", &reference)]);
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --output-file default.tsv");
+    let rows = tsv(&fixture.read("default.tsv"));
+    assert_eq!(read_value(&rows, "r1", "num_matches"), "10");
+    assert_eq!(read_value(&rows, "r1", "num_ambiguous_ref"), "0");
+    fixture.bampile_ok("-b reads.bam -f ref.fa -r chr1:1-20 --fasta-case-sensitive --output-file masked.tsv");
+    let rows = tsv(&fixture.read("masked.tsv"));
+    assert_eq!(read_value(&rows, "r1", "num_matches"), "8");
+    assert_eq!(read_value(&rows, "r1", "num_ambiguous_ref"), "2");
+}
+
+#[test]
+fn counts_against_the_md_tags_like_against_the_fasta_file() {
+    let fixture = Fixture::new();
+    let with_md = |line: String, md: &str| format!("{}\tMD:Z:{}", line, md);
+    let reads = [
+        with_md(sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10)), "10"),
+        // A T in place of the G at 3
+        with_md(sam("r2", 16, 1, "10M", "ACTTTGCAAG", &quals(10)), "2G7"),
+        // The G at 6 deleted
+        with_md(sam("r3", 0, 1, "5M1D4M", "ACGTTCAAG", &quals(9)), "5^G4"),
+    ];
+    fixture.write_inputs(&reads.iter().map(String::as_str).collect::<Vec<_>>());
+    for mode in ["per-read", "per-position"] {
+        let args = format!("-b reads.bam -r chr1:1-10 -m {} --no-header-comments --force", mode);
+        fixture.bampile_ok(&format!("{} -f ref.fa --output-file fasta.tsv", args));
+        fixture.bampile_ok(&format!("{} --use-md --output-file md.tsv", args));
+        let mut fasta = tsv(&fixture.read("fasta.tsv"));
+        let mut md = tsv(&fixture.read("md.tsv"));
+        fasta.sort();
+        md.sort();
+        assert!(fasta.len() > 3, "{}", mode);
+        assert_eq!(md, fasta, "{}", mode);
+    }
+    // Every read needs a tag
+    fixture.write_bam("untagged.bam", &[("chr1", CHR1.len())], &[&sam("r4", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    let (_, stderr) = fixture.bampile_err("-b untagged.bam -r chr1:1-10 --use-md --output-file untagged.tsv");
+    assert!(stderr.contains("read r4 has no MD tag, which --use-md needs"), "{}", stderr);
+}
+
+#[test]
+fn leaves_the_trimmed_ends_of_a_read_out_of_the_counts() {
+    let fixture = Fixture::new();
+    // Mismatches at aligned bases 1, 5, 6, 16 and 20 of a read with 3 soft-clipped bases before them
+    let mut aligned = chr1(1, 20).as_bytes().to_vec();
+    for index in [0, 4, 5, 15, 19] {
+        aligned[index] = if aligned[index] == b'A' { b'C' } else { b'A' };
+    }
+    let sequence = format!("GGG{}", String::from_utf8(aligned).unwrap());
+    fixture.write_inputs(&[&sam("r1", 0, 1, "3S20M", &sequence, &quals(23))]);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-30 --force --output-file out.tsv";
+    fixture.bampile_ok(args);
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!([read_value(&rows, "r1", "num_matches"), read_value(&rows, "r1", "num_mismatches")], ["15", "5"]);
+    // Only the aligned bases 6 to 15 are left
+    fixture.bampile_ok(&format!("{} --trim-ends 5", args));
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!([read_value(&rows, "r1", "num_matches"), read_value(&rows, "r1", "num_mismatches")], ["9", "1"]);
+}
+
+#[test]
+fn counts_a_deleted_target_position_as_mismatch_only_on_request() {
+    let fixture = Fixture::new();
+    // Deletes 6-7 of the interval 1-10
+    fixture.write_inputs(&[&sam("r1", 0, 1, "5M2D5M", &format!("{}{}", chr1(1, 5), chr1(8, 5)), &quals(10))]);
+    let args = "-b reads.bam -f ref.fa -r chr1:1-10 --force --output-file out.tsv";
+    let counts = |extra_args: &str| {
+        fixture.bampile_ok(&format!("{} {}", args, extra_args));
+        let rows = tsv(&fixture.read("out.tsv"));
+        ["num_matches", "num_mismatches", "num_deletions"].map(|name| read_value(&rows, "r1", name))
+    };
+    assert_eq!(counts(""), ["8", "0", "2"]);
+    assert_eq!(counts("--count-deletions-as-mismatch"), ["8", "2", "2"]);
+}
+
+#[test]
+fn reports_the_mean_quality_of_the_aligned_bases_in_the_interval() {
+    let fixture = Fixture::new();
+    // Soft-clipped bases of quality 0, then aligned bases of qualities 10, 20, 30, 40, 10, ...
+    let sequence = format!("GG{}", chr1(1, 8));
+    fixture.write_inputs(&[&sam("r1", 0, 1, "2S8M", &sequence, "!!+5?I+5?I")]);
+    let args = "-b reads.bam -f ref.fa --read-quality --force --output-file out.tsv";
+    let columns = |region: &str| {
+        fixture.bampile_ok(&format!("{} -r {}", args, region));
+        let rows = tsv(&fixture.read("out.tsv"));
+        [read_value(&rows, "r1", "aligned_length"), read_value(&rows, "r1", "mean_qual")]
+    };
+    assert_eq!(columns("chr1:1-4"), ["4", "25.00"]);
+    assert_eq!(columns("chr1:3-20"), ["6", "28.33"]);
+}
+
+#[test]
+fn reports_the_mismatches_of_a_read_over_its_covered_positions() {
+    let fixture = Fixture::new();
+    // Mismatches at 3 and 8 and a base of quality 10 at 5, below the cutoff of 30
+    let mut sequence = chr1(1, 10).to_string();
+    sequence.replace_range(2..3, "A");
+    sequence.replace_range(7..8, "T");
+    // Deletes 11-20, so only the deletion overlaps 12-15
+    let deleted = format!("{}{}", chr1(1, 10), chr1(21, 5));
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "10M", &sequence, "IIII+IIIII"),
+        &sam("r2", 0, 1, "10M10D5M", &deleted, &quals(15)),
+    ]);
+    let args = "-b reads.bam -f ref.fa --mismatch-rate --na-string NA --force --output-file out.tsv";
+    fixture.bampile_ok(&format!("{} -r chr1:1-10", args));
+    let rows = tsv(&fixture.read("out.tsv"));
+    let value = |name| read_value(&rows, "r1", name);
+    assert_eq!([value("num_matches"), value("num_mismatches"), value("covered_positions")], ["7", "2", "10"]);
+    let mismatch_rate: f64 = value("mismatch_rate").parse().unwrap();
+    assert_eq!(mismatch_rate, 2.0 / 10.0);
+
+    fixture.bampile_ok(&format!("{} -r chr1:12-15", args));
+    let rows = tsv(&fixture.read("out.tsv"));
+    assert_eq!([read_value(&rows, "r2", "covered_positions"), read_value(&rows, "r2", "mismatch_rate")], ["0", "NA"]);
+}
+
+#[test]
+fn counts_the_matches_of_a_clean_alignment_like_the_full_comparison() {
+    let fixture = Fixture::new();
+    // Clipped, inserted and deleted bases and a base of quality 10 at 8, but no mismatches
+    let r1 = format!("GGG{}TT{}", chr1(1, 20), chr1(21, 10));
+    let mut qualities = quals(35);
+    qualities.replace_range(10..11, "+");
+    let r2 = format!("{}{}", chr1(41, 15), chr1(59, 25));
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "3S20M2I10M", &r1, &qualities),
+        &sam("r2", 16, 41, "15M3D25M", &r2, &quals(40)),
+    ]);
+    let args = "-r chr1:1-100 --na-string NA --force --output-file out.tsv";
+    let counts = |extra_args: &str| {
+        fixture.bampile_ok(&format!("-b reads.bam {} {}", args, extra_args));
+        let rows = tsv(&fixture.read("out.tsv"));
+        ["r1", "r2"]
+            .map(|read_id| [read_value(&rows, read_id, "num_matches"), read_value(&rows, read_id, "num_mismatches")])
+    };
+    let full = counts("-f ref.fa");
+    assert_eq!(full, [["29", "0"], ["40", "0"]]);
+    let expected = full.map(|[matches, _]| [matches, "NA".to_string()]);
+    assert_eq!(counts("-f ref.fa --count-matches-only"), expected);
+    // The FASTA file isn't needed
+    assert_eq!(counts("--count-matches-only"), expected);
+}
+
+#[test]
+fn sums_the_counts_of_a_read_split_between_contigs_on_request() {
+    let fixture = Fixture::new();
+    let chr2: String = CHR1.chars().rev().collect();
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1), ("chr2", &chr2)]);
+    // The first 60 bases of r1 align to chr1, the last 40 to chr2 with a mismatch at their fifth
+    let mut chr2_bases = chr2[..40].to_string();
+    let mismatch = if &chr2_bases[4..5] == "A" { "C" } else { "A" };
+    chr2_bases.replace_range(4..5, mismatch);
+    let primary = sam("r1", 0, 1, "60M40S", &format!("{}{}", chr1(1, 60), chr2_bases), &quals(100));
+    let supplementary = sam("r1", 2048, 1, "60H40M", &chr2_bases, &quals(40)).replacen("\tchr1\t", "\tchr2\t", 1);
+    let other = sam("r2", 0, 11, "20M", chr1(11, 20), &quals(20));
+    fixture.write_bam("reads.bam", &[("chr1", CHR1.len()), ("chr2", chr2.len())], &[&primary, &other, &supplementary]);
+    fixture.write("regions.bed", "chr1\t0\t100\nchr2\t0\t100\n");
+    let args = "-b reads.bam -f ref.fa -e regions.bed -F 0x704 --no-header-comments --force --output-file out.tsv";
+    let rows = |extra_args: &str| {
+        fixture.bampile_ok(&format!("{} {}", args, extra_args));
+        let mut rows: Vec<[String; 4]> = tsv(&fixture.read("out.tsv"))[1..]
+            .iter()
+            .map(|row| [0, 1, 2, 3].map(|column| row[column].clone()))
+            .collect();
+        rows.sort();
+        rows
+    };
+    let owned = |rows: &[[&str; 4]]| rows.iter().map(|row| row.map(String::from)).collect::<Vec<_>>();
+    assert_eq!(rows(""), owned(&[["chr1", "r1", "60", "0"], ["chr1", "r2", "20", "0"], ["chr2", "r1", "39", "1"]]));
+    assert_eq!(
+        rows("--aggregate-read-across-contigs"),
+        owned(&[["chr1", "r2", "20", "0"], ["chr1,chr2", "r1", "99", "1"]])
+    );
+}
@@ -0,0 +1,18 @@
+use crate::fixture::*;
+
+#[test]
+fn logs_progress_instead_of_a_bar_without_a_terminal() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10))]);
+    fixture.write("regions.bed", "chr1\t0\t10\nchr1\t40\t50\n");
+    // The stderr of the tests is a pipe, not a terminal
+    let stderr = fixture.bampile_ok("-b reads.bam -f ref.fa -e regions.bed --keep-intervals --output-file out.tsv");
+    assert!(stderr.contains("processed 2/2 intervals"), "{}", stderr);
+    assert!(!stderr.contains("[="), "{}", stderr);
+
+    for args in ["--quiet", "-vv"] {
+        let stderr = fixture
+            .bampile_ok(&format!("-b reads.bam -f ref.fa -e regions.bed --output-file out.tsv --force {}", args));
+        assert!(!stderr.contains("processed"), "{}: {}", args, stderr);
+    }
+}
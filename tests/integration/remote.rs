@@ -0,0 +1,112 @@
+// Reads BAM files from a local HTTP server, only built with the http-tests feature
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::fixture::*;
+
+// Serves the files of a directory on a free port of localhost and returns the URL of the
+// directory. Range requests get their range only if `ranges` is set, else the whole file.
+fn serve(dir: &str, ranges: bool) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let dir = PathBuf::from(dir);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let dir = dir.clone();
+            thread::spawn(move || serve_connection(stream.unwrap(), &dir, ranges));
+        }
+    });
+    url
+}
+
+// Answers the GET requests of a connection until the client closes it
+fn serve_connection(stream: TcpStream, dir: &Path, ranges: bool) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut range = None;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("range") {
+                    range = value.trim().strip_prefix("bytes=").map(str::to_string);
+                }
+            }
+        }
+        let file_name = request_line.split_whitespace().nth(1).unwrap().trim_start_matches('/');
+        let Ok(contents) = std::fs::read(dir.join(file_name)) else {
+            stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").unwrap();
+            continue;
+        };
+        let (status, extra_header, body) = match range.filter(|_| ranges) {
+            Some(range) => {
+                let (start, end) = range.split_once('-').unwrap();
+                let start: usize = start.parse().unwrap();
+                let end = end.parse::<usize>().map_or(contents.len(), |end| end + 1).min(contents.len());
+                let content_range = format!("Content-Range: bytes {}-{}/{}\r\n", start, end - 1, contents.len());
+                ("206 Partial Content", content_range, &contents[start..end])
+            }
+            None => ("200 OK", String::new(), &contents[..]),
+        };
+        let head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n{}\r\n", status, body.len(), extra_header);
+        if stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(body)).is_err() {
+            return;
+        }
+    }
+}
+
+#[test]
+fn counts_a_remote_bam_like_the_local_file() {
+    let fixture = Fixture::new();
+    fixture.write_inputs(&[
+        &sam("r1", 0, 1, "40M", chr1(1, 40), &quals(40)),
+        &sam("r2", 16, 21, "30M", chr1(21, 30), &quals(30)),
+        &sam("r3", 0, 61, "30M", chr1(61, 30), &quals(30)),
+    ]);
+    let url = serve(&fixture.path(""), true);
+    for mode in ["per-read", "per-position"] {
+        let args = format!("-f ref.fa -r chr1:11-70 -m {} --no-header-comments --force", mode);
+        fixture.bampile_ok(&format!("-b {}/reads.bam {} --output-file remote.tsv", url, args));
+        fixture.bampile_ok(&format!("-b reads.bam {} --output-file local.tsv", args));
+        let mut remote = tsv(&fixture.read("remote.tsv"));
+        let mut local = tsv(&fixture.read("local.tsv"));
+        remote.sort();
+        local.sort();
+        assert!(local.len() > 3, "{}", mode);
+        assert_eq!(remote, local, "{}", mode);
+    }
+}
+
+#[test]
+fn needs_an_index_and_range_requests_for_a_remote_bam() {
+    let fixture = Fixture::new();
+    fixture.write_unindexed_bam(
+        "reads.bam",
+        &[("chr1", CHR1.len())],
+        &[&sam("r1", 0, 1, "10M", chr1(1, 10), &quals(10))],
+    );
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1)]);
+    let url = serve(&fixture.path(""), true);
+    let (code, stderr) =
+        fixture.bampile_err(&format!("-b {}/reads.bam -f ref.fa -r chr1:1-10 --output-file out.tsv", url));
+    assert_eq!(code, 6);
+    assert!(stderr.contains("remote BAM files need a .bai index next to them"), "{}", stderr);
+
+    bampile::index::ensure_bam_index(&fixture.path("reads.bam")).unwrap();
+    let url = serve(&fixture.path(""), false);
+    let (_, stderr) =
+        fixture.bampile_err(&format!("-b {}/reads.bam -f ref.fa -r chr1:1-10 --output-file out.tsv", url));
+    assert!(stderr.contains("does not support range requests"), "{}", stderr);
+}
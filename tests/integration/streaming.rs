@@ -0,0 +1,43 @@
+use bampile::output::{Compression, PositionColumns, PositionCountsWriter};
+use bampile::{Mode, PileupConfig};
+
+use crate::fixture::*;
+
+#[test]
+fn streams_the_tables_of_the_buffered_counts() {
+    let fixture = Fixture::new();
+    let chr2: String = CHR1.chars().rev().collect();
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1), ("chr2", &chr2)]);
+    let chr2_read = format!("r3\t0\tchr2\t11\t60\t15M\t*\t0\t0\t{}\t{}", &chr2[10..25], quals(15));
+    fixture.write_bam(
+        "reads.bam",
+        &[("chr1", CHR1.len()), ("chr2", chr2.len())],
+        &[
+            &sam("r1", 0, 1, "20M", chr1(1, 20), &quals(20)),
+            &sam("r2", 16, 31, "10M", "AAAAAAAAAA", &quals(10)),
+            &chr2_read,
+        ],
+    );
+    fixture.write("regions.bed", "chr1\t0\t15\nchr1\t30\t40\nchr2\t5\t20\n");
+    fixture.bampile_ok(
+        "-b reads.bam -f ref.fa -e regions.bed -m per-position -o out --compression none --no-header-comments",
+    );
+
+    let regions = bampile::load_bed_regions(&fixture.path("regions.bed"), false).unwrap();
+    let mut config = PileupConfig::new(&fixture.path("reads.bam"), &fixture.path("ref.fa"), regions.clone());
+    config.mode = Mode::PerPosition;
+    let summary = bampile::run(&config).unwrap();
+    for contig in ["chr1", "chr2"] {
+        let buffered_path = fixture.path(&format!("{}-buffered.tsv", contig));
+        let columns = PositionColumns::default();
+        let mut writer =
+            PositionCountsWriter::create(&buffered_path, Compression::None, None, columns, 0, false, true).unwrap();
+        let contig_regions: Vec<_> = regions.iter().filter(|region| region.ref_name == contig).cloned().collect();
+        writer.write_contig_samples(contig, &contig_regions, &[("", &summary.position_counts[contig])]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(
+            fixture.read(&format!("out/reads/{}.tsv", contig)),
+            fixture.read(&format!("{}-buffered.tsv", contig))
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use crate::fixture::*;
+
+#[test]
+fn counts_the_same_with_any_number_of_threads() {
+    let fixture = Fixture::new();
+    // 30 reads of 20 bases over chr1, every third one with a mismatch at its fifth base
+    let lines: Vec<String> = (0..30)
+        .map(|i| {
+            let pos = 1 + i * 80 / 30;
+            let mut sequence = chr1(pos, 20).to_string();
+            if i % 3 == 0 {
+                let mismatch = if &sequence[4..5] == "A" { "C" } else { "A" };
+                sequence.replace_range(4..5, mismatch);
+            }
+            sam(&format!("r{}", i), if i % 2 == 0 { 0 } else { 16 }, pos, "20M", &sequence, &quals(20))
+        })
+        .collect();
+    let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+    fixture.write_inputs(&lines);
+    let bed: String = (0..10).map(|i| format!("chr1\t{}\t{}\n", i * 10, i * 10 + 7)).collect();
+    fixture.write("regions.bed", &bed);
+    for mode in ["per-read", "per-position"] {
+        let mut outputs = Vec::new();
+        for threads in [1, 2, 4, 8] {
+            let output_file = format!("{}-{}.tsv", mode, threads);
+            fixture.bampile_ok(&format!(
+                "-b reads.bam -f ref.fa -e regions.bed -m {} --threads {} --output-file {}",
+                mode, threads, output_file
+            ));
+            // Reads are written in no particular order, even with a single thread
+            let mut rows = tsv(&fixture.read(&output_file));
+            rows[1..].sort();
+            outputs.push(rows);
+        }
+        assert!(outputs[0].len() > 10, "{}", mode);
+        for output in &outputs[1..] {
+            assert_eq!(output, &outputs[0], "{}", mode);
+        }
+    }
+}
+
+#[test]
+fn counts_the_same_of_several_samples_and_contigs_with_any_number_of_threads() {
+    let fixture = Fixture::new();
+    let chr2: String = CHR1.chars().rev().collect();
+    fixture.write_fasta("ref.fa", &[("chr1", CHR1), ("chr2", &chr2)]);
+    let contigs = [("chr1", CHR1.len()), ("chr2", chr2.len())];
+    for (sample, mismatch_every) in [("sampleA", 2), ("sampleB", 3)] {
+        // 20 reads of 25 bases on each contig, some of them with a mismatch at their tenth base
+        let mut lines = Vec::new();
+        for (contig, bases) in [("chr1", CHR1), ("chr2", chr2.as_str())] {
+            for i in 0..20 {
+                let pos = 1 + i * 75 / 20;
+                let mut sequence = bases[pos - 1..pos + 24].to_string();
+                if i % mismatch_every == 0 {
+                    let mismatch = if &sequence[9..10] == "A" { "C" } else { "A" };
+                    sequence.replace_range(9..10, mismatch);
+                }
+                let line = sam(&format!("r{}", i), 0, pos, "25M", &sequence, &quals(25));
+                lines.push(line.replacen("\tchr1\t", &format!("\t{}\t", contig), 1));
+            }
+        }
+        fixture.write_bam(&format!("{}.bam", sample), &contigs, &lines.iter().map(String::as_str).collect::<Vec<_>>());
+    }
+    // Overlapping and adjacent intervals on both contigs, whose bases are cached once
+    fixture.write("regions.bed", "chr1\t5\t30\nchr1\t20\t45\nchr1\t45\t60\nchr2\t10\t40\nchr2\t30\t90\n");
+    for mode in ["per-read", "per-position"] {
+        let mut outputs = Vec::new();
+        for threads in [1, 3] {
+            let output_file = format!("{}-{}.tsv", mode, threads);
+            fixture.bampile_ok(&format!(
+                "-b sampleA.bam,sampleB.bam -f ref.fa -e regions.bed -m {} --threads {} --output-file {}",
+                mode, threads, output_file
+            ));
+            let mut rows = tsv(&fixture.read(&output_file));
+            rows[1..].sort();
+            outputs.push(rows);
+        }
+        assert!(outputs[0].len() > 40, "{}", mode);
+        assert!(outputs[0].iter().any(|row| row.contains(&"chr2".to_string())), "{}", mode);
+        assert_eq!(outputs[1], outputs[0], "{}", mode);
+    }
+}